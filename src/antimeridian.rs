@@ -0,0 +1,410 @@
+use osmquadtree::elements::{Bbox, EARTH_WIDTH};
+
+use crate::wkb::{prep_wkb, write_ring, write_uint32};
+use crate::LonLat;
+
+use std::borrow::Borrow;
+use std::io::Result;
+
+//coordinates are stored as integers in units of 1e-7 degrees
+pub const HALF_WORLD: i64 = 1800000000;
+pub const FULL_WORLD: i64 = 3600000000;
+
+//a segment whose longitude jumps by more than 180 degrees is taken to cross
+//the antimeridian, rather than to run the long way round the world
+fn lon_step(prev: i32, curr: i32) -> i64 {
+    let d = curr as i64 - prev as i64;
+    if d > HALF_WORLD {
+        -FULL_WORLD
+    } else if d < -HALF_WORLD {
+        FULL_WORLD
+    } else {
+        0
+    }
+}
+
+//mercator x offset to apply to curr so that the segment from prev is continuous
+pub fn forward_offset(prev: &LonLat, curr: &LonLat) -> f64 {
+    (lon_step(prev.lon, curr.lon) / HALF_WORLD) as f64 * EARTH_WIDTH
+}
+
+pub fn crosses_antimeridian<T: Borrow<LonLat>>(lonlats: &[T]) -> bool {
+    for i in 1..lonlats.len() {
+        if lon_step(lonlats[i - 1].borrow().lon, lonlats[i].borrow().lon) != 0 {
+            return true;
+        }
+    }
+    false
+}
+
+fn wrap_lon(x: i64) -> i32 {
+    if x > HALF_WORLD {
+        (x - FULL_WORLD) as i32
+    } else if x < -HALF_WORLD {
+        (x + FULL_WORLD) as i32
+    } else {
+        x as i32
+    }
+}
+
+//shifts each point by a multiple of 360 degrees so that no segment crosses
+//the antimeridian. The first point is placed within 180 degrees of reference.
+fn unwrap_lonlats<T: Borrow<LonLat>>(lonlats: &[T], reference: i64) -> Vec<(i64, i64)> {
+    let mut res = Vec::with_capacity(lonlats.len());
+    if lonlats.is_empty() {
+        return res;
+    }
+    let f = lonlats[0].borrow();
+    let mut offset = 0;
+    if (f.lon as i64) - reference > HALF_WORLD {
+        offset = -FULL_WORLD;
+    } else if (f.lon as i64) - reference < -HALF_WORLD {
+        offset = FULL_WORLD;
+    }
+    res.push((f.lon as i64 + offset, f.lat as i64));
+    for i in 1..lonlats.len() {
+        let l = lonlats[i].borrow();
+        offset += lon_step(lonlats[i - 1].borrow().lon, l.lon);
+        res.push((l.lon as i64 + offset, l.lat as i64));
+    }
+    res
+}
+
+//as Bbox::expand, except that lines and rings crossing the antimeridian give
+//a box with minlon > maxlon (as in RFC 7946) rather than one spanning the world
+pub fn antimeridian_bounds<T: Borrow<LonLat>>(lonlats: &[T]) -> Bbox {
+    let mut res = Bbox::empty();
+    if !crosses_antimeridian(lonlats) {
+        for l in lonlats {
+            res.expand(l.borrow().lon, l.borrow().lat);
+        }
+        return res;
+    }
+    let uw = unwrap_lonlats(lonlats, 0);
+    let (mut minx, mut maxx) = (i64::MAX, i64::MIN);
+    for (x, y) in &uw {
+        minx = i64::min(minx, *x);
+        maxx = i64::max(maxx, *x);
+        res.minlat = i32::min(res.minlat, *y as i32);
+        res.maxlat = i32::max(res.maxlat, *y as i32);
+    }
+    if maxx - minx >= FULL_WORLD {
+        //circles a pole
+        res.minlon = -HALF_WORLD as i32;
+        res.maxlon = HALF_WORLD as i32;
+    } else {
+        res.minlon = wrap_lon(minx);
+        res.maxlon = wrap_lon(maxx);
+    }
+    res
+}
+
+fn bbox_interval(b: &Bbox) -> (i64, i64) {
+    if b.minlon > b.maxlon {
+        (b.minlon as i64, b.maxlon as i64 + FULL_WORLD)
+    } else {
+        (b.minlon as i64, b.maxlon as i64)
+    }
+}
+
+//smallest box (possibly crossing the antimeridian) containing all of boxes.
+//Used for multipolygons with separate parts either side of 180 degrees.
+pub fn bounds_union(boxes: &[Bbox]) -> Bbox {
+    let mut ints = Vec::with_capacity(boxes.len());
+    let (mut minlat, mut maxlat) = (i32::MAX, i32::MIN);
+    for b in boxes {
+        if b.minlat > b.maxlat {
+            //empty
+            continue;
+        }
+        ints.push(bbox_interval(b));
+        minlat = i32::min(minlat, b.minlat);
+        maxlat = i32::max(maxlat, b.maxlat);
+    }
+    if ints.is_empty() {
+        return Bbox::empty();
+    }
+    ints.sort();
+
+    //the result is the complement of the largest longitude gap between boxes
+    let mut reach = ints[0].1;
+    let mut gap = (0, 0);
+    for (lo, hi) in &ints[1..] {
+        if *lo > reach && lo - reach > gap.1 - gap.0 {
+            gap = (reach, *lo);
+        }
+        reach = i64::max(reach, *hi);
+    }
+    let wrap_gap = ints[0].0 + FULL_WORLD - reach;
+    if wrap_gap <= 0 {
+        return Bbox::new(-HALF_WORLD as i32, minlat, HALF_WORLD as i32, maxlat);
+    }
+    if wrap_gap >= gap.1 - gap.0 {
+        return Bbox::new(wrap_lon(ints[0].0), minlat, wrap_lon(reach), maxlat);
+    }
+    Bbox::new(wrap_lon(gap.1), minlat, wrap_lon(gap.0), maxlat)
+}
+
+//splits a box crossing the antimeridian into its east and west halves
+pub fn bbox_parts(b: &Bbox) -> Vec<Bbox> {
+    if b.minlon > b.maxlon && b.minlat <= b.maxlat {
+        vec![
+            Bbox::new(b.minlon, b.minlat, HALF_WORLD as i32, b.maxlat),
+            Bbox::new(-HALF_WORLD as i32, b.minlat, b.maxlon, b.maxlat),
+        ]
+    } else {
+        vec![b.clone()]
+    }
+}
+
+//quadtree tiles do not wrap, so an object crossing the antimeridian is
+//given the full width of the world, so that its quadtree contains both
+//halves and it is found by queries on either side
+pub fn quadtree_bounds(b: &Bbox) -> Bbox {
+    let mut parts = bbox_parts(b);
+    if parts.len() == 1 {
+        return parts.pop().unwrap();
+    }
+    Bbox::new(-HALF_WORLD as i32, b.minlat, HALF_WORLD as i32, b.maxlat)
+}
+
+fn interpolate_lat(a: (i64, i64), b: (i64, i64), x: i64) -> i64 {
+    if b.0 == a.0 {
+        return a.1;
+    }
+    a.1 + ((b.1 - a.1) as i128 * (x - a.0) as i128 / (b.0 - a.0) as i128) as i64
+}
+
+//splits a line into parts which do not cross the antimeridian. Each new
+//part starts and ends on the meridian itself.
+pub fn split_line_at_antimeridian(lonlats: &[LonLat]) -> Vec<Vec<LonLat>> {
+    let mut res = Vec::new();
+    if lonlats.is_empty() {
+        return res;
+    }
+    let mut curr = vec![lonlats[0].clone()];
+    for i in 1..lonlats.len() {
+        let p = &lonlats[i - 1];
+        let q = &lonlats[i];
+        let s = lon_step(p.lon, q.lon);
+        if s != 0 {
+            let a = (p.lon as i64, p.lat as i64);
+            let b = (q.lon as i64 + s, q.lat as i64);
+            let x = if s > 0 { HALF_WORLD } else { -HALF_WORLD };
+            let y = interpolate_lat(a, b, x) as i32;
+            curr.push(LonLat::new(x as i32, y));
+            res.push(std::mem::take(&mut curr));
+            curr.push(LonLat::new(-x as i32, y));
+        }
+        curr.push(q.clone());
+    }
+    res.push(curr);
+    res
+}
+
+//sutherland-hodgman against the half plane x>=bound (or x<=bound)
+fn clip_ring(ring: &[(i64, i64)], bound: i64, keep_greater: bool) -> Vec<(i64, i64)> {
+    let inside = |p: &(i64, i64)| {
+        if keep_greater {
+            p.0 >= bound
+        } else {
+            p.0 <= bound
+        }
+    };
+    let mut res = Vec::new();
+    if ring.is_empty() {
+        return res;
+    }
+    let n = ring.len();
+    for i in 0..n {
+        let curr = ring[i];
+        let prev = ring[(i + n - 1) % n];
+        match (inside(&prev), inside(&curr)) {
+            (true, true) => res.push(curr),
+            (true, false) => res.push((bound, interpolate_lat(prev, curr, bound))),
+            (false, true) => {
+                res.push((bound, interpolate_lat(prev, curr, bound)));
+                res.push(curr);
+            }
+            (false, false) => {}
+        }
+    }
+    res.dedup();
+    res
+}
+
+fn clip_strip(ring: &[(i64, i64)], lo: i64, hi: i64) -> Option<Vec<LonLat>> {
+    //drop the closing point while clipping
+    let open = if ring.len() > 1 && ring[0] == ring[ring.len() - 1] {
+        &ring[..ring.len() - 1]
+    } else {
+        ring
+    };
+    let c = clip_ring(&clip_ring(open, lo, true), hi, false);
+    if c.len() < 3 {
+        return None;
+    }
+    let shift = lo + HALF_WORLD;
+    let mut res: Vec<LonLat> = c
+        .iter()
+        .map(|(x, y)| LonLat::new((x - shift) as i32, *y as i32))
+        .collect();
+    res.push(res[0].clone());
+    Some(res)
+}
+
+//rings is an exterior followed by its holes. Returns one or more polygons,
+//each an exterior and holes, none of which cross the antimeridian.
+pub fn split_polygon_at_antimeridian(rings: &[Vec<LonLat>]) -> Vec<Vec<Vec<LonLat>>> {
+    let mut res = Vec::new();
+    if rings.is_empty() || rings[0].is_empty() {
+        return res;
+    }
+    let reference = rings[0][0].lon as i64;
+    let uw: Vec<Vec<(i64, i64)>> = rings.iter().map(|r| unwrap_lonlats(r, reference)).collect();
+
+    for k in -1..=1 {
+        let lo = -HALF_WORLD + k * FULL_WORLD;
+        let hi = HALF_WORLD + k * FULL_WORLD;
+        match clip_strip(&uw[0], lo, hi) {
+            None => {}
+            Some(ext) => {
+                let mut poly = vec![ext];
+                for ii in &uw[1..] {
+                    match clip_strip(ii, lo, hi) {
+                        None => {}
+                        Some(r) => {
+                            poly.push(r);
+                        }
+                    }
+                }
+                res.push(poly);
+            }
+        }
+    }
+    res
+}
+
+pub fn polygon_crosses_antimeridian(rings: &[Vec<LonLat>]) -> bool {
+    rings.iter().any(|r| crosses_antimeridian(r))
+}
+
+pub fn multilinestring_wkb(parts: &[Vec<LonLat>], transform: bool, with_srid: bool) -> Result<Vec<u8>> {
+    let mut res = prep_wkb(transform, with_srid, 5, 4)?;
+    write_uint32(&mut res, parts.len() as u32)?;
+    for p in parts {
+        res.extend(prep_wkb(transform, false, 2, 4 + 16 * p.len())?);
+        write_ring(&mut res, p.len(), p.iter().map(|l| l.to_xy(transform)))?;
+    }
+    Ok(res)
+}
+
+fn polygon_wkb(res: &mut Vec<u8>, rings: &[Vec<LonLat>], transform: bool) -> Result<()> {
+    write_uint32(res, rings.len() as u32)?;
+    for r in rings {
+        write_ring(res, r.len(), r.iter().map(|l| l.to_xy(transform)))?;
+    }
+    Ok(())
+}
+
+//writes a polygon if polys has one entry, otherwise a multipolygon
+pub fn multipolygon_wkb(polys: &[Vec<Vec<LonLat>>], transform: bool, with_srid: bool) -> Result<Vec<u8>> {
+    if polys.len() == 1 {
+        let mut res = prep_wkb(transform, with_srid, 3, 0)?;
+        polygon_wkb(&mut res, &polys[0], transform)?;
+        return Ok(res);
+    }
+    let mut res = prep_wkb(transform, with_srid, 6, 4)?;
+    write_uint32(&mut res, polys.len() as u32)?;
+    for p in polys {
+        res.extend(prep_wkb(transform, false, 3, 0)?);
+        polygon_wkb(&mut res, p, transform)?;
+    }
+    Ok(res)
+}
+
+pub fn polygon_coordinates(
+    rings: &Vec<Vec<LonLat>>,
+    is_reversed: bool,
+    transform: bool,
+) -> Vec<Vec<(f64, f64)>> {
+    let mut res = Vec::with_capacity(rings.len());
+    for r in rings {
+        let mut cc: Vec<(f64, f64)> = r
+            .iter()
+            .map(|l| {
+                let xy = l.to_xy(transform);
+                (xy.x, xy.y)
+            })
+            .collect();
+        if is_reversed {
+            cc.reverse();
+        }
+        res.push(cc);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //1e-7 degree units
+    fn ll(lon: f64, lat: f64) -> LonLat {
+        LonLat::new((lon * 1e7).round() as i32, (lat * 1e7).round() as i32)
+    }
+
+    #[test]
+    fn forward_offset_across_antimeridian() {
+        assert_eq!(forward_offset(&ll(179.0, 0.0), &ll(-179.0, 0.0)), 2.0 * EARTH_WIDTH);
+        assert_eq!(forward_offset(&ll(-179.0, 0.0), &ll(179.0, 0.0)), -2.0 * EARTH_WIDTH);
+        assert_eq!(forward_offset(&ll(-90.0, 0.0), &ll(89.0, 0.0)), 0.0);
+        assert_eq!(forward_offset(&ll(10.0, 0.0), &ll(11.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn bounds_across_antimeridian() {
+        let line = vec![ll(179.0, 0.0), ll(-179.0, 10.0)];
+        assert!(crosses_antimeridian(&line));
+        let b = antimeridian_bounds(&line);
+        assert_eq!((b.minlon, b.minlat, b.maxlon, b.maxlat), (1790000000, 0, -1790000000, 100000000));
+        let parts = bbox_parts(&b);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].maxlon, HALF_WORLD as i32);
+        assert_eq!(parts[1].minlon, -HALF_WORLD as i32);
+        let q = quadtree_bounds(&b);
+        assert_eq!((q.minlon, q.maxlon), (-HALF_WORLD as i32, HALF_WORLD as i32));
+
+        let u = bounds_union(&[Bbox::new(1700000000, 0, 1790000000, 10), Bbox::new(-1790000000, 0, -1700000000, 10)]);
+        assert_eq!((u.minlon, u.maxlon), (1700000000, -1700000000));
+    }
+
+    #[test]
+    fn split_line() {
+        let parts = split_line_at_antimeridian(&[ll(179.0, 0.0), ll(-179.0, 10.0), ll(-178.0, 10.0)]);
+        assert_eq!(
+            parts,
+            vec![
+                vec![ll(179.0, 0.0), ll(180.0, 5.0)],
+                vec![ll(-180.0, 5.0), ll(-179.0, 10.0), ll(-178.0, 10.0)],
+            ]
+        );
+        assert_eq!(split_line_at_antimeridian(&[ll(1.0, 1.0), ll(2.0, 2.0)]).len(), 1);
+    }
+
+    #[test]
+    fn split_polygon() {
+        let ring = vec![ll(179.0, 0.0), ll(-179.0, 0.0), ll(-179.0, 1.0), ll(179.0, 1.0), ll(179.0, 0.0)];
+        assert!(polygon_crosses_antimeridian(&[ring.clone()]));
+        let polys = split_polygon_at_antimeridian(&[ring]);
+        assert_eq!(polys.len(), 2);
+        for p in &polys {
+            assert_eq!(p.len(), 1);
+            let r = &p[0];
+            assert_eq!(r[0], r[r.len() - 1]);
+            assert!(!crosses_antimeridian(r));
+        }
+        assert!(polys[0][0].iter().all(|l| l.lon >= 1790000000));
+        assert!(polys[1][0].iter().all(|l| l.lon <= -1790000000));
+    }
+}
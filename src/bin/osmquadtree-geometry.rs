@@ -4,8 +4,11 @@ use clap::{value_t, App, AppSettings, Arg, SubCommand};
 
 //use osmquadtree::utils::{parse_timestamp, LogTimes};
 
-use osmquadtree_geometry::postgresql::{PostgresqlConnection, PostgresqlOptions,prepare_tables};
-use osmquadtree_geometry::{GeometryStyle, OutputType};
+use osmquadtree_geometry::postgresql::{
+    import_osm2pgsql_style, make_table_spec, read_table_spec, write_table_spec, PostgresqlConnection, PostgresqlOptions, prepare_tables,
+};
+use osmquadtree_geometry::serve::{serve_geometry, ServeOptions};
+use osmquadtree_geometry::{check_geometry_file, issues_to_text, Compression, CustomStages, GeometryPackOptions, GeometryStyle, IssueLevel, OutputType, ProcessGeometryOptions, ValidateMode};
 
 use osmquadtree::message;
 use osmquadtree::defaultlogger::register_messenger_default;
@@ -23,19 +26,257 @@ fn process_geometry(
     style_name: Option<&str>,
     max_minzoom: Option<i64>,
     numchan: usize,
+    error_report: Option<&str>,
+    validate: Option<&str>,
+    pack_options: Option<&str>,
+    compression: Option<&str>,
+    table_spec: Option<&str>,
 ) -> Result<()> {
-    osmquadtree_geometry::process_geometry(prfx, outfn, filter, timestamp, find_minzoom, style_name, max_minzoom, numchan)?;
+    let validate = match validate {
+        None => None,
+        Some(v) => Some(ValidateMode::from_str(v)?),
+    };
+    let pack_options = match pack_options {
+        None => None,
+        Some(p) => Some(GeometryPackOptions::from_list(p)?),
+    };
+    let compression = match compression {
+        None => None,
+        Some(c) => Some(Compression::from_str(c)?),
+    };
+    let mut outfn = outfn;
+    if let OutputType::Postgresql(po) = &mut outfn {
+        //the options are made before the style is loaded
+        if let Some(t) = table_spec {
+            po.table_spec = read_table_spec(t)?;
+        } else if let Some(s) = style_name {
+            po.table_spec = make_table_spec(&GeometryStyle::from_file(s)?, po.extended);
+        }
+        if let Some(c) = compression {
+            po.compression = c;
+        }
+    }
+    let options = ProcessGeometryOptions {
+        error_report: error_report.map(String::from),
+        validate: validate,
+        pack_options: pack_options,
+        compression: compression,
+        stages: CustomStages::new(),
+    };
+    osmquadtree_geometry::process_geometry(prfx, outfn, filter, timestamp, find_minzoom, style_name, max_minzoom, numchan, options)?;
     Ok(())
 }
 
+fn convert_geometry(
+    infn: &str,
+    format: &str,
+    outfn: Option<&str>,
+    connection: Option<&str>,
+    table_prefix: Option<&str>,
+    extended: bool,
+    exec_indices: bool,
+    filter: Option<&str>,
+    max_minzoom: Option<i64>,
+    numchan: usize,
+    pack_options: Option<&str>,
+    compression: Option<&str>,
+    tag_filter: Option<&str>,
+    geometry_types: Option<&str>,
+    table_spec: Option<&str>,
+) -> Result<()> {
+    let get_outfn = || match outfn {
+        Some(o) => Ok(String::from(o)),
+        None => Err(Error::UserSelectionError(format!("convert_geometry {} requires OUTFN", format))),
+    };
+    let pack_options = match pack_options {
+        None => None,
+        Some(p) => Some(GeometryPackOptions::from_list(p)?),
+    };
+    let compression = match compression {
+        None => None,
+        Some(c) => Some(Compression::from_str(c)?),
+    };
+    let copy_compression = compression.unwrap_or_default();
+    let mut table_spec = match table_spec {
+        None => None,
+        Some(t) => Some(read_table_spec(t)?),
+    };
+    //the table spec follows the style the input was written with
+    let style = match check_geometry_file(infn)? {
+        Some(h) => h.style()?.unwrap_or_else(GeometryStyle::default),
+        None => GeometryStyle::default(),
+    };
+    let mut make_options = |pc: PostgresqlConnection| {
+        let mut po = if extended {
+            PostgresqlOptions::extended(pc, &style)
+        } else {
+            PostgresqlOptions::osm2pgsql(pc, &style)
+        };
+        if let Some(t) = table_spec.take() {
+            po.table_spec = t;
+        }
+        po.compression = copy_compression;
+        po
+    };
+    
+    let ot = match format {
+        "json" => OutputType::Json(get_outfn()?),
+        "tiled_json" => OutputType::TiledJson(get_outfn()?),
+        "pbffile" => OutputType::PbfFile(get_outfn()?),
+        "pbffile_sorted" => OutputType::PbfFileSorted(get_outfn()?),
+        "postgresqlnull" => OutputType::Postgresql(make_options(PostgresqlConnection::Null)),
+        "postgresqlblob" => OutputType::Postgresql(make_options(PostgresqlConnection::CopyFilePrfx(get_outfn()?))),
+        "postgresqlblob_pbf" => OutputType::Postgresql(make_options(PostgresqlConnection::CopyFileBlob(get_outfn()?))),
+        "postgresql" => {
+            match (connection, table_prefix) {
+                (Some(c), Some(t)) => {
+                    let pc = PostgresqlConnection::Connection((String::from(c), String::from(t), exec_indices));
+                    let mut po = make_options(pc);
+                    if exec_indices {
+                        po.planet_osm_views = true;
+                        po.lowzoom = Some(vec![("lz6_".to_string(), 6, true), ("lz9_".to_string(), 9, false), ("lz11_".to_string(), 11, false)]);
+                    }
+                    OutputType::Postgresql(po)
+                },
+                _ => {
+                    return Err(Error::UserSelectionError(format!("convert_geometry postgresql requires CONNECTION and TABLE_PREFIX")));
+                }
+            }
+        },
+        _ => {
+            return Err(Error::UserSelectionError(format!("unknown format {}", format)));
+        }
+    };
+    
+    osmquadtree_geometry::convert_geometry(infn, ot, filter, max_minzoom, numchan, pack_options, compression, tag_filter, geometry_types)?;
+    Ok(())
+}
+fn split_geometry(infn: &str, targets: Option<Vec<&str>>, prfx: Option<&str>, depth: Option<usize>, numchan: usize) -> Result<()> {
+    let outfns = match (targets, prfx, depth) {
+        (Some(tt), None, None) => {
+            let mut targets = Vec::with_capacity(tt.len());
+            for t in tt {
+                match t.split_once('=') {
+                    Some((o, f)) => targets.push((String::from(o), String::from(f))),
+                    None => {
+                        return Err(Error::UserSelectionError(format!("expected OUTFN=FILTER, not {}", t)));
+                    }
+                }
+            }
+            osmquadtree_geometry::split_geometry_filters(infn, &targets, numchan)?
+        },
+        (None, Some(p), Some(d)) => osmquadtree_geometry::split_geometry_quadtree(infn, p, d, numchan)?,
+        _ => {
+            return Err(Error::UserSelectionError(format!("split_geometry requires either TARGETS or PREFIX and DEPTH")));
+        }
+    };
+    message!("wrote {}", outfns.join(", "));
+    Ok(())
+}
+
+fn inspect_geometry(infn: &str, outfn: Option<&str>, json: bool, top: Option<usize>, numchan: usize) -> Result<()> {
+    let res = osmquadtree_geometry::inspect_geometry(infn, top.unwrap_or(20), numchan)?;
+    let json = json || outfn.map_or(false, |o| o.ends_with(".json"));
+    let s = if json {
+        serde_json::to_string_pretty(&res.to_json())? + "\n"
+    } else {
+        res.to_text()
+    };
+    match outfn {
+        None => message!("{}", s),
+        Some(o) => std::fs::write(o, &s)?,
+    }
+    Ok(())
+}
+
+fn serve(
+    infn: &str,
+    addr: &str,
+    style_name: Option<&str>,
+    extended: bool,
+    table_spec: Option<&str>,
+    cache_size: Option<usize>,
+    max_zoom: Option<u32>,
+    numchan: usize,
+) -> Result<()> {
+    let style = match style_name {
+        None => GeometryStyle::default(),
+        Some(fname) => GeometryStyle::from_file(&fname)?,
+    };
+    let mut options = ServeOptions::new(addr, infn, &style, extended);
+    if let Some(t) = table_spec {
+        options.table_spec = read_table_spec(t)?;
+    }
+    if let Some(c) = cache_size {
+        options.cache_size = c;
+    }
+    if let Some(m) = max_zoom {
+        options.max_zoom = m;
+    }
+    options.num_workers = numchan;
+    serve_geometry(infn, options, numchan)
+}
+
+fn import_style(infn: &str, style_outfn: &str, table_spec_outfn: &str, extended: bool) -> Result<()> {
+    let (style, table_spec) = import_osm2pgsql_style(infn, extended)?;
+    let mut f = std::fs::File::create(style_outfn)?;
+    serde_json::to_writer_pretty(&mut f, &style)?;
+    write_table_spec(table_spec_outfn, &table_spec)?;
+    message!("wrote style to {} and table spec to {}", style_outfn, table_spec_outfn);
+    Ok(())
+}
+
+fn validate_style(
+    infn: &str,
+    table_spec_fn: Option<&str>,
+    sample: Option<&str>,
+    num_blocks: Option<usize>,
+    outfn: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let style = osmquadtree_geometry::read_style_unchecked(infn)?;
+    let table_spec = match table_spec_fn {
+        None => None,
+        Some(t) => Some(read_table_spec(t)?),
+    };
+    let issues = osmquadtree_geometry::validate_style(&style, table_spec.as_ref());
+    let num_errors = issues.iter().filter(|i| i.level == IssueLevel::Error).count();
+
+    let dry_run = match sample {
+        None => None,
+        Some(prfx) => Some(osmquadtree_geometry::style_dry_run(style, table_spec.as_ref(), prfx, num_blocks.unwrap_or(1))?),
+    };
+
+    let json = json || outfn.map_or(false, |o| o.ends_with(".json"));
+    let s = if json {
+        let mut v = serde_json::json!({"issues": issues});
+        if let Some(d) = &dry_run {
+            v["dry_run"] = d.to_json();
+        }
+        serde_json::to_string_pretty(&v)? + "\n"
+    } else {
+        let mut s = issues_to_text(&issues);
+        if let Some(d) = &dry_run {
+            s += &d.to_text();
+        }
+        s
+    };
+    match outfn {
+        None => message!("{}", s),
+        Some(o) => std::fs::write(o, &s)?,
+    }
+    if num_errors > 0 {
+        return Err(Error::InvalidDataError(format!("{}: {} errors", infn, num_errors)));
+    }
+    Ok(())
+}
 
-fn dump_geometry_style(outfn: Option<&str>) -> Result<()> {
+fn dump_geometry_style(outfn: Option<&str>, style_name: Option<&str>) -> Result<()> {
     let outfn = match outfn {
         Some(o) => String::from(o),
         None => String::from("default_style.json"),
     };
-    let mut f = std::fs::File::create(&outfn)?;
-    serde_json::to_writer_pretty(&mut f, &GeometryStyle::default())?;
+    osmquadtree_geometry::dump_geometry_style(&outfn, style_name)?;
     Ok(())
 }
 
@@ -46,6 +287,17 @@ fn get_i64(x: Option<&str>) -> Option<i64> {
     }
 }
 
+//an optional numeric argument, which is an error if it can't be parsed
+fn get_opt<T: std::str::FromStr>(name: &str, x: Option<&str>) -> Result<Option<T>> {
+    match x {
+        None => Ok(None),
+        Some(t) => match t.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(Error::UserSelectionError(format!("invalid value for {}: {}", name, t))),
+        },
+    }
+}
+
 //const NUMCHAN_DEFAULT: usize = 4;
 /*const RAM_GB_DEFAULT: usize= 8;
 const QT_MAX_LEVEL_DEFAULT: usize = 18;
@@ -73,8 +325,10 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_json")
@@ -84,9 +338,11 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_tiled_json")
@@ -96,9 +352,11 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_pbffile")
@@ -108,10 +366,14 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
                 .arg(Arg::with_name("SORT").short("-S").long("--sort").help("sort out pbffile"))
+                .arg(Arg::with_name("PACK_FIELDS").short("-P").long("--packfields").takes_value(true).help("optional fields to keep: comma separated list of refs,version,timestamp,changeset,user, or all or none [default all]"))
+                .arg(Arg::with_name("COMPRESSION").short("-Z").long("--compression").takes_value(true).help("none, zlib[:level], zstd[:level] or lz4 [default zlib:6]"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_postgresqlnull")
@@ -120,10 +382,13 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style [default from style]"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_postgresqlblob")
@@ -133,10 +398,14 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style [default from style]"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
+                .arg(Arg::with_name("COMPRESSION").short("-Z").long("--compression").takes_value(true).help("none, zlib[:level], zstd[:level] or lz4 [default zlib:6]"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_postgresqlblob_pbf")
@@ -146,10 +415,14 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style [default from style]"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
+                .arg(Arg::with_name("COMPRESSION").short("-Z").long("--compression").takes_value(true).help("none, zlib[:level], zstd[:level] or lz4 [default zlib:6]"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
         )
         .subcommand(
             SubCommand::with_name("process_geometry_postgresql")
@@ -160,15 +433,101 @@ fn main() {
                 .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
                 .arg(Arg::with_name("TIMESTAMP").short("-t").long("--timestamp").takes_value(true).help("timestamp for data"))
                 .arg(Arg::with_name("FIND_MINZOOM").short("-m").long("--minzoom").help("find minzoom"))
-                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename. merge_lines in the style only joins lines within each quadtree block"))
                 .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style [default from style]"))
                 .arg(Arg::with_name("EXEC_INDICES").short("-I").long("--exec_inidices").help("execute indices [can be very slow for planet imports]"))
                 .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
                 .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+                .arg(Arg::with_name("ERRORS").short("-E").long("--errors").takes_value(true).help("writes multipolygon errors to geojson (or csv) file"))
+                .arg(Arg::with_name("VALIDATE").short("-V").long("--validate").takes_value(true).possible_values(&["repair", "flag"]).help("validates polygons, and repairs or flags invalid objects"))
+        )
+        .subcommand(
+            SubCommand::with_name("convert_geometry")
+                .about("converts a geometry pbffile to another output format")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input geometry pbffile to use"))
+                .arg(Arg::with_name("FORMAT").short("-F").long("--format").required(true).takes_value(true)
+                    .possible_values(&["json", "tiled_json", "pbffile", "pbffile_sorted", "postgresqlnull", "postgresqlblob", "postgresqlblob_pbf", "postgresql"])
+                    .help("output format"))
+                .arg(Arg::with_name("OUTFN").short("-o").long("--outfn").takes_value(true).help("out filename, "))
+                .arg(Arg::with_name("CONNECTION").short("-c").long("--connection").takes_value(true).help("connection string"))
+                .arg(Arg::with_name("TABLE_PREFIX").short("-p").long("--tableprefix").takes_value(true).help("table prfx"))
+                .arg(Arg::allow_hyphen_values(Arg::with_name("FILTER").short("-f").long("--filter").takes_value(true).help("filters blocks by bbox FILTER"),true))
+                .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+                .arg(Arg::with_name("EXEC_INDICES").short("-I").long("--exec_inidices").help("execute indices [can be very slow for planet imports]"))
+                .arg(Arg::with_name("MAX_MINZOOM").short("-M").long("--maxminzoom").takes_value(true).help("maximum minzoom value"))
+                .arg(Arg::with_name("PACK_FIELDS").short("-P").long("--packfields").takes_value(true).help("optional fields to keep: comma separated list of refs,version,timestamp,changeset,user, or all or none [default same as input]"))
+                .arg(Arg::with_name("COMPRESSION").short("-Z").long("--compression").takes_value(true).help("none, zlib[:level], zstd[:level] or lz4 [default zlib:6, or as input for pbffile output]"))
+                .arg(Arg::with_name("TAG_FILTER").short("-T").long("--tagfilter").takes_value(true).help("keeps objects matching tag expression, eg \"highway in (primary,secondary) and not tunnel=yes\""))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style"))
+                .arg(Arg::with_name("GEOMETRY_TYPES").short("-G").long("--geometrytypes").takes_value(true).help("comma separated list of point, linestring, polygon, simple_polygon and complicated_polygon [default all]"))
+                .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+        )
+        .subcommand(
+            SubCommand::with_name("merge_geometry")
+                .about("merges geometry pbffiles into one sorted file, dropping duplicate objects")
+                .arg(Arg::with_name("INPUTS").required(true).multiple(true).help("Sets the input geometry pbffiles to use"))
+                .arg(Arg::with_name("OUTFN").short("-o").long("--outfn").required(true).takes_value(true).help("out filename, "))
+                .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+        )
+        .subcommand(
+            SubCommand::with_name("split_geometry")
+                .about("splits a geometry pbffile by bbox or poly filters, or by quadtree")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input geometry pbffile to use"))
+                .arg(Arg::allow_hyphen_values(Arg::with_name("TARGETS").short("-t").long("--targets").takes_value(true).multiple(true).help("OUTFN=FILTER pairs, where FILTER is a bbox or poly filename"),true))
+                .arg(Arg::with_name("PREFIX").short("-p").long("--prefix").takes_value(true).help("out filename prefix, for splitting by quadtree"))
+                .arg(Arg::with_name("DEPTH").short("-d").long("--depth").takes_value(true).help("quadtree depth to split at"))
+                .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+        )
+        .subcommand(
+            SubCommand::with_name("index_geometry")
+                .about("writes a sidecar index for a geometry pbffile")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input geometry pbffile to use"))
+                .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+        )
+        .subcommand(
+            SubCommand::with_name("inspect_geometry")
+                .about("summarises the contents of a geometry pbffile")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input geometry pbffile to use"))
+                .arg(Arg::with_name("OUTFN").short("-o").long("--outfn").takes_value(true).help("writes summary to txt (or json) file"))
+                .arg(Arg::with_name("JSON").short("-j").long("--json").help("summary as json"))
+                .arg(Arg::with_name("TOP").short("-N").long("--top").takes_value(true).help("number of keys, values and largest objects to show [default 20]"))
+                .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads"))
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("serves mvt and geojson tiles from a sorted geometry pbffile")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input geometry pbffile to use"))
+                .arg(Arg::with_name("ADDR").short("-a").long("--addr").takes_value(true).help("address to listen on [default 127.0.0.1:8080]"))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("style json filename, used for the layer table spec"))
+                .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style [default from style]"))
+                .arg(Arg::with_name("CACHE_SIZE").short("-C").long("--cachesize").takes_value(true).help("number of tiles to cache [default 1000]"))
+                .arg(Arg::with_name("MAX_ZOOM").short("-z").long("--maxzoom").takes_value(true).help("maxzoom given in tilejson, and the highest zoom served (at most 24) [default 14]"))
+                .arg(Arg::with_name("NUMCHAN").short("-n").long("--numchan").takes_value(true).help("uses NUMCHAN parallel threads to build index, and to answer requests"))
+        )
+        .subcommand(
+            SubCommand::with_name("import_osm2pgsql_style")
+                .about("converts an osm2pgsql .style file to a geometry style and table spec")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input osm2pgsql style file to use"))
+                .arg(Arg::with_name("STYLE_OUTFN").short("-s").long("--style").required(true).takes_value(true).help("style json out filename"))
+                .arg(Arg::with_name("TABLE_SPEC_OUTFN").short("-t").long("--tablespec").required(true).takes_value(true).help("table spec json out filename"))
+                .arg(Arg::with_name("EXTENDED").short("-e").long("--extended").help("extended table spec"))
+        )
+        .subcommand(
+            SubCommand::with_name("validate_style")
+                .about("checks a geometry style for keys which can't have the intended effect")
+                .arg(Arg::with_name("INPUT").required(true).help("Sets the input style json file to use"))
+                .arg(Arg::with_name("TABLE_SPEC").short("-S").long("--tablespec").takes_value(true).help("table spec json file, as written by import_osm2pgsql_style [default from style]"))
+                .arg(Arg::with_name("SAMPLE").short("-i").long("--sample").takes_value(true).help("shows which columns the tags of SAMPLE are written to"))
+                .arg(Arg::with_name("NUM_BLOCKS").short("-b").long("--numblocks").takes_value(true).help("number of SAMPLE blocks to read [default 1]"))
+                .arg(Arg::with_name("OUTFN").short("-o").long("--outfn").takes_value(true).help("writes report to txt (or json) file"))
+                .arg(Arg::with_name("JSON").short("-j").long("--json").help("report as json"))
         )
         .subcommand(
             SubCommand::with_name("dump_geometry_style")
                 .arg(Arg::with_name("OUTPUT").required(true))
+                .arg(Arg::with_name("STYLE_NAME").short("-s").long("--style").takes_value(true).help("writes style json file, with extends resolved, rather than the default style"))
         )
         .subcommand(
             SubCommand::with_name("show_after_queries")
@@ -192,6 +551,11 @@ fn main() {
             geom.value_of("STYLE_NAME"),
             get_i64(geom.value_of("MAX_MINZOOM")),
             value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+            geom.value_of("ERRORS"),
+            geom.value_of("VALIDATE"),
+            None,
+            None,
+            None,
         ),
         ("process_geometry_json", Some(geom)) => process_geometry(
             geom.value_of("INPUT").unwrap(),
@@ -202,6 +566,11 @@ fn main() {
             geom.value_of("STYLE_NAME"),
             get_i64(geom.value_of("MAX_MINZOOM")),
             value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+            geom.value_of("ERRORS"),
+            geom.value_of("VALIDATE"),
+            None,
+            None,
+            None,
         ),
         ("process_geometry_tiled_json", Some(geom)) => process_geometry(
             geom.value_of("INPUT").unwrap(),
@@ -212,6 +581,11 @@ fn main() {
             geom.value_of("STYLE_NAME"),
             get_i64(geom.value_of("MAX_MINZOOM")),
             value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+            geom.value_of("ERRORS"),
+            geom.value_of("VALIDATE"),
+            None,
+            None,
+            None,
         ),
         ("process_geometry_pbffile", Some(geom)) => {
             
@@ -230,6 +604,11 @@ fn main() {
                 geom.value_of("STYLE_NAME"),
                 get_i64(geom.value_of("MAX_MINZOOM")),
                 value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+                geom.value_of("ERRORS"),
+                geom.value_of("VALIDATE"),
+                geom.value_of("PACK_FIELDS"),
+                geom.value_of("COMPRESSION"),
+                None,
             )
         },
         ("process_geometry_postgresqlnull", Some(geom)) => {
@@ -252,6 +631,11 @@ fn main() {
                     geom.value_of("STYLE_NAME"),
                     get_i64(geom.value_of("MAX_MINZOOM")),
                     value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+                    geom.value_of("ERRORS"),
+                    geom.value_of("VALIDATE"),
+                    None,
+                    None,
+                    geom.value_of("TABLE_SPEC"),
                 )
             }
         }
@@ -276,6 +660,11 @@ fn main() {
                     geom.value_of("STYLE_NAME"),
                     get_i64(geom.value_of("MAX_MINZOOM")),
                     value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+                    geom.value_of("ERRORS"),
+                    geom.value_of("VALIDATE"),
+                    None,
+                    geom.value_of("COMPRESSION"),
+                    geom.value_of("TABLE_SPEC"),
                 )
             }
         }
@@ -300,6 +689,11 @@ fn main() {
                     geom.value_of("STYLE_NAME"),
                     get_i64(geom.value_of("MAX_MINZOOM")),
                     value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+                    geom.value_of("ERRORS"),
+                    geom.value_of("VALIDATE"),
+                    None,
+                    geom.value_of("COMPRESSION"),
+                    geom.value_of("TABLE_SPEC"),
                 )
             }
         }
@@ -332,10 +726,85 @@ fn main() {
                     geom.value_of("STYLE_NAME"),
                     get_i64(geom.value_of("MAX_MINZOOM")),
                     value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+                    geom.value_of("ERRORS"),
+                    geom.value_of("VALIDATE"),
+                    None,
+                    None,
+                    geom.value_of("TABLE_SPEC"),
                 )
             }
         }
-        ("dump_geometry_style", Some(geom)) => dump_geometry_style(geom.value_of("OUTPUT")),
+        ("convert_geometry", Some(geom)) => convert_geometry(
+            geom.value_of("INPUT").unwrap(),
+            geom.value_of("FORMAT").unwrap(),
+            geom.value_of("OUTFN"),
+            geom.value_of("CONNECTION"),
+            geom.value_of("TABLE_PREFIX"),
+            geom.is_present("EXTENDED"),
+            geom.is_present("EXEC_INDICES"),
+            geom.value_of("FILTER"),
+            get_i64(geom.value_of("MAX_MINZOOM")),
+            value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+            geom.value_of("PACK_FIELDS"),
+            geom.value_of("COMPRESSION"),
+            geom.value_of("TAG_FILTER"),
+            geom.value_of("GEOMETRY_TYPES"),
+            geom.value_of("TABLE_SPEC"),
+        ),
+        ("merge_geometry", Some(geom)) => osmquadtree_geometry::merge_geometry(
+            &geom.values_of("INPUTS").unwrap().map(String::from).collect::<Vec<String>>(),
+            geom.value_of("OUTFN").unwrap(),
+            value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+        ),
+        ("split_geometry", Some(geom)) => get_opt("DEPTH", geom.value_of("DEPTH")).and_then(|depth| split_geometry(
+            geom.value_of("INPUT").unwrap(),
+            geom.values_of("TARGETS").map(|t| t.collect()),
+            geom.value_of("PREFIX"),
+            depth,
+            value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+        )),
+        ("index_geometry", Some(geom)) => osmquadtree_geometry::write_geometry_index(
+            geom.value_of("INPUT").unwrap(),
+            value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+        ).and(Ok(())),
+        ("inspect_geometry", Some(geom)) => get_opt("TOP", geom.value_of("TOP")).and_then(|top| {
+            inspect_geometry(
+                geom.value_of("INPUT").unwrap(),
+                geom.value_of("OUTFN"),
+                geom.is_present("JSON"),
+                top,
+                value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+            )
+        }),
+        ("serve", Some(geom)) => (|| {
+            serve(
+                geom.value_of("INPUT").unwrap(),
+                geom.value_of("ADDR").unwrap_or("127.0.0.1:8080"),
+                geom.value_of("STYLE_NAME"),
+                geom.is_present("EXTENDED"),
+                geom.value_of("TABLE_SPEC"),
+                get_opt("CACHE_SIZE", geom.value_of("CACHE_SIZE"))?,
+                get_opt("MAX_ZOOM", geom.value_of("MAX_ZOOM"))?,
+                value_t!(geom, "NUMCHAN", usize).unwrap_or(numchan_default),
+            )
+        })(),
+        ("import_osm2pgsql_style", Some(geom)) => import_style(
+            geom.value_of("INPUT").unwrap(),
+            geom.value_of("STYLE_OUTFN").unwrap(),
+            geom.value_of("TABLE_SPEC_OUTFN").unwrap(),
+            geom.is_present("EXTENDED"),
+        ),
+        ("validate_style", Some(geom)) => get_opt("NUM_BLOCKS", geom.value_of("NUM_BLOCKS")).and_then(|num_blocks| {
+            validate_style(
+                geom.value_of("INPUT").unwrap(),
+                geom.value_of("TABLE_SPEC"),
+                geom.value_of("SAMPLE"),
+                num_blocks,
+                geom.value_of("OUTFN"),
+                geom.is_present("JSON"),
+            )
+        }),
+        ("dump_geometry_style", Some(geom)) => dump_geometry_style(geom.value_of("OUTPUT"), geom.value_of("STYLE_NAME")),
         
         ("show_after_queries", Some(geom)) => {
             (|| {
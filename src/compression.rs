@@ -0,0 +1,357 @@
+use crate::{Error, Result};
+
+use osmquadtree::pbfformat::FileBlock;
+use simple_protocolbuffers::{pack_data, pack_value, IterTags, PbfTag};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+const DEFAULT_ZLIB_LEVEL: u32 = 6;
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zlib(u32),
+    Zstd(i32),
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::Zlib(DEFAULT_ZLIB_LEVEL)
+    }
+}
+
+fn parse_level<T: std::str::FromStr>(name: &str, level: Option<&str>, default: T) -> Result<T> {
+    match level {
+        None => Ok(default),
+        Some(l) => l
+            .parse()
+            .or_else(|_| Err(Error::UserSelectionError(format!("can't parse {} level {}", name, l)))),
+    }
+}
+
+impl Compression {
+    //none, zlib, zstd or lz4, with an optional level as zlib:9 or zstd:19
+    pub fn from_str(s: &str) -> Result<Compression> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let level = parts.next();
+        match name {
+            "none" if level.is_none() => Ok(Compression::None),
+            "zlib" | "gzip" => {
+                let l = parse_level(name, level, DEFAULT_ZLIB_LEVEL)?;
+                if l > 9 {
+                    return Err(Error::UserSelectionError(format!("zlib level {} out of range 0-9", l)));
+                }
+                Ok(Compression::Zlib(l))
+            }
+            "zstd" => {
+                let l = parse_level(name, level, DEFAULT_ZSTD_LEVEL)?;
+                if l < 1 || l > 22 {
+                    return Err(Error::UserSelectionError(format!("zstd level {} out of range 1-22", l)));
+                }
+                Ok(Compression::Zstd(l))
+            }
+            "lz4" if level.is_none() => Ok(Compression::Lz4),
+            _ => Err(Error::UserSelectionError(format!(
+                "unknown compression {}, expected none, zlib[:level], zstd[:level] or lz4",
+                s
+            ))),
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        match self {
+            Compression::None => String::from("none"),
+            Compression::Zlib(l) => format!("zlib:{}", l),
+            Compression::Zstd(l) => format!("zstd:{}", l),
+            Compression::Lz4 => String::from("lz4"),
+        }
+    }
+
+    //file extension added to copy files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zlib(_) => ".gz",
+            Compression::Zstd(_) => ".zst",
+            Compression::Lz4 => ".lz4",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zlib(l) => {
+                let mut enc = flate2::write::ZlibEncoder::new(
+                    Vec::with_capacity(data.len() / 2),
+                    flate2::Compression::new(*l),
+                );
+                enc.write_all(data)?;
+                Ok(enc.finish()?)
+            }
+            Compression::Zstd(l) => Ok(zstd::encode_all(data, *l)?),
+            Compression::Lz4 => {
+                let mut enc = lz4_flex::frame::FrameEncoder::new(Vec::with_capacity(data.len() / 2));
+                enc.write_all(data)?;
+                enc.finish()
+                    .or_else(|e| Err(Error::InvalidDataError(format!("lz4 failed: {}", e))))
+            }
+        }
+    }
+
+    //the pbf Blob field holding data compressed this way: raw, zlib_data,
+    //zstd_data or lz4_data. Zlib at any level is ordinary zlib_data.
+    fn blob_field(&self) -> u64 {
+        match self {
+            Compression::None => 1,
+            Compression::Zlib(_) => 3,
+            Compression::Lz4 => 6,
+            Compression::Zstd(_) => 7,
+        }
+    }
+
+    //packs data as a pbf file block: the BlobHeader length, the BlobHeader
+    //and a Blob with the data in the field for this compression. Lz4 data is
+    //an lz4 frame.
+    pub fn pack_file_block(&self, block_type: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut blob = Vec::with_capacity(data.len() / 2 + 20);
+        if *self != Compression::None {
+            pack_value(&mut blob, 2, data.len() as u64);
+        }
+        pack_data(&mut blob, self.blob_field(), &self.compress(data)?);
+
+        let mut header = Vec::with_capacity(block_type.len() + 10);
+        pack_data(&mut header, 1, block_type.as_bytes());
+        pack_value(&mut header, 3, blob.len() as u64);
+
+        let mut res = Vec::with_capacity(4 + header.len() + blob.len());
+        res.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        res.extend_from_slice(&header);
+        res.extend_from_slice(&blob);
+        Ok(res)
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let matches = match self {
+            Compression::None => true,
+            Compression::Zlib(_) => is_zlib(data),
+            Compression::Zstd(_) => data.starts_with(ZSTD_MAGIC),
+            Compression::Lz4 => data.starts_with(LZ4_MAGIC),
+        };
+        if !matches {
+            return Err(Error::InvalidDataError(format!(
+                "block data isn't {} compressed, as given by its blob field",
+                self.to_string()
+            )));
+        }
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zlib(_) => {
+                let mut res = Vec::with_capacity(data.len() * 3);
+                flate2::read::ZlibDecoder::new(data).read_to_end(&mut res)?;
+                Ok(res)
+            }
+            Compression::Zstd(_) => Ok(zstd::decode_all(data)?),
+            Compression::Lz4 => {
+                let mut res = Vec::with_capacity(data.len() * 3);
+                lz4_flex::frame::FrameDecoder::new(data).read_to_end(&mut res)?;
+                Ok(res)
+            }
+        }
+    }
+
+    pub fn writer(&self, f: File) -> Result<CompressedWriter> {
+        Ok(match self {
+            Compression::None => CompressedWriter::None(BufWriter::new(f)),
+            Compression::Zlib(l) => {
+                CompressedWriter::Gzip(flate2::write::GzEncoder::new(f, flate2::Compression::new(*l)))
+            }
+            Compression::Zstd(l) => CompressedWriter::Zstd(zstd::Encoder::new(f, *l)?),
+            Compression::Lz4 => CompressedWriter::Lz4(lz4_flex::frame::FrameEncoder::new(f)),
+        })
+    }
+}
+
+fn is_zlib(data: &[u8]) -> bool {
+    data.len() > 2 && data[0] == 0x78 && (((data[0] as u16) << 8) | data[1] as u16) % 31 == 0
+}
+
+//the compression and data of a pbf Blob
+pub fn unpack_blob(blob: &[u8]) -> Result<(Compression, Vec<u8>)> {
+    for tg in IterTags::new(blob) {
+        match tg {
+            PbfTag::Data(1, d) => { return Ok((Compression::None, d.to_vec())); },
+            PbfTag::Data(3, d) => { return Ok((Compression::default(), Compression::default().decompress(d)?)); },
+            PbfTag::Data(6, d) => { return Ok((Compression::Lz4, Compression::Lz4.decompress(d)?)); },
+            PbfTag::Data(7, d) => { return Ok((Compression::Zstd(DEFAULT_ZSTD_LEVEL), Compression::Zstd(DEFAULT_ZSTD_LEVEL).decompress(d)?)); },
+            _ => {}
+        }
+    }
+    Err(Error::InvalidDataError(String::from("blob has no raw, zlib_data, lz4_data or zstd_data field")))
+}
+
+//the block type and data of a block packed by Compression::pack_file_block
+pub fn unpack_file_block(data: &[u8]) -> Result<(String, Vec<u8>)> {
+    if data.len() < 4 {
+        return Err(Error::InvalidDataError(String::from("file block too short")));
+    }
+    let header_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if data.len() < 4 + header_len {
+        return Err(Error::InvalidDataError(String::from("file block header truncated")));
+    }
+    let mut block_type = String::new();
+    let mut blob_len = 0;
+    for tg in IterTags::new(&data[4..4 + header_len]) {
+        match tg {
+            PbfTag::Data(1, d) => { block_type = String::from_utf8_lossy(d).to_string(); },
+            PbfTag::Value(3, v) => { blob_len = v as usize; },
+            _ => {}
+        }
+    }
+    let blob = &data[4 + header_len..];
+    if blob.len() != blob_len {
+        return Err(Error::InvalidDataError(format!("expected blob of {} bytes, found {}", blob_len, blob.len())));
+    }
+    Ok((block_type, unpack_blob(blob)?.1))
+}
+
+//the data of a block, decompressed as given by the field of its blob, so
+//files whose blocks were written with different compressions can be read
+pub fn file_block_data(fb: &FileBlock) -> Result<Vec<u8>> {
+    Ok(unpack_blob(&fb.data_raw)?.1)
+}
+
+pub enum CompressedWriter {
+    None(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+    Lz4(lz4_flex::frame::FrameEncoder<File>),
+}
+
+impl CompressedWriter {
+    pub fn finish(self) -> Result<()> {
+        match self {
+            CompressedWriter::None(mut w) => {
+                w.flush()?;
+            }
+            CompressedWriter::Gzip(w) => {
+                w.finish()?;
+            }
+            CompressedWriter::Zstd(w) => {
+                w.finish()?;
+            }
+            CompressedWriter::Lz4(w) => {
+                w.finish()
+                    .or_else(|e| Err(Error::InvalidDataError(format!("lz4 failed: {}", e))))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, d: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressedWriter::None(w) => w.write(d),
+            CompressedWriter::Gzip(w) => w.write(d),
+            CompressedWriter::Zstd(w) => w.write(d),
+            CompressedWriter::Lz4(w) => w.write(d),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressedWriter::None(w) => w.flush(),
+            CompressedWriter::Gzip(w) => w.flush(),
+            CompressedWriter::Zstd(w) => w.flush(),
+            CompressedWriter::Lz4(w) => w.flush(),
+        }
+    }
+}
+
+//opens a copy file written with any Compression
+pub fn open_compressed_file(fname: &str) -> Result<Box<dyn Read>> {
+    let mut f = BufReader::new(File::open(fname)?);
+    let mut magic = [0u8; 4];
+    let n = {
+        let b = f.fill_buf()?;
+        let n = usize::min(4, b.len());
+        magic[..n].copy_from_slice(&b[..n]);
+        n
+    };
+    let magic = &magic[..n];
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(f)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(f)?))
+    } else if magic.starts_with(LZ4_MAGIC) {
+        Ok(Box::new(lz4_flex::frame::FrameDecoder::new(f)))
+    } else {
+        Ok(Box::new(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data() -> Vec<u8> {
+        let mut res = Vec::new();
+        for i in 0..5000u32 {
+            res.extend_from_slice(&(i % 97).to_le_bytes());
+        }
+        res
+    }
+
+    fn round_trip(compression: Compression, field: u64) {
+        let data = test_data();
+        let packed = compression.pack_file_block("OSMData", &data).unwrap();
+
+        let header_len = u32::from_be_bytes([packed[0], packed[1], packed[2], packed[3]]) as usize;
+        let blob = &packed[4 + header_len..];
+        let fields: Vec<u64> = IterTags::new(blob)
+            .filter_map(|t| match t { PbfTag::Data(f, _) => Some(f), _ => None })
+            .collect();
+        assert_eq!(fields, vec![field]);
+
+        let (block_type, unpacked) = unpack_file_block(&packed).unwrap();
+        assert_eq!(block_type, "OSMData");
+        assert_eq!(unpacked, data);
+    }
+
+    #[test]
+    fn round_trip_none() {
+        round_trip(Compression::None, 1);
+    }
+
+    #[test]
+    fn round_trip_zlib() {
+        for l in [0, 1, 6, 9] {
+            round_trip(Compression::Zlib(l), 3);
+        }
+    }
+
+    #[test]
+    fn round_trip_zstd() {
+        round_trip(Compression::Zstd(3), 7);
+        round_trip(Compression::Zstd(19), 7);
+    }
+
+    #[test]
+    fn round_trip_lz4() {
+        round_trip(Compression::Lz4, 6);
+    }
+
+    #[test]
+    fn decompress_checks_compression() {
+        let data = Compression::Zstd(3).compress(&test_data()).unwrap();
+        assert!(Compression::Lz4.decompress(&data).is_err());
+        assert!(Compression::default().decompress(&data).is_err());
+    }
+}
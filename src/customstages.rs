@@ -0,0 +1,330 @@
+use channelled_callbacks::{CallFinish, Callback, Result as ccResult};
+use osmquadtree::message;
+use osmquadtree::utils::ThreadTimer;
+
+use std::fmt;
+
+use crate::{Error, GeometryBlock, OtherData, Result, Timings, WorkingBlock};
+
+type CallFinishWorkingBlock =
+    Box<dyn CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error>>;
+
+//runs on each WorkingBlock before geometries are made: pending_nodes,
+//pending_ways and pending_relations hold the (tagged) source elements
+pub trait WorkingBlockStage: Send {
+    fn name(&self) -> String;
+    //if process fails the block is passed on as it was left, so errors
+    //should be returned before making any changes
+    fn process(&mut self, wb: &mut WorkingBlock) -> Result<()>;
+
+    //messages reported with the timings
+    fn finish(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+//runs on the geometries of each block, after they have been made
+pub trait GeometryBlockStage: Send {
+    fn name(&self) -> String;
+    //as for WorkingBlockStage::process
+    fn process(&mut self, gb: &mut GeometryBlock) -> Result<()>;
+
+    fn finish(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+pub enum CustomStage {
+    Working(Box<dyn WorkingBlockStage>),
+    Geometry(Box<dyn GeometryBlockStage>),
+}
+
+impl CustomStage {
+    fn name(&self) -> String {
+        match self {
+            CustomStage::Working(s) => s.name(),
+            CustomStage::Geometry(s) => s.name(),
+        }
+    }
+}
+
+//where a custom stage runs, relative to the fixed stages of process_geometry_call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StagePoint {
+    AfterWayNodes,
+    AfterParentTags,
+    AfterRelationTags,
+    AfterMultiPolygons,
+    AfterMakeGeometries,
+    AfterValidate,
+    AfterMinZoom,
+}
+
+impl StagePoint {
+    pub fn from_str(s: &str) -> Result<StagePoint> {
+        match s {
+            "after_way_nodes" => Ok(StagePoint::AfterWayNodes),
+            "after_parent_tags" => Ok(StagePoint::AfterParentTags),
+            "after_relation_tags" => Ok(StagePoint::AfterRelationTags),
+            "after_multipolygons" => Ok(StagePoint::AfterMultiPolygons),
+            "after_make_geometries" => Ok(StagePoint::AfterMakeGeometries),
+            "after_validate" => Ok(StagePoint::AfterValidate),
+            "after_minzoom" => Ok(StagePoint::AfterMinZoom),
+            _ => Err(Error::UserSelectionError(format!(
+                "unknown stage point {}, expected after_way_nodes, after_parent_tags, after_relation_tags, after_multipolygons, after_make_geometries, after_validate or after_minzoom",
+                s
+            ))),
+        }
+    }
+
+    //points before MakeGeometries take WorkingBlockStages, the rest
+    //GeometryBlockStages
+    pub fn before_geometries(&self) -> bool {
+        match self {
+            StagePoint::AfterWayNodes
+            | StagePoint::AfterParentTags
+            | StagePoint::AfterRelationTags
+            | StagePoint::AfterMultiPolygons => true,
+            _ => false,
+        }
+    }
+}
+
+pub struct CustomStages {
+    stages: Vec<(StagePoint, CustomStage)>,
+}
+
+impl Default for CustomStages {
+    fn default() -> CustomStages {
+        CustomStages::new()
+    }
+}
+
+impl fmt::Debug for CustomStages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.stages.iter().map(|(p, s)| (p, s.name())))
+            .finish()
+    }
+}
+
+impl CustomStages {
+    pub fn new() -> CustomStages {
+        CustomStages { stages: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn add(&mut self, point: StagePoint, stage: CustomStage) -> Result<()> {
+        match (&stage, point.before_geometries()) {
+            (CustomStage::Working(_), false) | (CustomStage::Geometry(_), true) => {
+                return Err(Error::UserSelectionError(format!(
+                    "stage {} can't be added at {:?}",
+                    stage.name(),
+                    point
+                )));
+            }
+            _ => {}
+        }
+        self.stages.push((point, stage));
+        Ok(())
+    }
+
+    pub fn add_working(&mut self, point: StagePoint, stage: Box<dyn WorkingBlockStage>) -> Result<()> {
+        self.add(point, CustomStage::Working(stage))
+    }
+
+    pub fn add_geometry(&mut self, point: StagePoint, stage: Box<dyn GeometryBlockStage>) -> Result<()> {
+        self.add(point, CustomStage::Geometry(stage))
+    }
+
+    //wraps out with the stages registered at point, which run in the order
+    //they were added. When threaded each runs in its own thread.
+    pub(crate) fn wrap(&mut self, point: StagePoint, out: CallFinishWorkingBlock, threaded: bool) -> CallFinishWorkingBlock {
+        let mut here = Vec::new();
+        let mut rest = Vec::new();
+        for (p, s) in std::mem::take(&mut self.stages) {
+            if p == point {
+                here.push(s);
+            } else {
+                rest.push((p, s));
+            }
+        }
+        self.stages = rest;
+
+        let mut res = out;
+        for s in here.into_iter().rev() {
+            let rs = Box::new(RunCustomStage { out: res, stage: s, failed: 0, error: None, tm: 0.0 });
+            res = if threaded { Box::new(Callback::new(rs)) } else { rs };
+        }
+        res
+    }
+}
+
+//blocks for which the stage fails are still passed on, so that the
+//pending ways and relations they hold aren't lost. The first error is
+//returned from finish, once the rest of the pipeline has finished.
+struct RunCustomStage {
+    out: CallFinishWorkingBlock,
+    stage: CustomStage,
+    failed: usize,
+    error: Option<Error>,
+    tm: f64,
+}
+
+impl CallFinish for RunCustomStage {
+    type CallType = WorkingBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, mut wb: WorkingBlock) {
+        let tx = ThreadTimer::new();
+        let res = match &mut self.stage {
+            CustomStage::Working(s) => s.process(&mut wb),
+            CustomStage::Geometry(s) => s.process(&mut wb.geometry_block),
+        };
+        self.tm += tx.since();
+        if let Err(e) = res {
+            self.failed += 1;
+            if self.error.is_none() {
+                self.error = Some(e);
+            }
+        }
+        self.out.call(wb);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = self.out.finish()?;
+        let name = self.stage.name();
+        if let Some(e) = self.error.take() {
+            message!("{}: failed for {} blocks", name, self.failed);
+            return Err(e);
+        }
+        let msgs = match &mut self.stage {
+            CustomStage::Working(s) => s.finish()?,
+            CustomStage::Geometry(s) => s.finish()?,
+        };
+        tms.add(&name, self.tm);
+        if !msgs.is_empty() {
+            tms.add_other(&name, OtherData::Messages(msgs));
+        }
+        Ok(tms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmquadtree::elements::Quadtree;
+    use std::sync::{Arc, Mutex};
+
+    struct Collect {
+        blocks: Arc<Mutex<Vec<WorkingBlock>>>,
+    }
+
+    impl CallFinish for Collect {
+        type CallType = WorkingBlock;
+        type ReturnType = Timings;
+        type ErrorType = Error;
+
+        fn call(&mut self, wb: WorkingBlock) {
+            self.blocks.lock().unwrap().push(wb);
+        }
+
+        fn finish(&mut self) -> ccResult<Timings, Error> {
+            Ok(Timings::new())
+        }
+    }
+
+    //fails for blocks with a negative index
+    struct MarkBlock;
+
+    impl WorkingBlockStage for MarkBlock {
+        fn name(&self) -> String {
+            String::from("MarkBlock")
+        }
+        fn process(&mut self, wb: &mut WorkingBlock) -> Result<()> {
+            if wb.geometry_block.index < 0 {
+                return Err(Error::InvalidDataError(String::from("negative index")));
+            }
+            wb.geometry_block.end_date = 50;
+            Ok(())
+        }
+    }
+
+    struct SetEndDate(usize);
+
+    impl GeometryBlockStage for SetEndDate {
+        fn name(&self) -> String {
+            String::from("SetEndDate")
+        }
+        fn process(&mut self, gb: &mut GeometryBlock) -> Result<()> {
+            gb.end_date = 100;
+            self.0 += 1;
+            Ok(())
+        }
+        fn finish(&mut self) -> Result<Vec<String>> {
+            Ok(vec![format!("{} blocks", self.0)])
+        }
+    }
+
+    fn run(stages: &mut CustomStages, point: StagePoint, blocks: Vec<WorkingBlock>) -> (ccResult<Timings, Error>, Vec<WorkingBlock>) {
+        let res = Arc::new(Mutex::new(Vec::new()));
+        let mut cb = stages.wrap(point, Box::new(Collect { blocks: res.clone() }), false);
+        for wb in blocks {
+            cb.call(wb);
+        }
+        let tms = cb.finish();
+        let blocks = std::mem::take(&mut *res.lock().unwrap());
+        (tms, blocks)
+    }
+
+    fn block(index: i64) -> WorkingBlock {
+        WorkingBlock::new(index, Quadtree::new(0), 0)
+    }
+
+    #[test]
+    fn working_block_stage() {
+        let mut stages = CustomStages::new();
+        stages.add_working(StagePoint::AfterRelationTags, Box::new(MarkBlock)).unwrap();
+        let (tms, blocks) = run(&mut stages, StagePoint::AfterRelationTags, vec![block(0), block(1)]);
+        assert!(tms.is_ok());
+        assert!(stages.is_empty());
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|wb| wb.geometry_block.end_date == 50));
+    }
+
+    #[test]
+    fn working_block_stage_error() {
+        let mut stages = CustomStages::new();
+        stages.add_working(StagePoint::AfterWayNodes, Box::new(MarkBlock)).unwrap();
+        let (tms, blocks) = run(&mut stages, StagePoint::AfterWayNodes, vec![block(0), block(-1), block(2)]);
+        assert!(tms.is_err());
+        let idx: Vec<i64> = blocks.iter().map(|wb| wb.geometry_block.index).collect();
+        assert_eq!(idx, vec![0, -1, 2]);
+        let end_dates: Vec<i64> = blocks.iter().map(|wb| wb.geometry_block.end_date).collect();
+        assert_eq!(end_dates, vec![50, 0, 50]);
+    }
+
+    #[test]
+    fn geometry_block_stage() {
+        let mut stages = CustomStages::new();
+        stages.add_geometry(StagePoint::AfterMinZoom, Box::new(SetEndDate(0))).unwrap();
+        let (tms, blocks) = run(&mut stages, StagePoint::AfterMinZoom, vec![block(0), block(1)]);
+        let tms = tms.unwrap();
+        assert!(blocks.iter().all(|wb| wb.geometry_block.end_date == 100));
+        assert!(tms.others.iter().any(|(n, o)| match o {
+            OtherData::Messages(mm) => n == "SetEndDate" && mm == &vec![String::from("2 blocks")],
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn stage_at_wrong_point() {
+        let mut stages = CustomStages::new();
+        assert!(stages.add_geometry(StagePoint::AfterMultiPolygons, Box::new(SetEndDate(0))).is_err());
+        assert!(stages.add_working(StagePoint::AfterMakeGeometries, Box::new(MarkBlock)).is_err());
+    }
+}
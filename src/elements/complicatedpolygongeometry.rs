@@ -1,5 +1,9 @@
 use osmquadtree::elements::{Bbox, Info, Quadtree, Relation, Tag};
 
+use crate::antimeridian::{
+    antimeridian_bounds, bounds_union, multipolygon_wkb, polygon_coordinates,
+    polygon_crosses_antimeridian, split_polygon_at_antimeridian,
+};
 use crate::elements::pointgeometry::pack_tags;
 use crate::elements::simplepolygongeometry::{pack_bounds, read_lonlats};
 use crate::elements::{GeoJsonable,WithBounds};
@@ -25,8 +29,8 @@ impl fmt::Debug for RingPart {
             .field("orig_id", &self.orig_id)
             .field("is_reversed", &self.is_reversed)
             .field("np", &self.refs.len())
-            .field("first", &self.refs[0])
-            .field("last", &self.refs[self.refs.len() - 1])
+            .field("first", &self.refs.first())
+            .field("last", &self.refs.last())
             .finish()
     }
 }
@@ -96,28 +100,6 @@ impl Ring {
         r
     }
 
-    pub fn first_last(&self) -> (i64, i64) {
-        let p = &self.parts[0];
-        let f = if p.is_reversed {
-            p.refs[p.refs.len() - 1]
-        } else {
-            p.refs[0]
-        };
-
-        let q = &self.parts[self.parts.len() - 1];
-        let t = if q.is_reversed {
-            q.refs[0]
-        } else {
-            q.refs[q.refs.len() - 1]
-        };
-        (f, t)
-    }
-
-    pub fn is_ring(&self) -> bool {
-        let (f, t) = self.first_last();
-        f == t
-    }
-
     /*pub fn refs<'a>(&'a self) -> Result<Vec<&'a i64>> {
         let mut res = Vec::new();
         for p in &self.parts {
@@ -283,95 +265,6 @@ impl<'a> Iterator for RingLonLatsIter<'a> {
     }
 }
 
-fn merge_rings(rings: &mut Vec<Ring>) -> (bool, Option<Ring>) {
-    if rings.len() == 0 {
-        return (false, None);
-    }
-    if rings.len() == 1 {
-        if rings[0].is_ring() {
-            let zz = rings.remove(0);
-            return (true, Some(zz));
-        }
-        return (false, None);
-    }
-
-    for i in 0..rings.len() - 1 {
-        let (f, t) = rings[i].first_last();
-        if f == t {
-            let zz = rings.remove(i);
-            return (true, Some(zz));
-        }
-        for j in i + 1..rings.len() {
-            let (g, u) = rings[j].first_last();
-
-            if t == g {
-                let zz = rings.remove(j);
-                rings[i].parts.extend(zz.parts);
-                if rings[i].is_ring() {
-                    let zz = rings.remove(i);
-                    return (true, Some(zz));
-                }
-                return (true, None);
-            } else if t == u {
-                let mut zz = rings.remove(j);
-                zz.reverse();
-                rings[i].parts.extend(zz.parts);
-                if rings[i].is_ring() {
-                    let zz = rings.remove(i);
-                    return (true, Some(zz));
-                }
-                return (true, None);
-            } else if f == u {
-                let mut zz = rings.remove(j);
-                zz.reverse();
-                rings[i].reverse();
-                rings[i].parts.extend(zz.parts);
-                return (true, None);
-            } else if f == g {
-                let zz = rings.remove(j);
-                rings[i].reverse();
-                rings[i].parts.extend(zz.parts);
-
-                return (true, None);
-            }
-        }
-    }
-    return (false, None);
-}
-
-pub fn collect_rings(ww: Vec<RingPart>) -> Result<(Vec<Ring>, Vec<RingPart>)> {
-    //let nw=ww.len();
-    let mut parts = Vec::new();
-    for w in ww {
-        let mut r = Ring::new();
-        r.parts.push(w);
-        parts.push(r);
-    }
-
-    let mut res = Vec::new();
-    loop {
-        let (f, r) = merge_rings(&mut parts);
-        match r {
-            None => {}
-            Some(r) => {
-                res.push(r);
-            }
-        }
-        if !f {
-            break;
-        }
-    }
-
-    let mut rem = Vec::new();
-    for p in parts {
-        for q in p.parts {
-            rem.push(q);
-        }
-    }
-
-    Ok((res, rem))
-}
-
 #[derive(Debug, Serialize,Clone)]
 pub struct PolygonPart {
     pub exterior: Ring,
@@ -406,6 +299,14 @@ impl PolygonPart {
         self.interiors.push(p);
     }
 
+    pub fn rings(&self) -> Result<Vec<Vec<LonLat>>> {
+        let mut rings = vec![self.exterior.lonlats()?];
+        for ii in &self.interiors {
+            rings.push(ii.lonlats()?);
+        }
+        Ok(rings)
+    }
+
     pub fn prep_coordinates(&self, transform: bool) -> Result<Vec<Vec<(f64, f64)>>> {
         let mut rings = Vec::new();
 
@@ -447,16 +348,16 @@ pub struct ComplicatedPolygonGeometry {
     pub area: f64,
     pub minzoom: Option<i64>,
     pub quadtree: Quadtree,
+    pub label_point: Option<LonLat>,
 }
 impl WithBounds for ComplicatedPolygonGeometry {
     fn bounds(&self) -> Bbox {
-        let mut res = Bbox::empty();
-        for p in &self.parts {
-            for l in &p.exterior.lonlats().unwrap() {
-                res.expand(l.lon, l.lat);
-            }
-        }
-        res
+        let bxs: Vec<Bbox> = self
+            .parts
+            .iter()
+            .map(|p| antimeridian_bounds(&p.exterior.lonlats().unwrap()))
+            .collect();
+        bounds_union(&bxs)
     }
 }
 
@@ -464,7 +365,8 @@ impl WithBounds for ComplicatedPolygonGeometry {
 impl ComplicatedPolygonGeometry {
     pub fn empty() -> ComplicatedPolygonGeometry {
         ComplicatedPolygonGeometry{id: 0, info: None, tags: Vec::new(), parts: Vec::new(),
-            area: 0.0, layer: None, z_order: None, minzoom: None, quadtree: Quadtree::empty()}
+            area: 0.0, layer: None, z_order: None, minzoom: None, quadtree: Quadtree::empty(),
+            label_point: None}
     }
     
     
@@ -490,6 +392,7 @@ impl ComplicatedPolygonGeometry {
             area: area,
             minzoom: None,
             quadtree: relation.quadtree,
+            label_point: None,
         }
     }
 /*
@@ -510,7 +413,34 @@ impl ComplicatedPolygonGeometry {
         geo::MultiPolygon(polys)
     }
 */
+    //None unless some ring crosses the antimeridian, otherwise all parts with
+    //those that cross split at 180 degrees
+    fn split_antimeridian(&self) -> Result<Option<Vec<Vec<Vec<LonLat>>>>> {
+        let mut rings = Vec::with_capacity(self.parts.len());
+        let mut crosses = false;
+        for p in &self.parts {
+            let r = p.rings()?;
+            crosses = crosses || polygon_crosses_antimeridian(&r);
+            rings.push(r);
+        }
+        if !crosses {
+            return Ok(None);
+        }
+        let mut res = Vec::new();
+        for r in rings {
+            if polygon_crosses_antimeridian(&r) {
+                res.extend(split_polygon_at_antimeridian(&r));
+            } else {
+                res.push(r);
+            }
+        }
+        Ok(Some(res))
+    }
+
     pub fn to_wkb(&self, transform: bool, with_srid: bool) -> std::io::Result<Vec<u8>> {
+        if let Some(polys) = self.split_antimeridian()? {
+            return multipolygon_wkb(&polys, transform, with_srid);
+        }
         /*let xx = self.to_geo(transform);
         let srid = if with_srid {
             Some(if transform { 3857 } else { 4326 })
@@ -539,6 +469,20 @@ impl ComplicatedPolygonGeometry {
 
     pub fn to_geometry_geojson(&self, transform: bool) -> std::io::Result<Value> {
         let mut res = Map::new();
+        if let Some(polys) = self.split_antimeridian()? {
+            let mut cc: Vec<Vec<Vec<(f64, f64)>>> = polys
+                .iter()
+                .map(|p| polygon_coordinates(p, false, transform))
+                .collect();
+            if cc.len() == 1 {
+                res.insert(String::from("type"), json!("Polygon"));
+                res.insert(String::from("coordinates"), json!(cc.pop().unwrap()));
+            } else {
+                res.insert(String::from("type"), json!("MultiPolygon"));
+                res.insert(String::from("coordinates"), json!(cc));
+            }
+            return Ok(json!(res));
+        }
         if self.parts.len() == 1 {
             res.insert(String::from("type"), json!("Polygon"));
             res.insert(
@@ -591,6 +535,13 @@ impl GeoJsonable for ComplicatedPolygonGeometry {
                 res.insert(String::from("minzoom"), json!(l));
             }
         }
+        match &self.label_point {
+            None => {}
+            Some(l) => {
+                let xy = l.to_xy(transform);
+                res.insert(String::from("label_point"), json!((xy.x, xy.y)));
+            }
+        }
         res.insert(String::from("bbox"), pack_bounds(&self.bounds(),transform));
 
         Ok(json!(res))
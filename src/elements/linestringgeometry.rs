@@ -1,4 +1,7 @@
 use osmquadtree::elements::{Bbox, Info, Quadtree, Tag, Way};
+use crate::antimeridian::{
+    antimeridian_bounds, crosses_antimeridian, multilinestring_wkb, split_line_at_antimeridian,
+};
 use crate::elements::pointgeometry::pack_tags;
 use crate::elements::simplepolygongeometry::{pack_bounds, read_lonlats};
 use crate::elements::{GeoJsonable,WithBounds};
@@ -21,21 +24,18 @@ pub struct LinestringGeometry {
     pub layer: Option<i64>,
     pub minzoom: Option<i64>,
     pub quadtree: Quadtree,
+    pub source_ids: Vec<i64>,
 }
 impl WithBounds for LinestringGeometry {
     fn bounds(&self) -> Bbox {
-        let mut res = Bbox::empty();
-        for l in &self.lonlats {
-            res.expand(l.lon, l.lat);
-        }
-        res
+        antimeridian_bounds(&self.lonlats)
     }
 }
 
 impl LinestringGeometry {
     pub fn empty() -> LinestringGeometry {
         LinestringGeometry{id: 0, info: None, tags: Vec::new(), refs: Vec::new(), lonlats: Vec::new(),
-            length: 0.0, layer: None, z_order: None, minzoom: None, quadtree: Quadtree::empty()}
+            length: 0.0, layer: None, z_order: None, minzoom: None, quadtree: Quadtree::empty(), source_ids: Vec::new()}
     }
     
     pub fn from_way(
@@ -57,6 +57,7 @@ impl LinestringGeometry {
             layer: layer,
             z_order: z_order,
             minzoom: None,
+            source_ids: Vec::new(),
         }
     }
 
@@ -65,6 +66,9 @@ impl LinestringGeometry {
     }*/
 
     pub fn to_wkb(&self, transform: bool, with_srid: bool) -> std::io::Result<Vec<u8>> {
+        if crosses_antimeridian(&self.lonlats) {
+            return multilinestring_wkb(&split_line_at_antimeridian(&self.lonlats), transform, with_srid);
+        }
         let mut res = prep_wkb(transform, with_srid, 2, 4 + 16 * self.lonlats.len())?;
         write_ring(
             &mut res,
@@ -78,6 +82,15 @@ impl LinestringGeometry {
 
     pub fn to_geometry_geojson(&self, transform: bool) -> std::io::Result<Value> {
         let mut res = Map::new();
+        if crosses_antimeridian(&self.lonlats) {
+            res.insert(String::from("type"), json!("MultiLineString"));
+            let cc: Vec<Vec<(f64, f64)>> = split_line_at_antimeridian(&self.lonlats)
+                .iter()
+                .map(|p| read_lonlats(p, false, transform))
+                .collect();
+            res.insert(String::from("coordinates"), json!(cc));
+            return Ok(json!(res));
+        }
 
         res.insert(String::from("type"), json!("LineString"));
         res.insert(
@@ -122,6 +135,9 @@ impl GeoJsonable for LinestringGeometry {
                 res.insert(String::from("minzoom"), json!(l));
             }
         }
+        if !self.source_ids.is_empty() {
+            res.insert(String::from("source_ids"), json!(self.source_ids));
+        }
         res.insert(String::from("bbox"), pack_bounds(&self.bounds(),transform));
 
         Ok(json!(res))
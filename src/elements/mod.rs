@@ -3,9 +3,7 @@ mod linestringgeometry;
 mod pointgeometry;
 mod simplepolygongeometry;
 
-pub use complicatedpolygongeometry::{
-    collect_rings, ComplicatedPolygonGeometry, PolygonPart, Ring, RingPart,
-};
+pub use complicatedpolygongeometry::{ComplicatedPolygonGeometry, PolygonPart, Ring, RingPart};
 pub use linestringgeometry::LinestringGeometry;
 pub use pointgeometry::PointGeometry;
 pub use simplepolygongeometry::SimplePolygonGeometry;
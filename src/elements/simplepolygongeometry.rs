@@ -1,5 +1,9 @@
 use osmquadtree::elements::{coordinate_as_float, Bbox, Info, Quadtree, Tag, Way};
 
+use crate::antimeridian::{
+    antimeridian_bounds, crosses_antimeridian, multipolygon_wkb, polygon_coordinates,
+    split_polygon_at_antimeridian,
+};
 use crate::elements::pointgeometry::pack_tags;
 use crate::elements::{GeoJsonable,WithBounds};
 use crate::wkb::{prep_wkb, write_ring, write_uint32};
@@ -54,20 +58,18 @@ pub struct SimplePolygonGeometry {
     pub layer: Option<i64>,
     pub minzoom: Option<i64>,
     pub quadtree: Quadtree,
+    pub label_point: Option<LonLat>,
 }
 impl WithBounds for SimplePolygonGeometry {
     fn bounds(&self) -> Bbox {
-        let mut res = Bbox::empty();
-        for l in &self.lonlats {
-            res.expand(l.lon, l.lat);
-        }
-        res
+        antimeridian_bounds(&self.lonlats)
     }
 }
 impl SimplePolygonGeometry {
     pub fn empty() -> SimplePolygonGeometry {
         SimplePolygonGeometry{id: 0, info: None, tags: Vec::new(), refs: Vec::new(), lonlats: Vec::new(),
-            area: 0.0, reversed: false, layer: None, z_order: None, minzoom: None, quadtree: Quadtree::empty()}
+            area: 0.0, reversed: false, layer: None, z_order: None, minzoom: None, quadtree: Quadtree::empty(),
+            label_point: None}
     }
     
     
@@ -92,6 +94,7 @@ impl SimplePolygonGeometry {
             z_order: z_order,
             minzoom: None,
             reversed: reversed,
+            label_point: None,
         }
     }
 /*
@@ -102,6 +105,10 @@ impl SimplePolygonGeometry {
         )
     }*/
     pub fn to_wkb(&self, transform: bool, with_srid: bool) -> std::io::Result<Vec<u8>> {
+        if crosses_antimeridian(&self.lonlats) {
+            let polys = split_polygon_at_antimeridian(&[self.lonlats.clone()]);
+            return multipolygon_wkb(&polys, transform, with_srid);
+        }
         let mut res = prep_wkb(transform, with_srid, 3, 4 + 4 + 16 * self.lonlats.len())?;
         write_uint32(&mut res, 1)?;
         write_ring(
@@ -116,6 +123,21 @@ impl SimplePolygonGeometry {
 
     pub fn to_geometry_geojson(&self, transform: bool) -> std::io::Result<Value> {
         let mut res = Map::new();
+        if crosses_antimeridian(&self.lonlats) {
+            let polys = split_polygon_at_antimeridian(&[self.lonlats.clone()]);
+            let mut cc: Vec<Vec<Vec<(f64, f64)>>> = polys
+                .iter()
+                .map(|p| polygon_coordinates(p, self.reversed, transform))
+                .collect();
+            if cc.len() == 1 {
+                res.insert(String::from("type"), json!("Polygon"));
+                res.insert(String::from("coordinates"), json!(cc.pop().unwrap()));
+            } else {
+                res.insert(String::from("type"), json!("MultiPolygon"));
+                res.insert(String::from("coordinates"), json!(cc));
+            }
+            return Ok(json!(res));
+        }
 
         res.insert(String::from("type"), json!("Polygon"));
         res.insert(
@@ -160,6 +182,13 @@ impl GeoJsonable for SimplePolygonGeometry {
                 res.insert(String::from("minzoom"), json!(l));
             }
         }
+        match &self.label_point {
+            None => {}
+            Some(l) => {
+                let xy = l.to_xy(transform);
+                res.insert(String::from("label_point"), json!((xy.x, xy.y)));
+            }
+        }
         res.insert(String::from("bbox"), pack_bounds(&self.bounds(),transform));
 
         Ok(json!(res))
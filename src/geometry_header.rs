@@ -0,0 +1,228 @@
+use crate::compression::Compression;
+use crate::pack_geometry::GeometryPackOptions;
+use crate::{Error, GeometryStyle, MinZoomSpec, Result};
+
+use osmquadtree::elements::Bbox;
+use osmquadtree::pbfformat::{pack_file_block, read_file_block, CompressionType, FileBlock};
+use simple_protocolbuffers::{pack_data, pack_value, un_zig_zag, zig_zag, IterTags, PbfTag};
+
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufReader, Seek};
+
+//increment whenever the packed geometry format changes
+//2: dense points, zigzag ring part ids, optional refs
+pub const GEOMETRY_FORMAT_VERSION: u64 = 2;
+pub const GEOMETRY_HEADER_BLOCK: &str = "GeometryHeader";
+
+#[derive(Debug, Clone)]
+pub struct GeometryHeader {
+    pub format_version: u64,
+    pub generator: String,
+    pub source_timestamp: Option<i64>,
+    pub bbox: Bbox,
+    pub style: Option<Value>,
+    pub minzoom: Option<Value>,
+    pub pack_options: GeometryPackOptions,
+    //used when writing blocks: readers take the compression of each block
+    //from its blob
+    pub compression: Compression,
+}
+
+impl GeometryHeader {
+    pub fn new(
+        bbox: Bbox,
+        style: Option<&GeometryStyle>,
+        minzoom: Option<&MinZoomSpec>,
+        source_timestamp: Option<i64>,
+    ) -> Result<GeometryHeader> {
+        let style = match style {
+            None => None,
+            Some(s) => Some(serde_json::to_value(s)?),
+        };
+        Ok(GeometryHeader {
+            format_version: GEOMETRY_FORMAT_VERSION,
+            generator: format!("osmquadtree-geometry {}", env!("CARGO_PKG_VERSION")),
+            source_timestamp: source_timestamp,
+            bbox: bbox,
+            style: style,
+            minzoom: minzoom.map(|m| m.to_json()),
+            pack_options: GeometryPackOptions::all(),
+            compression: Compression::default(),
+        })
+    }
+
+    pub fn style(&self) -> Result<Option<GeometryStyle>> {
+        match &self.style {
+            None => Ok(None),
+            Some(s) => Ok(Some(serde_json::from_value(s.clone())?)),
+        }
+    }
+
+    pub fn check_version(&self) -> Result<()> {
+        if self.format_version != GEOMETRY_FORMAT_VERSION {
+            return Err(Error::InvalidDataError(format!(
+                "geometry format version {} (written by {}), expected version {}",
+                self.format_version, self.generator, GEOMETRY_FORMAT_VERSION
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let mut res = Vec::new();
+        pack_value(&mut res, 1, self.format_version);
+        pack_data(&mut res, 2, self.generator.as_bytes());
+        match self.source_timestamp {
+            None => {}
+            Some(t) => pack_value(&mut res, 3, zig_zag(t)),
+        }
+        pack_value(&mut res, 4, zig_zag(self.bbox.minlon as i64));
+        pack_value(&mut res, 5, zig_zag(self.bbox.minlat as i64));
+        pack_value(&mut res, 6, zig_zag(self.bbox.maxlon as i64));
+        pack_value(&mut res, 7, zig_zag(self.bbox.maxlat as i64));
+        match &self.style {
+            None => {}
+            Some(s) => pack_data(&mut res, 8, &serde_json::to_vec(s)?),
+        }
+        match &self.minzoom {
+            None => {}
+            Some(m) => pack_data(&mut res, 9, &serde_json::to_vec(m)?),
+        }
+        //field 10 is left unused: coordinates are always stored as lon/lat
+        pack_data(&mut res, 11, self.pack_options.to_list().as_bytes());
+        pack_data(&mut res, 12, self.compression.to_string().as_bytes());
+        Ok(res)
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<GeometryHeader> {
+        let mut res = GeometryHeader {
+            format_version: 0,
+            generator: String::new(),
+            source_timestamp: None,
+            bbox: Bbox::empty(),
+            style: None,
+            minzoom: None,
+            pack_options: GeometryPackOptions::all(),
+            compression: Compression::default(),
+        };
+        for tg in IterTags::new(data) {
+            match tg {
+                PbfTag::Value(1, v) => { res.format_version = v; },
+                PbfTag::Data(2, d) => { res.generator = String::from_utf8_lossy(d).to_string(); },
+                PbfTag::Value(3, t) => { res.source_timestamp = Some(un_zig_zag(t)); },
+                PbfTag::Value(4, v) => { res.bbox.minlon = un_zig_zag(v) as i32; },
+                PbfTag::Value(5, v) => { res.bbox.minlat = un_zig_zag(v) as i32; },
+                PbfTag::Value(6, v) => { res.bbox.maxlon = un_zig_zag(v) as i32; },
+                PbfTag::Value(7, v) => { res.bbox.maxlat = un_zig_zag(v) as i32; },
+                PbfTag::Data(8, d) => { res.style = Some(serde_json::from_slice(d)?); },
+                PbfTag::Data(9, d) => { res.minzoom = Some(serde_json::from_slice(d)?); },
+                PbfTag::Data(11, d) => { res.pack_options = GeometryPackOptions::from_list(&String::from_utf8_lossy(d))?; },
+                PbfTag::Data(12, d) => { res.compression = Compression::from_str(&String::from_utf8_lossy(d))?; },
+                _ => {}
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn pack_file_block(&self) -> Result<Vec<u8>> {
+        Ok(pack_file_block(GEOMETRY_HEADER_BLOCK, &self.pack()?, &CompressionType::Zlib)?)
+    }
+}
+
+pub fn is_header_block(fb: &FileBlock) -> bool {
+    fb.block_type == GEOMETRY_HEADER_BLOCK
+}
+
+//the header block directly follows the OSMHeader block written by WriteFile
+fn find_header_block(fname: &str) -> Result<Option<(u64, FileBlock)>> {
+    let mut f = BufReader::new(File::open(fname)?);
+    for _ in 0..2 {
+        let pos = f.stream_position()?;
+        let fb = read_file_block(&mut f)?;
+        if is_header_block(&fb) {
+            return Ok(Some((pos, fb)));
+        }
+        if fb.block_type == "OSMData" {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+pub fn read_geometry_header(fname: &str) -> Result<GeometryHeader> {
+    match find_header_block(fname)? {
+        Some((_, fb)) => GeometryHeader::unpack(&fb.data()),
+        None => Err(Error::InvalidDataError(format!(
+            "{} has no geometry header: written by an older version, expected format version {}",
+            fname, GEOMETRY_FORMAT_VERSION
+        ))),
+    }
+}
+
+//the file offset of the header block, which readers of the geometry
+//blocks skip
+pub fn geometry_header_offset(fname: &str) -> Result<Option<u64>> {
+    if !std::path::Path::new(fname).is_file() {
+        return Ok(None);
+    }
+    Ok(find_header_block(fname)?.map(|(p, _)| p))
+}
+
+//directories of (non-geometry) pbf files are not checked
+pub fn check_geometry_file(fname: &str) -> Result<Option<GeometryHeader>> {
+    if !std::path::Path::new(fname).is_file() {
+        return Ok(None);
+    }
+    let header = read_geometry_header(fname)?;
+    header.check_version()?;
+    Ok(Some(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let style = GeometryStyle::default();
+        let minzoom = MinZoomSpec::default(5.0, Some(14));
+        let mut header = GeometryHeader::new(Bbox::new(-10000000, 20000000, 30000000, 40000000), Some(&style), Some(&minzoom), Some(1600000000)).unwrap();
+        header.pack_options = GeometryPackOptions::from_list("refs,version").unwrap();
+        header.compression = Compression::Zstd(3);
+
+        let hh = GeometryHeader::unpack(&header.pack().unwrap()).unwrap();
+        assert_eq!(hh.format_version, GEOMETRY_FORMAT_VERSION);
+        assert_eq!(hh.generator, header.generator);
+        assert_eq!(hh.source_timestamp, Some(1600000000));
+        assert_eq!(
+            (hh.bbox.minlon, hh.bbox.minlat, hh.bbox.maxlon, hh.bbox.maxlat),
+            (-10000000, 20000000, 30000000, 40000000)
+        );
+        assert_eq!(hh.style, header.style);
+        assert_eq!(hh.minzoom, header.minzoom);
+        assert_eq!(hh.pack_options, header.pack_options);
+        assert_eq!(hh.compression, Compression::Zstd(3));
+        assert!(hh.style().unwrap().is_some());
+        hh.check_version().unwrap();
+    }
+
+    #[test]
+    fn header_without_optional_fields() {
+        let header = GeometryHeader::new(Bbox::empty(), None, None, None).unwrap();
+        let hh = GeometryHeader::unpack(&header.pack().unwrap()).unwrap();
+        assert_eq!(hh.source_timestamp, None);
+        assert!(hh.style.is_none());
+        assert!(hh.minzoom.is_none());
+        assert!(hh.style().unwrap().is_none());
+        assert_eq!(hh.compression, Compression::default());
+    }
+
+    #[test]
+    fn other_versions_rejected() {
+        let mut header = GeometryHeader::new(Bbox::empty(), None, None, None).unwrap();
+        header.format_version = GEOMETRY_FORMAT_VERSION - 1;
+        let hh = GeometryHeader::unpack(&header.pack().unwrap()).unwrap();
+        assert!(hh.check_version().is_err());
+    }
+}
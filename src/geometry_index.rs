@@ -0,0 +1,468 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::elements::{Bbox, Block, Quadtree};
+use osmquadtree::message;
+use osmquadtree::pbfformat::read_file_block;
+use osmquadtree::utils::ThreadTimer;
+
+use crate::antimeridian::{bbox_parts, bounds_union};
+use crate::compression::file_block_data;
+use crate::elements::WithBounds;
+use crate::geometry_header::{check_geometry_file, geometry_header_offset, is_header_block};
+use crate::pack_geometry::{geometry_file_locs, unpack_geometry_block_filter, GeometryFilter};
+use crate::tagfilter::ObjectFilter;
+use crate::{read_geometry_blocks, Error, GeometryBlock, OtherData, Result, Timings};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeometryIndexEntry {
+    pub quadtree: i64,
+    pub offset: u64,
+    pub bbox: (i32, i32, i32, i32),
+    pub minzoom: Option<(i64, i64)>,
+    pub num_objects: usize,
+}
+
+impl GeometryIndexEntry {
+    pub fn bounds(&self) -> Bbox {
+        Bbox::new(self.bbox.0, self.bbox.1, self.bbox.2, self.bbox.3)
+    }
+
+    //blocks with no minzoom values set always match
+    pub fn check(&self, bbox: &Bbox, max_minzoom: &Option<i64>) -> bool {
+        match (max_minzoom, &self.minzoom) {
+            (Some(m), Some((lo, _))) if lo > m => {
+                return false;
+            }
+            _ => {}
+        }
+        let b = self.bounds();
+        bbox_parts(&b).iter().any(|x| bbox_parts(bbox).iter().any(|y| y.overlaps(x)))
+    }
+}
+
+//file_size and modified (in nanoseconds) are those of the geometry file
+//when the index was built, and are zero for indexes written before they
+//were added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeometryIndex {
+    pub entries: Vec<GeometryIndexEntry>,
+    #[serde(default)]
+    pub file_size: u64,
+    #[serde(default)]
+    pub modified: u64,
+}
+
+fn file_stamp(fname: &str) -> Result<(u64, u64)> {
+    let md = std::fs::metadata(fname)?;
+    let modified = md
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    Ok((md.len(), modified))
+}
+
+pub fn geometry_index_filename(fname: &str) -> String {
+    format!("{}-geometryindex.json", fname)
+}
+
+impl GeometryIndex {
+    pub fn read(fname: &str) -> Result<GeometryIndex> {
+        let f = BufReader::new(File::open(geometry_index_filename(fname))?);
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    //false if the geometry file has been rewritten since the index was built
+    pub fn is_current(&self, fname: &str) -> bool {
+        match file_stamp(fname) {
+            Ok(st) => st == (self.file_size, self.modified),
+            Err(_) => false,
+        }
+    }
+
+    pub fn write(&self, fname: &str) -> Result<()> {
+        let f = BufWriter::new(File::create(geometry_index_filename(fname))?);
+        serde_json::to_writer(f, self)?;
+        Ok(())
+    }
+}
+
+fn add_minzoom(minzoom: &mut Option<(i64, i64)>, m: &Option<i64>) {
+    if let Some(m) = m {
+        *minzoom = Some(match minzoom {
+            None => (*m, *m),
+            Some((lo, hi)) => (i64::min(*lo, *m), i64::max(*hi, *m)),
+        });
+    }
+}
+
+fn block_index_entry(bl: &GeometryBlock) -> (Bbox, Option<(i64, i64)>, usize) {
+    let mut bxs = Vec::with_capacity(bl.len());
+    let mut minzoom: Option<(i64, i64)> = None;
+    for p in &bl.points {
+        bxs.push(p.bounds());
+        add_minzoom(&mut minzoom, &p.minzoom);
+    }
+    for p in &bl.linestrings {
+        bxs.push(p.bounds());
+        add_minzoom(&mut minzoom, &p.minzoom);
+    }
+    for p in &bl.simple_polygons {
+        bxs.push(p.bounds());
+        add_minzoom(&mut minzoom, &p.minzoom);
+    }
+    for p in &bl.complicated_polygons {
+        bxs.push(p.bounds());
+        add_minzoom(&mut minzoom, &p.minzoom);
+    }
+    (bounds_union(&bxs), minzoom, bxs.len())
+}
+
+struct CollectIndexEntries {
+    entries: BTreeMap<i64, (Bbox, Option<(i64, i64)>, usize)>,
+    tm: f64,
+}
+
+impl CallFinish for CollectIndexEntries {
+    type CallType = GeometryBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: GeometryBlock) {
+        let tx = ThreadTimer::new();
+        self.entries.insert(bl.index, block_index_entry(&bl));
+        self.tm += tx.since();
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = Timings::new();
+        tms.add("CollectIndexEntries", self.tm);
+        tms.add_other(
+            "CollectIndexEntries",
+            OtherData::IndexEntries(std::mem::take(&mut self.entries)),
+        );
+        Ok(tms)
+    }
+}
+
+//reads every block of a geometry pbf file to find its bbox and minzoom range
+pub fn build_geometry_index(fname: &str, numchan: usize) -> Result<GeometryIndex> {
+    let (file_size, modified) = file_stamp(fname)?;
+    //the header block is left out, so that locs match the block indices
+    //given by read_geometry_blocks
+    let (_, locs, _) = geometry_file_locs(fname, None, None)?;
+
+    let cc = Box::new(CollectIndexEntries {
+        entries: BTreeMap::new(),
+        tm: 0.0,
+    });
+    let tm = read_geometry_blocks(fname, cc, None, None, None, numchan)?;
+
+    let mut found = BTreeMap::new();
+    for (_, x) in tm.others {
+        match x {
+            OtherData::IndexEntries(ee) => {
+                found.extend(ee);
+            }
+            _ => {}
+        }
+    }
+
+    let mut entries = Vec::with_capacity(locs.len());
+    for (i, (q, ll)) in locs.iter().enumerate() {
+        if ll.is_empty() {
+            continue;
+        }
+        let (b, minzoom, num_objects) = match found.remove(&(i as i64)) {
+            Some(f) => f,
+            None => (Bbox::empty(), None, 0),
+        };
+        entries.push(GeometryIndexEntry {
+            quadtree: q.as_int(),
+            offset: ll[0].1,
+            bbox: (b.minlon, b.minlat, b.maxlon, b.maxlat),
+            minzoom: minzoom,
+            num_objects: num_objects,
+        });
+    }
+    Ok(GeometryIndex { entries: entries, file_size: file_size, modified: modified })
+}
+
+pub fn write_geometry_index(fname: &str, numchan: usize) -> Result<GeometryIndex> {
+    let idx = build_geometry_index(fname, numchan)?;
+    idx.write(fname)?;
+    message!(
+        "wrote index of {} blocks to {}",
+        idx.entries.len(),
+        geometry_index_filename(fname)
+    );
+    Ok(idx)
+}
+
+//the sidecar index, unless it is missing, unreadable or out of date.
+//indexes written before the header block was skipped list it as a block,
+//and are also out of date
+fn read_current_index(fname: &str) -> Result<Option<GeometryIndex>> {
+    let header_offset = geometry_header_offset(fname)?;
+    match GeometryIndex::read(fname) {
+        Ok(idx) if idx.is_current(fname) && !idx.entries.iter().any(|e| Some(e.offset) == header_offset) => {
+            Ok(Some(idx))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub struct GeometryFileReader {
+    fname: String,
+    file: BufReader<File>,
+    index: Arc<GeometryIndex>,
+    object_filter: ObjectFilter,
+}
+
+impl GeometryFileReader {
+    //uses the sidecar index if present and current, otherwise builds it and
+    //tries to write it: if the index can't be written (e.g. the directory is
+    //read only) the built index is only kept in memory
+    pub fn open(fname: &str, numchan: usize) -> Result<GeometryFileReader> {
+        check_geometry_file(fname)?;
+        let index = match read_current_index(fname)? {
+            Some(idx) => idx,
+            None => {
+                let idx = build_geometry_index(fname, numchan)?;
+                match idx.write(fname) {
+                    Ok(()) => message!(
+                        "wrote index of {} blocks to {}",
+                        idx.entries.len(),
+                        geometry_index_filename(fname)
+                    ),
+                    Err(e) => message!(
+                        "can't write {}: {}, using index in memory",
+                        geometry_index_filename(fname),
+                        e
+                    ),
+                }
+                idx
+            }
+        };
+        Ok(GeometryFileReader {
+            fname: String::from(fname),
+            file: BufReader::new(File::open(fname)?),
+            index: Arc::new(index),
+            object_filter: ObjectFilter::all(),
+        })
+    }
+
+    //objects not matching object_filter are dropped as blocks are read,
+    //as with read_geometry_blocks
+    pub fn object_filter(mut self, object_filter: ObjectFilter) -> GeometryFileReader {
+        self.object_filter = object_filter;
+        self
+    }
+
+    pub fn index(&self) -> &GeometryIndex {
+        &self.index
+    }
+
+    //opens the file again, sharing the index, so that each thread can
+    //seek and read independently
+    pub fn try_clone(&self) -> Result<GeometryFileReader> {
+        Ok(GeometryFileReader {
+            fname: self.fname.clone(),
+            file: BufReader::new(File::open(&self.fname)?),
+            index: self.index.clone(),
+            object_filter: self.object_filter.clone(),
+        })
+    }
+
+    pub fn read_block(&mut self, entry: &GeometryIndexEntry, filter: &GeometryFilter, max_minzoom: &Option<i64>) -> Result<GeometryBlock> {
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        let fb = read_file_block(&mut self.file)?;
+        if is_header_block(&fb) {
+            return Err(Error::InvalidDataError(format!(
+                "block at offset {} is the geometry header, not a geometry block",
+                entry.offset
+            )));
+        }
+        let mut bl = unpack_geometry_block_filter(0, &file_block_data(&fb)?, filter, max_minzoom, &self.object_filter)?;
+        bl.quadtree = Quadtree::new(entry.quadtree);
+        Ok(bl)
+    }
+
+    //returns all objects overlapping bbox with minzoom at most max_minzoom
+    pub fn query(&mut self, bbox: &Bbox, max_minzoom: Option<i64>) -> Result<GeometryBlock> {
+        let filter = GeometryFilter::Bbox(bbox.clone());
+        let entries: Vec<GeometryIndexEntry> = self
+            .index
+            .entries
+            .iter()
+            .filter(|e| e.check(bbox, &max_minzoom))
+            .cloned()
+            .collect();
+
+        let mut res = GeometryBlock::new(0, Quadtree::empty(), 0);
+        for e in &entries {
+            let bl = self.read_block(e, &filter, &max_minzoom)?;
+            res.extend(bl);
+        }
+        res.sort();
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GeometryHeader, LonLat, PointGeometry};
+
+    //one block for each point, at lon 0, 10, 20 .. degrees with minzoom 0, 1, 2 ..
+    fn write_test_file(name: &str, num_blocks: i64) -> String {
+        let fname = std::env::temp_dir()
+            .join(format!("osmquadtree-geometry-index-{}-{}.pbf", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        remove_files(&fname);
+        let bbox = Bbox::new(-1800000000, -900000000, 1800000000, 900000000);
+        let header = GeometryHeader::new(bbox.clone(), None, None, None).unwrap();
+        let mut out = crate::tempfile::prep_write_geometry_pbffile(&fname, &bbox, &header, 0).unwrap();
+        for i in 0..num_blocks {
+            let lon = (i as i32) * 100000000;
+            let q = Quadtree::calculate(&Bbox::new(lon, 0, lon, 0), 18, 0.0);
+            let mut gb = GeometryBlock::new(i, q, 0);
+            let mut p = PointGeometry::empty();
+            p.id = i;
+            p.lonlat = LonLat::new(lon, 0);
+            p.minzoom = Some(i);
+            p.quadtree = q;
+            gb.points.push(p);
+            out.call(gb);
+        }
+        out.finish().unwrap();
+        fname
+    }
+
+    fn remove_files(fname: &str) {
+        let _ = std::fs::remove_file(fname);
+        let idxfn = geometry_index_filename(fname);
+        let _ = std::fs::remove_file(&idxfn);
+        let _ = std::fs::remove_dir(&idxfn);
+    }
+
+    fn query_ids(reader: &mut GeometryFileReader, bbox: &Bbox, max_minzoom: Option<i64>) -> Vec<i64> {
+        reader.query(bbox, max_minzoom).unwrap().points.iter().map(|p| p.id).collect()
+    }
+
+    fn entry(minzoom: Option<(i64, i64)>) -> GeometryIndexEntry {
+        GeometryIndexEntry {
+            quadtree: 0,
+            offset: 0,
+            bbox: (0, 0, 10000000, 10000000),
+            minzoom: minzoom,
+            num_objects: 1,
+        }
+    }
+
+    #[test]
+    fn entry_check() {
+        let inside = Bbox::new(5000000, 5000000, 20000000, 20000000);
+        let outside = Bbox::new(20000000, 20000000, 30000000, 30000000);
+        assert!(entry(None).check(&inside, &None));
+        assert!(!entry(None).check(&outside, &None));
+        assert!(entry(None).check(&inside, &Some(5)));
+        assert!(entry(Some((3, 8))).check(&inside, &Some(5)));
+        assert!(!entry(Some((7, 8))).check(&inside, &Some(5)));
+    }
+
+    #[test]
+    fn build_index_entries() {
+        let fname = write_test_file("build", 3);
+        let idx = build_geometry_index(&fname, 0).unwrap();
+        let header_offset = geometry_header_offset(&fname).unwrap();
+        assert!(idx.is_current(&fname));
+        assert_eq!(idx.entries.len(), 3);
+        for (i, e) in idx.entries.iter().enumerate() {
+            let lon = (i as i32) * 100000000;
+            assert_eq!(e.quadtree, Quadtree::calculate(&Bbox::new(lon, 0, lon, 0), 18, 0.0).as_int());
+            assert_eq!(e.bbox, (lon, 0, lon, 0));
+            assert_eq!(e.minzoom, Some((i as i64, i as i64)));
+            assert_eq!(e.num_objects, 1);
+            assert!(Some(e.offset) != header_offset);
+        }
+        //the same entries are found with threads
+        assert_eq!(build_geometry_index(&fname, 2).unwrap().entries.len(), 3);
+        remove_files(&fname);
+    }
+
+    #[test]
+    fn query_by_bbox_and_minzoom() {
+        let fname = write_test_file("query", 4);
+        let mut reader = GeometryFileReader::open(&fname, 0).unwrap();
+        assert!(std::path::Path::new(&geometry_index_filename(&fname)).is_file());
+
+        let all = Bbox::new(-1800000000, -900000000, 1800000000, 900000000);
+        assert_eq!(query_ids(&mut reader, &all, None), vec![0, 1, 2, 3]);
+        assert_eq!(query_ids(&mut reader, &all, Some(1)), vec![0, 1]);
+        let part = Bbox::new(50000000, -10000000, 250000000, 10000000);
+        assert_eq!(query_ids(&mut reader, &part, None), vec![1, 2]);
+        assert_eq!(query_ids(&mut reader, &part, Some(1)), vec![1]);
+        assert!(query_ids(&mut reader, &Bbox::new(0, 10000000, 10000000, 20000000), None).is_empty());
+
+        //a cloned reader shares the index
+        let mut cloned = reader.try_clone().unwrap();
+        assert_eq!(query_ids(&mut cloned, &part, None), vec![1, 2]);
+        remove_files(&fname);
+    }
+
+    #[test]
+    fn stale_index_rebuilt() {
+        let fname = write_test_file("stale", 2);
+        let idx = write_geometry_index(&fname, 0).unwrap();
+
+        //an index for a file of a different size or age is rebuilt
+        let mut stale = idx.clone();
+        stale.file_size += 1;
+        stale.write(&fname).unwrap();
+        let reader = GeometryFileReader::open(&fname, 0).unwrap();
+        assert_eq!(reader.index().file_size, idx.file_size);
+        assert!(GeometryIndex::read(&fname).unwrap().is_current(&fname));
+
+        let mut stale = idx.clone();
+        stale.modified = 0;
+        stale.write(&fname).unwrap();
+        let reader = GeometryFileReader::open(&fname, 0).unwrap();
+        assert_eq!(reader.index().modified, idx.modified);
+
+        //as is a current index which lists the header block
+        let mut stale = idx.clone();
+        let mut header_entry = stale.entries[0].clone();
+        header_entry.offset = geometry_header_offset(&fname).unwrap().unwrap();
+        stale.entries.insert(0, header_entry);
+        stale.write(&fname).unwrap();
+        let reader = GeometryFileReader::open(&fname, 0).unwrap();
+        assert_eq!(reader.index().entries.len(), 2);
+        assert_eq!(GeometryIndex::read(&fname).unwrap().entries.len(), 2);
+
+        //a current index is used as it is
+        let mut current = idx.clone();
+        current.entries[1].num_objects = 5;
+        current.write(&fname).unwrap();
+        let reader = GeometryFileReader::open(&fname, 0).unwrap();
+        assert_eq!(reader.index().entries[1].num_objects, 5);
+        remove_files(&fname);
+    }
+
+    #[test]
+    fn unwritable_index_kept_in_memory() {
+        let fname = write_test_file("unwritable", 2);
+        //a directory in place of the index file can't be read or written
+        std::fs::create_dir(geometry_index_filename(&fname)).unwrap();
+        let mut reader = GeometryFileReader::open(&fname, 0).unwrap();
+        assert_eq!(reader.index().entries.len(), 2);
+        let all = Bbox::new(-1800000000, -900000000, 1800000000, 900000000);
+        assert_eq!(query_ids(&mut reader, &all, None), vec![0, 1]);
+        remove_files(&fname);
+    }
+}
@@ -0,0 +1,586 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::elements::{Quadtree, Tag};
+use osmquadtree::pbfformat::get_file_locs_max_depth;
+use osmquadtree::utils::ThreadTimer;
+
+use crate::geometry_header::{check_geometry_file, GeometryHeader};
+use crate::{read_geometry_blocks, Error, GeometryBlock, OtherData, Result, Timings};
+
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+//values of keys such as name are mostly unique: stop counting them
+//separately after this many
+const MAX_VALUES_PER_KEY: usize = 1000;
+const OTHER_VALUES: &str = "(other)";
+const MAX_CONTAINMENT_EXAMPLES: usize = 20;
+
+const GEOMETRY_TYPES: [&str; 4] = ["point", "linestring", "simple_polygon", "complicated_polygon"];
+
+#[derive(Debug, Clone)]
+pub struct LargestObject {
+    pub geometry_type: &'static str,
+    pub id: i64,
+    pub quadtree: Quadtree,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyCount {
+    pub count: usize,
+    pub values: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeometryInspection {
+    pub header: Option<GeometryHeader>,
+    pub top: usize,
+    pub num_blocks: usize,
+    pub block_sizes: BTreeMap<u64, usize>,
+    pub block_objects: BTreeMap<usize, usize>,
+    pub by_zoom: BTreeMap<(&'static str, usize), usize>,
+    pub by_minzoom: BTreeMap<(&'static str, Option<i64>), usize>,
+    pub keys: HashMap<String, KeyCount>,
+    pub largest_vertices: Vec<LargestObject>,
+    pub largest_area: Vec<LargestObject>,
+    pub not_contained: usize,
+    pub not_contained_examples: Vec<(Quadtree, LargestObject)>,
+}
+
+//histograms are bucketed by the next power of two
+fn bucket(v: u64) -> u64 {
+    if v == 0 {
+        0
+    } else {
+        v.next_power_of_two()
+    }
+}
+
+fn keep_largest(objs: &mut Vec<LargestObject>, top: usize) {
+    objs.sort_by(|a, b| b.value.partial_cmp(&a.value).unwrap_or(std::cmp::Ordering::Equal));
+    objs.truncate(top);
+}
+
+fn is_contained(block: &Quadtree, obj: &Quadtree) -> bool {
+    if block.is_empty() || block.as_int() < 0 {
+        return true;
+    }
+    obj.depth() >= block.depth() && obj.round(block.depth()) == *block
+}
+
+impl GeometryInspection {
+    pub fn new(header: Option<GeometryHeader>, top: usize) -> GeometryInspection {
+        GeometryInspection {
+            header: header,
+            top: top,
+            num_blocks: 0,
+            block_sizes: BTreeMap::new(),
+            block_objects: BTreeMap::new(),
+            by_zoom: BTreeMap::new(),
+            by_minzoom: BTreeMap::new(),
+            keys: HashMap::new(),
+            largest_vertices: Vec::new(),
+            largest_area: Vec::new(),
+            not_contained: 0,
+            not_contained_examples: Vec::new(),
+        }
+    }
+
+    fn add_tags(&mut self, tags: &[Tag]) {
+        for t in tags {
+            let kc = self.keys.entry(t.key.clone()).or_insert_with(|| KeyCount {
+                count: 0,
+                values: HashMap::new(),
+            });
+            kc.count += 1;
+            if let Some(c) = kc.values.get_mut(&t.val) {
+                *c += 1;
+            } else if kc.values.len() < MAX_VALUES_PER_KEY {
+                kc.values.insert(t.val.clone(), 1);
+            } else {
+                *kc.values.entry(String::from(OTHER_VALUES)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn add_object(
+        &mut self,
+        block: &Quadtree,
+        geometry_type: &'static str,
+        id: i64,
+        quadtree: &Quadtree,
+        minzoom: &Option<i64>,
+        tags: &[Tag],
+        vertices: usize,
+        area: Option<f64>,
+    ) {
+        *self.by_zoom.entry((geometry_type, quadtree.depth())).or_insert(0) += 1;
+        *self.by_minzoom.entry((geometry_type, minzoom.clone())).or_insert(0) += 1;
+        self.add_tags(tags);
+
+        let obj = LargestObject {
+            geometry_type: geometry_type,
+            id: id,
+            quadtree: quadtree.clone(),
+            value: vertices as f64,
+        };
+        if !is_contained(block, quadtree) {
+            self.not_contained += 1;
+            if self.not_contained_examples.len() < MAX_CONTAINMENT_EXAMPLES {
+                self.not_contained_examples.push((block.clone(), obj.clone()));
+            }
+        }
+        if vertices > 1 {
+            self.largest_vertices.push(obj.clone());
+        }
+        if let Some(a) = area {
+            self.largest_area.push(LargestObject { value: a.abs(), ..obj });
+        }
+    }
+
+    pub fn add_block(&mut self, bl: &GeometryBlock) {
+        self.num_blocks += 1;
+        let n = bl.points.len()
+            + bl.linestrings.len()
+            + bl.simple_polygons.len()
+            + bl.complicated_polygons.len();
+        *self.block_objects.entry(bucket(n as u64) as usize).or_insert(0) += 1;
+
+        for p in &bl.points {
+            self.add_object(&bl.quadtree, GEOMETRY_TYPES[0], p.id, &p.quadtree, &p.minzoom, &p.tags, 1, None);
+        }
+        for p in &bl.linestrings {
+            self.add_object(&bl.quadtree, GEOMETRY_TYPES[1], p.id, &p.quadtree, &p.minzoom, &p.tags, p.lonlats.len(), None);
+        }
+        for p in &bl.simple_polygons {
+            self.add_object(&bl.quadtree, GEOMETRY_TYPES[2], p.id, &p.quadtree, &p.minzoom, &p.tags, p.lonlats.len(), Some(p.area));
+        }
+        for p in &bl.complicated_polygons {
+            let vertices = p
+                .parts
+                .iter()
+                .map(|pp| pp.exterior.len() + pp.interiors.iter().map(|r| r.len()).sum::<usize>())
+                .sum();
+            self.add_object(&bl.quadtree, GEOMETRY_TYPES[3], p.id, &p.quadtree, &p.minzoom, &p.tags, vertices, Some(p.area));
+        }
+        keep_largest(&mut self.largest_vertices, self.top);
+        keep_largest(&mut self.largest_area, self.top);
+    }
+
+    fn top_keys(&self) -> Vec<(&String, &KeyCount)> {
+        let mut kk: Vec<(&String, &KeyCount)> = self.keys.iter().collect();
+        kk.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(b.0)));
+        kk.truncate(self.top);
+        kk
+    }
+
+    fn top_values(&self, kc: &KeyCount) -> Vec<(String, usize)> {
+        let mut vv: Vec<(String, usize)> = kc.values.iter().map(|(v, c)| (v.clone(), *c)).collect();
+        vv.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        vv.truncate(self.top);
+        vv
+    }
+
+    fn type_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut res = BTreeMap::new();
+        for ((t, _), c) in &self.by_zoom {
+            *res.entry(*t).or_insert(0) += c;
+        }
+        res
+    }
+
+    pub fn to_json(&self) -> Value {
+        let header = match &self.header {
+            None => Value::Null,
+            Some(h) => json!({
+                "format_version": h.format_version,
+                "generator": h.generator,
+                "source_timestamp": h.source_timestamp,
+                "bbox": [h.bbox.minlon, h.bbox.minlat, h.bbox.maxlon, h.bbox.maxlat],
+                "pack_options": h.pack_options.to_list(),
+                "compression": h.compression.to_string(),
+                "has_style": h.style.is_some(),
+                "has_minzoom": h.minzoom.is_some(),
+            }),
+        };
+
+        let mut by_zoom = Map::new();
+        for ((t, z), c) in &self.by_zoom {
+            by_zoom.entry(String::from(*t)).or_insert_with(|| json!({}))[z.to_string()] = json!(c);
+        }
+        let mut by_minzoom = Map::new();
+        for ((t, m), c) in &self.by_minzoom {
+            let m = match m {
+                None => String::from("none"),
+                Some(m) => m.to_string(),
+            };
+            by_minzoom.entry(String::from(*t)).or_insert_with(|| json!({}))[m] = json!(c);
+        }
+
+        let mut keys = Vec::new();
+        for (k, kc) in self.top_keys() {
+            let values: Vec<Value> = self
+                .top_values(kc)
+                .into_iter()
+                .map(|(v, c)| json!({"value": v, "count": c}))
+                .collect();
+            keys.push(json!({"key": k, "count": kc.count, "num_values": kc.values.len(), "values": values}));
+        }
+
+        let largest = |objs: &Vec<LargestObject>| -> Vec<Value> {
+            objs.iter()
+                .map(|o| json!({"type": o.geometry_type, "id": o.id, "quadtree": o.quadtree.as_string(), "value": o.value}))
+                .collect()
+        };
+
+        let not_contained: Vec<Value> = self
+            .not_contained_examples
+            .iter()
+            .map(|(b, o)| json!({"block": b.as_string(), "type": o.geometry_type, "id": o.id, "quadtree": o.quadtree.as_string()}))
+            .collect();
+
+        json!({
+            "header": header,
+            "num_blocks": self.num_blocks,
+            "counts": self.type_counts(),
+            "by_zoom": by_zoom,
+            "by_minzoom": by_minzoom,
+            "keys": keys,
+            "largest_by_vertices": largest(&self.largest_vertices),
+            "largest_by_area": largest(&self.largest_area),
+            "block_sizes": self.block_sizes.iter().map(|(s, c)| json!([s, c])).collect::<Vec<Value>>(),
+            "block_objects": self.block_objects.iter().map(|(s, c)| json!([s, c])).collect::<Vec<Value>>(),
+            "not_contained": self.not_contained,
+            "not_contained_examples": not_contained,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut res = String::new();
+        self.write_text(&mut res).unwrap();
+        res
+    }
+
+    fn write_text(&self, res: &mut String) -> std::fmt::Result {
+        match &self.header {
+            None => writeln!(res, "no header")?,
+            Some(h) => {
+                writeln!(res, "format version {}, written by {}", h.format_version, h.generator)?;
+                writeln!(
+                    res,
+                    "bbox {} {} {} {}, timestamp {:?}",
+                    h.bbox.minlon, h.bbox.minlat, h.bbox.maxlon, h.bbox.maxlat, h.source_timestamp
+                )?;
+                writeln!(
+                    res,
+                    "pack options {}, compression {}, style {}, minzoom {}",
+                    h.pack_options.to_list(),
+                    h.compression.to_string(),
+                    h.style.is_some(),
+                    h.minzoom.is_some()
+                )?;
+            }
+        }
+        writeln!(res, "\n{} blocks", self.num_blocks)?;
+        for (t, c) in self.type_counts() {
+            writeln!(res, "{:20} {:>12}", t, c)?;
+        }
+
+        writeln!(res, "\nobjects by zoom")?;
+        write!(res, "{:>5}", "zoom")?;
+        for t in &GEOMETRY_TYPES {
+            write!(res, " {:>20}", t)?;
+        }
+        writeln!(res)?;
+        let zooms: std::collections::BTreeSet<usize> = self.by_zoom.keys().map(|(_, z)| *z).collect();
+        for z in zooms {
+            write!(res, "{:>5}", z)?;
+            for t in &GEOMETRY_TYPES {
+                write!(res, " {:>20}", self.by_zoom.get(&(*t, z)).unwrap_or(&0))?;
+            }
+            writeln!(res)?;
+        }
+
+        writeln!(res, "\nobjects by minzoom")?;
+        write!(res, "{:>7}", "minzoom")?;
+        for t in &GEOMETRY_TYPES {
+            write!(res, " {:>20}", t)?;
+        }
+        writeln!(res)?;
+        let minzooms: std::collections::BTreeSet<Option<i64>> = self.by_minzoom.keys().map(|(_, m)| *m).collect();
+        for m in minzooms {
+            match m {
+                None => write!(res, "{:>7}", "none")?,
+                Some(m) => write!(res, "{:>7}", m)?,
+            }
+            for t in &GEOMETRY_TYPES {
+                write!(res, " {:>20}", self.by_minzoom.get(&(*t, m)).unwrap_or(&0))?;
+            }
+            writeln!(res)?;
+        }
+
+        writeln!(res, "\ntop {} keys", self.top)?;
+        for (k, kc) in self.top_keys() {
+            writeln!(res, "{:30} {:>12} ({} values)", k, kc.count, kc.values.len())?;
+            for (v, c) in self.top_values(kc) {
+                writeln!(res, "    {:26} {:>12}", v, c)?;
+            }
+        }
+
+        for (title, objs) in &[("vertices", &self.largest_vertices), ("area", &self.largest_area)] {
+            writeln!(res, "\nlargest by {}", title)?;
+            for o in objs.iter() {
+                writeln!(res, "{:20} {:>12} {:20} {:>16.1}", o.geometry_type, o.id, o.quadtree.as_string(), o.value)?;
+            }
+        }
+
+        writeln!(res, "\nblock sizes (bytes)")?;
+        for (s, c) in &self.block_sizes {
+            writeln!(res, "<= {:>12} {:>8}", s, c)?;
+        }
+        writeln!(res, "\nblock sizes (objects)")?;
+        for (s, c) in &self.block_objects {
+            writeln!(res, "<= {:>12} {:>8}", s, c)?;
+        }
+
+        writeln!(res, "\n{} objects not contained in their block quadtree", self.not_contained)?;
+        for (b, o) in &self.not_contained_examples {
+            writeln!(res, "{:20} {:>12} {:20} in block {}", o.geometry_type, o.id, o.quadtree.as_string(), b.as_string())?;
+        }
+        Ok(())
+    }
+}
+
+struct InspectGeometry {
+    inspection: Arc<Mutex<GeometryInspection>>,
+    tm: f64,
+}
+
+impl CallFinish for InspectGeometry {
+    type CallType = GeometryBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: GeometryBlock) {
+        let tx = ThreadTimer::new();
+        if !bl.quadtree.is_empty() {
+            self.inspection.lock().unwrap().add_block(&bl);
+        }
+        self.tm += tx.since();
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = Timings::new();
+        tms.add("InspectGeometry", self.tm);
+        tms.add_other(
+            "InspectGeometry",
+            OtherData::Messages(vec![format!("inspected {} blocks", self.inspection.lock().unwrap().num_blocks)]),
+        );
+        Ok(tms)
+    }
+}
+
+//packed block sizes are found from the offsets of consecutive blocks
+fn block_sizes(infn: &str) -> Result<BTreeMap<u64, usize>> {
+    let (_, locs, _) = get_file_locs_max_depth(infn, None, None, None)?;
+    let mut offsets: Vec<(u64, bool)> = Vec::new();
+    for (q, ll) in locs {
+        for (_, p) in ll {
+            offsets.push((p, q.as_int() >= 0));
+        }
+    }
+    offsets.sort();
+    let file_len = std::fs::metadata(infn)?.len();
+
+    let mut res = BTreeMap::new();
+    for (i, (p, is_data)) in offsets.iter().enumerate() {
+        if !is_data {
+            continue;
+        }
+        let end = offsets.get(i + 1).map(|x| x.0).unwrap_or(file_len);
+        *res.entry(bucket(end - p)).or_insert(0) += 1;
+    }
+    Ok(res)
+}
+
+pub fn inspect_geometry(infn: &str, top: usize, numchan: usize) -> Result<GeometryInspection> {
+    let header = check_geometry_file(infn)?;
+    if header.is_none() {
+        return Err(Error::UserSelectionError(format!("{} is not a geometry pbf file", infn)));
+    }
+    let mut inspection = GeometryInspection::new(header, top);
+    inspection.block_sizes = block_sizes(infn)?;
+
+    let inspection = Arc::new(Mutex::new(inspection));
+    let cc = Box::new(InspectGeometry {
+        inspection: inspection.clone(),
+        tm: 0.0,
+    });
+    read_geometry_blocks(infn, cc, None, None, None, numchan)?;
+
+    let res = inspection.lock().unwrap().clone();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LinestringGeometry, LonLat, PointGeometry, SimplePolygonGeometry};
+    use osmquadtree::elements::Bbox;
+
+    fn quadtree_at(lon: i32, lat: i32) -> Quadtree {
+        Quadtree::calculate(&Bbox::new(lon, lat, lon, lat), 18, 0.0)
+    }
+
+    fn point(id: i64, lon: i32, amenity: &str) -> PointGeometry {
+        let mut p = PointGeometry::empty();
+        p.id = id;
+        p.lonlat = LonLat::new(lon, 100000000);
+        p.quadtree = quadtree_at(lon, 100000000);
+        p.tags = vec![Tag::new(String::from("amenity"), String::from(amenity))];
+        p
+    }
+
+    //a block at depth 4 around lon -40, with one point far outside it
+    fn test_block() -> GeometryBlock {
+        let mut bl = GeometryBlock::new(0, quadtree_at(-400000000, 100000000).round(4), 0);
+        bl.points.push(point(1, -400000000, "cafe"));
+        bl.points.push(point(2, 400000000, "pub"));
+
+        let mut l = LinestringGeometry::empty();
+        l.id = 3;
+        l.lonlats = vec![LonLat::new(-400000000, 100000000), LonLat::new(-400000100, 100000000), LonLat::new(-400000200, 100000000)];
+        l.quadtree = quadtree_at(-400000000, 100000000);
+        bl.linestrings.push(l);
+
+        let mut sp = SimplePolygonGeometry::empty();
+        sp.id = 4;
+        sp.lonlats = vec![LonLat::new(-400000000, 100000000); 4];
+        sp.area = -25.0;
+        sp.quadtree = quadtree_at(-400000000, 100000000);
+        sp.tags = vec![Tag::new(String::from("amenity"), String::from("cafe"))];
+        bl.simple_polygons.push(sp);
+        bl
+    }
+
+    fn largest(obj: i64, value: f64) -> LargestObject {
+        LargestObject { geometry_type: GEOMETRY_TYPES[0], id: obj, quadtree: Quadtree::empty(), value: value }
+    }
+
+    #[test]
+    fn bucket_by_power_of_two() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(3), 4);
+        assert_eq!(bucket(4), 4);
+        assert_eq!(bucket(5), 8);
+    }
+
+    #[test]
+    fn keep_largest_sorts_and_truncates() {
+        let mut objs = vec![largest(1, 1.0), largest(2, 5.0), largest(3, 3.0)];
+        keep_largest(&mut objs, 2);
+        let ids: Vec<i64> = objs.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn add_block_counts() {
+        let bl = test_block();
+        let mut ins = GeometryInspection::new(None, 20);
+        ins.add_block(&bl);
+
+        assert_eq!(ins.num_blocks, 1);
+        assert_eq!(ins.block_objects, BTreeMap::from([(4, 1)]));
+        assert_eq!(ins.type_counts(), BTreeMap::from([("point", 2), ("linestring", 1), ("simple_polygon", 1)]));
+        assert_eq!(ins.by_minzoom.get(&("point", None)), Some(&2));
+
+        let amenity = ins.keys.get("amenity").unwrap();
+        assert_eq!(amenity.count, 3);
+        assert_eq!(amenity.values.get("cafe"), Some(&2));
+        assert_eq!(amenity.values.get("pub"), Some(&1));
+
+        //points have a single vertex, so are not listed
+        let ids: Vec<i64> = ins.largest_vertices.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![4, 3]);
+        assert_eq!(ins.largest_area.len(), 1);
+        assert_eq!(ins.largest_area[0].value, 25.0);
+
+        //only point 2 is outside the block quadtree
+        assert_eq!(ins.not_contained, 1);
+        assert_eq!(ins.not_contained_examples.len(), 1);
+        assert_eq!(ins.not_contained_examples[0].0, bl.quadtree);
+        assert_eq!(ins.not_contained_examples[0].1.id, 2);
+    }
+
+    #[test]
+    fn empty_block_quadtree_contains_all() {
+        let mut bl = test_block();
+        bl.quadtree = Quadtree::empty();
+        let mut ins = GeometryInspection::new(None, 20);
+        ins.add_block(&bl);
+        assert_eq!(ins.not_contained, 0);
+    }
+
+    #[test]
+    fn values_spill_into_other() {
+        let mut ins = GeometryInspection::new(None, 20);
+        let tags: Vec<Tag> = (0..MAX_VALUES_PER_KEY + 5)
+            .map(|i| Tag::new(String::from("name"), format!("name {}", i)))
+            .collect();
+        ins.add_tags(&tags);
+        //values already seen are still counted themselves
+        ins.add_tags(&tags[..1]);
+
+        let kc = ins.keys.get("name").unwrap();
+        assert_eq!(kc.count, MAX_VALUES_PER_KEY + 6);
+        assert_eq!(kc.values.len(), MAX_VALUES_PER_KEY + 1);
+        assert_eq!(kc.values.get(OTHER_VALUES), Some(&5));
+        assert_eq!(kc.values.get("name 0"), Some(&2));
+    }
+
+    #[test]
+    fn inspection_as_json() {
+        let bl = test_block();
+        let mut ins = GeometryInspection::new(None, 1);
+        ins.add_block(&bl);
+        let js = ins.to_json();
+
+        assert_eq!(js["header"], Value::Null);
+        assert_eq!(js["num_blocks"], 1);
+        assert_eq!(js["counts"], json!({"linestring": 1, "point": 2, "simple_polygon": 1}));
+        let z = bl.points[0].quadtree.depth().to_string();
+        assert_eq!(js["by_zoom"]["point"][z], 2);
+        assert_eq!(js["by_minzoom"]["point"]["none"], 2);
+        //top is 1, so only the most common key and value are listed
+        assert_eq!(
+            js["keys"],
+            json!([{"key": "amenity", "count": 3, "num_values": 2, "values": [{"value": "cafe", "count": 2}]}])
+        );
+        assert_eq!(js["largest_by_vertices"][0]["id"], 4);
+        assert_eq!(js["largest_by_area"][0]["value"], 25.0);
+        assert_eq!(js["block_objects"], json!([[4, 1]]));
+        assert_eq!(js["not_contained"], 1);
+        assert_eq!(js["not_contained_examples"][0]["id"], 2);
+        assert_eq!(js["not_contained_examples"][0]["block"], bl.quadtree.as_string());
+    }
+
+    #[test]
+    fn inspection_as_text() {
+        let bl = test_block();
+        let mut ins = GeometryInspection::new(None, 20);
+        ins.add_block(&bl);
+        let txt = ins.to_text();
+
+        assert!(txt.starts_with("no header\n"));
+        assert!(txt.contains("\n1 blocks\n"));
+        assert!(txt.contains(&format!("{:20} {:>12}\n", "point", 2)));
+        assert!(txt.contains("\ntop 20 keys\n"));
+        assert!(txt.contains(&format!("{:30} {:>12} ({} values)\n", "amenity", 3, 2)));
+        assert!(txt.contains(&format!("    {:26} {:>12}\n", "cafe", 2)));
+        assert!(txt.contains("\n1 objects not contained in their block quadtree\n"));
+        assert!(txt.contains(&format!("in block {}", bl.quadtree.as_string())));
+    }
+}
@@ -0,0 +1,172 @@
+use regex::RegexSet;
+
+//style keys are patterns if they are globs, such as name:* or addr:?, or
+//regexes starting with ^, such as ^tiger:
+pub fn is_key_pattern(k: &str) -> bool {
+    k.starts_with('^') || k.contains('*') || k.contains('?')
+}
+
+fn is_regex_meta(c: char) -> bool {
+    "\\.+*?()|[]{}^$".contains(c)
+}
+
+//globs ending in a single * and regexes of the form ^literal are checked
+//with starts_with, which covers most patterns without using the RegexSet
+fn as_prefix(k: &str) -> Option<&str> {
+    if let Some(p) = k.strip_suffix('*') {
+        if !p.starts_with('^') && !p.contains('*') && !p.contains('?') {
+            return Some(p);
+        }
+    }
+    if let Some(p) = k.strip_prefix('^') {
+        if !p.chars().any(is_regex_meta) {
+            return Some(p);
+        }
+    }
+    None
+}
+
+fn glob_to_regex(k: &str) -> String {
+    let mut res = String::from("^");
+    for c in k.chars() {
+        match c {
+            '*' => res.push_str(".*"),
+            '?' => res.push('.'),
+            c if is_regex_meta(c) => {
+                res.push('\\');
+                res.push(c);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('$');
+    res
+}
+
+//the pattern keys of one style field, precompiled
+#[derive(Debug)]
+pub struct KeyPatterns {
+    prefixes: Vec<(String, usize)>,
+    set: Option<(RegexSet, Vec<usize>)>,
+    keys: Vec<String>,
+}
+
+impl KeyPatterns {
+    //the exact keys are skipped, so these are only checked when a tag's key
+    //isn't found directly. Returns the failing pattern on error.
+    pub fn new<'a>(keys: impl Iterator<Item = &'a String>) -> Result<KeyPatterns, (String, String)> {
+        let mut prefixes = Vec::new();
+        let mut regexes = Vec::new();
+        let mut regex_idx = Vec::new();
+        let mut all_keys = Vec::new();
+        for k in keys {
+            if !is_key_pattern(k) {
+                continue;
+            }
+            let i = all_keys.len();
+            all_keys.push(k.clone());
+            match as_prefix(k) {
+                Some(p) => prefixes.push((String::from(p), i)),
+                None => {
+                    regexes.push(if k.starts_with('^') { k.clone() } else { glob_to_regex(k) });
+                    regex_idx.push(i);
+                }
+            }
+        }
+        let set = if regexes.is_empty() {
+            None
+        } else {
+            match RegexSet::new(&regexes) {
+                Ok(s) => Some((s, regex_idx)),
+                Err(e) => {
+                    //find which pattern failed to compile
+                    for (r, i) in regexes.iter().zip(regex_idx.iter()) {
+                        if let Err(e) = RegexSet::new(&[r]) {
+                            return Err((all_keys[*i].clone(), e.to_string()));
+                        }
+                    }
+                    return Err((String::new(), e.to_string()));
+                }
+            }
+        };
+        Ok(KeyPatterns { prefixes: prefixes, set: set, keys: all_keys })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn matches(&self, k: &str) -> bool {
+        self.first_match(k).is_some()
+    }
+
+    //the first pattern, in the order given, which matches k
+    pub fn first_match(&self, k: &str) -> Option<&str> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let mut best: Option<usize> = None;
+        for (p, i) in &self.prefixes {
+            if k.starts_with(p.as_str()) {
+                best = Some(*i);
+                break;
+            }
+        }
+        if let Some((set, idx)) = &self.set {
+            if let Some(m) = set.matches(k).iter().next() {
+                let i = idx[m];
+                if best.map_or(true, |b| i < b) {
+                    best = Some(i);
+                }
+            }
+        }
+        best.map(|i| self.keys[i].as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(keys: &[&str]) -> KeyPatterns {
+        let keys: Vec<String> = keys.iter().map(|k| String::from(*k)).collect();
+        KeyPatterns::new(keys.iter()).unwrap()
+    }
+
+    #[test]
+    fn prefix_patterns() {
+        assert_eq!(as_prefix("name:*"), Some("name:"));
+        assert_eq!(as_prefix("^tiger:"), Some("tiger:"));
+        assert_eq!(as_prefix("addr:?"), None);
+        assert_eq!(as_prefix("*:en*"), None);
+        assert_eq!(as_prefix("^name:(en|de)"), None);
+        assert_eq!(as_prefix("^*"), None);
+    }
+
+    #[test]
+    fn first_match_follows_given_order() {
+        assert_eq!(patterns(&["name:*", "*:en"]).first_match("name:en"), Some("name:*"));
+        assert_eq!(patterns(&["*:en", "name:*"]).first_match("name:en"), Some("*:en"));
+        assert_eq!(patterns(&["^name:e", "name:*"]).first_match("name:en"), Some("^name:e"));
+        assert_eq!(patterns(&["name:*", "^name:e"]).first_match("name:en"), Some("name:*"));
+
+        let p = patterns(&["addr:?", "^ti[gr]+er:"]);
+        assert_eq!(p.first_match("addr:x"), Some("addr:?"));
+        assert_eq!(p.first_match("addr:xy"), None);
+        assert_eq!(p.first_match("tiger:county"), Some("^ti[gr]+er:"));
+    }
+
+    #[test]
+    fn exact_keys_are_skipped() {
+        let p = patterns(&["name", "highway"]);
+        assert!(p.is_empty());
+        assert!(!p.matches("name"));
+    }
+
+    #[test]
+    fn invalid_regex_is_reported() {
+        let keys = vec![String::from("name:*"), String::from("^(a")];
+        let (k, _) = KeyPatterns::new(keys.iter()).unwrap_err();
+        assert_eq!(k, "^(a");
+    }
+}
@@ -1,23 +1,42 @@
 mod addparenttag;
+mod antimeridian;
+mod compression;
+mod customstages;
 mod default_minzoom_values;
 mod default_style;
 mod elements;
 mod geometry_block;
+mod geometry_header;
+mod geometry_index;
+mod inspect;
+mod keymatch;
+mod localizednames;
+mod mergelines;
+mod mergesplit;
 mod minzoom;
+mod multipolygonerrors;
 mod multipolygons;
 mod pack_geometry;
+mod pipeline;
+mod polylabel;
 mod position;
 pub mod postgresql;
 mod process_geometry;
 mod relationtags;
+mod ringbuilder;
+pub mod serve;
 mod style;
+mod stylelint;
+mod tagfilter;
+mod transform;
 mod waywithnodes;
 mod wkb;
 mod tempfile;
+mod validate;
 
 
 
-use osmquadtree::elements::{Element, Node, Quadtree, Relation, Way};
+use osmquadtree::elements::{Bbox, Element, Node, Quadtree, Relation, Way};
 use osmquadtree::sortblocks::TempData;
 pub use crate::position::{get_srid, LonLat, XY,calc_line_length};
 pub use crate::waywithnodes::{CollectWayNodes,Locations};
@@ -27,13 +46,50 @@ pub use crate::elements::{
     SimplePolygonGeometry, GeoJsonable, WithBounds
 };
 pub use crate::geometry_block::{GeometryElement,GeometryBlock};
-pub use crate::process_geometry::{process_geometry, OutputType, StoreBlocks,process_geometry_call};
-pub use crate::style::GeometryStyle;
+pub use crate::compression::{file_block_data, open_compressed_file, CompressedWriter, Compression};
+pub use crate::geometry_header::{
+    check_geometry_file, read_geometry_header, GeometryHeader, GEOMETRY_FORMAT_VERSION,
+};
+pub use crate::geometry_index::{
+    build_geometry_index, geometry_index_filename, write_geometry_index, GeometryFileReader,
+    GeometryIndex, GeometryIndexEntry,
+};
+pub use crate::inspect::{inspect_geometry, GeometryInspection};
+pub use crate::customstages::{
+    CustomStage, CustomStages, GeometryBlockStage, StagePoint, WorkingBlockStage,
+};
+pub use crate::pipeline::{GeometryBlocks, GeometryPipeline};
+pub use crate::process_geometry::{convert_geometry, process_geometry, OutputType, ProcessGeometryOptions, StoreBlocks,process_geometry_call};
+pub use crate::style::{dump_geometry_style, GeometryStyle};
+pub use crate::keymatch::is_key_pattern;
+pub use crate::localizednames::{localized_name_key, resolve_localized_name, LocalizedNameSpec};
+pub use crate::stylelint::{
+    issues_to_text, read_style_unchecked, style_dry_run, validate_style, IssueLevel, StyleDryRun, StyleIssue,
+};
+pub use crate::transform::TagTransform;
+pub use crate::tagfilter::{GeometryTypes, ObjectFilter, TagFilter};
 pub use crate::tempfile::{prep_write_geometry_pbffile, make_write_temp_geometry, write_temp_geometry};
 pub use crate::minzoom::{FindMinZoom, MinZoomSpec};
+pub use crate::mergelines::{merge_linestrings, MergeLines};
+pub use crate::mergesplit::{merge_geometry, split_geometry_filters, split_geometry_quadtree};
+pub use crate::antimeridian::{
+    antimeridian_bounds, bounds_union, crosses_antimeridian, quadtree_bounds,
+    split_line_at_antimeridian, split_polygon_at_antimeridian,
+};
+pub use crate::polylabel::{
+    complicated_polygon_label_point, polylabel, simple_polygon_label_point, FindLabelPoints,
+};
+pub use crate::validate::{
+    repair_complicated_polygon, repair_simple_polygon, validate_complicated_polygon,
+    validate_simple_polygon, ValidateGeometries, ValidateMode, ValidationIssue,
+};
+pub use crate::multipolygonerrors::{write_multipolygon_errors, MultiPolygonError, MultiPolygonErrorKind};
 pub use crate::default_minzoom_values::DEFAULT_MINZOOM_VALUES;
 
-pub use pack_geometry::read_geometry_blocks;
+pub use pack_geometry::{
+    pack_geometry_block_with_options, read_geometry_blocks, read_geometry_blocks_unordered,
+    GeometryFilter, GeometryPackOptions,
+};
 
 
 pub use osmquadtree::{Error,Result};
@@ -63,6 +119,8 @@ pub enum OtherData {
     Messages(Vec<String>),
     GeometryBlocks(BTreeMap<Quadtree, GeometryBlock>),
     TempData(TempData),
+    MultiPolygonErrors(Vec<MultiPolygonError>),
+    IndexEntries(BTreeMap<i64, (Bbox, Option<(i64, i64)>, usize)>),
 }
 
 pub type Timings = channelled_callbacks::Timings<OtherData>;
@@ -0,0 +1,121 @@
+use osmquadtree::elements::Tag;
+
+use deunicode::deunicode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+fn default_name_fallback() -> Vec<String> {
+    vec![String::from("int_name"), String::from("name")]
+}
+
+//the default column name holding the name for lang
+pub fn localized_name_key(lang: &str) -> String {
+    format!("name_{}", lang)
+}
+
+//latin letters, including the accented ranges, are left alone
+fn is_latin(c: char) -> bool {
+    !c.is_alphabetic()
+        || c.is_ascii()
+        || ('\u{00C0}'..='\u{024F}').contains(&c)
+        || ('\u{1E00}'..='\u{1EFF}').contains(&c)
+}
+
+//name:<lang>, or else the first fallback key present. Fallback values in a
+//non-latin script are transliterated if transliterate is set.
+pub fn resolve_localized_name(lang: &str, fallback: &[String], transliterate: bool, tags: &[Tag]) -> Option<String> {
+    let key = format!("name:{}", lang);
+    if let Some(t) = tags.iter().find(|t| t.key == key) {
+        return Some(t.val.clone());
+    }
+    for k in fallback {
+        if let Some(t) = tags.iter().find(|t| &t.key == k) {
+            if transliterate && !t.val.chars().all(is_latin) {
+                return Some(deunicode(&t.val));
+            }
+            return Some(t.val.clone());
+        }
+    }
+    None
+}
+
+//a name_<lang> column for each language, resolved from the object's tags
+//when the output is written. The keys used are always kept by the style.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalizedNameSpec {
+    pub languages: Vec<String>,
+    #[serde(default = "default_name_fallback")]
+    pub fallback: Vec<String>,
+    #[serde(default)]
+    pub transliterate: BTreeSet<String>,
+}
+
+impl LocalizedNameSpec {
+    pub fn uses_key(&self, k: &str) -> bool {
+        if self.fallback.iter().any(|f| f == k) {
+            return true;
+        }
+        match k.strip_prefix("name:") {
+            Some(l) => self.languages.iter().any(|x| x == l),
+            None => false,
+        }
+    }
+
+    pub fn resolve(&self, lang: &str, tags: &[Tag]) -> Option<String> {
+        resolve_localized_name(lang, &self.fallback, self.transliterate.contains(lang), tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(kv: &[(&str, &str)]) -> Vec<Tag> {
+        kv.iter().map(|(k, v)| Tag::new(String::from(*k), String::from(*v))).collect()
+    }
+
+    fn spec() -> LocalizedNameSpec {
+        serde_json::from_str(r#"{"languages": ["de", "en"], "transliterate": ["en"]}"#).unwrap()
+    }
+
+    #[test]
+    fn fallback_chain() {
+        let sp = spec();
+        assert_eq!(sp.fallback, vec![String::from("int_name"), String::from("name")]);
+
+        let tgs = tags(&[("name", "München"), ("name:de", "München"), ("name:en", "Munich")]);
+        assert_eq!(sp.resolve("en", &tgs), Some(String::from("Munich")));
+
+        let tgs = tags(&[("name", "Roma"), ("int_name", "Rome")]);
+        assert_eq!(sp.resolve("de", &tgs), Some(String::from("Rome")));
+
+        let tgs = tags(&[("name", "Zürich")]);
+        assert_eq!(sp.resolve("de", &tgs), Some(String::from("Zürich")));
+        assert_eq!(sp.resolve("en", &tgs), Some(String::from("Zürich")));
+
+        assert_eq!(sp.resolve("de", &tags(&[("highway", "primary")])), None);
+    }
+
+    #[test]
+    fn transliterate_fallback_only() {
+        let sp = spec();
+        let tgs = tags(&[("name", "Москва")]);
+        assert_eq!(sp.resolve("en", &tgs), Some(String::from("Moskva")));
+        assert_eq!(sp.resolve("de", &tgs), Some(String::from("Москва")));
+
+        //name:<lang> values are never transliterated
+        let tgs = tags(&[("name", "Москва"), ("name:en", "Москва")]);
+        assert_eq!(sp.resolve("en", &tgs), Some(String::from("Москва")));
+    }
+
+    #[test]
+    fn keys_used() {
+        let sp = spec();
+        assert!(sp.uses_key("name"));
+        assert!(sp.uses_key("int_name"));
+        assert!(sp.uses_key("name:de"));
+        assert!(!sp.uses_key("name:fr"));
+        assert!(!sp.uses_key("highway"));
+        assert_eq!(localized_name_key("de"), "name_de");
+    }
+}
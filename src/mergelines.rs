@@ -0,0 +1,345 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::elements::{Quadtree, Tag};
+use osmquadtree::utils::ThreadTimer;
+
+use crate::antimeridian::quadtree_bounds;
+use crate::elements::WithBounds;
+use crate::{LinestringGeometry, OtherData, Timings, WorkingBlock, Error};
+
+use std::collections::BTreeMap;
+
+type LineKey = (Vec<Tag>, Option<i64>, Option<i64>, Option<i64>);
+
+fn line_key(ln: &LinestringGeometry) -> LineKey {
+    let mut tags = ln.tags.clone();
+    tags.sort();
+    (tags, ln.layer, ln.z_order, ln.minzoom)
+}
+
+fn is_oneway(tags: &[Tag]) -> bool {
+    for t in tags {
+        if t.key == "oneway" && t.val != "no" {
+            return true;
+        }
+    }
+    false
+}
+
+fn first_ref(ln: &LinestringGeometry) -> i64 {
+    ln.refs[0]
+}
+
+fn last_ref(ln: &LinestringGeometry) -> i64 {
+    ln.refs[ln.refs.len() - 1]
+}
+
+fn reverse_line(ln: &mut LinestringGeometry) {
+    ln.refs.reverse();
+    ln.lonlats.reverse();
+}
+
+fn append_line(res: &mut LinestringGeometry, other: LinestringGeometry) {
+    res.refs.extend(other.refs.into_iter().skip(1));
+    res.lonlats.extend(other.lonlats.into_iter().skip(1));
+    res.length += other.length;
+    if other.source_ids.is_empty() {
+        res.source_ids.push(other.id);
+    } else {
+        res.source_ids.extend(other.source_ids);
+    }
+}
+
+fn prepend_line(res: &mut LinestringGeometry, mut other: LinestringGeometry) {
+    other.refs.extend(res.refs.iter().skip(1).cloned());
+    other.lonlats.extend(res.lonlats.iter().skip(1).cloned());
+    res.refs = other.refs;
+    res.lonlats = other.lonlats;
+    res.length += other.length;
+
+    let mut ids = if other.source_ids.is_empty() {
+        vec![other.id]
+    } else {
+        other.source_ids
+    };
+    ids.extend(std::mem::take(&mut res.source_ids));
+    res.source_ids = ids;
+}
+
+struct LineGroup {
+    lines: Vec<Option<LinestringGeometry>>,
+    ends: BTreeMap<i64, Vec<usize>>,
+    oneway: bool,
+}
+
+impl LineGroup {
+    fn new(lines: Vec<LinestringGeometry>) -> LineGroup {
+        let oneway = is_oneway(&lines[0].tags);
+        let mut ends: BTreeMap<i64, Vec<usize>> = BTreeMap::new();
+        for (i, ln) in lines.iter().enumerate() {
+            ends.entry(first_ref(ln)).or_insert_with(Vec::new).push(i);
+            ends.entry(last_ref(ln)).or_insert_with(Vec::new).push(i);
+        }
+        LineGroup {
+            lines: lines.into_iter().map(Some).collect(),
+            ends,
+            oneway,
+        }
+    }
+
+    //only join at nodes where exactly two line ends meet: anything else is a junction
+    fn find_next(&self, node: i64, curr: usize) -> Option<usize> {
+        match self.ends.get(&node) {
+            Some(ii) if ii.len() == 2 => {
+                let j = if ii[0] == curr { ii[1] } else { ii[0] };
+                if j == curr || self.lines[j].is_none() {
+                    None
+                } else {
+                    Some(j)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn merge_from(&mut self, idx: usize) -> LinestringGeometry {
+        let mut res = self.lines[idx].take().unwrap();
+        if first_ref(&res) == last_ref(&res) {
+            return res;
+        }
+        res.source_ids.push(res.id);
+
+        let mut curr = idx;
+        loop {
+            let node = last_ref(&res);
+            if node == first_ref(&res) {
+                break;
+            }
+            match self.find_next(node, curr) {
+                None => {
+                    break;
+                }
+                Some(j) => {
+                    let ln = self.lines[j].as_ref().unwrap();
+                    if first_ref(ln) == last_ref(ln) {
+                        break;
+                    }
+                    if first_ref(ln) != node && self.oneway {
+                        break;
+                    }
+                    let mut ln = self.lines[j].take().unwrap();
+                    if first_ref(&ln) != node {
+                        reverse_line(&mut ln);
+                    }
+                    append_line(&mut res, ln);
+                    curr = j;
+                }
+            }
+        }
+
+        curr = idx;
+        loop {
+            let node = first_ref(&res);
+            if node == last_ref(&res) {
+                break;
+            }
+            match self.find_next(node, curr) {
+                None => {
+                    break;
+                }
+                Some(j) => {
+                    let ln = self.lines[j].as_ref().unwrap();
+                    if first_ref(ln) == last_ref(ln) {
+                        break;
+                    }
+                    if last_ref(ln) != node && self.oneway {
+                        break;
+                    }
+                    let mut ln = self.lines[j].take().unwrap();
+                    if last_ref(&ln) != node {
+                        reverse_line(&mut ln);
+                    }
+                    prepend_line(&mut res, ln);
+                    curr = j;
+                }
+            }
+        }
+        res
+    }
+}
+
+//joins lines with the same tags, layer, z_order and minzoom where exactly two
+//line ends meet. A merged line keeps the id and info of the way it was
+//started from, with the ids of all its ways in source_ids.
+pub fn merge_linestrings(lines: Vec<LinestringGeometry>) -> (Vec<LinestringGeometry>, usize) {
+    let mut groups: BTreeMap<LineKey, Vec<LinestringGeometry>> = BTreeMap::new();
+    let mut res = Vec::with_capacity(lines.len());
+    for ln in lines {
+        if ln.refs.len() < 2 {
+            res.push(ln);
+            continue;
+        }
+        groups.entry(line_key(&ln)).or_insert_with(Vec::new).push(ln);
+    }
+
+    let mut num_merged = 0;
+    for (_, gg) in groups {
+        if gg.len() == 1 {
+            res.extend(gg);
+            continue;
+        }
+
+        let mut group = LineGroup::new(gg);
+        for i in 0..group.lines.len() {
+            if group.lines[i].is_none() {
+                continue;
+            }
+            let mut ln = group.merge_from(i);
+            if ln.source_ids.len() == 1 {
+                ln.source_ids.clear();
+            } else if !ln.source_ids.is_empty() {
+                num_merged += ln.source_ids.len();
+                ln.quadtree = Quadtree::calculate(&quadtree_bounds(&ln.bounds()), 18, 0.0);
+            }
+            res.push(ln);
+        }
+    }
+    res.sort_by_key(|l| l.id);
+    (res, num_merged)
+}
+
+//merges the linestrings of each block separately: ways are only joined to
+//ways in the same quadtree block, so lines which continue into another block
+//are left as separate fragments. A way which crosses a block boundary is in
+//the block of the smallest quadtree containing it, so the ways left unmerged
+//are those meeting at a node on the edge between two blocks.
+pub struct MergeLines<T: ?Sized> {
+    out: Box<T>,
+    tm: f64,
+    num_lines: usize,
+    num_merged: usize,
+    num_result: usize,
+}
+
+impl<T> MergeLines<T>
+where
+    T: CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error> + ?Sized,
+{
+    pub fn new(out: Box<T>) -> MergeLines<T> {
+        MergeLines {
+            out: out,
+            tm: 0.0,
+            num_lines: 0,
+            num_merged: 0,
+            num_result: 0,
+        }
+    }
+}
+
+impl<T> CallFinish for MergeLines<T>
+where
+    T: CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error> + ?Sized,
+{
+    type CallType = WorkingBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, mut bl: WorkingBlock) {
+        let tx = ThreadTimer::new();
+        let lines = std::mem::take(&mut bl.geometry_block.linestrings);
+        self.num_lines += lines.len();
+        let (lines, nm) = merge_linestrings(lines);
+        self.num_merged += nm;
+        self.num_result += lines.len();
+        bl.geometry_block.linestrings = lines;
+        self.tm += tx.since();
+        self.out.call(bl);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = self.out.finish()?;
+        tms.add("MergeLines", self.tm);
+        tms.add_other(
+            "MergeLines",
+            OtherData::Messages(vec![format!(
+                "{} linestrings in, {} out: merged {} source ways",
+                self.num_lines, self.num_result, self.num_merged
+            )]),
+        );
+        Ok(tms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LonLat;
+
+    fn line(id: i64, refs: &[i64], tags: &[(&str, &str)]) -> LinestringGeometry {
+        let mut ln = LinestringGeometry::empty();
+        ln.id = id;
+        ln.refs = refs.to_vec();
+        ln.lonlats = refs.iter().map(|r| LonLat::new(*r as i32 * 10, 0)).collect();
+        ln.tags = tags.iter().map(|(k, v)| Tag::new(k.to_string(), v.to_string())).collect();
+        ln.length = (refs.len() - 1) as f64;
+        ln
+    }
+
+    fn ids(lines: &[LinestringGeometry]) -> Vec<(i64, Vec<i64>, Vec<i64>)> {
+        lines.iter().map(|l| (l.id, l.refs.clone(), l.source_ids.clone())).collect()
+    }
+
+    #[test]
+    fn merge_connected() {
+        let hw = [("highway", "primary")];
+        let (res, nm) = merge_linestrings(vec![line(1, &[1, 2, 3], &hw), line(2, &[3, 4], &hw), line(3, &[6, 5, 4], &hw)]);
+        assert_eq!(nm, 3);
+        assert_eq!(ids(&res), vec![(1, vec![1, 2, 3, 4, 5, 6], vec![1, 2, 3])]);
+        assert_eq!(res[0].lonlats.len(), 6);
+        assert_eq!(res[0].length, 5.0);
+    }
+
+    #[test]
+    fn merge_prepends() {
+        let hw = [("highway", "primary")];
+        let (res, _) = merge_linestrings(vec![line(1, &[3, 4], &hw), line(2, &[1, 2, 3], &hw)]);
+        assert_eq!(ids(&res), vec![(1, vec![1, 2, 3, 4], vec![2, 1])]);
+    }
+
+    #[test]
+    fn different_tags_not_merged() {
+        let (res, nm) = merge_linestrings(vec![
+            line(1, &[1, 2], &[("highway", "primary")]),
+            line(2, &[2, 3], &[("highway", "secondary")]),
+        ]);
+        assert_eq!(nm, 0);
+        assert_eq!(ids(&res), vec![(1, vec![1, 2], vec![]), (2, vec![2, 3], vec![])]);
+    }
+
+    #[test]
+    fn junctions_not_merged() {
+        //three line ends meet at node 2
+        let hw = [("highway", "primary")];
+        let (res, nm) = merge_linestrings(vec![line(1, &[1, 2], &hw), line(2, &[2, 3], &hw), line(3, &[2, 4], &hw)]);
+        assert_eq!(nm, 0);
+        assert_eq!(res.len(), 3);
+    }
+
+    #[test]
+    fn oneway_not_reversed() {
+        let ow = [("highway", "primary"), ("oneway", "yes")];
+        let (res, nm) = merge_linestrings(vec![line(1, &[1, 2], &ow), line(2, &[3, 2], &ow)]);
+        assert_eq!(nm, 0);
+        assert_eq!(res.len(), 2);
+
+        let (res, _) = merge_linestrings(vec![line(1, &[1, 2], &ow), line(2, &[2, 3], &ow)]);
+        assert_eq!(ids(&res), vec![(1, vec![1, 2, 3], vec![1, 2])]);
+    }
+
+    #[test]
+    fn closed_lines_kept() {
+        let hw = [("highway", "primary")];
+        let (res, nm) = merge_linestrings(vec![line(1, &[1, 2, 3, 1], &hw), line(2, &[3, 4], &hw)]);
+        assert_eq!(nm, 0);
+        assert_eq!(res.len(), 2);
+    }
+}
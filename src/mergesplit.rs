@@ -0,0 +1,533 @@
+use channelled_callbacks::{
+    CallFinish, Callback, CallbackMerge, CallbackSync, MergeTimings, ReplaceNoneWithTimings,
+    Result as ccResult,
+};
+use osmquadtree::elements::{Bbox, Block, Quadtree};
+use osmquadtree::message;
+use osmquadtree::pbfformat::{FileBlock, ParallelFileLocs};
+use osmquadtree::utils::{LogTimes, ThreadTimer};
+
+use crate::antimeridian::bounds_union;
+use crate::compression::file_block_data;
+use crate::geometry_header::{check_geometry_file, GeometryHeader};
+use crate::pack_geometry::{geometry_file_locs, read_all_blocks_result, read_geometry_blocks, unpack_geometry_block};
+use crate::tempfile::{make_write_temp_geometry, prep_write_geometry_pbffile, write_temp_geometry_call};
+use crate::{
+    write_geometry_index, CallFinishGeometryBlock, Error, GeometryBlock, GeometryFilter, OtherData,
+    Result, Timings,
+};
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+type CallFinishFileBlocks =
+    Box<dyn CallFinish<CallType = (usize, Vec<FileBlock>), ReturnType = Timings, ErrorType = Error>>;
+
+//blocks with the same quadtree from each input are read together, in the
+//order the files were given
+fn merge_file_locs(infns: &[String]) -> Result<ParallelFileLocs> {
+    let mut files = Vec::new();
+    let mut locs: BTreeMap<Quadtree, Vec<(usize, u64)>> = BTreeMap::new();
+    let mut total_len = 0;
+    for infn in infns {
+        let (ff, ll, tl) = geometry_file_locs(infn, None, None)?;
+        let off = files.len();
+        files.extend(ff);
+        for (q, xx) in ll {
+            locs.entry(q)
+                .or_insert_with(Vec::new)
+                .extend(xx.into_iter().map(|(f, p)| (f + off, p)));
+        }
+        total_len += tl;
+    }
+    Ok((files, locs.into_iter().collect(), total_len))
+}
+
+fn merge_headers(infns: &[String]) -> Result<GeometryHeader> {
+    let mut headers = Vec::with_capacity(infns.len());
+    for infn in infns {
+        headers.push(read_header(infn)?);
+    }
+    let bxs: Vec<Bbox> = headers.iter().map(|h| h.bbox.clone()).collect();
+    let mut res = headers.remove(0);
+    for (h, f) in headers.iter().zip(infns.iter().skip(1)) {
+        if h.style != res.style || h.minzoom != res.minzoom {
+            message!("{} has a different style or minzoom spec to {}", f, infns[0]);
+        }
+        if h.source_timestamp > res.source_timestamp {
+            res.source_timestamp = h.source_timestamp;
+        }
+    }
+    res.bbox = bounds_union(&bxs);
+    res.generator = GeometryHeader::new(res.bbox.clone(), None, None, None)?.generator;
+    Ok(res)
+}
+
+//objects are kept the first time their geometry type and id is seen
+fn dedup_block(bl: GeometryBlock, seen: &mut HashSet<(u8, i64)>, res: &mut GeometryBlock) -> usize {
+    let mut dups = 0;
+    for p in bl.points {
+        if seen.insert((0, p.id)) { res.points.push(p); } else { dups += 1; }
+    }
+    for p in bl.linestrings {
+        if seen.insert((1, p.id)) { res.linestrings.push(p); } else { dups += 1; }
+    }
+    for p in bl.simple_polygons {
+        if seen.insert((2, p.id)) { res.simple_polygons.push(p); } else { dups += 1; }
+    }
+    for p in bl.complicated_polygons {
+        if seen.insert((3, p.id)) { res.complicated_polygons.push(p); } else { dups += 1; }
+    }
+    dups
+}
+
+//copies of an object in blocks with different quadtrees are removed
+//once the output is sorted, by dedup_sorted_block
+fn unpack_merged(i: usize, fbs: Vec<FileBlock>) -> Result<GeometryBlock> {
+    let mut res = GeometryBlock::new(i as i64, Quadtree::empty(), 0);
+    for fb in &fbs {
+        let bl = unpack_geometry_block(i as i64, &file_block_data(fb)?)?;
+        if res.quadtree.is_empty() {
+            res.quadtree = bl.quadtree.clone();
+        }
+        res.extend(bl);
+    }
+    Ok(res)
+}
+
+//copies of an object have the same quadtree, so are found in the same
+//block of the sorted output. Returns the number dropped.
+pub(crate) fn dedup_sorted_block(bl: GeometryBlock) -> (GeometryBlock, usize) {
+    let mut res = GeometryBlock::new(bl.index, bl.quadtree.clone(), bl.end_date);
+    let dups = dedup_block(bl, &mut HashSet::new(), &mut res);
+    (res, dups)
+}
+
+//blocks which can't be unpacked are passed on empty, so that blocks
+//resynchronised by CallbackSync stay in order, and the first error is
+//returned from finish
+struct UnpackMerged {
+    out: CallFinishGeometryBlock,
+    error: Option<Error>,
+    tm: f64,
+}
+
+impl CallFinish for UnpackMerged {
+    type CallType = (usize, Vec<FileBlock>);
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, (i, fbs): (usize, Vec<FileBlock>)) {
+        let tx = ThreadTimer::new();
+        let bl = match unpack_merged(i, fbs) {
+            Ok(bl) => bl,
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+                GeometryBlock::new(i as i64, Quadtree::empty(), 0)
+            }
+        };
+        self.tm += tx.since();
+        self.out.call(bl);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = self.out.finish()?;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        tms.add("UnpackMerged", self.tm);
+        Ok(tms)
+    }
+}
+
+fn make_unpack_merged(cb: CallFinishGeometryBlock, numchan: usize) -> CallFinishFileBlocks {
+    if numchan == 0 {
+        Box::new(UnpackMerged { out: cb, error: None, tm: 0.0 })
+    } else {
+        let cbs = CallbackSync::new(cb, numchan);
+        let mut pps: Vec<CallFinishFileBlocks> = Vec::new();
+        for c in cbs {
+            let c2 = Box::new(ReplaceNoneWithTimings::new(c));
+            pps.push(Box::new(Callback::new(Box::new(UnpackMerged {
+                out: c2,
+                error: None,
+                tm: 0.0,
+            }))));
+        }
+        Box::new(CallbackMerge::new(pps, Box::new(MergeTimings::new())))
+    }
+}
+
+//merges geometry pbffiles, such as from separate regional runs, into one
+//sorted file. Objects found in more than one file are only written once.
+pub fn merge_geometry(infns: &[String], outfn: &str, numchan: usize) -> Result<()> {
+    if infns.is_empty() {
+        return Err(Error::UserSelectionError(String::from("no input files given")));
+    }
+    let mut tx = LogTimes::new();
+    let header = merge_headers(infns)?;
+    let mut pfilelocs = merge_file_locs(infns)?;
+    tx.add("get_file_locs");
+
+    let (out, groups) = make_write_temp_geometry(outfn, &pfilelocs, &None, numchan)?;
+
+    let tm = read_all_blocks_result(
+        &mut pfilelocs,
+        make_unpack_merged(out, numchan),
+        &format!("merge {} files, numchan={}", infns.len(), numchan),
+    )?;
+    tx.add("read and collect blocks");
+
+    let mut tempdata = None;
+    for (w, x) in tm.others {
+        match x {
+            OtherData::Messages(mm) => {
+                for m in mm {
+                    message!("{}: {}", w, m);
+                }
+            }
+            OtherData::TempData(td) => {
+                tempdata = Some(td);
+            }
+            _ => {}
+        }
+    }
+    match tempdata {
+        Some(td) => {
+            let tm = write_temp_geometry_call(outfn, &header.bbox, &header, td, groups, numchan, true)?;
+            for (w, x) in tm.others {
+                if let OtherData::Messages(mm) = x {
+                    for m in mm {
+                        message!("{}: {}", w, m);
+                    }
+                }
+            }
+            tx.add("write final pbf");
+        }
+        None => {
+            return Err(Error::InvalidDataError(format!("no temp data to write to {}", outfn)));
+        }
+    }
+    write_geometry_index(outfn, numchan)?;
+    tx.add("write index");
+    message!("{}", tx);
+    Ok(())
+}
+
+enum SplitTargets {
+    Filters(Vec<(GeometryFilter, CallFinishGeometryBlock)>),
+    Quadtrees(String, usize, GeometryHeader, BTreeMap<Quadtree, CallFinishGeometryBlock>),
+}
+
+fn filter_block(bl: &GeometryBlock, filter: &GeometryFilter) -> GeometryBlock {
+    let mut res = GeometryBlock::new(bl.index, bl.quadtree.clone(), bl.end_date);
+    res.points.extend(bl.points.iter().filter(|p| filter.check(*p)).cloned());
+    res.linestrings.extend(bl.linestrings.iter().filter(|p| filter.check(*p)).cloned());
+    res.simple_polygons.extend(bl.simple_polygons.iter().filter(|p| filter.check(*p)).cloned());
+    res.complicated_polygons.extend(bl.complicated_polygons.iter().filter(|p| filter.check(*p)).cloned());
+    res
+}
+
+//the first error opening or writing an output is returned from finish
+struct SplitGeometry {
+    targets: SplitTargets,
+    outfns: Arc<Mutex<Vec<String>>>,
+    error: Option<Error>,
+    tm: f64,
+}
+
+impl SplitGeometry {
+    fn split_quadtree(&mut self, bl: GeometryBlock) -> Result<()> {
+        match &mut self.targets {
+            SplitTargets::Filters(_) => Ok(()),
+            SplitTargets::Quadtrees(prfx, depth, header, outs) => {
+                //blocks above depth hold objects too large for any subtree
+                let q = if bl.quadtree.depth() < *depth {
+                    Quadtree::new(0)
+                } else {
+                    bl.quadtree.round(*depth)
+                };
+                if !outs.contains_key(&q) {
+                    let name = if q.depth() == 0 { String::from("top") } else { q.as_string() };
+                    let ofn = format!("{}{}.pbf", prfx, name);
+                    let out = prep_write_geometry_pbffile(&ofn, &header.bbox, header, 0)?;
+                    outs.insert(q.clone(), out);
+                    self.outfns.lock().unwrap().push(ofn);
+                }
+                outs.get_mut(&q).unwrap().call(bl);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn add_timings(tms: &mut Timings, mut other: Timings) {
+    tms.timings.extend(std::mem::take(&mut other.timings));
+    tms.others.extend(std::mem::take(&mut other.others));
+}
+
+impl CallFinish for SplitGeometry {
+    type CallType = GeometryBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: GeometryBlock) {
+        if bl.len() == 0 {
+            return;
+        }
+        let tx = ThreadTimer::new();
+        match &mut self.targets {
+            SplitTargets::Filters(outs) => {
+                for (f, out) in outs.iter_mut() {
+                    let fb = filter_block(&bl, f);
+                    if fb.len() > 0 {
+                        out.call(fb);
+                    }
+                }
+            }
+            SplitTargets::Quadtrees(..) => {
+                if let Err(e) = self.split_quadtree(bl) {
+                    if self.error.is_none() {
+                        self.error = Some(e);
+                    }
+                }
+            }
+        }
+        self.tm += tx.since();
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = Timings::new();
+        match &mut self.targets {
+            SplitTargets::Filters(outs) => {
+                for (_, out) in outs.iter_mut() {
+                    add_timings(&mut tms, out.finish()?);
+                }
+            }
+            SplitTargets::Quadtrees(_, _, _, outs) => {
+                for (_, out) in outs.iter_mut() {
+                    add_timings(&mut tms, out.finish()?);
+                }
+            }
+        }
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        tms.add("SplitGeometry", self.tm);
+        tms.add_other(
+            "SplitGeometry",
+            OtherData::Messages(vec![format!("wrote {} files", self.outfns.lock().unwrap().len())]),
+        );
+        Ok(tms)
+    }
+}
+
+fn run_split(infn: &str, targets: SplitTargets, outfns: Vec<String>, numchan: usize) -> Result<Vec<String>> {
+    let outfns = Arc::new(Mutex::new(outfns));
+    let split = Box::new(SplitGeometry {
+        targets: targets,
+        outfns: outfns.clone(),
+        error: None,
+        tm: 0.0,
+    });
+    let tm = read_geometry_blocks(infn, split, None, None, None, numchan)?;
+    for (w, x) in tm.others {
+        match x {
+            OtherData::Messages(mm) => {
+                for m in mm {
+                    message!("{}: {}", w, m);
+                }
+            }
+            _ => {}
+        }
+    }
+    let outfns = std::mem::take(&mut *outfns.lock().unwrap());
+    for o in &outfns {
+        write_geometry_index(o, numchan)?;
+    }
+    Ok(outfns)
+}
+
+fn read_header(infn: &str) -> Result<GeometryHeader> {
+    match check_geometry_file(infn)? {
+        Some(h) => Ok(h),
+        None => Err(Error::UserSelectionError(format!("{} is not a geometry pbffile", infn))),
+    }
+}
+
+//splits a geometry pbffile into one file for each (outfn, filter) pair.
+//Each filter is a bbox or poly filename, as for process_geometry, and
+//objects overlapping more than one filter are written to each.
+pub fn split_geometry_filters(infn: &str, targets: &[(String, String)], numchan: usize) -> Result<Vec<String>> {
+    let header = read_header(infn)?;
+    let mut outs = Vec::with_capacity(targets.len());
+    let mut outfns = Vec::with_capacity(targets.len());
+    for (ofn, filter_str) in targets {
+        let filter = GeometryFilter::from_str(filter_str)?;
+        let mut h = header.clone();
+        if let GeometryFilter::Bbox(b) = &filter {
+            h.bbox = b.clone();
+        }
+        outs.push((filter, prep_write_geometry_pbffile(ofn, &h.bbox, &h, 0)?));
+        outfns.push(ofn.clone());
+    }
+    run_split(infn, SplitTargets::Filters(outs), outfns, numchan)
+}
+
+//splits a sorted geometry pbffile into one file for each quadtree at depth,
+//named prfx followed by the quadtree string. Objects in blocks above depth
+//go to prfx + "top.pbf".
+pub fn split_geometry_quadtree(infn: &str, prfx: &str, depth: usize, numchan: usize) -> Result<Vec<String>> {
+    let header = read_header(infn)?;
+    let targets = SplitTargets::Quadtrees(String::from(prfx), depth, header, BTreeMap::new());
+    run_split(infn, targets, Vec::new(), numchan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{geometry_index_filename, LinestringGeometry, LonLat, PointGeometry};
+
+    fn point(id: i64, lon: i32) -> PointGeometry {
+        let mut p = PointGeometry::empty();
+        p.id = id;
+        p.lonlat = LonLat::new(lon, 0);
+        p.quadtree = Quadtree::calculate(&Bbox::new(lon, 0, lon, 0), 18, 0.0);
+        p
+    }
+
+    fn temp_name(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("osmquadtree-geometry-{}-{}.pbf", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn remove_files(fname: &str) {
+        let _ = std::fs::remove_file(fname);
+        let _ = std::fs::remove_file(geometry_index_filename(fname));
+    }
+
+    fn read_point_ids(fname: &str) -> Vec<i64> {
+        struct CollectIds(Arc<Mutex<Vec<i64>>>);
+        impl CallFinish for CollectIds {
+            type CallType = GeometryBlock;
+            type ReturnType = Timings;
+            type ErrorType = Error;
+            fn call(&mut self, bl: GeometryBlock) {
+                self.0.lock().unwrap().extend(bl.points.iter().map(|p| p.id));
+            }
+            fn finish(&mut self) -> ccResult<Timings, Error> {
+                Ok(Timings::new())
+            }
+        }
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        read_geometry_blocks(fname, Box::new(CollectIds(ids.clone())), None, None, None, 0).unwrap();
+        let mut ids = std::mem::take(&mut *ids.lock().unwrap());
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn dedup_by_type_and_id() {
+        let mut seen = HashSet::new();
+        let mut res = GeometryBlock::new(0, Quadtree::empty(), 0);
+
+        let mut a = GeometryBlock::new(0, Quadtree::empty(), 0);
+        a.points.push(point(1, 0));
+        a.points.push(point(2, 0));
+        let mut l = LinestringGeometry::empty();
+        l.id = 1;
+        a.linestrings.push(l);
+        assert_eq!(dedup_block(a, &mut seen, &mut res), 0);
+
+        let mut b = GeometryBlock::new(0, Quadtree::empty(), 0);
+        b.points.push(point(2, 100));
+        b.points.push(point(3, 0));
+        assert_eq!(dedup_block(b, &mut seen, &mut res), 1);
+
+        let ids: Vec<i64> = res.points.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        //the first copy is kept
+        assert_eq!(res.points[1].lonlat, LonLat::new(0, 0));
+        assert_eq!(res.linestrings.len(), 1);
+    }
+
+    #[test]
+    fn merge_drops_copies_across_blocks() {
+        let bbox = Bbox::new(-1800000000, -900000000, 1800000000, 900000000);
+        let header = GeometryHeader::new(bbox.clone(), None, None, None).unwrap();
+
+        //point 1 is in a root block in a, and in a block of its own in b
+        let a = temp_name("merge-a");
+        let mut out = prep_write_geometry_pbffile(&a, &bbox, &header, 0).unwrap();
+        let mut bl = GeometryBlock::new(0, Quadtree::new(0), 0);
+        bl.points.push(point(1, 0));
+        bl.points.push(point(2, 100000000));
+        out.call(bl);
+        out.finish().unwrap();
+
+        let b = temp_name("merge-b");
+        let mut out = prep_write_geometry_pbffile(&b, &bbox, &header, 0).unwrap();
+        let p = point(1, 0);
+        let mut bl = GeometryBlock::new(0, p.quadtree.clone(), 0);
+        bl.points.push(p);
+        out.call(bl);
+        let mut bl = GeometryBlock::new(1, Quadtree::new(0), 0);
+        bl.points.push(point(3, 200000000));
+        out.call(bl);
+        out.finish().unwrap();
+
+        let outfn = temp_name("merge-out");
+        merge_geometry(&[a.clone(), b.clone()], &outfn, 0).unwrap();
+        assert_eq!(read_point_ids(&outfn), vec![1, 2, 3]);
+
+        for f in [&a, &b, &outfn] {
+            remove_files(f);
+        }
+    }
+
+    #[test]
+    fn filter_block_by_bbox() {
+        let mut bl = GeometryBlock::new(3, Quadtree::new(0), 0);
+        for i in 0..5 {
+            bl.points.push(point(i, (i as i32) * 100000000));
+        }
+        let filter = GeometryFilter::Bbox(Bbox::new(50000000, -10000000, 250000000, 10000000));
+        let res = filter_block(&bl, &filter);
+        assert_eq!(res.index, 3);
+        let ids: Vec<i64> = res.points.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn split_by_filters() {
+        let infn = temp_name("split-input");
+        let bbox = Bbox::new(-1800000000, -900000000, 1800000000, 900000000);
+        let header = GeometryHeader::new(bbox.clone(), None, None, None).unwrap();
+        let mut out = prep_write_geometry_pbffile(&infn, &bbox, &header, 0).unwrap();
+        for i in 0..6 {
+            let p = point(i, (i as i32) * 100000000);
+            let mut bl = GeometryBlock::new(i, p.quadtree, 0);
+            bl.points.push(p);
+            out.call(bl);
+        }
+        out.finish().unwrap();
+
+        let (west, east) = (temp_name("split-west"), temp_name("split-east"));
+        let targets = [
+            (west.clone(), String::from("-1,-1,25,1")),
+            (east.clone(), String::from("15,-1,45,1")),
+        ];
+        let outfns = split_geometry_filters(&infn, &targets, 0).unwrap();
+        assert_eq!(outfns, vec![west.clone(), east.clone()]);
+        assert_eq!(read_point_ids(&west), vec![0, 1, 2]);
+        assert_eq!(read_point_ids(&east), vec![2, 3, 4]);
+
+        for f in [&infn, &west, &east] {
+            remove_files(f);
+        }
+    }
+}
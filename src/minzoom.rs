@@ -3,6 +3,7 @@ use osmquadtree::elements::{Tag, EARTH_WIDTH};
 use crate::default_minzoom_values::DEFAULT_MINZOOM_VALUES;
 use crate::{GeometryBlock, OtherData, Timings, WorkingBlock, Error, Result};
 use osmquadtree::utils::ThreadTimer;
+use serde_json::{json, Value};
 use std::collections::BTreeMap;
 use std::io::Read;
 use osmquadtree::message;
@@ -133,6 +134,17 @@ impl MinZoomSpec {
         Ok(res)
     }
 
+    //rows as in the csv format read by from_reader
+    pub fn to_json(&self) -> Value {
+        let mut rows = Vec::new();
+        for (typ, vals) in [(0, &self.points), (1, &self.lines), (2, &self.polygons)] {
+            for (t, (z, table)) in vals {
+                rows.push(json!((typ, t.key, t.val, z, table)));
+            }
+        }
+        json!({"min_area": self.min_area, "max_minzoom": self.max_minzoom, "values": rows})
+    }
+
     pub fn default(min_area: f64, max_minzoom: Option<i64>) -> MinZoomSpec {
         MinZoomSpec::from_reader(min_area, max_minzoom, DEFAULT_MINZOOM_VALUES.as_bytes())
             .expect("!!")
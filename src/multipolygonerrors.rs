@@ -0,0 +1,231 @@
+use crate::validate::ValidationIssue;
+use crate::{ComplicatedPolygonGeometry, RingPart, Result};
+
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::io::{BufWriter, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MultiPolygonErrorKind {
+    NoRingParts,
+    UnclosedRing,
+    MissingMemberWay,
+    //assemble_rings failed, or found no rings
+    AssemblyFailed,
+    //the rings were assembled, but the polygon fails validate_complicated_polygon
+    InvalidAfterAssembly,
+}
+
+impl MultiPolygonErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MultiPolygonErrorKind::NoRingParts => "no_ring_parts",
+            MultiPolygonErrorKind::UnclosedRing => "unclosed_ring",
+            MultiPolygonErrorKind::MissingMemberWay => "missing_member_way",
+            MultiPolygonErrorKind::AssemblyFailed => "assembly_failed",
+            MultiPolygonErrorKind::InvalidAfterAssembly => "invalid_after_assembly",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiPolygonError {
+    pub relation_id: i64,
+    pub kind: MultiPolygonErrorKind,
+    pub message: String,
+    pub missing_ways: Vec<i64>,
+    pub partial: Vec<RingPart>,
+}
+
+impl MultiPolygonError {
+    pub fn new(relation_id: i64, kind: MultiPolygonErrorKind, message: String) -> MultiPolygonError {
+        MultiPolygonError {
+            relation_id: relation_id,
+            kind: kind,
+            message: message,
+            missing_ways: Vec::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    //an assembled polygon which fails validate_complicated_polygon, with all its rings as the partial geometry
+    pub fn invalid_after_assembly(
+        cp: &ComplicatedPolygonGeometry,
+        issues: &[ValidationIssue],
+    ) -> MultiPolygonError {
+        let names: Vec<&str> = issues.iter().map(|i| i.as_str()).collect();
+        let mut e = MultiPolygonError::new(
+            cp.id,
+            MultiPolygonErrorKind::InvalidAfterAssembly,
+            names.join(", "),
+        );
+        e.partial = cp
+            .parts
+            .iter()
+            .flat_map(|p| std::iter::once(&p.exterior).chain(p.interiors.iter()))
+            .flat_map(|r| r.parts.iter().cloned())
+            .collect();
+        e
+    }
+
+    fn partial_coordinates(&self) -> Vec<Vec<(f64, f64)>> {
+        self.partial
+            .iter()
+            .map(|p| {
+                p.lonlats
+                    .iter()
+                    .map(|l| {
+                        let xy = l.as_xy();
+                        (xy.x, xy.y)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn to_geojson(&self) -> Value {
+        let mut props = Map::new();
+        props.insert(String::from("relation_id"), json!(self.relation_id));
+        props.insert(String::from("kind"), json!(self.kind.as_str()));
+        props.insert(String::from("message"), json!(self.message));
+        props.insert(String::from("missing_ways"), json!(self.missing_ways));
+        props.insert(
+            String::from("partial_ways"),
+            json!(self.partial.iter().map(|p| p.orig_id).collect::<Vec<i64>>()),
+        );
+
+        let geom = if self.partial.is_empty() {
+            Value::Null
+        } else {
+            json!({"type": "MultiLineString", "coordinates": self.partial_coordinates()})
+        };
+
+        json!({"type": "Feature", "id": self.relation_id, "properties": props, "geometry": geom})
+    }
+
+    fn to_wkt(&self) -> String {
+        if self.partial.is_empty() {
+            return String::new();
+        }
+        let lines: Vec<String> = self
+            .partial_coordinates()
+            .iter()
+            .map(|ll| {
+                let pts: Vec<String> = ll.iter().map(|(x, y)| format!("{} {}", x, y)).collect();
+                format!("({})", pts.join(", "))
+            })
+            .collect();
+        format!("MULTILINESTRING({})", lines.join(", "))
+    }
+}
+
+impl fmt::Display for MultiPolygonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind.as_str(), self.message)
+    }
+}
+
+fn write_errors_csv<W: Write>(out: W, errs: &[MultiPolygonError]) -> Result<()> {
+    let mut wr = csv::Writer::from_writer(out);
+    wr.write_record(&["relation_id", "kind", "message", "missing_ways", "partial_ways", "wkt"])
+        .map_err(std::io::Error::from)?;
+    for e in errs {
+        let mw: Vec<String> = e.missing_ways.iter().map(|w| w.to_string()).collect();
+        let pw: Vec<String> = e.partial.iter().map(|p| p.orig_id.to_string()).collect();
+        wr.write_record(&[
+            e.relation_id.to_string(),
+            String::from(e.kind.as_str()),
+            e.message.clone(),
+            mw.join(";"),
+            pw.join(";"),
+            e.to_wkt(),
+        ])
+        .map_err(std::io::Error::from)?;
+    }
+    wr.flush()?;
+    Ok(())
+}
+
+//writes a csv file if outfn ends with .csv, otherwise a geojson feature collection
+pub fn write_multipolygon_errors(outfn: &str, errs: &[MultiPolygonError]) -> Result<()> {
+    let mut out = BufWriter::new(std::fs::File::create(outfn)?);
+    if outfn.ends_with(".csv") {
+        write_errors_csv(&mut out, errs)?;
+    } else {
+        let feats: Vec<Value> = errs.iter().map(|e| e.to_geojson()).collect();
+        serde_json::to_writer(&mut out, &json!({"type": "FeatureCollection", "features": feats}))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LonLat;
+
+    fn test_error() -> MultiPolygonError {
+        let mut e = MultiPolygonError::new(5, MultiPolygonErrorKind::UnclosedRing, String::from("1 ring parts not closed"));
+        e.missing_ways = vec![11, 12];
+        e.partial = vec![RingPart::new(
+            21,
+            false,
+            vec![1, 2, 3],
+            vec![LonLat::new(0, 0), LonLat::new(10000000, 0), LonLat::new(10000000, 20000000)],
+        )];
+        e
+    }
+
+    fn temp_name(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("osmquadtree-geometry-mperrors-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn error_to_geojson() {
+        let gj = test_error().to_geojson();
+        assert_eq!(gj["type"], "Feature");
+        assert_eq!(gj["id"], 5);
+        assert_eq!(gj["properties"]["kind"], "unclosed_ring");
+        assert_eq!(gj["properties"]["message"], "1 ring parts not closed");
+        assert_eq!(gj["properties"]["missing_ways"], json!([11, 12]));
+        assert_eq!(gj["properties"]["partial_ways"], json!([21]));
+        assert_eq!(gj["geometry"]["type"], "MultiLineString");
+        assert_eq!(gj["geometry"]["coordinates"], json!([[[0.0, 0.0], [1.0, 0.0], [1.0, 2.0]]]));
+
+        let e = MultiPolygonError::new(6, MultiPolygonErrorKind::NoRingParts, String::from("no ring parts"));
+        assert_eq!(e.to_geojson()["geometry"], Value::Null);
+    }
+
+    #[test]
+    fn error_to_wkt() {
+        assert_eq!(test_error().to_wkt(), "MULTILINESTRING((0 0, 1 0, 1 2))");
+        let e = MultiPolygonError::new(6, MultiPolygonErrorKind::NoRingParts, String::from("no ring parts"));
+        assert_eq!(e.to_wkt(), "");
+    }
+
+    #[test]
+    fn write_csv_or_geojson() {
+        let errs = vec![test_error()];
+
+        let csvfn = temp_name("errors.csv");
+        write_multipolygon_errors(&csvfn, &errs).unwrap();
+        let csv = std::fs::read_to_string(&csvfn).unwrap();
+        let _ = std::fs::remove_file(&csvfn);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "relation_id,kind,message,missing_ways,partial_ways,wkt");
+        assert_eq!(
+            lines[1],
+            "5,unclosed_ring,1 ring parts not closed,11;12,21,\"MULTILINESTRING((0 0, 1 0, 1 2))\""
+        );
+
+        let jsonfn = temp_name("errors.geojson");
+        write_multipolygon_errors(&jsonfn, &errs).unwrap();
+        let gj: Value = serde_json::from_str(&std::fs::read_to_string(&jsonfn).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&jsonfn);
+        assert_eq!(gj["type"], "FeatureCollection");
+        assert_eq!(gj["features"], json!([errs[0].to_geojson()]));
+    }
+}
@@ -1,10 +1,12 @@
 use channelled_callbacks::{CallFinish, Result as ccResult};
-use osmquadtree::elements::{Element, ElementType, Quadtree, Relation};
-use crate::elements::collect_rings;
-use crate::position::point_in_poly_iter;
+use osmquadtree::elements::{ElementType, Quadtree, Relation, Tag, Way};
+use crate::multipolygonerrors::{MultiPolygonError, MultiPolygonErrorKind};
+use crate::ringbuilder::{assemble_rings, nest_rings};
+use crate::style::is_transform_failure;
+use crate::validate::validate_complicated_polygon;
 use crate::{
-    ComplicatedPolygonGeometry, GeometryStyle, OtherData, PolygonPart, Ring, RingPart, Timings,
-    WorkingBlock, Result, Error
+    ComplicatedPolygonGeometry, GeometryStyle, LonLat, OtherData, RingPart, Timings,
+    WorkingBlock, Error
 };
 use osmquadtree::message;
 use osmquadtree::utils::ThreadTimer;
@@ -13,69 +15,10 @@ use std::collections::{BTreeMap, BTreeSet};
 
 use std::sync::Arc;
 
-type PendingWays = BTreeMap<i64, (BTreeSet<i64>, Option<RingPart>)>;
+type PendingWays = BTreeMap<i64, (BTreeSet<i64>, Option<RingPart>, Vec<Tag>)>;
+type PendingRelations = BTreeMap<i64, (Quadtree, Relation, BTreeSet<i64>)>;
 
 
-fn add_ring<'a>(res: &mut Vec<PolygonPart>, q: Ring, must_be_inner: bool) -> Option<Ring> {
-    let y = &q.parts[0].lonlats[0];
-    for a in res.iter_mut() {
-        if a.exterior.bbox.contains(&q.bbox) {
-            if point_in_poly_iter(a.exterior.lonlats_iter(), &y) {
-                a.add_interior(q);
-                return None;
-            }
-        }
-    }
-    if must_be_inner {
-        return Some(q);
-    }
-    res.push(PolygonPart::new(q));
-    None
-}
-
-fn order_rings(rings: Vec<Ring>, inner_rings: Vec<Ring>) -> (Vec<PolygonPart>, Vec<Ring>) {
-    let mut pp = Vec::new();
-    for mut r in rings {
-        r.calc_area_bbox().expect("!");
-        pp.push(r);
-    }
-
-    pp.sort_by(|p, q| {
-        (-1.0 * f64::abs(p.area))
-            .partial_cmp(&(-1.0 * f64::abs(q.area)))
-            .unwrap()
-    });
-
-    let mut res = Vec::new();
-
-    for p in pp {
-        add_ring(&mut res, p, false);
-    }
-
-    let mut pp2 = Vec::new();
-    for mut r in inner_rings {
-        r.calc_area_bbox().expect("!");
-        pp2.push(r);
-    }
-
-    pp2.sort_by(|p, q| {
-        (-1.0 * f64::abs(p.area))
-            .partial_cmp(&(-1.0 * f64::abs(q.area)))
-            .unwrap()
-    });
-    let mut rem = Vec::new();
-    for p in pp2 {
-        match add_ring(&mut res, p, true) {
-            None => {}
-            Some(r) => {
-                rem.push(r);
-            }
-        }
-    }
-
-    (res, rem)
-}
-
 fn is_multipolygon_rel(rel: &Relation) -> bool {
     //let mut is_bound = false;
     //let mut is_admin = true;
@@ -98,16 +41,74 @@ fn is_multipolygon_rel(rel: &Relation) -> bool {
     false
 }
 
-const MAX_ERR_COUNT: usize = 0; //1000;
+//old-style multipolygons have their feature tags on the outer ways rather than the relation
+fn is_old_style(style: &GeometryStyle, rel: &Relation) -> bool {
+    style.multipolygon_outer_tags && !style.has_feature_key(&rel.tags)
+}
+
+fn common_tags(tt: &[(i64, Vec<Tag>)]) -> Vec<Tag> {
+    if tt.is_empty() {
+        return Vec::new();
+    }
+    let mut res = tt[0].1.clone();
+    for (_, t) in &tt[1..] {
+        res.retain(|x| t.contains(x));
+    }
+    res
+}
+
+//only outer ways with no tags beyond those taken by the relation are consumed
+fn is_consumed_outer(tags: &[Tag], common: &[Tag]) -> bool {
+    tags.iter().all(|t| common.contains(t))
+}
+
+fn is_held_outer(
+    style: &GeometryStyle,
+    pending_relations: &PendingRelations,
+    w: &Way,
+    rels: &BTreeSet<i64>,
+) -> bool {
+    if w.refs.len() < 4 || w.refs[0] != w.refs[w.refs.len() - 1] {
+        return false;
+    }
+    //transform failures are counted when the way itself is processed
+    match style.process_way(&w.tags, true) {
+        Ok((true, _, _, _)) => {}
+        _ => {
+            return false;
+        }
+    }
+    for r in rels {
+        match pending_relations.get(r) {
+            Some((_, rel, _)) => {
+                if is_old_style(style, rel)
+                    && rel.members.iter().any(|m| {
+                        m.mem_type == ElementType::Way && m.mem_ref == w.id && m.role != "inner"
+                    })
+                {
+                    return true;
+                }
+            }
+            None => {}
+        }
+    }
+    false
+}
+
+//errors past this are counted but not reported
+pub(crate) const MAX_MP_ERRORS: usize = 100000;
 
 struct MultiPolygons {
     style: Arc<GeometryStyle>,
 
-    pending_relations: BTreeMap<i64, (Quadtree, Relation, BTreeSet<i64>)>,
+    pending_relations: PendingRelations,
     pending_ways: PendingWays,
+    //with the quadtree of the block each way came from
+    held_ways: BTreeMap<i64, (Quadtree, Way, Vec<LonLat>, bool)>,
 
-    errs: Vec<(Element, String)>,
+    mp_errors: Vec<MultiPolygonError>,
     pass_rels: bool,
+    check_valid: bool,
     tma: f64,
     tmb: f64,
     tmc: f64,
@@ -117,16 +118,23 @@ struct MultiPolygons {
     skipped_big_poly: usize,
     err_count: usize,
     wnas: usize,
+    num_outer_tags: usize,
+    num_suppressed: usize,
+    num_crossings: usize,
+    not_feature: usize,
+    transform_failures: usize,
 }
 
 impl MultiPolygons {
-    pub fn new(style: Arc<GeometryStyle>, pass_rels: bool) -> MultiPolygons {
+    pub fn new(style: Arc<GeometryStyle>, pass_rels: bool, check_valid: bool) -> MultiPolygons {
         MultiPolygons {
             style: style,
             pending_relations: BTreeMap::new(),
             pending_ways: BTreeMap::new(),
-            errs: Vec::new(),
+            held_ways: BTreeMap::new(),
+            mp_errors: Vec::new(),
             pass_rels: pass_rels,
+            check_valid: check_valid,
             tma: 0.0,
             tmb: 0.0,
             tmc: 0.0,
@@ -136,6 +144,11 @@ impl MultiPolygons {
             skipped_big_poly: 0,
             err_count: 0,
             wnas: 0,
+            num_outer_tags: 0,
+            num_suppressed: 0,
+            num_crossings: 0,
+            not_feature: 0,
+            transform_failures: 0,
         }
     }
 
@@ -152,7 +165,7 @@ impl MultiPolygons {
                         None => {
                             let mut x = BTreeSet::new();
                             x.insert(r.id);
-                            self.pending_ways.insert(m.mem_ref, (x, None));
+                            self.pending_ways.insert(m.mem_ref, (x, None, Vec::new()));
                         }
                     }
                 }
@@ -167,39 +180,99 @@ impl MultiPolygons {
         outer_ringparts: Vec<RingPart>,
         inner_ringparts: Vec<RingPart>,
         rel: &Relation,
-    ) -> Result<Option<ComplicatedPolygonGeometry>> {
+        outer_tags: Option<&[Tag]>,
+        missing_ways: &[i64],
+    ) -> std::result::Result<Option<ComplicatedPolygonGeometry>, MultiPolygonError> {
+        let unclosed_kind = if missing_ways.is_empty() {
+            MultiPolygonErrorKind::UnclosedRing
+        } else {
+            MultiPolygonErrorKind::MissingMemberWay
+        };
+        let make_error = |kind: MultiPolygonErrorKind, msg: String, partial: Vec<RingPart>| {
+            let mut e = MultiPolygonError::new(rel.id, kind, msg);
+            e.missing_ways = missing_ways.to_vec();
+            e.partial = partial;
+            e
+        };
+
         if outer_ringparts.is_empty() {
-            return Err(Error::InvalidDataError(format!("no ring parts")));
+            let kind = if missing_ways.is_empty() {
+                MultiPolygonErrorKind::NoRingParts
+            } else {
+                MultiPolygonErrorKind::MissingMemberWay
+            };
+            return Err(make_error(kind, format!("no ring parts"), inner_ringparts));
         }
 
         let mut tm = ThreadTimer::new();
-        let (tags, _, layer) = self.style.process_multipolygon_relation(&rel.tags)?; //no zorder for polys
+        let tags_result = match outer_tags {
+            None => self.style.process_multipolygon_relation(&rel.tags),
+            Some(ot) => self.style.process_multipolygon_outer_tags(&rel.tags, ot),
+        };
+        let (tags, _, layer) = match tags_result {
+            Ok(t) => t, //no zorder for polys
+            Err(e) => {
+                if is_transform_failure(&e) {
+                    if self.transform_failures == 0 {
+                        message!("MultiPolygons: relation {}: {}", rel.id, e);
+                    }
+                    self.transform_failures += 1;
+                } else {
+                    self.not_feature += 1;
+                }
+                return Ok(None);
+            }
+        };
 
-        //let rp = ringparts.len();
-        let (rings, _left) = collect_rings(outer_ringparts)?;
-        let (rings2, _left2) = collect_rings(inner_ringparts)?;
+        let mut rings = match assemble_rings(outer_ringparts, inner_ringparts) {
+            Ok(r) => r,
+            Err(e) => {
+                return Err(make_error(MultiPolygonErrorKind::AssemblyFailed, e.to_string(), Vec::new()));
+            }
+        };
+        if rings.num_crossings > 0 {
+            self.num_crossings += 1;
+        }
+        let left = std::mem::take(&mut rings.left);
         self.tmca += tm.since();
         tm = ThreadTimer::new();
 
-        if rings.is_empty() {
-            return Err(Error::InvalidDataError(format!("no rings")));
-        }
-
-        /*if rings.len()+rings2.len()>200 {
-            self.skipped_big_poly+=1;
-            return Ok(None);
-        }*/
+        let (polys, _left) = nest_rings(rings);
+        self.tmcb += tm.since();
 
-        let (polys, _left3) = order_rings(rings, rings2);
         if polys.is_empty() {
-            return Err(Error::InvalidDataError(format!("no polys")));
+            if left.is_empty() {
+                return Err(make_error(MultiPolygonErrorKind::AssemblyFailed, format!("no polys"), left));
+            }
+            return Err(make_error(unclosed_kind, format!("no closed rings"), left));
+        }
+        if !left.is_empty() {
+            //still make the polygon from the closed rings, but report the parts left over
+            self.add_error(make_error(
+                unclosed_kind,
+                format!("{} ring parts not closed", left.len()),
+                left,
+            ));
         }
 
-        self.tmcb += tm.since();
+        let cp = ComplicatedPolygonGeometry::new(rel, tags, None, layer, polys);
+        if self.check_valid {
+            //when validating, ValidateGeometries checks the polygon and reports it instead
+            let issues = validate_complicated_polygon(&cp);
+            if !issues.is_empty() {
+                let mut e = MultiPolygonError::invalid_after_assembly(&cp, &issues);
+                e.missing_ways = missing_ways.to_vec();
+                self.add_error(e);
+            }
+        }
+        Ok(Some(cp))
+    }
 
-        Ok(Some(ComplicatedPolygonGeometry::new(
-            rel, tags, None, layer, polys,
-        )))
+    fn add_error(&mut self, e: MultiPolygonError) {
+        self.err_count += 1;
+        if self.mp_errors.len() < MAX_MP_ERRORS {
+            self.mp_errors.push(e);
+        }
     }
 
     fn finish_relation(
@@ -209,12 +282,17 @@ impl MultiPolygons {
     ) -> Option<ComplicatedPolygonGeometry> {
         let mut inner_ringparts = Vec::new();
         let mut ringparts = Vec::new();
+        let old_style = is_old_style(&self.style, &rel);
+        let mut outer_tags = Vec::new();
+        let mut missing_ways = Vec::new();
 
         for m in &rel.members {
             match m.mem_type {
                 ElementType::Way => {
                     match self.pending_ways.get_mut(&m.mem_ref) {
-                        None => {}
+                        None => {
+                            missing_ways.push(m.mem_ref);
+                        }
 
                         Some(p) => {
                             match &p.1 {
@@ -227,12 +305,16 @@ impl MultiPolygons {
                                         inner_ringparts.push(qq.clone());
                                     } else {
                                         ringparts.push(qq.clone());
+                                        if old_style {
+                                            outer_tags.push((m.mem_ref, p.2.clone()));
+                                        }
                                     }
                                 }
                                 None => {
                                     //message!("way object {} never added? [{:?}]", m.mem_ref, p.0);
                                     self.wnas += 1;
                                     p.0.remove(&rel.id);
+                                    missing_ways.push(m.mem_ref);
                                 }
                             }
                         }
@@ -242,15 +324,46 @@ impl MultiPolygons {
             }
         }
 
-        match self.make_complicated_polygon(ringparts, inner_ringparts, &rel) {
+        let common = if old_style {
+            Some(common_tags(&outer_tags))
+        } else {
+            None
+        };
+
+        match self.make_complicated_polygon(ringparts, inner_ringparts, &rel, common.as_deref(), &missing_ways) {
             Err(e) => {
-                self.err_count += 1;
-                if self.errs.len() < MAX_ERR_COUNT {
-                    self.errs.push((Element::Relation(rel), e.to_string()));
-                }
+                self.add_error(e);
                 None
             }
-            Ok(p) => p,
+            Ok(p) => {
+                if p.is_some() {
+                    if let Some(common) = common {
+                        self.num_outer_tags += 1;
+                        for (w, t) in &outer_tags {
+                            if is_consumed_outer(t, &common) {
+                                if let Some(h) = self.held_ways.get_mut(w) {
+                                    h.3 = true;
+                                }
+                            }
+                        }
+                    }
+                }
+                p
+            }
+        }
+    }
+
+    fn release_held_way(&mut self, w: i64) -> Option<(Quadtree, Way, Vec<LonLat>)> {
+        match self.held_ways.remove(&w) {
+            None => None,
+            Some((q, w, ll, consumed)) => {
+                if consumed {
+                    self.num_suppressed += 1;
+                    None
+                } else {
+                    Some((q, w, ll))
+                }
+            }
         }
     }
 
@@ -286,6 +399,7 @@ impl MultiPolygons {
         self.tma += tm.since();
         tm = ThreadTimer::new();
 
+        let mut held = BTreeSet::new();
         for (w, ll) in &wb.pending_ways {
             match self.pending_ways.get_mut(&w.id) {
                 None => {}
@@ -301,6 +415,14 @@ impl MultiPolygons {
                         w.refs.clone(),
                         ll.clone(),
                     ));
+                    if self.style.multipolygon_outer_tags {
+                        pw.2 = w.tags.clone();
+                    }
+                    if self.style.suppress_consumed_outers
+                        && is_held_outer(&self.style, &self.pending_relations, w, &pw.0)
+                    {
+                        held.insert(w.id);
+                    }
                     for r in &pw.0 {
                         match self.pending_relations.get_mut(r) {
                             None => {
@@ -317,6 +439,15 @@ impl MultiPolygons {
                 }
             }
         }
+        if !held.is_empty() {
+            let (hh, keep): (Vec<_>, Vec<_>) = std::mem::take(&mut wb.pending_ways)
+                .into_iter()
+                .partition(|(w, _)| held.contains(&w.id));
+            wb.pending_ways = keep;
+            for (w, ll) in hh {
+                self.held_ways.insert(w.id, (wb.geometry_block.quadtree.clone(), w, ll, false));
+            }
+        }
         if wb.geometry_block.quadtree.as_int() >= 0 {
             for (k, (tile, _, _)) in &self.pending_relations {
                 if !tile.is_parent(&wb.geometry_block.quadtree) {
@@ -329,8 +460,9 @@ impl MultiPolygons {
         tm = ThreadTimer::new();
         let mut finished_ways = BTreeSet::new();
 
+        let curr_quadtree = wb.geometry_block.quadtree.clone();
         let mut outblocks = BTreeMap::new();
-        outblocks.insert(wb.geometry_block.quadtree.clone(), wb);
+        outblocks.insert(curr_quadtree.clone(), wb);
 
         for r in finished_rels {
             let (_, (tq, rel, _)) = self.pending_relations.remove_entry(&r).expect("!");
@@ -368,6 +500,18 @@ impl MultiPolygons {
                     }
                 },
             }
+            //the block the way came from has already been passed on, so it
+            //goes into the current block, keeping its own quadtree
+            match self.release_held_way(w) {
+                Some((_, w, ll)) => {
+                    outblocks
+                        .get_mut(&curr_quadtree)
+                        .expect("current block")
+                        .pending_ways
+                        .push((w, ll));
+                }
+                None => {}
+            }
         }
         self.tmd += tm.since();
 
@@ -381,7 +525,7 @@ impl MultiPolygons {
         &mut self,
     ) -> (
         BTreeMap<Quadtree, WorkingBlock>,
-        Vec<(Element, String)>,
+        Vec<MultiPolygonError>,
         Vec<String>,
     ) {
         let mut tm = ThreadTimer::new();
@@ -421,12 +565,29 @@ impl MultiPolygons {
                 },
             }
         }
+        //any ways not consumed by a relation are passed on to be made into
+        //geometries, in one block for each quadtree along with any relations
+        for (_, (q, w, ll, consumed)) in std::mem::take(&mut self.held_ways) {
+            if consumed {
+                self.num_suppressed += 1;
+            } else {
+                res.entry(q.clone())
+                    .or_insert_with(|| WorkingBlock::new(-1, q, 0))
+                    .pending_ways
+                    .push((w, ll));
+            }
+        }
         self.tmd += tm.since();
-        let mm = vec![
-            format!("check rels: {:0.1}s, check ways: {:0.1}s, make poly: {:0.1}s [collect rings: {:0.1}s, merge rings: {:0.1}s], finish ways: {:0.1}s. Skipped {} big polys", self.tma, self.tmb, self.tmc, self.tmca, self.tmcb, self.tmd, self.skipped_big_poly),
-            format!("{} errors [{} passed]. {} ways never added?", self.err_count, self.errs.len(), self.wnas)
+        let mut mm = vec![
+            format!("check rels: {:0.1}s, check ways: {:0.1}s, make poly: {:0.1}s [assemble rings: {:0.1}s, nest rings: {:0.1}s], finish ways: {:0.1}s. Skipped {} big polys", self.tma, self.tmb, self.tmc, self.tmca, self.tmcb, self.tmd, self.skipped_big_poly),
+            format!("{} errors [{} reported]. {} ways never added? {} rels not features", self.err_count, self.mp_errors.len(), self.wnas, self.not_feature),
+            format!("{} rels took tags from outer ways, {} outer ways suppressed", self.num_outer_tags, self.num_suppressed),
+            format!("{} rels had self-intersecting rings split", self.num_crossings)
         ];
-        (res, std::mem::take(&mut self.errs), mm)
+        if self.transform_failures > 0 {
+            mm.push(format!("transform script failed for {} rels", self.transform_failures));
+        }
+        (res, std::mem::take(&mut self.mp_errors), mm)
     }
 }
 
@@ -440,9 +601,11 @@ impl<T> ProcessMultiPolygons<T>
 where
     T: CallFinish<CallType = WorkingBlock, ReturnType = Timings> + ?Sized,
 {
-    pub fn new(style: Arc<GeometryStyle>, out: Box<T>) -> ProcessMultiPolygons<T> {
+    //check_valid reports assembled polygons which fail validation as
+    //InvalidAfterAssembly errors: only needed for an error report
+    pub fn new(style: Arc<GeometryStyle>, check_valid: bool, out: Box<T>) -> ProcessMultiPolygons<T> {
         ProcessMultiPolygons {
-            multipolygons: Some(MultiPolygons::new(style, false)),
+            multipolygons: Some(MultiPolygons::new(style, false, check_valid)),
             out: out,
             tm: 0.0,
             counts: (0, 0, 0, 0),
@@ -474,7 +637,7 @@ where
     fn finish(&mut self) -> ccResult<Timings, Error> {
         let tx = ThreadTimer::new();
 
-        let (ans, errs, mut msgs) = {
+        let (ans, mp_errs, mut msgs) = {
             let mut multis = self.multipolygons.take().unwrap();
             multis.finish()
         };
@@ -502,7 +665,37 @@ where
         ));
 
         tms.add_other("ProcessMultiPolygons", OtherData::Messages(msgs));
-        tms.add_other("ProcessMultiPolygons", OtherData::Errors(errs));
+        tms.add_other("ProcessMultiPolygons", OtherData::MultiPolygonErrors(mp_errs));
         Ok(tms)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tt: &[(&str, &str)]) -> Vec<Tag> {
+        tt.iter().map(|(k, v)| Tag::new(k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn common_outer_tags() {
+        let outer = vec![
+            (1, tags(&[("landuse", "forest"), ("name", "Big Wood")])),
+            (2, tags(&[("name", "Big Wood"), ("landuse", "forest"), ("source", "survey")])),
+            (3, tags(&[("landuse", "forest"), ("name", "Little Wood")])),
+        ];
+        assert_eq!(common_tags(&outer[..2]), tags(&[("landuse", "forest"), ("name", "Big Wood")]));
+        assert_eq!(common_tags(&outer), tags(&[("landuse", "forest")]));
+        assert!(common_tags(&[]).is_empty());
+    }
+
+    #[test]
+    fn consumed_outer_compares_tags() {
+        let common = tags(&[("landuse", "forest"), ("name", "Big Wood")]);
+        assert!(is_consumed_outer(&tags(&[("name", "Big Wood"), ("landuse", "forest")]), &common));
+        //the same number of tags, but not the same tags
+        assert!(!is_consumed_outer(&tags(&[("landuse", "forest"), ("name", "Little Wood")]), &common));
+        assert!(!is_consumed_outer(&tags(&[("landuse", "forest"), ("name", "Big Wood"), ("source", "survey")]), &common));
+    }
+}
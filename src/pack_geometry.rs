@@ -4,7 +4,12 @@ use crate::{
     Timings, Error, Result
 };
 
-use osmquadtree::elements::{pack_head, PackStringTable, read_stringtable, read_common, Quadtree, Bbox};
+use crate::antimeridian::bbox_parts;
+use crate::compression::file_block_data;
+use crate::geometry_header::{check_geometry_file, geometry_header_offset};
+use crate::tagfilter::ObjectFilter;
+
+use osmquadtree::elements::{pack_head, PackStringTable, read_stringtable, read_common, Quadtree, Bbox, Info, Tag};
 use osmquadtree::mergechanges::{read_filter,Poly};
 
 use simple_protocolbuffers::{
@@ -12,6 +17,88 @@ use simple_protocolbuffers::{
 };
 
 
+//which of the optional fields are written by pack_geometry_block_with_options.
+//Coordinates, tags and the other geometry fields are always kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeometryPackOptions {
+    pub refs: bool,
+    pub version: bool,
+    pub timestamp: bool,
+    pub changeset: bool,
+    pub user: bool,
+}
+
+impl GeometryPackOptions {
+    pub fn all() -> GeometryPackOptions {
+        GeometryPackOptions{refs: true, version: true, timestamp: true, changeset: true, user: true}
+    }
+    
+    pub fn coordinates_only() -> GeometryPackOptions {
+        GeometryPackOptions{refs: false, version: false, timestamp: false, changeset: false, user: false}
+    }
+    
+    //comma separated list of refs, version, timestamp, changeset and user,
+    //or all or none
+    pub fn from_list(fields: &str) -> Result<GeometryPackOptions> {
+        match fields {
+            "all" => { return Ok(GeometryPackOptions::all()); },
+            "none" | "" => { return Ok(GeometryPackOptions::coordinates_only()); },
+            _ => {}
+        }
+        let mut res = GeometryPackOptions::coordinates_only();
+        for f in fields.split(',') {
+            match f.trim() {
+                "refs" => { res.refs = true; },
+                "info" => { res.version=true; res.timestamp=true; res.changeset=true; res.user=true; },
+                "version" => { res.version = true; },
+                "timestamp" => { res.timestamp = true; },
+                "changeset" => { res.changeset = true; },
+                "user" => { res.user = true; },
+                x => {
+                    return Err(Error::UserSelectionError(format!("unknown pack field {}, expected refs, version, timestamp, changeset or user", x)));
+                }
+            }
+        }
+        Ok(res)
+    }
+    
+    pub fn to_list(&self) -> String {
+        let mut res = Vec::new();
+        for (f, n) in [(self.refs, "refs"), (self.version, "version"), (self.timestamp, "timestamp"), (self.changeset, "changeset"), (self.user, "user")] {
+            if f {
+                res.push(n);
+            }
+        }
+        if res.is_empty() {
+            String::from("none")
+        } else {
+            res.join(",")
+        }
+    }
+    
+    pub fn has_info(&self) -> bool {
+        self.version || self.timestamp || self.changeset || self.user
+    }
+    
+    fn filter_info(&self, info: &Option<Info>) -> Option<Info> {
+        match info {
+            None => None,
+            Some(_) if !self.has_info() => None,
+            Some(i) => {
+                let mut i = i.clone();
+                if !self.version { i.version = 0; }
+                if !self.timestamp { i.timestamp = 0; }
+                if !self.changeset { i.changeset = 0; }
+                if !self.user {
+                    i.user_id = 0;
+                    i.user = String::new();
+                }
+                Some(i)
+            }
+        }
+    }
+}
+
 fn pack_all(tag: u64, objs: Vec<Vec<u8>>) -> Vec<u8> {
     if objs.is_empty() {
         return Vec::new();
@@ -29,53 +116,179 @@ fn pack_all(tag: u64, objs: Vec<Vec<u8>>) -> Vec<u8> {
     res
 }
 
-fn pack_point_geometry(pst: &mut Box<PackStringTable>, geom: &PointGeometry) -> Result<Vec<u8>> {
-    let mut res = Vec::with_capacity(95 + 10 * geom.tags.len());
-
-    pack_head(&geom.id, &geom.info, &geom.tags, &mut res, pst)?;
-    pack_value(&mut res, 8, zig_zag(geom.lonlat.lat as i64));
-    pack_value(&mut res, 9, zig_zag(geom.lonlat.lon as i64));
-
-    pack_value(&mut res, 20, zig_zag(geom.quadtree.as_int()));
-
-    match geom.minzoom {
-        None => {}
-        Some(m) => pack_value(&mut res, 22, m as u64),
+//points are sorted by id, with every field delta packed
+fn pack_dense_points(pst: &mut Box<PackStringTable>, points: &[PointGeometry], opts: &GeometryPackOptions) -> Result<Vec<u8>> {
+    let mut pp: Vec<&PointGeometry> = points.iter().collect();
+    pp.sort_by_key(|p| p.id);
+    
+    let mut res = Vec::with_capacity(30 * pp.len());
+    pack_data(&mut res, 1, &pack_delta_int(pp.iter().map(|p| p.id)));
+    
+    if opts.has_info() && pp.iter().any(|p| !p.info.is_none()) {
+        let infos: Vec<Option<Info>> = pp.iter().map(|p| opts.filter_info(&p.info)).collect();
+        pack_data(&mut res, 2, &pack_delta_int(infos.iter().map(|i| if i.is_none() { 0 } else { 1 })));
+        if opts.version {
+            pack_data(&mut res, 3, &pack_delta_int(infos.iter().map(|i| i.as_ref().map_or(0, |i| i.version))));
+        }
+        if opts.timestamp {
+            pack_data(&mut res, 4, &pack_delta_int(infos.iter().map(|i| i.as_ref().map_or(0, |i| i.timestamp))));
+        }
+        if opts.changeset {
+            pack_data(&mut res, 5, &pack_delta_int(infos.iter().map(|i| i.as_ref().map_or(0, |i| i.changeset))));
+        }
+        if opts.user {
+            pack_data(&mut res, 6, &pack_delta_int(infos.iter().map(|i| i.as_ref().map_or(0, |i| i.user_id))));
+            let mut users = Vec::with_capacity(infos.len());
+            for i in &infos {
+                users.push(match i {
+                    None => 0,
+                    Some(i) => pst.call(&i.user) as i64,
+                });
+            }
+            pack_data(&mut res, 7, &pack_delta_int(users.into_iter()));
+        }
     }
-    match geom.layer {
-        None => {}
-        Some(l) => pack_value(&mut res, 24, zig_zag(l)),
+    
+    pack_data(&mut res, 8, &pack_delta_int(pp.iter().map(|p| p.lonlat.lat as i64)));
+    pack_data(&mut res, 9, &pack_delta_int(pp.iter().map(|p| p.lonlat.lon as i64)));
+    
+    let mut keys = Vec::new();
+    let mut vals = Vec::new();
+    for p in &pp {
+        for t in &p.tags {
+            keys.push(pst.call(&t.key) as i64);
+            vals.push(pst.call(&t.val) as i64);
+        }
+    }
+    pack_data(&mut res, 10, &pack_delta_int(pp.iter().map(|p| p.tags.len() as i64)));
+    pack_data(&mut res, 11, &pack_delta_int(keys.into_iter()));
+    pack_data(&mut res, 12, &pack_delta_int(vals.into_iter()));
+    
+    pack_data(&mut res, 20, &pack_delta_int(pp.iter().map(|p| p.quadtree.as_int())));
+    
+    if pp.iter().any(|p| !p.minzoom.is_none()) {
+        pack_data(&mut res, 22, &pack_delta_int(pp.iter().map(|p| p.minzoom.map_or(0, |m| m + 1))));
+    }
+    if pp.iter().any(|p| !p.layer.is_none()) {
+        pack_data(&mut res, 24, &pack_delta_int(pp.iter().map(|p| p.layer.unwrap_or(0))));
+        pack_data(&mut res, 25, &pack_delta_int(pp.iter().map(|p| if p.layer.is_none() { 0 } else { 1 })));
     }
     Ok(res)
 }
 
+fn check_dense_len(name: &str, vals: &Vec<i64>, len: usize) -> Result<()> {
+    if !vals.is_empty() && vals.len() != len {
+        return Err(Error::InvalidDataError(format!("dense points: {}.len()!=ids.len()", name)));
+    }
+    Ok(())
+}
+
+fn get_string(strings: &Vec<String>, idx: i64) -> Result<String> {
+    match strings.get(idx as usize) {
+        Some(s) => Ok(s.clone()),
+        None => Err(Error::InvalidDataError(format!("string index {} out of range", idx))),
+    }
+}
 
-fn unpack_point_geometry(strings: &Vec<String>, data: &[u8]) -> Result<PointGeometry> {
+fn unpack_dense_points(strings: &Vec<String>, data: &[u8]) -> Result<Vec<PointGeometry>> {
+    let mut ids = Vec::new();
+    let mut has_info = Vec::new();
+    let mut versions = Vec::new();
+    let mut timestamps = Vec::new();
+    let mut changesets = Vec::new();
+    let mut user_ids = Vec::new();
+    let mut users = Vec::new();
+    let mut lats = Vec::new();
+    let mut lons = Vec::new();
+    let mut num_tags = Vec::new();
+    let mut keys = Vec::new();
+    let mut vals = Vec::new();
+    let mut quadtrees = Vec::new();
+    let mut minzooms = Vec::new();
+    let mut layers = Vec::new();
+    let mut has_layer = Vec::new();
     
-    let mut geom = PointGeometry::empty();
+    for tg in IterTags::new(&data) {
+        match tg {
+            PbfTag::Data(1, d) => { ids = read_delta_packed_int(&d); },
+            PbfTag::Data(2, d) => { has_info = read_delta_packed_int(&d); },
+            PbfTag::Data(3, d) => { versions = read_delta_packed_int(&d); },
+            PbfTag::Data(4, d) => { timestamps = read_delta_packed_int(&d); },
+            PbfTag::Data(5, d) => { changesets = read_delta_packed_int(&d); },
+            PbfTag::Data(6, d) => { user_ids = read_delta_packed_int(&d); },
+            PbfTag::Data(7, d) => { users = read_delta_packed_int(&d); },
+            PbfTag::Data(8, d) => { lats = read_delta_packed_int(&d); },
+            PbfTag::Data(9, d) => { lons = read_delta_packed_int(&d); },
+            PbfTag::Data(10, d) => { num_tags = read_delta_packed_int(&d); },
+            PbfTag::Data(11, d) => { keys = read_delta_packed_int(&d); },
+            PbfTag::Data(12, d) => { vals = read_delta_packed_int(&d); },
+            PbfTag::Data(20, d) => { quadtrees = read_delta_packed_int(&d); },
+            PbfTag::Data(22, d) => { minzooms = read_delta_packed_int(&d); },
+            PbfTag::Data(24, d) => { layers = read_delta_packed_int(&d); },
+            PbfTag::Data(25, d) => { has_layer = read_delta_packed_int(&d); },
+            _ => {}
+        }
+    }
     
-    let rem = read_common(&mut geom, &strings, &data, false)?;
+    let n = ids.len();
+    for (name, vv) in [("has_info", &has_info), ("versions", &versions), ("timestamps", &timestamps),
+            ("changesets", &changesets), ("user_ids", &user_ids), ("users", &users), ("num_tags", &num_tags),
+            ("quadtrees", &quadtrees), ("minzooms", &minzooms), ("layers", &layers), ("has_layer", &has_layer)] {
+        check_dense_len(name, vv, n)?;
+    }
+    if lats.len() != n || lons.len() != n {
+        return Err(Error::InvalidDataError(format!("dense points: lons.len()!=lats.len()")));
+    }
+    if keys.len() != vals.len() || keys.len() != num_tags.iter().sum::<i64>() as usize {
+        return Err(Error::InvalidDataError(format!("dense points: wrong number of keys or vals")));
+    }
     
-    for tg in rem {
-        match tg {
-            PbfTag::Value(8, lat) => { geom.lonlat.lat = un_zig_zag(lat) as i32; },
-            PbfTag::Value(9, lon) => { geom.lonlat.lon = un_zig_zag(lon) as i32; },
-            PbfTag::Value(22, m) => { geom.minzoom = Some(m as i64); },
-            PbfTag::Value(24, l) => { geom.layer = Some(un_zig_zag(l)); },
-            _ => {},
+    let mut res = Vec::with_capacity(n);
+    let mut ti = 0;
+    for i in 0..n {
+        let mut geom = PointGeometry::empty();
+        geom.id = ids[i];
+        geom.lonlat = LonLat::new(lons[i] as i32, lats[i] as i32);
+        if !has_info.is_empty() && has_info[i] == 1 {
+            let mut info = Info::new();
+            if !versions.is_empty() { info.version = versions[i]; }
+            if !timestamps.is_empty() { info.timestamp = timestamps[i]; }
+            if !changesets.is_empty() { info.changeset = changesets[i]; }
+            if !user_ids.is_empty() { info.user_id = user_ids[i]; }
+            if !users.is_empty() { info.user = get_string(strings, users[i])?; }
+            geom.info = Some(info);
+        }
+        if !num_tags.is_empty() {
+            for _ in 0..num_tags[i] {
+                geom.tags.push(Tag::new(get_string(strings, keys[ti])?, get_string(strings, vals[ti])?));
+                ti += 1;
+            }
+        }
+        if !quadtrees.is_empty() {
+            geom.quadtree = Quadtree::new(quadtrees[i]);
+        }
+        if !minzooms.is_empty() && minzooms[i] > 0 {
+            geom.minzoom = Some(minzooms[i] - 1);
         }
+        if !has_layer.is_empty() && has_layer[i] == 1 {
+            geom.layer = Some(layers[i]);
+        }
+        res.push(geom);
     }
-    Ok(geom)
-} 
+    Ok(res)
+}
 
 fn pack_linestring_geometry(
     pst: &mut Box<PackStringTable>,
     geom: &LinestringGeometry,
+    opts: &GeometryPackOptions,
 ) -> Result<Vec<u8>> {
     let mut res = Vec::with_capacity(95 + 10 * geom.tags.len() + 25 * geom.refs.len());
 
-    pack_head(&geom.id, &geom.info, &geom.tags, &mut res, pst)?;
-    pack_data(&mut res, 8, &pack_delta_int_ref(geom.refs.iter()));
+    pack_head(&geom.id, &opts.filter_info(&geom.info), &geom.tags, &mut res, pst)?;
+    if opts.refs {
+        pack_data(&mut res, 8, &pack_delta_int_ref(geom.refs.iter()));
+    }
     match geom.z_order {
         None => {}
         Some(z) => {
@@ -93,6 +306,9 @@ fn pack_linestring_geometry(
         &pack_delta_int(geom.lonlats.iter().map(|l| l.lat as i64)),
     );
     pack_value(&mut res, 15, zig_zag((geom.length * 100.0).round() as i64));
+    if !geom.source_ids.is_empty() {
+        pack_data(&mut res, 17, &pack_delta_int_ref(geom.source_ids.iter()));
+    }
 
     pack_value(&mut res, 20, zig_zag(geom.quadtree.as_int()));
 
@@ -121,6 +337,7 @@ fn unpack_linestring_geometry(strings: &Vec<String>, data: &[u8]) -> Result<Line
             PbfTag::Data(13, d) => { lons = read_delta_packed_int(&d);},
             PbfTag::Data(14, d) => { lats = read_delta_packed_int(&d); },
             PbfTag::Value(15, l) => { geom.length = un_zig_zag(l) as f64 / 100.0; },
+            PbfTag::Data(17, d) => { geom.source_ids = read_delta_packed_int(&d); },
             PbfTag::Value(22, m) => { geom.minzoom = Some(m as i64); },
             PbfTag::Value(24, l) => { geom.layer = Some(un_zig_zag(l)); },
             _ => {},
@@ -143,14 +360,34 @@ fn set_lon_lats(lons: Vec<i64>, lats: Vec<i64>) -> Result<Vec<LonLat>> {
     Ok(res)
 }
 
+fn pack_label_point(res: &mut Vec<u8>, label_point: &Option<LonLat>) {
+    match label_point {
+        None => {}
+        Some(l) => {
+            pack_value(res, 26, zig_zag(l.lon as i64));
+            pack_value(res, 27, zig_zag(l.lat as i64));
+        }
+    }
+}
+
+fn read_label_point(lon: Option<i64>, lat: Option<i64>) -> Option<LonLat> {
+    match (lon, lat) {
+        (Some(x), Some(y)) => Some(LonLat::new(x as i32, y as i32)),
+        _ => None,
+    }
+}
+
 fn pack_simple_polygon_geometry(
     pst: &mut Box<PackStringTable>,
     geom: &SimplePolygonGeometry,
+    opts: &GeometryPackOptions,
 ) -> Result<Vec<u8>> {
     let mut res = Vec::with_capacity(95 + 10 * geom.tags.len() + 25 * geom.refs.len());
 
-    pack_head(&geom.id, &geom.info, &geom.tags, &mut res, pst)?;
-    pack_data(&mut res, 8, &pack_delta_int_ref(geom.refs.iter()));
+    pack_head(&geom.id, &opts.filter_info(&geom.info), &geom.tags, &mut res, pst)?;
+    if opts.refs {
+        pack_data(&mut res, 8, &pack_delta_int_ref(geom.refs.iter()));
+    }
     match geom.z_order {
         None => {}
         Some(z) => {
@@ -186,6 +423,7 @@ fn pack_simple_polygon_geometry(
             pack_value(&mut res, 24, zig_zag(l));
         }
     }
+    pack_label_point(&mut res, &geom.label_point);
     Ok(res)
 }
 fn unpack_simplepolygon_geometry(strings: &Vec<String>, data: &[u8]) -> Result<SimplePolygonGeometry> {
@@ -195,6 +433,8 @@ fn unpack_simplepolygon_geometry(strings: &Vec<String>, data: &[u8]) -> Result<S
     let rem = read_common(&mut geom, &strings, &data, false)?;
     let mut lats = Vec::new();
     let mut lons = Vec::new();
+    let mut label_lon = None;
+    let mut label_lat = None;
     for tg in rem {
         match tg {
             PbfTag::Data(8, d) => { geom.refs = read_delta_packed_int(&d); },
@@ -205,20 +445,24 @@ fn unpack_simplepolygon_geometry(strings: &Vec<String>, data: &[u8]) -> Result<S
             PbfTag::Value(22, m) => { geom.minzoom = Some(m as i64); },
             PbfTag::Value(23, r) => { geom.reversed = r==1; },
             PbfTag::Value(24, l) => { geom.layer = Some(un_zig_zag(l)); },
+            PbfTag::Value(26, x) => { label_lon = Some(un_zig_zag(x)); },
+            PbfTag::Value(27, y) => { label_lat = Some(un_zig_zag(y)); },
             _ => {},
         }
     }
     
     geom.lonlats = set_lon_lats(lons, lats)?;
-    
+    geom.label_point = read_label_point(label_lon, label_lat);
         
     Ok(geom)
 } 
-fn pack_ringpart(rp: &RingPart) -> Result<Vec<u8>> {
+fn pack_ringpart(rp: &RingPart, opts: &GeometryPackOptions) -> Result<Vec<u8>> {
     let mut res = Vec::with_capacity(40 + 25 * rp.refs.len());
 
-    pack_value(&mut res, 1, rp.orig_id as u64);
-    pack_data(&mut res, 2, &pack_delta_int_ref(rp.refs.iter()));
+    pack_value(&mut res, 1, zig_zag(rp.orig_id));
+    if opts.refs {
+        pack_data(&mut res, 2, &pack_delta_int_ref(rp.refs.iter()));
+    }
     pack_data(
         &mut res,
         3,
@@ -241,7 +485,7 @@ fn unpack_ringpart(data: &[u8]) -> Result<RingPart> {
     let mut lons = Vec::new();
     for tg in IterTags::new(&data) {
         match tg {
-            PbfTag::Value(1, i) => { rp.orig_id = i as i64; },
+            PbfTag::Value(1, i) => { rp.orig_id = un_zig_zag(i); },
             PbfTag::Data(2, d) => { rp.refs = read_delta_packed_int(&d); },
             PbfTag::Data(3, d) => { lons = read_delta_packed_int(&d);},
             PbfTag::Data(4, d) => { lats = read_delta_packed_int(&d); },
@@ -255,11 +499,11 @@ fn unpack_ringpart(data: &[u8]) -> Result<RingPart> {
 } 
     
 
-fn pack_ring(rr: &Ring) -> Result<Vec<u8>> {
+fn pack_ring(rr: &Ring, opts: &GeometryPackOptions) -> Result<Vec<u8>> {
     let mut tl = 0;
     let mut parts = Vec::with_capacity(rr.parts.len());
     for p in &rr.parts {
-        let q = pack_ringpart(p)?;
+        let q = pack_ringpart(p, opts)?;
         tl += data_length(1, q.len());
         parts.push(q);
     }
@@ -284,15 +528,15 @@ fn unpack_ring(data: &[u8]) -> Result<Ring> {
 }
 
 
-fn pack_polygon_part(idx: usize, part: &PolygonPart) -> Result<Vec<u8>> {
+fn pack_polygon_part(idx: usize, part: &PolygonPart, opts: &GeometryPackOptions) -> Result<Vec<u8>> {
     let mut tl = 20;
 
-    let extr = pack_ring(&part.exterior)?;
+    let extr = pack_ring(&part.exterior, opts)?;
     tl += data_length(2, extr.len());
 
     let mut intrs = Vec::with_capacity(part.interiors.len());
     for ii in &part.interiors {
-        let intr = pack_ring(&ii)?;
+        let intr = pack_ring(&ii, opts)?;
         tl += data_length(2, intr.len());
         intrs.push(intr);
     }
@@ -328,18 +572,19 @@ fn unpack_polygon_part(data: &[u8]) -> Result<(usize, PolygonPart)> {
 fn pack_complicated_polygon_geometry(
     pst: &mut Box<PackStringTable>,
     geom: &ComplicatedPolygonGeometry,
+    opts: &GeometryPackOptions,
 ) -> Result<Vec<u8>> {
     let mut packed_parts = Vec::with_capacity(geom.parts.len());
     let mut pl = 0;
     for (i, p) in geom.parts.iter().enumerate() {
-        let q = pack_polygon_part(i, p)?;
+        let q = pack_polygon_part(i, p, opts)?;
         pl += data_length(25, q.len());
         packed_parts.push(q);
     }
 
     let mut res = Vec::with_capacity(95 + 10 * geom.tags.len() + pl);
 
-    pack_head(&geom.id, &geom.info, &geom.tags, &mut res, pst)?;
+    pack_head(&geom.id, &opts.filter_info(&geom.info), &geom.tags, &mut res, pst)?;
     match geom.z_order {
         None => {}
         Some(z) => {
@@ -363,6 +608,7 @@ fn pack_complicated_polygon_geometry(
     for p in packed_parts {
         pack_data(&mut res, 25, &p);
     }
+    pack_label_point(&mut res, &geom.label_point);
     Ok(res)
 }
 
@@ -371,6 +617,8 @@ fn unpack_complicated_polygon_geometry(strings: &Vec<String>, data: &[u8]) -> Re
     let mut geom = ComplicatedPolygonGeometry::empty();
     
     let rem = read_common(&mut geom, &strings, &data, false)?;
+    let mut label_lon = None;
+    let mut label_lat = None;
     for tg in rem {
         match tg {
             
@@ -385,10 +633,13 @@ fn unpack_complicated_polygon_geometry(strings: &Vec<String>, data: &[u8]) -> Re
                 }
                 geom.parts.push(p);
             },
+            PbfTag::Value(26, x) => { label_lon = Some(un_zig_zag(x)); },
+            PbfTag::Value(27, y) => { label_lat = Some(un_zig_zag(y)); },
                 
             _ => {},
         }
     }
+    geom.label_point = read_label_point(label_lon, label_lat);
         
     Ok(geom)
 } 
@@ -396,29 +647,33 @@ fn unpack_complicated_polygon_geometry(strings: &Vec<String>, data: &[u8]) -> Re
 
 
 pub fn pack_geometry_block(gb: &GeometryBlock) -> Result<Vec<u8>> {
+    pack_geometry_block_with_options(gb, &GeometryPackOptions::all())
+}
+
+pub fn pack_geometry_block_with_options(gb: &GeometryBlock, opts: &GeometryPackOptions) -> Result<Vec<u8>> {
     let mut pst = Box::new(PackStringTable::new());
 
-    let mut points = Vec::with_capacity(gb.points.len());
-    for p in &gb.points {
-        points.push(pack_point_geometry(&mut pst, p)?);
-    }
-    let points_group = pack_all(20, points);
+    let points_group = if gb.points.is_empty() {
+        Vec::new()
+    } else {
+        pack_all(24, vec![pack_dense_points(&mut pst, &gb.points, opts)?])
+    };
 
     let mut linestrings = Vec::with_capacity(gb.linestrings.len());
     for p in &gb.linestrings {
-        linestrings.push(pack_linestring_geometry(&mut pst, p)?);
+        linestrings.push(pack_linestring_geometry(&mut pst, p, opts)?);
     }
     let linestrings_group = pack_all(21, linestrings);
 
     let mut simple_polygons = Vec::with_capacity(gb.simple_polygons.len());
     for p in &gb.simple_polygons {
-        simple_polygons.push(pack_simple_polygon_geometry(&mut pst, p)?);
+        simple_polygons.push(pack_simple_polygon_geometry(&mut pst, p, opts)?);
     }
     let simple_polygons_group = pack_all(22, simple_polygons);
 
     let mut complicated_polygons = Vec::with_capacity(gb.complicated_polygons.len());
     for p in &gb.complicated_polygons {
-        complicated_polygons.push(pack_complicated_polygon_geometry(&mut pst, p)?);
+        complicated_polygons.push(pack_complicated_polygon_geometry(&mut pst, p, opts)?);
     }
     let complicated_polygons_group = pack_all(23, complicated_polygons);
 
@@ -466,7 +721,7 @@ fn unpack_group(gb: &mut GeometryBlock, strs: &Vec<String>, data: &[u8]) -> Resu
     
     for tg in IterTags::new(&data) {
         match tg {
-            PbfTag::Data(20, d) => { gb.points.push(unpack_point_geometry(&strs, &d)?); },
+            PbfTag::Data(24, d) => { gb.points.extend(unpack_dense_points(&strs, &d)?); },
             PbfTag::Data(21, d) => { gb.linestrings.push(unpack_linestring_geometry(&strs, &d)?); },
             PbfTag::Data(22, d) => { gb.simple_polygons.push(unpack_simplepolygon_geometry(&strs, &d)?); },
             PbfTag::Data(23, d) => { gb.complicated_polygons.push(unpack_complicated_polygon_geometry(&strs, &d)?); },
@@ -502,11 +757,20 @@ pub enum GeometryFilter {
 }
 
 impl GeometryFilter {
+    //bbox as minlon,minlat,maxlon,maxlat or a poly filename, as read_filter
+    pub fn from_str(filter_str: &str) -> Result<GeometryFilter> {
+        let (bx, poly) = read_filter(Some(filter_str))?;
+        match poly {
+            Some(pp) => Ok(GeometryFilter::Poly(pp)),
+            None => Ok(GeometryFilter::Bbox(bx)),
+        }
+    }
+    
     pub fn check<T: WithBounds>(&self, obj: &T) -> bool {
         match self {
             GeometryFilter::Null => true,
-            GeometryFilter::Bbox(b) => b.overlaps(&obj.bounds()),
-            GeometryFilter::Poly(p) => p.check_box(&obj.bounds())
+            GeometryFilter::Bbox(b) => bbox_parts(&obj.bounds()).iter().any(|x| b.overlaps(x)),
+            GeometryFilter::Poly(p) => bbox_parts(&obj.bounds()).iter().any(|x| p.check_box(x))
         }
     }
     pub fn is_null(&self) -> bool {
@@ -527,29 +791,39 @@ fn max_minzoom_check(test_minzoom: &Option<i64>, val_minzoom: &Option<i64>) -> b
     }
 }
 
-fn unpack_group_filter(gb: &mut GeometryBlock, strs: &Vec<String>, data: &[u8], filter: &GeometryFilter, max_minzoom: &Option<i64>) -> Result<()> {
+fn unpack_group_filter(gb: &mut GeometryBlock, strs: &Vec<String>, data: &[u8], filter: &GeometryFilter, max_minzoom: &Option<i64>, object_filter: &ObjectFilter) -> Result<()> {
     
     for tg in IterTags::new(&data) {
         match tg {
-            PbfTag::Data(20, d) => {
-                let p = unpack_point_geometry(&strs, &d)?;
-                if filter.check(&p) && max_minzoom_check(max_minzoom, &p.minzoom) { 
-                    gb.points.push(p);
+            PbfTag::Data(24, d) if object_filter.types.point => {
+                for p in unpack_dense_points(&strs, &d)? {
+                    if filter.check(&p) && max_minzoom_check(max_minzoom, &p.minzoom) && object_filter.check_tags(&p.tags) { 
+                        gb.points.push(p);
+                    }
                 }
             },
-            PbfTag::Data(21, d) => {
+            PbfTag::Data(21, d) if object_filter.types.linestring => {
+                if !object_filter.check_packed(&strs, &d)? {
+                    continue;
+                }
                 let p = unpack_linestring_geometry(&strs, &d)?;
                 if filter.check(&p) && max_minzoom_check(max_minzoom, &p.minzoom) { 
                     gb.linestrings.push(p);
                 }
             },
-            PbfTag::Data(22, d) => {
+            PbfTag::Data(22, d) if object_filter.types.simple_polygon => {
+                if !object_filter.check_packed(&strs, &d)? {
+                    continue;
+                }
                 let p = unpack_simplepolygon_geometry(&strs, &d)?;
                 if filter.check(&p) && max_minzoom_check(max_minzoom, &p.minzoom) { 
                     gb.simple_polygons.push(p);
                 }
             },
-            PbfTag::Data(23, d) => {
+            PbfTag::Data(23, d) if object_filter.types.complicated_polygon => {
+                if !object_filter.check_packed(&strs, &d)? {
+                    continue;
+                }
                 let p = unpack_complicated_polygon_geometry(&strs, &d)?;
                 if filter.check(&p) && max_minzoom_check(max_minzoom, &p.minzoom) { 
                     gb.complicated_polygons.push(p);
@@ -562,8 +836,8 @@ fn unpack_group_filter(gb: &mut GeometryBlock, strs: &Vec<String>, data: &[u8],
 }
     
 
-pub fn unpack_geometry_block_filter(idx: i64, data: &[u8], filter: &GeometryFilter, max_minzoom: &Option<i64>) -> Result<GeometryBlock> {
-    if filter.is_null() && max_minzoom.is_none() {
+pub fn unpack_geometry_block_filter(idx: i64, data: &[u8], filter: &GeometryFilter, max_minzoom: &Option<i64>, object_filter: &ObjectFilter) -> Result<GeometryBlock> {
+    if filter.is_null() && max_minzoom.is_none() && object_filter.is_null() {
         return unpack_geometry_block(idx, data);
     }
     
@@ -573,7 +847,7 @@ pub fn unpack_geometry_block_filter(idx: i64, data: &[u8], filter: &GeometryFilt
     for tg in IterTags::new(&data) {
         match tg {
             PbfTag::Data(1, d) => { strs = read_stringtable(&d)?; },
-            PbfTag::Data(2, d) => { unpack_group_filter(&mut gb, &strs, &d, filter, max_minzoom)?; },
+            PbfTag::Data(2, d) => { unpack_group_filter(&mut gb, &strs, &d, filter, max_minzoom, object_filter)?; },
             PbfTag::Value(32, q) => { gb.quadtree = Quadtree::new(un_zig_zag(q)); },
             PbfTag::Value(34, q) => { gb.end_date = q as i64; },
             _ => {}
@@ -584,14 +858,128 @@ pub fn unpack_geometry_block_filter(idx: i64, data: &[u8], filter: &GeometryFilt
     
 }
 
-use channelled_callbacks::{/*CallFinish,Timings,CallbackSync,CallbackMerge,*/CallAll};
+use channelled_callbacks::{CallFinish, Callback, CallbackMerge, CallbackSync, MergeTimings, ReplaceNoneWithTimings, Result as ccResult};
 use osmquadtree::message;
-use osmquadtree::pbfformat::{get_file_locs_max_depth,read_all_blocks_parallel_with_progbar,FileBlock};
-use std::sync::Arc;
+use osmquadtree::pbfformat::{get_file_locs_max_depth,read_all_blocks_parallel_with_progbar,FileBlock,ParallelFileLocs};
+use osmquadtree::utils::ThreadTimer;
+use std::sync::{Arc, Mutex};
+
+type CallFinishFileBlocks =
+    Box<dyn CallFinish<CallType = (usize, Vec<FileBlock>), ReturnType = Timings, ErrorType = Error>>;
+
+//the locations of the data blocks of a geometry file, without its header block
+pub(crate) fn geometry_file_locs(infn: &str, bbox: Option<Bbox>, max_depth: Option<usize>) -> Result<ParallelFileLocs> {
+    let (files, mut locs, total_len) = get_file_locs_max_depth(infn, bbox, None, max_depth)?;
+    if let Some(off) = geometry_header_offset(infn)? {
+        locs.retain(|(_, ll)| !ll.iter().any(|(_, p)| *p == off));
+    }
+    Ok((files, locs, total_len))
+}
+
+//read_all_blocks_parallel_with_progbar expects finish to succeed, so the
+//first error is held here and returned once all blocks have been read
+struct KeepFinishError {
+    out: CallFinishFileBlocks,
+    error: Arc<Mutex<Option<Error>>>,
+}
 
+impl CallFinish for KeepFinishError {
+    type CallType = (usize, Vec<FileBlock>);
+    type ReturnType = Timings;
+    type ErrorType = Error;
 
-pub fn read_geometry_blocks(
-    infn: &str, cb: CallFinishGeometryBlock, filter_str: Option<&str>, max_minzoom: Option<i64>, numchan: usize) -> Result<Timings> {
+    fn call(&mut self, bls: (usize, Vec<FileBlock>)) {
+        self.out.call(bls);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        match self.out.finish() {
+            Ok(tm) => Ok(tm),
+            Err(e) => {
+                *self.error.lock().unwrap() = Some(e);
+                Ok(Timings::new())
+            }
+        }
+    }
+}
+
+//reads the blocks at pfilelocs into pp, returning the first error from
+//any of the callbacks
+pub(crate) fn read_all_blocks_result(pfilelocs: &mut ParallelFileLocs, pp: CallFinishFileBlocks, msg: &str) -> Result<Timings> {
+    let error = Arc::new(Mutex::new(None));
+    let kf = Box::new(KeepFinishError { out: pp, error: error.clone() });
+    let tm = read_all_blocks_parallel_with_progbar(&mut pfilelocs.0, &pfilelocs.1, kf, msg, pfilelocs.2);
+    let err = error.lock().unwrap().take();
+    match err {
+        Some(e) => Err(e),
+        None => Ok(tm),
+    }
+}
+
+//blocks which can't be unpacked are passed on empty, so that blocks
+//resynchronised by CallbackSync stay in order, and the first error is
+//returned from finish
+struct UnpackGeometry {
+    out: CallFinishGeometryBlock,
+    filter: Arc<GeometryFilter>,
+    max_minzoom: Option<i64>,
+    object_filter: Arc<ObjectFilter>,
+    error: Option<Error>,
+    tm: f64,
+}
+
+impl UnpackGeometry {
+    fn unpack(&self, i: usize, fb: &FileBlock) -> Result<GeometryBlock> {
+        unpack_geometry_block_filter(i as i64, &file_block_data(fb)?, &self.filter, &self.max_minzoom, &self.object_filter)
+    }
+}
+
+impl CallFinish for UnpackGeometry {
+    type CallType = (usize, Vec<FileBlock>);
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, (i, fbs): (usize, Vec<FileBlock>)) {
+        let tx = ThreadTimer::new();
+        let bl = match self.unpack(i, &fbs[0]) {
+            Ok(bl) => bl,
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+                GeometryBlock::new(i as i64, Quadtree::empty(), 0)
+            }
+        };
+        self.tm += tx.since();
+        self.out.call(bl);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = self.out.finish()?;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        tms.add("UnpackGeometry", self.tm);
+        Ok(tms)
+    }
+}
+
+fn make_unpack_geometry(cb: CallFinishGeometryBlock, filter: Arc<GeometryFilter>, max_minzoom: Option<i64>, object_filter: Arc<ObjectFilter>) -> CallFinishFileBlocks {
+    Box::new(UnpackGeometry {
+        out: cb,
+        filter: filter,
+        max_minzoom: max_minzoom,
+        object_filter: object_filter,
+        error: None,
+        tm: 0.0,
+    })
+}
+
+fn read_geometry_blocks_call(infn: &str, filter_str: Option<&str>, max_minzoom: Option<i64>, make_call: Box<dyn FnOnce(Arc<GeometryFilter>) -> CallFinishFileBlocks>, msg: &str) -> Result<Timings> {
+    
+    //the compression of each block is found from its blob, so the header
+    //is only checked for a supported format version
+    check_geometry_file(infn)?;
     
     let (bx, poly) = read_filter(filter_str)?;
     
@@ -609,28 +997,341 @@ pub fn read_geometry_blocks(
         Some(md) => Some(md as usize)
     };
     
-    let (mut files, locs, total_len)  = get_file_locs_max_depth(infn, Some(bx), None, max_depth)?;
-    
+    let mut pfilelocs = geometry_file_locs(infn, Some(bx), max_depth)?;
     
-    
-    let r = if numchan == 0 {
-        let cc = Box::new(CallAll::new(
-            cb, "UnpackGeometry", 
-            Box::new(move |(i,fb): (usize,Vec<FileBlock>)| {
-                unpack_geometry_block_filter(i as i64, &fb[0].data(), &geometry_filter, &max_minzoom).unwrap()
-            })
-        ));
-        
-        Ok(read_all_blocks_parallel_with_progbar(&mut files, &locs, cc, "read geometry blocks", total_len))
-    } else {
-        Err(Error::NotImplementedError)
-    }?;
+    let r = read_all_blocks_result(&mut pfilelocs, make_call(geometry_filter), msg)?;
     
     message!("{}", r);
     
     Ok(r)
 }
+
+//blocks are passed to cb in file order. With numchan>0 they are unpacked
+//by numchan threads, and resynchronised before calling cb.
+pub fn read_geometry_blocks(
+    infn: &str, cb: CallFinishGeometryBlock, filter_str: Option<&str>, max_minzoom: Option<i64>, object_filter: Option<ObjectFilter>, numchan: usize) -> Result<Timings> {
     
-        
-        
-        
+    let object_filter = Arc::new(object_filter.unwrap_or_else(ObjectFilter::all));
+    read_geometry_blocks_call(infn, filter_str, max_minzoom, Box::new(move |geometry_filter| {
+        if numchan == 0 {
+            make_unpack_geometry(cb, geometry_filter, max_minzoom, object_filter)
+        } else {
+            let cbs = CallbackSync::new(cb, numchan);
+            let mut pps: Vec<CallFinishFileBlocks> = Vec::new();
+            for c in cbs {
+                let c2 = Box::new(ReplaceNoneWithTimings::new(c));
+                pps.push(Box::new(Callback::new(make_unpack_geometry(c2, geometry_filter.clone(), max_minzoom, object_filter.clone()))));
+            }
+            Box::new(CallbackMerge::new(pps, Box::new(MergeTimings::new())))
+        }
+    }), &format!("read geometry blocks, numchan={}", numchan))
+}
+
+//each of cbs runs in its own thread, receiving blocks in no particular
+//order. The timings returned by each are merged.
+pub fn read_geometry_blocks_unordered(
+    infn: &str, cbs: Vec<CallFinishGeometryBlock>, filter_str: Option<&str>, max_minzoom: Option<i64>, object_filter: Option<ObjectFilter>) -> Result<Timings> {
+    
+    if cbs.is_empty() {
+        return Err(Error::UserSelectionError(String::from("no callbacks given")));
+    }
+    let numchan = cbs.len();
+    let object_filter = Arc::new(object_filter.unwrap_or_else(ObjectFilter::all));
+    read_geometry_blocks_call(infn, filter_str, max_minzoom, Box::new(move |geometry_filter| {
+        let mut pps: Vec<CallFinishFileBlocks> = Vec::new();
+        for c in cbs {
+            pps.push(Box::new(Callback::new(make_unpack_geometry(c, geometry_filter.clone(), max_minzoom, object_filter.clone()))));
+        }
+        Box::new(CallbackMerge::new(pps, Box::new(MergeTimings::new())))
+    }), &format!("read geometry blocks unordered, numchan={}", numchan))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+
+    fn ll(lon: i32, lat: i32) -> LonLat {
+        LonLat::new(lon, lat)
+    }
+
+    fn tags(kv: &[(&str, &str)]) -> Vec<Tag> {
+        kv.iter().map(|(k, v)| Tag::new(String::from(*k), String::from(*v))).collect()
+    }
+
+    fn info(version: i64) -> Option<Info> {
+        let mut i = Info::new();
+        i.version = version;
+        i.timestamp = 1600000000;
+        i.changeset = 12345;
+        i.user_id = 99;
+        i.user = String::from("someone");
+        Some(i)
+    }
+
+    fn ring(parts: Vec<RingPart>) -> Ring {
+        let mut r = Ring::new();
+        r.parts = parts;
+        r.calc_area_bbox().unwrap();
+        r
+    }
+
+    fn test_block() -> GeometryBlock {
+        let mut gb = GeometryBlock::new(0, Quadtree::new(1234), 0);
+
+        let mut p = PointGeometry::empty();
+        p.id = 20;
+        p.tags = tags(&[("amenity", "cafe"), ("name", "A")]);
+        p.lonlat = ll(-1000, 2000);
+        p.quadtree = Quadtree::new(5678);
+        p.minzoom = Some(0);
+        p.layer = Some(-1);
+        p.info = info(3);
+        gb.points.push(p);
+
+        let mut p = PointGeometry::empty();
+        p.id = 10;
+        p.tags = tags(&[("amenity", "pub")]);
+        p.lonlat = ll(3000, -4000);
+        p.quadtree = Quadtree::new(91011);
+        gb.points.push(p);
+
+        let mut l = LinestringGeometry::empty();
+        l.id = 30;
+        l.info = info(2);
+        l.tags = tags(&[("highway", "primary")]);
+        l.refs = vec![100, 99, 105];
+        l.lonlats = vec![ll(0, 0), ll(-500, 700), ll(1500, -300)];
+        l.length = 123.45;
+        l.z_order = Some(-7);
+        l.layer = Some(2);
+        l.minzoom = Some(11);
+        l.quadtree = Quadtree::new(1213);
+        l.source_ids = vec![31, 30];
+        gb.linestrings.push(l);
+
+        let mut s = SimplePolygonGeometry::empty();
+        s.id = 40;
+        s.tags = tags(&[("building", "yes")]);
+        s.refs = vec![200, 201, 202, 200];
+        s.lonlats = vec![ll(0, 0), ll(0, 10), ll(10, 10), ll(0, 0)];
+        s.area = 50.25;
+        s.reversed = true;
+        s.quadtree = Quadtree::new(1415);
+        s.label_point = Some(ll(3, 6));
+        gb.simple_polygons.push(s);
+
+        let ext = ring(vec![
+            RingPart::new(50, false, vec![300, 301, 302], vec![ll(0, 0), ll(100, 0), ll(100, 100)]),
+            RingPart::new(51, true, vec![300, 303, 302], vec![ll(0, 0), ll(0, 100), ll(100, 100)]),
+        ]);
+        let hole = ring(vec![RingPart::new(
+            52,
+            true,
+            vec![310, 311, 312, 310],
+            vec![ll(20, 20), ll(80, 20), ll(80, 80), ll(20, 20)],
+        )]);
+        let mut part = PolygonPart::new(ext);
+        part.add_interior(hole);
+
+        let mut c = ComplicatedPolygonGeometry::empty();
+        c.id = 60;
+        c.info = info(5);
+        c.tags = tags(&[("natural", "water")]);
+        c.area = part.area;
+        c.parts = vec![part];
+        c.z_order = Some(3);
+        c.quadtree = Quadtree::new(1617);
+        c.label_point = Some(ll(50, 90));
+        gb.complicated_polygons.push(c);
+        gb
+    }
+
+    fn round_trip(opts: &GeometryPackOptions) -> GeometryBlock {
+        let data = pack_geometry_block_with_options(&test_block(), opts).unwrap();
+        unpack_geometry_block(0, &data).unwrap()
+    }
+
+    #[test]
+    fn round_trip_all() {
+        let gb = round_trip(&GeometryPackOptions::all());
+        assert_eq!(gb.quadtree.as_int(), 1234);
+
+        //points are packed in id order
+        assert_eq!(gb.points.len(), 2);
+        let (p, q) = (&gb.points[0], &gb.points[1]);
+        assert_eq!((p.id, q.id), (10, 20));
+        assert_eq!(p.tags, tags(&[("amenity", "pub")]));
+        assert_eq!(p.lonlat, ll(3000, -4000));
+        assert_eq!(p.quadtree.as_int(), 91011);
+        assert!(p.info.is_none() && p.minzoom.is_none() && p.layer.is_none());
+        assert_eq!(q.tags, tags(&[("amenity", "cafe"), ("name", "A")]));
+        assert_eq!(q.lonlat, ll(-1000, 2000));
+        assert_eq!(q.minzoom, Some(0));
+        assert_eq!(q.layer, Some(-1));
+        let qi = q.info.as_ref().unwrap();
+        assert_eq!((qi.version, qi.timestamp, qi.changeset, qi.user_id), (3, 1600000000, 12345, 99));
+        assert_eq!(qi.user, "someone");
+
+        let l = &gb.linestrings[0];
+        assert_eq!(l.id, 30);
+        assert_eq!(l.refs, vec![100, 99, 105]);
+        assert_eq!(l.lonlats, vec![ll(0, 0), ll(-500, 700), ll(1500, -300)]);
+        assert_eq!(l.length, 123.45);
+        assert_eq!((l.z_order, l.layer, l.minzoom), (Some(-7), Some(2), Some(11)));
+        assert_eq!(l.source_ids, vec![31, 30]);
+        assert_eq!(l.info.as_ref().unwrap().version, 2);
+
+        let s = &gb.simple_polygons[0];
+        assert_eq!(s.refs, vec![200, 201, 202, 200]);
+        assert_eq!(s.lonlats, vec![ll(0, 0), ll(0, 10), ll(10, 10), ll(0, 0)]);
+        assert_eq!(s.area, 50.25);
+        assert!(s.reversed);
+        assert_eq!(s.label_point, Some(ll(3, 6)));
+
+        let c = &gb.complicated_polygons[0];
+        let orig = &test_block().complicated_polygons[0];
+        assert_eq!(c.id, 60);
+        assert_eq!(c.z_order, Some(3));
+        assert_eq!(c.label_point, Some(ll(50, 90)));
+        assert!((c.area - orig.area).abs() < 0.01);
+        assert_eq!(c.parts.len(), 1);
+        assert_eq!(c.parts[0].exterior.lonlats().unwrap(), orig.parts[0].exterior.lonlats().unwrap());
+        assert_eq!(c.parts[0].exterior.refs().unwrap(), orig.parts[0].exterior.refs().unwrap());
+        assert_eq!(c.parts[0].interiors.len(), 1);
+        assert_eq!(c.parts[0].interiors[0].lonlats().unwrap(), orig.parts[0].interiors[0].lonlats().unwrap());
+        let rp: Vec<(i64, bool)> = c.parts[0].exterior.parts.iter().map(|p| (p.orig_id, p.is_reversed)).collect();
+        let orig_rp: Vec<(i64, bool)> = orig.parts[0].exterior.parts.iter().map(|p| (p.orig_id, p.is_reversed)).collect();
+        assert_eq!(rp, orig_rp);
+    }
+
+    #[test]
+    fn round_trip_coordinates_only() {
+        let gb = round_trip(&GeometryPackOptions::coordinates_only());
+        assert!(gb.points.iter().all(|p| p.info.is_none()));
+        assert_eq!(gb.points[1].lonlat, ll(-1000, 2000));
+
+        let l = &gb.linestrings[0];
+        assert!(l.refs.is_empty());
+        assert!(l.info.is_none());
+        assert_eq!(l.lonlats, vec![ll(0, 0), ll(-500, 700), ll(1500, -300)]);
+
+        let s = &gb.simple_polygons[0];
+        assert!(s.refs.is_empty());
+        assert_eq!(s.lonlats, vec![ll(0, 0), ll(0, 10), ll(10, 10), ll(0, 0)]);
+
+        let c = &gb.complicated_polygons[0];
+        assert!(c.info.is_none());
+        assert!(c.parts[0].exterior.parts.iter().all(|p| p.refs.is_empty()));
+        let orig = &test_block().complicated_polygons[0];
+        assert_eq!(c.parts[0].exterior.lonlats().unwrap(), orig.parts[0].exterior.lonlats().unwrap());
+    }
+
+    #[test]
+    fn round_trip_selected_info() {
+        let gb = round_trip(&GeometryPackOptions::from_list("version,user").unwrap());
+        let l = &gb.linestrings[0];
+        assert!(l.refs.is_empty());
+        let i = l.info.as_ref().unwrap();
+        assert_eq!((i.version, i.timestamp, i.changeset, i.user_id), (2, 0, 0, 99));
+        assert_eq!(i.user, "someone");
+    }
+
+    //collects blocks in the order they are received
+    struct CollectBlocks(Arc<std::sync::Mutex<Vec<GeometryBlock>>>);
+
+    impl CallFinish for CollectBlocks {
+        type CallType = GeometryBlock;
+        type ReturnType = Timings;
+        type ErrorType = Error;
+
+        fn call(&mut self, bl: GeometryBlock) {
+            self.0.lock().unwrap().push(bl);
+        }
+
+        fn finish(&mut self) -> ccResult<Timings, Error> {
+            Ok(Timings::new())
+        }
+    }
+
+    //one block of a single point per quadtree, written with a header block
+    fn write_test_file(name: &str, num_blocks: i64) -> String {
+        write_test_file_compressed(name, num_blocks, Compression::default())
+    }
+
+    fn write_test_file_compressed(name: &str, num_blocks: i64, compression: Compression) -> String {
+        let fname = std::env::temp_dir()
+            .join(format!("osmquadtree-geometry-{}-{}.pbf", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let bbox = Bbox::new(-1800000000, -900000000, 1800000000, 900000000);
+        let mut header = crate::GeometryHeader::new(bbox.clone(), None, None, None).unwrap();
+        header.compression = compression;
+        let mut out = crate::tempfile::prep_write_geometry_pbffile(&fname, &bbox, &header, 0).unwrap();
+        for i in 0..num_blocks {
+            let lon = (i as i32) * 100000000;
+            let q = Quadtree::calculate(&Bbox::new(lon, 0, lon + 1000, 1000), 18, 0.0);
+            let mut gb = GeometryBlock::new(i, q, 0);
+            let mut p = PointGeometry::empty();
+            p.id = i;
+            p.lonlat = ll(lon, 0);
+            p.quadtree = q;
+            gb.points.push(p);
+            out.call(gb);
+        }
+        out.finish().unwrap();
+        fname
+    }
+
+    fn read_ids(fname: &str, numchan: usize) -> Vec<(i64, i64)> {
+        let res = Arc::new(std::sync::Mutex::new(Vec::new()));
+        read_geometry_blocks(fname, Box::new(CollectBlocks(res.clone())), None, None, None, numchan).unwrap();
+        let bls = res.lock().unwrap();
+        bls.iter().map(|bl| (bl.index, bl.points[0].id)).collect()
+    }
+
+    #[test]
+    fn read_blocks_skips_header_in_order() {
+        let fname = write_test_file("read-ordered", 6);
+        let expected: Vec<(i64, i64)> = (0..6).map(|i| (i, i)).collect();
+        assert_eq!(read_ids(&fname, 0), expected);
+        assert_eq!(read_ids(&fname, 3), expected);
+
+        let (_, locs, _) = geometry_file_locs(&fname, None, None).unwrap();
+        assert_eq!(locs.len(), 6);
+        let _ = std::fs::remove_file(&fname);
+    }
+
+    #[test]
+    fn read_blocks_compressed() {
+        let expected: Vec<(i64, i64)> = (0..4).map(|i| (i, i)).collect();
+        for c in [Compression::Zstd(3), Compression::Lz4, Compression::None, Compression::Zlib(9)] {
+            let fname = write_test_file_compressed(&format!("read-{}", c.to_string().replace(':', "")), 4, c);
+            assert_eq!(read_ids(&fname, 0), expected, "{}", c.to_string());
+            assert_eq!(read_ids(&fname, 2), expected, "{}", c.to_string());
+            let _ = std::fs::remove_file(&fname);
+        }
+    }
+
+    #[test]
+    fn read_blocks_unordered() {
+        let fname = write_test_file("read-unordered", 6);
+        let res = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cbs: Vec<CallFinishGeometryBlock> = (0..3).map(|_| -> CallFinishGeometryBlock { Box::new(CollectBlocks(res.clone())) }).collect();
+        read_geometry_blocks_unordered(&fname, cbs, None, None, None).unwrap();
+        let mut ids: Vec<i64> = res.lock().unwrap().iter().map(|bl| bl.points[0].id).collect();
+        ids.sort();
+        assert_eq!(ids, (0..6).collect::<Vec<i64>>());
+        let _ = std::fs::remove_file(&fname);
+    }
+
+    #[test]
+    fn pack_options_list() {
+        assert_eq!(GeometryPackOptions::from_list("all").unwrap(), GeometryPackOptions::all());
+        assert_eq!(GeometryPackOptions::from_list("none").unwrap(), GeometryPackOptions::coordinates_only());
+        let o = GeometryPackOptions::from_list("refs,info").unwrap();
+        assert_eq!(o, GeometryPackOptions::all());
+        assert_eq!(GeometryPackOptions::from_list(&o.to_list()).unwrap(), o);
+        assert!(GeometryPackOptions::from_list("refs,colour").is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::mergechanges::read_filter;
+use osmquadtree::pbfformat::get_file_locs;
+use osmquadtree::utils::parse_timestamp;
+
+use crate::customstages::{CustomStage, CustomStages, StagePoint};
+use crate::process_geometry::process_geometry_call;
+use crate::validate::ValidateMode;
+use crate::{
+    Error, GeometryBlock, GeometryElement, GeometryFilter, GeometryStyle, MinZoomSpec, OtherData,
+    Result, Timings,
+};
+
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+const DEFAULT_CHANNEL_SIZE: usize = 16;
+
+//with a poly filter, objects outside the poly are dropped: the file
+//blocks read only cover its bbox
+struct SendBlocks {
+    sender: Option<SyncSender<GeometryBlock>>,
+    filter: GeometryFilter,
+    num_blocks: usize,
+}
+
+impl CallFinish for SendBlocks {
+    type CallType = GeometryBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    //once the receiver has been dropped the remaining blocks are discarded
+    fn call(&mut self, mut bl: GeometryBlock) {
+        if !self.filter.is_null() {
+            let filter = &self.filter;
+            bl.points.retain(|p| filter.check(p));
+            bl.linestrings.retain(|p| filter.check(p));
+            bl.simple_polygons.retain(|p| filter.check(p));
+            bl.complicated_polygons.retain(|p| filter.check(p));
+        }
+        if bl.points.is_empty()
+            && bl.linestrings.is_empty()
+            && bl.simple_polygons.is_empty()
+            && bl.complicated_polygons.is_empty()
+        {
+            return;
+        }
+        if let Some(s) = &self.sender {
+            if s.send(bl).is_err() {
+                self.sender = None;
+            } else {
+                self.num_blocks += 1;
+            }
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        self.sender = None;
+        let mut tms = Timings::new();
+        tms.add_other(
+            "SendBlocks",
+            OtherData::Messages(vec![format!("sent {} blocks", self.num_blocks)]),
+        );
+        Ok(tms)
+    }
+}
+
+//builds a process_geometry run whose output blocks are passed back to the
+//caller, as a channel or an iterator, rather than written out
+pub struct GeometryPipeline {
+    prfx: String,
+    filter: Option<String>,
+    timestamp: Option<String>,
+    style: Option<GeometryStyle>,
+    minzoom: Option<MinZoomSpec>,
+    validate: Option<ValidateMode>,
+    stages: Vec<(StagePoint, CustomStage)>,
+    numchan: usize,
+    channel_size: usize,
+}
+
+impl GeometryPipeline {
+    pub fn new(prfx: &str) -> GeometryPipeline {
+        GeometryPipeline {
+            prfx: String::from(prfx),
+            filter: None,
+            timestamp: None,
+            style: None,
+            minzoom: None,
+            validate: None,
+            stages: Vec::new(),
+            numchan: num_cpus::get(),
+            channel_size: DEFAULT_CHANNEL_SIZE,
+        }
+    }
+
+    //bbox as minlon,minlat,maxlon,maxlat or a poly filename
+    pub fn filter(mut self, filter: &str) -> GeometryPipeline {
+        self.filter = Some(String::from(filter));
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: &str) -> GeometryPipeline {
+        self.timestamp = Some(String::from(timestamp));
+        self
+    }
+
+    pub fn style(mut self, style: GeometryStyle) -> GeometryPipeline {
+        self.style = Some(style);
+        self
+    }
+
+    pub fn minzoom(mut self, minzoom: MinZoomSpec) -> GeometryPipeline {
+        self.minzoom = Some(minzoom);
+        self
+    }
+
+    pub fn validate(mut self, mode: ValidateMode) -> GeometryPipeline {
+        self.validate = Some(mode);
+        self
+    }
+
+    //stages are checked against their insertion point when the pipeline
+    //is started
+    pub fn stage(mut self, point: StagePoint, stage: CustomStage) -> GeometryPipeline {
+        self.stages.push((point, stage));
+        self
+    }
+
+    pub fn numchan(mut self, numchan: usize) -> GeometryPipeline {
+        self.numchan = numchan;
+        self
+    }
+
+    //number of blocks held before the processing threads wait for the
+    //receiver to catch up
+    pub fn channel_size(mut self, channel_size: usize) -> GeometryPipeline {
+        self.channel_size = channel_size;
+        self
+    }
+
+    //starts processing in a new thread. Blocks are sent as they are
+    //completed, in no particular order; the thread returns the timings
+    //once all have been sent, or the error which stopped processing.
+    pub fn spawn(self) -> Result<(Receiver<GeometryBlock>, JoinHandle<Result<Timings>>)> {
+        let (bbox, poly) = read_filter(self.filter.as_deref())?;
+        let filter = match poly {
+            Some(p) => GeometryFilter::Poly(p),
+            None => GeometryFilter::Null,
+        };
+        let timestamp = match &self.timestamp {
+            None => None,
+            Some(ts) => Some(parse_timestamp(ts)?),
+        };
+        let mut pfilelocs = get_file_locs(&self.prfx, Some(bbox), timestamp)?;
+        let style = Arc::new(self.style.unwrap_or_else(GeometryStyle::default));
+        let mut stages = CustomStages::new();
+        for (point, stage) in self.stages {
+            stages.add(point, stage)?;
+        }
+
+        let (sender, receiver) = sync_channel(self.channel_size);
+        let out = Box::new(SendBlocks { sender: Some(sender), filter: filter, num_blocks: 0 });
+
+        let minzoom = self.minzoom;
+        let validate = self.validate;
+        let numchan = self.numchan;
+        let handle = std::thread::spawn(move || {
+            process_geometry_call(&mut pfilelocs, Some(out), style, minzoom, numchan, validate, false, stages)
+        });
+        Ok((receiver, handle))
+    }
+
+    pub fn blocks(self) -> Result<GeometryBlocks> {
+        let (receiver, handle) = self.spawn()?;
+        Ok(GeometryBlocks { receiver: receiver, handle: Some(handle), timings: None })
+    }
+
+    pub fn elements(self) -> Result<impl Iterator<Item = Result<GeometryElement>>> {
+        Ok(self.blocks()?.flat_map(|bl| -> Box<dyn Iterator<Item = Result<GeometryElement>>> {
+            match bl {
+                Ok(bl) => Box::new(bl.into_iter().map(Ok)),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        }))
+    }
+}
+
+//blocking iterator over the output of a GeometryPipeline. A failure in
+//the processing threads is returned as the final item.
+pub struct GeometryBlocks {
+    receiver: Receiver<GeometryBlock>,
+    handle: Option<JoinHandle<Result<Timings>>>,
+    timings: Option<Timings>,
+}
+
+impl GeometryBlocks {
+    //only available once the iterator is exhausted
+    pub fn timings(&self) -> Option<&Timings> {
+        self.timings.as_ref()
+    }
+}
+
+impl Iterator for GeometryBlocks {
+    type Item = Result<GeometryBlock>;
+
+    fn next(&mut self) -> Option<Result<GeometryBlock>> {
+        match self.receiver.recv() {
+            Ok(bl) => Some(Ok(bl)),
+            Err(_) => match self.handle.take()?.join() {
+                Ok(Ok(tm)) => {
+                    self.timings = Some(tm);
+                    None
+                }
+                Ok(Err(e)) => Some(Err(e)),
+                Err(_) => Some(Err(Error::InvalidDataError(String::from(
+                    "geometry pipeline thread panicked",
+                )))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::customstages::GeometryBlockStage;
+    use osmquadtree::elements::{Changetype, Node, PrimitiveBlock, Quadtree, Tag};
+    use osmquadtree::pbfformat::{pack_file_block, CompressionType, HeaderType, WriteFile};
+
+    fn temp_name(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("osmquadtree-geometry-pipeline-{}-{}.pbf", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    //one block of three cafes and an untagged node
+    fn write_test_file(fname: &str) {
+        let mut pb = PrimitiveBlock::new(0, 0);
+        pb.quadtree = Quadtree::new(0);
+        for id in 1..5 {
+            let mut n = Node::new(id, Changetype::Normal);
+            n.lon = 10000000 * id as i32;
+            n.lat = 5000000 * id as i32;
+            n.quadtree = Quadtree::new(0);
+            if id < 4 {
+                n.tags.push(Tag::new(String::from("amenity"), String::from("cafe")));
+            }
+            pb.nodes.push(n);
+        }
+        let p = pb.pack(true, false).unwrap();
+        let q = pack_file_block("OSMData", &p, &CompressionType::Zlib).unwrap();
+
+        let mut wf = WriteFile::with_bbox(fname, HeaderType::NoLocs, None);
+        wf.call(vec![(0, q)]);
+        wf.finish().unwrap();
+    }
+
+    fn point_ids(elements: &[GeometryElement]) -> Vec<i64> {
+        let mut ids: Vec<i64> = elements
+            .iter()
+            .filter_map(|e| match e {
+                GeometryElement::Point(p) => Some(p.id),
+                _ => None,
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    struct FailStage;
+
+    impl GeometryBlockStage for FailStage {
+        fn name(&self) -> String {
+            String::from("FailStage")
+        }
+        fn process(&mut self, _gb: &mut GeometryBlock) -> Result<()> {
+            Err(Error::InvalidDataError(String::from("stage failed")))
+        }
+    }
+
+    #[test]
+    fn blocks_from_file() {
+        let fname = temp_name("blocks");
+        write_test_file(&fname);
+
+        let mut blocks = GeometryPipeline::new(&fname).numchan(0).blocks().unwrap();
+        let mut elements = Vec::new();
+        for bl in &mut blocks {
+            elements.extend(bl.unwrap());
+        }
+        assert!(blocks.timings().is_some());
+        assert_eq!(point_ids(&elements), [1, 2, 3]);
+
+        let _ = std::fs::remove_file(&fname);
+    }
+
+    #[test]
+    fn elements_from_file() {
+        let fname = temp_name("elements");
+        write_test_file(&fname);
+
+        let elements: Vec<GeometryElement> = GeometryPipeline::new(&fname)
+            .numchan(2)
+            .elements()
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(point_ids(&elements), [1, 2, 3]);
+
+        let _ = std::fs::remove_file(&fname);
+    }
+
+    #[test]
+    fn failing_stage_is_last_item() {
+        let fname = temp_name("failing");
+        write_test_file(&fname);
+
+        let items: Vec<Result<GeometryBlock>> = GeometryPipeline::new(&fname)
+            .numchan(0)
+            .stage(StagePoint::AfterMinZoom, CustomStage::Geometry(Box::new(FailStage)))
+            .blocks()
+            .unwrap()
+            .collect();
+        let (last, rest) = items.split_last().unwrap();
+        assert!(rest.iter().all(|bl| bl.is_ok()));
+        match last {
+            Err(e) => assert!(e.to_string().contains("stage failed")),
+            Ok(_) => panic!("expected the stage error"),
+        }
+
+        let _ = std::fs::remove_file(&fname);
+    }
+}
@@ -0,0 +1,361 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::elements::EARTH_WIDTH;
+use osmquadtree::utils::ThreadTimer;
+
+use crate::antimeridian::forward_offset;
+use crate::{
+    ComplicatedPolygonGeometry, Error, LonLat, OtherData, PolygonPart, SimplePolygonGeometry,
+    Timings, WorkingBlock, XY,
+};
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+//pole of inaccessibility, following https://github.com/mapbox/polylabel
+
+fn seg_dist_sq(px: f64, py: f64, a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (mut x, mut y) = a;
+    let mut dx = b.0 - x;
+    let mut dy = b.1 - y;
+
+    if dx != 0.0 || dy != 0.0 {
+        let t = ((px - x) * dx + (py - y) * dy) / (dx * dx + dy * dy);
+        if t > 1.0 {
+            x = b.0;
+            y = b.1;
+        } else if t > 0.0 {
+            x += dx * t;
+            y += dy * t;
+        }
+    }
+    dx = px - x;
+    dy = py - y;
+    dx * dx + dy * dy
+}
+
+//signed distance from point to polygon outline: positive if inside
+fn point_to_polygon_dist(x: f64, y: f64, rings: &[Vec<(f64, f64)>]) -> f64 {
+    let mut inside = false;
+    let mut min_dist_sq = f64::INFINITY;
+
+    for ring in rings {
+        if ring.len() < 2 {
+            continue;
+        }
+        let mut j = ring.len() - 1;
+        for i in 0..ring.len() {
+            let a = ring[i];
+            let b = ring[j];
+            if (a.1 > y) != (b.1 > y) && x < (b.0 - a.0) * (y - a.1) / (b.1 - a.1) + a.0 {
+                inside = !inside;
+            }
+            min_dist_sq = f64::min(min_dist_sq, seg_dist_sq(x, y, a, b));
+            j = i;
+        }
+    }
+    let d = f64::sqrt(min_dist_sq);
+    if inside {
+        d
+    } else {
+        -d
+    }
+}
+
+struct Cell {
+    x: f64,
+    y: f64,
+    h: f64,
+    d: f64,
+    max: f64,
+}
+
+impl Cell {
+    fn new(x: f64, y: f64, h: f64, rings: &[Vec<(f64, f64)>]) -> Cell {
+        let d = point_to_polygon_dist(x, y, rings);
+        Cell {
+            x: x,
+            y: y,
+            h: h,
+            d: d,
+            max: d + h * std::f64::consts::SQRT_2,
+        }
+    }
+}
+
+impl PartialEq for Cell {
+    fn eq(&self, other: &Cell) -> bool {
+        self.max == other.max
+    }
+}
+impl Eq for Cell {}
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Cell) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Cell {
+    fn cmp(&self, other: &Cell) -> Ordering {
+        self.max.partial_cmp(&other.max).unwrap_or(Ordering::Equal)
+    }
+}
+
+fn centroid_cell(rings: &[Vec<(f64, f64)>]) -> Cell {
+    let ring = &rings[0];
+    let mut area = 0.0;
+    let mut x = 0.0;
+    let mut y = 0.0;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        let a = ring[i];
+        let b = ring[j];
+        let f = a.0 * b.1 - b.0 * a.1;
+        x += (a.0 + b.0) * f;
+        y += (a.1 + b.1) * f;
+        area += f * 3.0;
+        j = i;
+    }
+    if area == 0.0 {
+        return Cell::new(ring[0].0, ring[0].1, 0.0, rings);
+    }
+    Cell::new(x / area, y / area, 0.0, rings)
+}
+
+//rings are the exterior followed by any holes. Returns the label point and its distance from
+//the polygon outline. precision must be positive, or cells are split forever.
+pub fn polylabel(rings: &[Vec<(f64, f64)>], precision: f64) -> ((f64, f64), f64) {
+    if rings.is_empty() || rings[0].is_empty() {
+        return ((0.0, 0.0), 0.0);
+    }
+    let (mut minx, mut miny) = (f64::INFINITY, f64::INFINITY);
+    let (mut maxx, mut maxy) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for p in &rings[0] {
+        minx = f64::min(minx, p.0);
+        miny = f64::min(miny, p.1);
+        maxx = f64::max(maxx, p.0);
+        maxy = f64::max(maxy, p.1);
+    }
+    let width = maxx - minx;
+    let height = maxy - miny;
+    let cell_size = f64::min(width, height);
+    if cell_size == 0.0 {
+        return ((minx, miny), 0.0);
+    }
+    let mut h = cell_size / 2.0;
+
+    let mut queue = BinaryHeap::new();
+    let mut x = minx;
+    while x < maxx {
+        let mut y = miny;
+        while y < maxy {
+            queue.push(Cell::new(x + h, y + h, h, rings));
+            y += cell_size;
+        }
+        x += cell_size;
+    }
+
+    let mut best = centroid_cell(rings);
+    let bbox_cell = Cell::new(minx + width / 2.0, miny + height / 2.0, 0.0, rings);
+    if bbox_cell.d > best.d {
+        best = bbox_cell;
+    }
+
+    while let Some(cell) = queue.pop() {
+        if cell.d > best.d {
+            best = Cell::new(cell.x, cell.y, 0.0, rings);
+        }
+        if cell.max - best.d <= precision {
+            continue;
+        }
+        h = cell.h / 2.0;
+        queue.push(Cell::new(cell.x - h, cell.y - h, h, rings));
+        queue.push(Cell::new(cell.x + h, cell.y - h, h, rings));
+        queue.push(Cell::new(cell.x - h, cell.y + h, h, rings));
+        queue.push(Cell::new(cell.x + h, cell.y + h, h, rings));
+    }
+    ((best.x, best.y), best.d)
+}
+
+//rings crossing the antimeridian are unwrapped, so x may run past EARTH_WIDTH. Holes pass the
+//exterior's first point as reference, so they are unwrapped onto the same side.
+fn to_xy_ring<'a, T: Iterator<Item = &'a LonLat>>(
+    iter: T,
+    reference: Option<&'a LonLat>,
+) -> Vec<(f64, f64)> {
+    let mut res = Vec::new();
+    let mut prev = reference;
+    let mut offset = 0.0;
+    for l in iter {
+        if let Some(p) = prev {
+            offset += forward_offset(p, l);
+        }
+        let xy = l.forward();
+        res.push((xy.x + offset, xy.y));
+        prev = Some(l);
+    }
+    res
+}
+
+//the label point of an unwrapped ring is moved back into the world
+fn from_xy(p: (f64, f64)) -> LonLat {
+    let mut x = p.0;
+    if x > EARTH_WIDTH {
+        x -= 2.0 * EARTH_WIDTH;
+    } else if x < -EARTH_WIDTH {
+        x += 2.0 * EARTH_WIDTH;
+    }
+    LonLat::backward(&XY::from((x, p.1)))
+}
+
+//precision is in (mercator) metres
+pub fn simple_polygon_label_point(sp: &SimplePolygonGeometry, precision: f64) -> Option<LonLat> {
+    if sp.lonlats.len() < 4 {
+        return None;
+    }
+    let rings = vec![to_xy_ring(sp.lonlats.iter(), None)];
+    let (p, _) = polylabel(&rings, precision);
+    Some(from_xy(p))
+}
+
+fn polygon_part_label_point(part: &PolygonPart, precision: f64) -> ((f64, f64), f64) {
+    let reference = part.exterior.lonlats_iter().next();
+    let mut rings = vec![to_xy_ring(part.exterior.lonlats_iter(), None)];
+    for ii in &part.interiors {
+        rings.push(to_xy_ring(ii.lonlats_iter(), reference));
+    }
+    polylabel(&rings, precision)
+}
+
+//the label is placed in whichever part has the most distant pole of inaccessibility
+pub fn complicated_polygon_label_point(
+    cp: &ComplicatedPolygonGeometry,
+    precision: f64,
+) -> Option<LonLat> {
+    let mut best: Option<((f64, f64), f64)> = None;
+    for p in &cp.parts {
+        if p.exterior.parts.is_empty() {
+            continue;
+        }
+        let (q, d) = polygon_part_label_point(p, precision);
+        match &best {
+            Some((_, bd)) if *bd >= d => {}
+            _ => {
+                best = Some((q, d));
+            }
+        }
+    }
+    best.map(|(q, _)| from_xy(q))
+}
+
+pub struct FindLabelPoints<T: ?Sized> {
+    out: Box<T>,
+    precision: f64,
+    tm: f64,
+    count: usize,
+}
+
+impl<T> FindLabelPoints<T>
+where
+    T: CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error> + ?Sized,
+{
+    pub fn new(out: Box<T>, precision: f64) -> FindLabelPoints<T> {
+        FindLabelPoints {
+            out: out,
+            precision: precision,
+            tm: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl<T> CallFinish for FindLabelPoints<T>
+where
+    T: CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error> + ?Sized,
+{
+    type CallType = WorkingBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, mut bl: WorkingBlock) {
+        let tx = ThreadTimer::new();
+        for sp in bl.geometry_block.simple_polygons.iter_mut() {
+            sp.label_point = simple_polygon_label_point(sp, self.precision);
+            self.count += 1;
+        }
+        for cp in bl.geometry_block.complicated_polygons.iter_mut() {
+            cp.label_point = complicated_polygon_label_point(cp, self.precision);
+            self.count += 1;
+        }
+        self.tm += tx.since();
+        self.out.call(bl);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = self.out.finish()?;
+        tms.add("FindLabelPoints", self.tm);
+        tms.add_other(
+            "FindLabelPoints",
+            OtherData::Messages(vec![format!(
+                "found {} label points, precision {}m",
+                self.count, self.precision
+            )]),
+        );
+        Ok(tms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //1e-7 degree units
+    fn ll(lon: f64, lat: f64) -> LonLat {
+        LonLat::new((lon * 1e7).round() as i32, (lat * 1e7).round() as i32)
+    }
+
+    fn square(x0: f64, y0: f64, x1: f64, y1: f64) -> Vec<(f64, f64)> {
+        vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)]
+    }
+
+    #[test]
+    fn square_label_at_centre() {
+        let ((x, y), d) = polylabel(&[square(0.0, 0.0, 10.0, 10.0)], 0.01);
+        assert!((x - 5.0).abs() < 0.01);
+        assert!((y - 5.0).abs() < 0.01);
+        assert!((d - 5.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn label_avoids_hole() {
+        let rings = [square(0.0, 0.0, 10.0, 10.0), square(1.0, 1.0, 5.0, 9.0)];
+        let ((x, _), d) = polylabel(&rings, 0.01);
+        assert!((x - 7.5).abs() < 0.1);
+        assert!((d - 2.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn ring_unwrapped_across_antimeridian() {
+        let ring = [ll(179.0, -1.0), ll(-178.0, -1.0), ll(-178.0, 1.0), ll(179.0, 1.0), ll(179.0, -1.0)];
+        let xy = to_xy_ring(ring.iter(), None);
+        assert!(xy[1].0 > EARTH_WIDTH);
+        assert!(xy[2].0 > EARTH_WIDTH);
+        assert!(xy[3].0 < EARTH_WIDTH);
+        assert_eq!(xy[0], xy[4]);
+    }
+
+    #[test]
+    fn hole_unwrapped_onto_exterior_side() {
+        let exterior = [ll(179.0, -1.0), ll(-178.0, -1.0), ll(-178.0, 1.0), ll(179.0, 1.0), ll(179.0, -1.0)];
+        let hole = [ll(-179.5, -0.5), ll(-179.5, 0.5), ll(-178.5, 0.5), ll(-178.5, -0.5), ll(-179.5, -0.5)];
+        let xy = to_xy_ring(hole.iter(), exterior.first());
+        assert!(xy.iter().all(|p| p.0 > EARTH_WIDTH));
+    }
+
+    #[test]
+    fn antimeridian_label_on_correct_side() {
+        let ring = [ll(179.0, -1.5), ll(-178.0, -1.5), ll(-178.0, 1.5), ll(179.0, 1.5), ll(179.0, -1.5)];
+        let (p, _) = polylabel(&[to_xy_ring(ring.iter(), None)], 1.0);
+        let l = from_xy(p);
+        assert!((l.lon - ll(-179.5, 0.0).lon).abs() < 100000);
+        assert!(l.lat.abs() < 100000);
+    }
+}
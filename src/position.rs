@@ -5,6 +5,7 @@ use osmquadtree::elements::{
 
 //pub use geo::Coordinate;
 
+use crate::antimeridian::forward_offset;
 use std::borrow::Borrow;
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
@@ -122,8 +123,11 @@ pub fn calc_line_length<T: Borrow<LonLat>>(lonlats: &[T]) -> f64 {
 
     let mut ans = 0.0;
     let mut prev = lonlats[0].borrow().forward();
+    let mut offset = 0.0;
     for i in 1..lonlats.len() {
-        let curr = lonlats[i].borrow().forward();
+        let mut curr = lonlats[i].borrow().forward();
+        offset += forward_offset(lonlats[i - 1].borrow(), lonlats[i].borrow());
+        curr.x += offset;
         ans += pythag(&prev, &curr);
         prev = curr;
     }
@@ -138,9 +142,11 @@ pub fn calc_ring_area<T: Borrow<LonLat>>(lonlats: &[T]) -> f64 {
     let mut area = 0.0;
 
     let mut prev = lonlats[0].borrow().forward();
-
+    let mut offset = 0.0;
     for i in 1..lonlats.len() {
-        let curr = lonlats[i].borrow().forward();
+        let mut curr = lonlats[i].borrow().forward();
+        offset += forward_offset(lonlats[i - 1].borrow(), lonlats[i].borrow());
+        curr.x += offset;
         area += prev.x * curr.y - prev.y * curr.x;
         prev = curr
     }
@@ -160,11 +166,13 @@ pub fn calc_ring_area_and_bbox<T: Borrow<LonLat>>(lonlats: &[T]) -> (f64, Bbox)
     let l = lonlats[0].borrow();
     bbox.expand(l.lon, l.lat);
     let mut prev = l.forward();
-
+    let mut offset = 0.0;
     for i in 1..lonlats.len() {
         let l = lonlats[i].borrow();
         bbox.expand(l.lon, l.lat);
-        let curr = l.forward();
+        let mut curr = l.forward();
+        offset += forward_offset(lonlats[i - 1].borrow(), l);
+        curr.x += offset;
         area += prev.x * curr.y - prev.y * curr.x;
 
         prev = curr
@@ -208,19 +216,38 @@ pub fn calc_ring_centroid<T: Borrow<LonLat>>(lonlats: &[T]) -> XY {
     res
 }
 
-fn segment_side(p1: &LonLat, p2: &LonLat, q: &LonLat) -> i32 {
-    let s = (q.lon as f64 - p1.lon as f64) * (p2.lat as f64 - p1.lat as f64)
-        - (p2.lon as f64 - p1.lon as f64) * (q.lat as f64 - p1.lat as f64);
+//exact orientation of q relative to the line p1->p2: 1 to the left, -1 to the right, 0 if collinear
+pub fn orient(p1: &LonLat, p2: &LonLat, q: &LonLat) -> i32 {
+    let s = (p2.lon as i128 - p1.lon as i128) * (q.lat as i128 - p1.lat as i128)
+        - (p2.lat as i128 - p1.lat as i128) * (q.lon as i128 - p1.lon as i128);
 
-    if s < 0.0 {
+    if s < 0 {
         -1
-    } else if s > 0.0 {
+    } else if s > 0 {
         1
     } else {
         0
     }
 }
 
+//true if q is on the segment p1->p2, excluding the end points
+pub fn on_segment_interior(p1: &LonLat, p2: &LonLat, q: &LonLat) -> bool {
+    if q == p1 || q == p2 {
+        return false;
+    }
+    if orient(p1, p2, q) != 0 {
+        return false;
+    }
+    i32::min(p1.lon, p2.lon) <= q.lon
+        && q.lon <= i32::max(p1.lon, p2.lon)
+        && i32::min(p1.lat, p2.lat) <= q.lat
+        && q.lat <= i32::max(p1.lat, p2.lat)
+}
+
+fn segment_side(p1: &LonLat, p2: &LonLat, q: &LonLat) -> i32 {
+    orient(p1, p2, q)
+}
+
 fn asbox(p1: &LonLat, p2: &LonLat) -> Bbox {
     Bbox::new(
         i32::min(p1.lon, p2.lon),
@@ -231,7 +258,7 @@ fn asbox(p1: &LonLat, p2: &LonLat) -> Bbox {
 }
 
 pub fn segment_intersects(p1: &LonLat, p2: &LonLat, q1: &LonLat, q2: &LonLat) -> bool {
-    if !asbox(p1, p2).overlaps(&asbox(p1, p2)) {
+    if !asbox(p1, p2).overlaps(&asbox(q1, q2)) {
         return false;
     }
     let pq1 = segment_side(p1, p2, q1);
@@ -325,57 +352,34 @@ pub fn point_in_poly<T: Borrow<LonLat>>(line: &[T], pt: &LonLat) -> bool {
     c
 }
 
-fn check_seg(test: &XY, aa: &XY, bb: &XY) -> bool {
-    if (aa.y > test.y) != (bb.y > test.y) {
-        if test.x < (bb.x - aa.x) * (test.y - aa.y) / (bb.y - aa.y) + aa.x {
-            return true;
-        }
-    }
-    false
-}
-//crate::geometry::elements::PolygonPart
-
-
-pub fn point_in_poly_iter<'a, T: Iterator<Item = &'a LonLat>>(
-    mut line: T,
-    pt: &LonLat,
-) -> bool {
-    //let mut line = ring.exterior.lonlats_iter();
-
-    let mut prev: XY;
-    match line.next() {
-        None => {
-            return false;
+//exact winding number test: 1 if pt is inside ring, 0 if on the boundary, -1 if outside
+pub fn point_in_ring_exact<T: Borrow<LonLat>>(ring: &[T], pt: &LonLat) -> i32 {
+    let mut wn = 0;
+    for i in 1..ring.len() {
+        let a = ring[i - 1].borrow();
+        let b = ring[i].borrow();
+        if pt == a {
+            return 0;
         }
-        Some(f) => {
-            prev = f.as_xy();
+        let o = orient(a, b, pt);
+        if o == 0 && on_segment_interior(a, b, pt) {
+            return 0;
         }
-    }
-    let test = pt.as_xy();
-
-    let mut c = false;
-
-    loop {
-        match line.next() {
-            None => {
-                break;
-            }
-            Some(p) => {
-                let curr = p.as_xy();
-                if check_seg(&test, &prev, &curr) {
-                    c = !c;
-                }
-                prev = curr;
+        if a.lat <= pt.lat {
+            if b.lat > pt.lat && o > 0 {
+                wn += 1;
             }
+        } else if b.lat <= pt.lat && o < 0 {
+            wn -= 1;
         }
     }
-    /*don't need: always first == last
-    if check_seg(&test, &prev, &first) {
-        c = !c;
+    if wn != 0 {
+        1
+    } else {
+        -1
     }
-    */
-    c
 }
+
 /*
 pub fn point_in_poly_xy(line: &geo::LineString<f64>, test: &XY) -> bool {
     //let mut line = ring.exterior.lonlats_iter();
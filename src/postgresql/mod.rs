@@ -1,3 +1,4 @@
+mod osm2pgsqlstyle;
 mod postgresqloptions;
 mod prepcopy;
 mod tablespec;
@@ -10,8 +11,10 @@ pub use crate::postgresql::postgresqloptions::{
 };
 pub use crate::postgresql::prepcopy::{pack_geometry_block, GeometryType, PrepTable};
 pub use crate::postgresql::tablespec::{
-    make_table_spec, prepare_tables, ColumnSource, ColumnType, TableSpec,
+    add_localized_name_columns, make_table_spec, make_table_spec_from_columns, prepare_tables, read_table_spec,
+    write_table_spec, ColumnSource, ColumnType, TableSpec,
 };
+pub use crate::postgresql::osm2pgsqlstyle::import_osm2pgsql_style;
 pub use crate::postgresql::writepostgresql::make_write_postgresql_geometry;
 
 //mod altconnection;
@@ -0,0 +1,278 @@
+use crate::postgresql::tablespec::{make_table_spec_from_columns, TableSpec};
+use crate::style::PolyTagSpec;
+use crate::{is_key_pattern, Error, GeometryStyle, Result};
+
+use osmquadtree::message;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+//filled in by the table spec itself, rather than from tags
+const SPECIAL_KEYS: [&str; 3] = ["z_order", "way_area", "layer"];
+
+struct StyleLine {
+    node: bool,
+    way: bool,
+    key: String,
+    data_type: String,
+    flags: BTreeSet<String>,
+}
+
+fn parse_line(line: &str, lineno: usize) -> Result<Option<StyleLine>> {
+    let line = match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.is_empty() {
+        return Ok(None);
+    }
+    if parts.len() < 3 || parts.len() > 4 {
+        return Err(Error::InvalidDataError(format!(
+            "line {}: expected OSMTYPE TAG DATATYPE [FLAGS], not {}",
+            lineno, line
+        )));
+    }
+    let mut res = StyleLine {
+        node: false,
+        way: false,
+        key: String::from(parts[1]),
+        data_type: String::from(parts[2]),
+        flags: BTreeSet::new(),
+    };
+    for t in parts[0].split(',') {
+        match t {
+            "node" => { res.node = true; },
+            "way" => { res.way = true; },
+            _ => {
+                return Err(Error::InvalidDataError(format!("line {}: unknown osm type {}", lineno, t)));
+            }
+        }
+    }
+    if parts.len() == 4 {
+        for f in parts[3].split(',') {
+            res.flags.insert(String::from(f));
+        }
+    }
+    Ok(Some(res))
+}
+
+//keys with the polygon or linear flag are feature keys, polygon keys are
+//always polygons when closed, delete keys are dropped and all other keys
+//are kept, as tag columns unless flagged nocolumn. Everything else is taken
+//from the default style.
+pub fn import_osm2pgsql_style(fname: &str, extended: bool) -> Result<(GeometryStyle, Vec<TableSpec>)> {
+    let lines = std::fs::read_to_string(fname)?;
+
+    let mut feature_keys = BTreeSet::new();
+    let mut other_keys = BTreeSet::new();
+    let mut polygon_tags = BTreeMap::new();
+    let mut drop_keys = BTreeSet::new();
+    let mut point_tag_cols = Vec::new();
+    let mut line_tag_cols = Vec::new();
+
+    for (i, l) in lines.lines().enumerate() {
+        let sl = match parse_line(l, i + 1)? {
+            None => { continue; },
+            Some(sl) => sl,
+        };
+        if SPECIAL_KEYS.contains(&sl.key.as_str()) {
+            continue;
+        }
+        for f in &sl.flags {
+            match f.as_str() {
+                "polygon" | "linear" | "nocolumn" | "delete" => {}
+                "phstore" | "nocache" => {
+                    message!("{}: flag {} for {} ignored", fname, f, sl.key);
+                }
+                _ => {
+                    return Err(Error::InvalidDataError(format!("line {}: unknown flag {}", i + 1, f)));
+                }
+            }
+        }
+
+        if sl.flags.contains("delete") {
+            drop_keys.insert(sl.key.clone());
+            continue;
+        }
+        if sl.flags.contains("polygon") {
+            feature_keys.insert(sl.key.clone());
+            polygon_tags.insert(sl.key.clone(), PolyTagSpec::All);
+        } else if sl.flags.contains("linear") {
+            feature_keys.insert(sl.key.clone());
+        } else {
+            other_keys.insert(sl.key.clone());
+        }
+
+        if sl.flags.contains("nocolumn") || is_key_pattern(&sl.key) {
+            continue;
+        }
+        if sl.data_type != "text" {
+            message!("{}: column {} will be text, not {}", fname, sl.key, sl.data_type);
+        }
+        if sl.node {
+            point_tag_cols.push(sl.key.clone());
+        }
+        if sl.way {
+            line_tag_cols.push(sl.key.clone());
+        }
+    }
+    if feature_keys.is_empty() {
+        return Err(Error::InvalidDataError(format!("{} has no polygon or linear keys", fname)));
+    }
+
+    let mut style = GeometryStyle::default();
+    style.set_feature_keys(feature_keys)?;
+    style.set_other_keys(Some(other_keys))?;
+    style.set_polygon_tags(polygon_tags)?;
+    style.set_drop_keys(drop_keys)?;
+    style.all_objs = false;
+
+    point_tag_cols.sort();
+    point_tag_cols.dedup();
+    line_tag_cols.sort();
+    line_tag_cols.dedup();
+    let poly_tag_cols = line_tag_cols.clone();
+    for (l, _) in &style.parent_tags {
+        point_tag_cols.push(l.clone());
+    }
+    for l in &style.relation_tag_spec {
+        line_tag_cols.push(l.target_key.clone());
+    }
+
+    let table_spec = make_table_spec_from_columns(&point_tag_cols, &line_tag_cols, &poly_tag_cols, extended);
+    Ok((style, table_spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgresql::tablespec::ColumnSource;
+
+    const TEST_STYLE: &str = "# osm2pgsql style
+node,way   building     text   polygon
+way        highway      text   linear
+node,way   name         text
+node,way   name:*       text
+node       population   int4
+way        tracktype    text   nocolumn
+node,way   note         text   delete
+node,way   source:*     text   delete
+node,way   z_order      int4   linear
+way        way_area     real   linear,nocache
+";
+
+    fn write_style(name: &str, contents: &str) -> String {
+        let fname = std::env::temp_dir()
+            .join(format!("osmquadtree-geometry-{}-{}.style", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::write(&fname, contents).unwrap();
+        fname
+    }
+
+    fn import(name: &str, contents: &str, extended: bool) -> Result<(GeometryStyle, Vec<TableSpec>)> {
+        let fname = write_style(name, contents);
+        let res = import_osm2pgsql_style(&fname, extended);
+        let _ = std::fs::remove_file(&fname);
+        res
+    }
+
+    fn keys(kk: &[&str]) -> BTreeSet<String> {
+        kk.iter().map(|k| k.to_string()).collect()
+    }
+
+    fn tag_columns(spec: &TableSpec) -> Vec<String> {
+        spec.columns
+            .iter()
+            .filter(|(_, s, _)| *s == ColumnSource::Tag)
+            .map(|(n, _, _)| n.clone())
+            .collect()
+    }
+
+    #[test]
+    fn parse_style_lines() {
+        assert!(parse_line("", 1).unwrap().is_none());
+        assert!(parse_line("   # a comment", 1).unwrap().is_none());
+
+        let sl = parse_line("node,way  name  text  linear,nocolumn # trailing comment", 1).unwrap().unwrap();
+        assert!(sl.node && sl.way);
+        assert_eq!(sl.key, "name");
+        assert_eq!(sl.data_type, "text");
+        assert_eq!(sl.flags, keys(&["linear", "nocolumn"]));
+
+        let sl = parse_line("way highway text", 1).unwrap().unwrap();
+        assert!(!sl.node && sl.way);
+        assert!(sl.flags.is_empty());
+    }
+
+    #[test]
+    fn parse_line_errors() {
+        for l in &["node name", "node name text polygon extra", "relation name text", "node,area name text"] {
+            match parse_line(l, 7) {
+                Err(Error::InvalidDataError(m)) => assert!(m.starts_with("line 7: "), "{}", m),
+                _ => panic!("expected error for {}", l),
+            }
+        }
+    }
+
+    #[test]
+    fn import_flags() {
+        let (style, _) = import("flags", TEST_STYLE, false).unwrap();
+        //polygon and linear keys are feature keys, only polygon keys are polygon tags
+        assert_eq!(style.feature_keys(), &keys(&["building", "highway"]));
+        assert_eq!(style.polygon_tags().len(), 1);
+        assert!(matches!(style.polygon_tags().get("building"), Some(PolyTagSpec::All)));
+        //other keys are kept, whether or not they have a column
+        assert_eq!(style.other_keys(), Some(&keys(&["name", "name:*", "population", "tracktype"])));
+        //delete keys, including wildcards, are dropped
+        assert_eq!(style.drop_keys(), &keys(&["note", "source:*"]));
+        assert!(!style.all_objs);
+    }
+
+    #[test]
+    fn import_skips_special_keys() {
+        let (style, table_spec) = import("special", TEST_STYLE, false).unwrap();
+        for k in &SPECIAL_KEYS {
+            assert!(!style.feature_keys().contains(*k));
+            assert!(!style.other_keys().unwrap().contains(*k));
+            for t in &table_spec {
+                assert!(!tag_columns(t).contains(&k.to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn import_table_spec_columns() {
+        let (style, table_spec) = import("columns", TEST_STYLE, false).unwrap();
+        let names: Vec<&str> = table_spec.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["point", "line", "polygon"]);
+
+        //nocolumn, delete and wildcard keys have no column
+        let mut point = vec![String::from("building"), String::from("name"), String::from("population")];
+        point.extend(style.parent_tags.iter().map(|(l, _)| l.clone()));
+        assert_eq!(tag_columns(&table_spec[0]), point);
+
+        let mut line = vec![String::from("building"), String::from("highway"), String::from("name")];
+        line.extend(style.relation_tag_spec.iter().map(|l| l.target_key.clone()));
+        assert_eq!(tag_columns(&table_spec[1]), line);
+
+        assert_eq!(tag_columns(&table_spec[2]), vec!["building", "highway", "name"]);
+
+        let (_, table_spec) = import("columns-extended", TEST_STYLE, true).unwrap();
+        let names: Vec<&str> = table_spec.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["point", "line", "polygon", "highway", "building", "boundary"]);
+    }
+
+    #[test]
+    fn import_errors() {
+        match import("unknown-flag", "node,way building text polygon,unknown\n", false) {
+            Err(Error::InvalidDataError(m)) => assert_eq!(m, "line 1: unknown flag unknown"),
+            _ => panic!("expected unknown flag error"),
+        }
+        match import("no-features", "node,way name text\n", false) {
+            Err(Error::InvalidDataError(m)) => assert!(m.ends_with("has no polygon or linear keys"), "{}", m),
+            _ => panic!("expected no feature keys error"),
+        }
+        assert!(import("bad-line", "node,way building\n", false).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use osmquadtree::elements::Tag;
 use crate::postgresql::GeometryType;
 use crate::postgresql::{make_table_spec, TableSpec};
-use crate::GeometryStyle;
+use crate::{Compression, GeometryStyle};
 use std::sync::Arc;
 pub enum PostgresqlConnection {
     Null,
@@ -18,7 +18,8 @@ pub struct PostgresqlOptions {
     pub table_spec: Vec<TableSpec>,
     pub extended: bool,
     pub planet_osm_views: bool,
-    pub lowzoom: Option<Vec<(String,i64,bool)>>
+    pub lowzoom: Option<Vec<(String,i64,bool)>>,
+    pub compression: Compression,
 }
 
 impl PostgresqlOptions {
@@ -29,7 +30,8 @@ impl PostgresqlOptions {
             table_spec: make_table_spec(style, false),
             extended: false,
             planet_osm_views: false,
-            lowzoom: None
+            lowzoom: None,
+            compression: Compression::default(),
         }
     }
 
@@ -40,7 +42,8 @@ impl PostgresqlOptions {
             table_spec: make_table_spec(style, true),
             extended: true,
             planet_osm_views: false,
-            lowzoom: None
+            lowzoom: None,
+            compression: Compression::default(),
         }
     }
 
@@ -57,7 +60,8 @@ impl PostgresqlOptions {
             table_spec: table_spec,
             extended: false,
             planet_osm_views: false,
-            lowzoom: None
+            lowzoom: None,
+            compression: Compression::default(),
         }
     }
 }
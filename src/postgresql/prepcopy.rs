@@ -1,8 +1,9 @@
 use osmquadtree::elements::{Quadtree, Tag, WithId, WithQuadtree, WithTags};
 use crate::postgresql::{ColumnSource, ColumnType, GeosGeometry, TableSpec};
 
+use crate::wkb::{prep_wkb, write_point};
 use crate::{
-    ComplicatedPolygonGeometry, GeometryBlock, LinestringGeometry, PointGeometry,
+    crosses_antimeridian, resolve_localized_name, ComplicatedPolygonGeometry, GeometryBlock, LinestringGeometry, LonLat, PointGeometry,
     SimplePolygonGeometry,
 };
 
@@ -22,6 +23,7 @@ pub struct PrepTable {
 
     tag_cols: BTreeMap<String, usize>,
     other_tags_col: Option<usize>,
+    localized_name_cols: Vec<(usize, String, Vec<String>, bool)>,
 
     minzoom_col: Option<usize>,
     layer_col: Option<usize>,
@@ -31,6 +33,7 @@ pub struct PrepTable {
     geometry_col: Option<(ColumnType, usize)>,
     representative_point_geometry_col: Option<usize>,
     boundary_line_geometry_col: Option<usize>,
+    label_point_col: Option<usize>,
 
     num_cols: usize,
     validate_geometry: bool,
@@ -77,6 +80,12 @@ fn write_f64_be<W: Write>(w: &mut W, f: f64) -> Result<()> {
 }
 
 
+fn label_point_wkb(l: &LonLat) -> Result<Vec<u8>> {
+    let mut res = prep_wkb(true, true, 1, 16)?;
+    write_point(&mut res, &l.to_xy(true))?;
+    Ok(res)
+}
+
 fn check_type(
     i: usize,
     n: &str,
@@ -136,6 +145,7 @@ impl PrepTable {
             block_quadtree_col: None,
             tag_cols: BTreeMap::new(),
             other_tags_col: None,
+            localized_name_cols: Vec::new(),
             minzoom_col: None,
             layer_col: None,
             z_order_col: None,
@@ -144,6 +154,7 @@ impl PrepTable {
             geometry_col: None,
             representative_point_geometry_col: None,
             boundary_line_geometry_col: None,
+            label_point_col: None,
             num_cols: 0,
             validate_geometry: false,
         }
@@ -177,6 +188,10 @@ impl PrepTable {
                     pt.tag_cols.insert(n.clone(), i);
                     //pt.null_row.push(Box::new(&None: Option<String>));
                 }
+                ColumnSource::LocalizedName { lang, fallback, transliterate } => {
+                    check_type(i, n, src, typ, &ColumnType::Text)?;
+                    pt.localized_name_cols.push((i, lang.clone(), fallback.clone(), *transliterate));
+                }
                 ColumnSource::OtherTags => {
                     check_type(i, n, src, typ, &ColumnType::Hstore)?;
                     pt.other_tags_col = Some(i);
@@ -230,6 +245,10 @@ impl PrepTable {
                     pt.boundary_line_geometry_col = Some(i);
                     //pt.null_row.push(Box::new(None: Option<postgis::ewkb::Geometry>));
                 }
+                ColumnSource::LabelPoint => {
+                    check_type(i, n, src, typ, &ColumnType::PointGeometry)?;
+                    pt.label_point_col = Some(i);
+                }
             }
         }
         Ok(pt)
@@ -285,6 +304,11 @@ impl PrepTable {
                 res[i] = CopyValue::HStore(other_tags);
             }
         }
+        for (i, lang, fallback, transliterate) in &self.localized_name_cols {
+            if let Some(v) = resolve_localized_name(lang, fallback, *transliterate, o.get_tags()) {
+                res[*i] = CopyValue::Text(v);
+            }
+        }
 
         Ok(res)
     }
@@ -366,14 +390,25 @@ impl PrepTable {
         match &self.geometry_col {
             None => {}
             Some((typ, i)) => {
-                if *typ != ColumnType::LineGeometry {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("{:?} wrong type for LinestringGeometry", typ),
-                    ));
+                let crosses = crosses_antimeridian(&pg.lonlats);
+                match typ {
+                    ColumnType::Geometry => {}
+                    ColumnType::LineGeometry if !crosses => {}
+                    ColumnType::LineGeometry => {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            format!("linestring {} crosses the antimeridian, so needs a table spec with a Geometry way column", pg.id),
+                        ));
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            format!("{:?} wrong type for LinestringGeometry", typ),
+                        ));
+                    }
                 }
 
-                if self.validate_geometry {
+                if self.validate_geometry && !crosses {
                     let geos = GeosGeometry::from_linestring(pg)?;
 
                     if !geos.is_valid() {
@@ -600,6 +635,12 @@ impl PrepTable {
                 res[i] = CopyValue::Double(pg.area);
             }
         }
+        match (self.label_point_col, &pg.label_point) {
+            (Some(i), Some(l)) => {
+                res[i] = CopyValue::Wkb(label_point_wkb(l)?);
+            }
+            _ => {}
+        }
         match self.minzoom_col {
             None => {}
             Some(i) => match &pg.minzoom {
@@ -671,6 +712,12 @@ impl PrepTable {
                 res[i] = CopyValue::Double(pg.area);
             }
         }
+        match (self.label_point_col, &pg.label_point) {
+            (Some(i), Some(l)) => {
+                res[i] = CopyValue::Wkb(label_point_wkb(l)?);
+            }
+            _ => {}
+        }
         match self.minzoom_col {
             None => {}
             Some(i) => match &pg.minzoom {
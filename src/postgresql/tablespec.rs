@@ -1,4 +1,4 @@
-use crate::GeometryStyle;
+use crate::{is_key_pattern, localized_name_key, GeometryStyle, LocalizedNameSpec};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::io::{Error,ErrorKind};
@@ -46,7 +46,7 @@ fn is_geom_columntype(ct: &ColumnType) -> bool {
     }
 }
 
-#[derive(Eq, PartialEq, Debug, Clone, Serialize)]
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum ColumnSource {
     OsmId,
     //Part,
@@ -62,9 +62,15 @@ pub enum ColumnSource {
     Geometry,
     RepresentativePointGeometry,
     BoundaryLineGeometry,
+    LabelPoint,
+    LocalizedName {
+        lang: String,
+        fallback: Vec<String>,
+        transliterate: bool,
+    },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TableSpec {
     pub name: String,
     pub columns: Vec<(String, ColumnSource, ColumnType)>,
@@ -78,6 +84,16 @@ impl TableSpec {
     }
 }
 
+pub fn read_table_spec(fname: &str) -> std::io::Result<Vec<TableSpec>> {
+    let f = std::io::BufReader::new(std::fs::File::open(fname)?);
+    Ok(serde_json::from_reader(f)?)
+}
+
+pub fn write_table_spec(fname: &str, spec: &Vec<TableSpec>) -> std::io::Result<()> {
+    let f = std::io::BufWriter::new(std::fs::File::create(fname)?);
+    Ok(serde_json::to_writer_pretty(f, spec)?)
+}
+
 pub fn prepare_tables(
     prfx: Option<&str>,
     spec: &Vec<TableSpec>,
@@ -527,6 +543,8 @@ fn make_linestring_spec(
             ColumnType::BigInteger,
         ));
     }
+    //lines crossing the antimeridian are written as MultiLineStrings, so
+    //can only be loaded with a table spec giving way the Geometry type
     res.push((
         String::from("way"),
         ColumnSource::Geometry,
@@ -626,17 +644,16 @@ const DEFAULT_EXTRA_NODE_COLS: &str = r#"["access","addr:housename","addr:housen
 const DEFAULT_EXTRA_WAY_COLS: &str = r#"["addr:housenumber", "admin_level", "bicycle", "name", "tracktype", "addr:interpolation", "addr:housename", "horse", "surface", "access", "religion", "oneway", "foot", "covered", "ref"]"#; //"layer"
 
 pub fn make_table_spec(style: &GeometryStyle, extended: bool) -> Vec<TableSpec> {
-    let mut res = Vec::new();
-
     let mut point_tag_cols = Vec::new();
     let mut line_tag_cols = Vec::new();
 
-    for k in &style.feature_keys {
+    //pattern keys only go in the tags hstore
+    for k in style.feature_keys().iter().filter(|k| !is_key_pattern(k)) {
         point_tag_cols.push(k.clone());
         line_tag_cols.push(k.clone());
     }
 
-    match &style.other_keys {
+    match style.other_keys() {
         None => {
             let enc: Vec<String> = serde_json::from_str(&DEFAULT_EXTRA_NODE_COLS).expect("!!");
             for k in &enc {
@@ -649,7 +666,7 @@ pub fn make_table_spec(style: &GeometryStyle, extended: bool) -> Vec<TableSpec>
             }
         }
         Some(oo) => {
-            for k in oo {
+            for k in oo.iter().filter(|k| !is_key_pattern(k)) {
                 point_tag_cols.push(k.clone());
                 line_tag_cols.push(k.clone());
             }
@@ -670,7 +687,42 @@ pub fn make_table_spec(style: &GeometryStyle, extended: bool) -> Vec<TableSpec>
             line_tag_cols.push(l.target_key.clone());
         }
     }
+    
+    let mut res = make_table_spec_from_columns(&point_tag_cols, &line_tag_cols, &poly_tag_cols, extended);
+    if let Some(ln) = &style.localized_names {
+        add_localized_name_columns(&mut res, ln);
+    }
+    res
+}
+
+//adds a name_<lang> column for each language, after the tag columns
+pub fn add_localized_name_columns(spec: &mut Vec<TableSpec>, names: &LocalizedNameSpec) {
+    for table in spec {
+        let pos = match table.columns.iter().rposition(|(_, s, _)| *s == ColumnSource::Tag) {
+            Some(p) => p + 1,
+            None => table.columns.len(),
+        };
+        for (i, lang) in names.languages.iter().enumerate() {
+            let src = ColumnSource::LocalizedName {
+                lang: lang.clone(),
+                fallback: names.fallback.clone(),
+                transliterate: names.transliterate.contains(lang),
+            };
+            table.columns.insert(pos + i, (localized_name_key(lang), src, ColumnType::Text));
+        }
+    }
+}
 
+//tables point, line and polygon (and highway, building and boundary if
+//extended), with the given tag columns
+pub fn make_table_spec_from_columns(
+    point_tag_cols: &Vec<String>,
+    line_tag_cols: &Vec<String>,
+    poly_tag_cols: &Vec<String>,
+    extended: bool,
+) -> Vec<TableSpec> {
+    let mut res = Vec::new();
+    
     res.push(TableSpec::new(
         "point",
         make_point_spec(true/*extended*/, &point_tag_cols, true, true), //extended),
@@ -7,7 +7,7 @@ use crate::postgresql::{
     pack_geometry_block, prepare_tables, AllocFunc, PostgresqlConnection, PostgresqlOptions,
     PrepTable, TableSpec,
 };
-use crate::{GeometryBlock, OtherData, Timings,Result,Error};
+use crate::{CompressedWriter, Compression, GeometryBlock, OtherData, Timings,Result,Error};
 use osmquadtree::pbfformat::{pack_file_block, HeaderType, WriteFile, CompressionType};
 use osmquadtree::utils::{ThreadTimer, Timer};
 use osmquadtree::message;
@@ -47,31 +47,36 @@ const PGCOPY_HEADER: &[u8] = &[
 ];
 const PGCOPY_TAIL: &[u8] = &[255, 255];
 
-struct WriteGzipBlob {
-    output: flate2::write::GzEncoder<File>,
+struct WriteCompressedBlob {
+    output: Option<CompressedWriter>,
 }
 
-impl WriteGzipBlob {
-    fn new(fname: &str) -> WriteGzipBlob {
+impl WriteCompressedBlob {
+    fn new(fname: &str, compression: &Compression) -> WriteCompressedBlob {
         let f = File::create(fname).expect("!");
-        let mut pp = flate2::write::GzEncoder::new(f, flate2::Compression::default());
+        let mut pp = compression.writer(f).expect("!");
         pp.write_all(&PGCOPY_HEADER).expect("!");
 
-        WriteGzipBlob { output: pp }
+        WriteCompressedBlob { output: Some(pp) }
     }
 }
 
-impl CallFinish for WriteGzipBlob {
+impl CallFinish for WriteCompressedBlob {
     type CallType = Vec<u8>;
     type ReturnType = ();
     type ErrorType = Error;
 
     fn call(&mut self, b: Vec<u8>) {
-        self.output.write_all(&b).expect("!");
+        self.output.as_mut().unwrap().write_all(&b).expect("!");
     }
     fn finish(&mut self) -> ccResult<(),Error> {
-        self.output.write_all(&PGCOPY_TAIL).map_err(|e| Error::Io(e))?;
-        self.output.try_finish().map_err(|e| Error::Io(e))?;
+        match self.output.take() {
+            None => {},
+            Some(mut output) => {
+                output.write_all(&PGCOPY_TAIL).map_err(|e| Error::Io(e))?;
+                output.finish()?;
+            }
+        }
         Ok(())
     }
 }
@@ -82,16 +87,20 @@ struct CopySpec<'a> {
     pub before: Vec<String>,
     pub copy: Vec<String>,
     pub after: Vec<String>,
+    //blobs other than none or zlib:6 are compressed before packing, and
+    //stored as raw data
+    pub compression: String,
 }
 
 impl<'a> CopySpec<'a> {
-    pub fn new(tabs: &'a Vec<TableSpec>, extended: bool) -> CopySpec<'a> {
+    pub fn new(tabs: &'a Vec<TableSpec>, extended: bool, compression: &Compression) -> CopySpec<'a> {
         let (before, copy, after) = prepare_tables(None, &tabs, extended, false, &None).expect("!");
         CopySpec {
             tabs,
             before,
             copy,
             after,
+            compression: compression.to_string(),
         }
     }
 }
@@ -108,14 +117,14 @@ struct WritePackedFiles {
 }
 
 impl WritePackedFiles {
-    pub fn new(prfx: Option<&str>, tabs: &Vec<TableSpec>, septhreads: bool) -> WritePackedFiles {
+    pub fn new(prfx: Option<&str>, tabs: &Vec<TableSpec>, compression: &Compression, septhreads: bool) -> WritePackedFiles {
         let mut outputs = BTreeMap::new();
 
         match prfx {
             Some(prfx) => {
                 serde_json::to_writer(
                     std::fs::File::create(&format!("{}spec.json", prfx)).expect("!"),
-                    &CopySpec::new(tabs, tabs.len() > 3),
+                    &CopySpec::new(tabs, tabs.len() > 3, compression),
                 )
                 .expect("!");
             }
@@ -124,12 +133,12 @@ impl WritePackedFiles {
         for (i, t) in tabs.iter().enumerate() {
             match prfx {
                 Some(prfx) => {
-                    let fname = format!("{}{}.copy.gz", prfx, t.name);
+                    let fname = format!("{}{}.copy{}", prfx, t.name, compression.extension());
                     let x: Box<dyn CallFinish<CallType = Vec<u8>, ReturnType = (), ErrorType=Error>> = if septhreads
                     {
-                        Box::new(Callback::new(Box::new(WriteGzipBlob::new(&fname))))
+                        Box::new(Callback::new(Box::new(WriteCompressedBlob::new(&fname, compression))))
                     } else {
-                        Box::new(WriteGzipBlob::new(&fname))
+                        Box::new(WriteCompressedBlob::new(&fname, compression))
                     };
                     outputs.insert(i, (0, Some(x)));
                 }
@@ -220,7 +229,7 @@ impl CallFinish for WritePackedPbfFile {
     }
 }
 
-fn pack_pbf_blobs(pbbs: Vec<PackedBlob>) -> Vec<(i64, Vec<u8>)> {
+fn pack_pbf_blobs(pbbs: Vec<PackedBlob>, compression: &Compression) -> Vec<(i64, Vec<u8>)> {
     let mut res = Vec::new();
     for p in pbbs {
         if !p.data.is_empty() {
@@ -236,7 +245,7 @@ fn pack_pbf_blobs(pbbs: Vec<PackedBlob>) -> Vec<(i64, Vec<u8>)> {
 
             res.push((
                 p.table as i64,
-                pack_file_block("BlobData", &packed, &CompressionType::Zlib).expect("!"),
+                compression.pack_file_block("BlobData", &packed).expect("!"),
             ));
         }
     }
@@ -246,27 +255,30 @@ fn pack_pbf_blobs(pbbs: Vec<PackedBlob>) -> Vec<(i64, Vec<u8>)> {
 fn make_write_packed_pbffile(
     p: &str,
     tabs: &Vec<TableSpec>,
+    compression: &Compression,
     numchan: usize,
 ) -> Box<dyn CallFinish<CallType = Vec<PackedBlob>, ReturnType = Timings, ErrorType = Error>> {
     let mut wf = Box::new(WritePackedPbfFile::new(&p));
-    let header = serde_json::to_vec(&CopySpec::new(tabs, tabs.len() > 3)).expect("!");
+    let header = serde_json::to_vec(&CopySpec::new(tabs, tabs.len() > 3, compression)).expect("!");
     wf.call(vec![(
         -1,
         pack_file_block("BlobHeaderJson", &header, &CompressionType::Zlib).expect("!"),
     )]);
 
     if numchan == 0 {
-        Box::new(CallAll::new(wf, "pack_pbf_blobs", Box::new(pack_pbf_blobs)))
+        let compression = compression.clone();
+        Box::new(CallAll::new(wf, "pack_pbf_blobs", Box::new(move |pbbs| pack_pbf_blobs(pbbs, &compression))))
     } else {
         let wfs = CallbackSync::new(wf, numchan);
         let mut packs: Vec<Box<dyn CallFinish<CallType = Vec<PackedBlob>, ReturnType = Timings, ErrorType = Error>>> =
             Vec::new();
         for w in wfs {
             let w2 = Box::new(ReplaceNoneWithTimings::new(w));
+            let compression = compression.clone();
             packs.push(Box::new(Callback::new(Box::new(CallAll::new(
                 w2,
                 "pack_pbf_blobs",
-                Box::new(pack_pbf_blobs),
+                Box::new(move |pbbs| pack_pbf_blobs(pbbs, &compression)),
             )))));
         }
         Box::new(CallbackMerge::new(packs, Box::new(MergeTimings::new())))
@@ -418,15 +430,17 @@ fn prep_output(
         PostgresqlConnection::Null => Ok(Box::new(WritePackedFiles::new(
             None,
             &opts.table_spec,
+            &opts.compression,
             numchan != 0,
         ))),
         PostgresqlConnection::CopyFilePrfx(p) => Ok(Box::new(WritePackedFiles::new(
             Some(&p),
             &opts.table_spec,
+            &opts.compression,
             numchan != 0,
         ))),
         PostgresqlConnection::CopyFileBlob(p) => {
-            Ok(make_write_packed_pbffile(&p, &opts.table_spec, numchan))
+            Ok(make_write_packed_pbffile(&p, &opts.table_spec, &opts.compression, numchan))
         }
     }
 }
@@ -1,16 +1,27 @@
 pub use crate::postgresql::{make_write_postgresql_geometry, PostgresqlOptions};
 
 use crate::addparenttag::AddParentTag;
+use crate::customstages::{CustomStages, StagePoint};
+use crate::antimeridian::quadtree_bounds;
 use crate::elements::{GeoJsonable,WithBounds};
+use crate::geometry_header::{check_geometry_file, GeometryHeader};
+use crate::pack_geometry::{geometry_file_locs, read_all_blocks_result, GeometryPackOptions};
+use crate::compression::Compression;
+use crate::mergelines::MergeLines;
+use crate::polylabel::FindLabelPoints;
+use crate::validate::{ValidateGeometries, ValidateMode};
 use crate::minzoom::{FindMinZoom, MinZoomSpec};
 use crate::multipolygons::ProcessMultiPolygons;
 
 use crate::position::{calc_line_length, calc_ring_area};
 use crate::relationtags::AddRelationTags;
+use crate::style::is_transform_failure;
+use crate::tagfilter::ObjectFilter;
 use crate::{
     CollectWayNodes, GeometryBlock, GeometryStyle, LinestringGeometry, OtherData, PointGeometry,
     SimplePolygonGeometry, ComplicatedPolygonGeometry, Timings, WorkingBlock,CallFinishGeometryBlock,
-    prep_write_geometry_pbffile, make_write_temp_geometry, write_temp_geometry
+    prep_write_geometry_pbffile, make_write_temp_geometry, write_temp_geometry, write_multipolygon_errors,
+    read_geometry_blocks, write_geometry_index, MultiPolygonError
 };
 
 use crate::{Error, Result};
@@ -20,13 +31,13 @@ use osmquadtree::utils::{
     parse_timestamp, LogTimes, ThreadTimer,
 };
 use osmquadtree::message;
-use osmquadtree::elements::{Block, Quadtree};
+use osmquadtree::elements::{Bbox, Block, Quadtree};
 use osmquadtree::mergechanges::read_filter;
 
 use osmquadtree::pbfformat::{
     make_read_primitive_blocks_combine_call_all,
     //read_primitive_blocks_combine,
-    read_all_blocks_parallel_with_progbar, FileBlock,
+    FileBlock,
     ParallelFileLocs, get_file_locs
 };
 use osmquadtree::sortblocks::{TempData,QuadtreeTree};
@@ -223,6 +234,7 @@ struct MakeGeometries<T: ?Sized> {
     npt: usize,
     nls: usize,
     nsp: usize,
+    transform_failures: usize,
 }
 
 impl<T> MakeGeometries<T>
@@ -238,6 +250,17 @@ where
             npt: 0,
             nls: 0,
             nsp: 0,
+            transform_failures: 0,
+        }
+    }
+
+    //objects are still skipped, but counted rather than silently dropped
+    fn transform_failure(&mut self, e: &std::io::Error) {
+        if is_transform_failure(e) {
+            if self.transform_failures == 0 {
+                message!("MakeGeometries: {}", e);
+            }
+            self.transform_failures += 1;
         }
     }
 
@@ -246,7 +269,7 @@ where
 
         for n in std::mem::take(&mut bl.pending_nodes) {
             match self.style.process_node(&n.tags) {
-                Err(_) => {}
+                Err(e) => self.transform_failure(&e),
                 Ok((t, l)) => {
                     bl.geometry_block
                         .points
@@ -260,7 +283,7 @@ where
             let is_ring = w.refs[0] == w.refs[w.refs.len() - 1];
 
             match self.style.process_way(&w.tags, is_ring) {
-                Err(_) => {}
+                Err(e) => self.transform_failure(&e),
                 Ok((is_poly, tgs, zorder, layer)) => {
                     if is_poly {
                         let area = calc_ring_area(&ll); //.iter().collect::<Vec<&LonLat>>());
@@ -296,16 +319,16 @@ where
             }
 
             for l in bl.geometry_block.linestrings.iter_mut() {
-                l.quadtree = Quadtree::calculate(&l.bounds(), 18, 0.0);
+                l.quadtree = Quadtree::calculate(&quadtree_bounds(&l.bounds()), 18, 0.0);
             }
 
             for sp in bl.geometry_block.simple_polygons.iter_mut() {
-                sp.quadtree = Quadtree::calculate(&sp.bounds(), 18, 0.0);
+                sp.quadtree = Quadtree::calculate(&quadtree_bounds(&sp.bounds()), 18, 0.0);
             }
 
             for sp in bl.geometry_block.complicated_polygons.iter_mut() {
                 let bnd = sp.bounds();
-                sp.quadtree = Quadtree::calculate(&bnd, 18, 0.0);
+                sp.quadtree = Quadtree::calculate(&quadtree_bounds(&bnd), 18, 0.0);
             }
         }
     }
@@ -329,13 +352,14 @@ where
     fn finish(&mut self) -> ccResult<Timings, Error> {
         let mut tms = self.out.finish()?;
         tms.add("MakeGeometries", self.tm);
-        tms.add_other(
-            "MakeGeometries",
-            OtherData::Messages(vec![format!(
-                "{} points, {} linestrings, {} simple polygons",
-                self.npt, self.nls, self.nsp
-            )]),
-        );
+        let mut msgs = vec![format!(
+            "{} points, {} linestrings, {} simple polygons",
+            self.npt, self.nls, self.nsp
+        )];
+        if self.transform_failures > 0 {
+            msgs.push(format!("transform script failed for {} objects", self.transform_failures));
+        }
+        tms.add_other("MakeGeometries", OtherData::Messages(msgs));
         Ok(tms)
     }
 }
@@ -415,16 +439,21 @@ fn make_read_primitive_blocks_combine_call_all(
 }
 */
 
+//the first error returned by finish from any stage, or from out, is
+//returned once all the blocks have been read. with report_errors, invalid
+//multipolygons are included in the MultiPolygonErrors: they are checked by
+//ValidateGeometries if validate is set, otherwise by ProcessMultiPolygons
 pub fn process_geometry_call(
     pfilelocs: &mut ParallelFileLocs,
     out: Option<CallFinishGeometryBlock>,
     style: Arc<GeometryStyle>,
     minzoom: Option<MinZoomSpec>,
     numchan: usize,
-
-) -> Timings {
-    
-    
+    validate: Option<ValidateMode>,
+    report_errors: bool,
+    mut stages: CustomStages,
+) -> Result<Timings> {
+    let check_assembled = report_errors && validate.is_none();
 
     let cf = Box::new(CollectWorkingTiles::new(out));
 
@@ -433,54 +462,93 @@ pub fn process_geometry_call(
 
     let pp: Box<dyn CallFinish<CallType = (usize, Vec<FileBlock>), ReturnType = Timings, ErrorType=Error>> =
         if numchan == 0 {
+            let cf = stages.wrap(StagePoint::AfterMinZoom, cf, false);
             let fm: CallFinishWorkingBlock = if !minzoom.is_none() {
                 Box::new(FindMinZoom::new(cf, minzoom))
             } else {
                 cf
             };
 
-            let mg = Box::new(MakeGeometries::new(fm, style.clone(), true));
+            let lp: CallFinishWorkingBlock = match style.label_point_precision {
+                Some(p) => Box::new(FindLabelPoints::new(fm, p)),
+                None => fm,
+            };
+
+            let lp = stages.wrap(StagePoint::AfterValidate, lp, false);
+            let vg: CallFinishWorkingBlock = match validate {
+                Some(mode) => Box::new(ValidateGeometries::new(lp, mode, report_errors)),
+                None => lp,
+            };
+
+            let ml: CallFinishWorkingBlock = if style.merge_lines {
+                Box::new(MergeLines::new(vg))
+            } else {
+                vg
+            };
+
+            let ml = stages.wrap(StagePoint::AfterMakeGeometries, ml, false);
+            let mg = stages.wrap(StagePoint::AfterMultiPolygons, Box::new(MakeGeometries::new(ml, style.clone(), true)), false);
 
             let mm: CallFinishWorkingBlock = if style.multipolygons || style.boundary_relations {
-                Box::new(ProcessMultiPolygons::new(style.clone(), mg))
+                Box::new(ProcessMultiPolygons::new(style.clone(), check_assembled, mg))
             } else {
                 mg
             };
 
+            let mm = stages.wrap(StagePoint::AfterRelationTags, mm, false);
             let rt: CallFinishWorkingBlock = if !style.relation_tag_spec.is_empty() {
                 Box::new(AddRelationTags::new(mm, style.clone()))
             } else {
                 mm
             };
 
+            let rt = stages.wrap(StagePoint::AfterParentTags, rt, false);
             let ap: CallFinishWorkingBlock = if !style.parent_tags.is_empty() {
                 Box::new(AddParentTag::new(rt, style.clone()))
             } else {
                 rt
             };
 
+            let ap = stages.wrap(StagePoint::AfterWayNodes, ap, false);
             let ww = Box::new(CollectWayNodes::new(ap, style.clone()));
             make_read_primitive_blocks_combine_call_all(ww)
         } else {
-            let cfb = Box::new(Callback::new(cf));
+            let cfb = stages.wrap(StagePoint::AfterMinZoom, Box::new(Callback::new(cf)), true);
             let fm: CallFinishWorkingBlock = if !minzoom.is_none() {
                 Box::new(Callback::new(Box::new(FindMinZoom::new(cfb, minzoom))))
             } else {
                 cfb
             };
-            let mg = Box::new(Callback::new(Box::new(MakeGeometries::new(
-                fm,
+            let lp: CallFinishWorkingBlock = match style.label_point_precision {
+                Some(p) => Box::new(Callback::new(Box::new(FindLabelPoints::new(fm, p)))),
+                None => fm,
+            };
+            let lp = stages.wrap(StagePoint::AfterValidate, lp, true);
+            let vg: CallFinishWorkingBlock = match validate {
+                Some(mode) => Box::new(Callback::new(Box::new(ValidateGeometries::new(lp, mode, report_errors)))),
+                None => lp,
+            };
+            let ml: CallFinishWorkingBlock = if style.merge_lines {
+                Box::new(Callback::new(Box::new(MergeLines::new(vg))))
+            } else {
+                vg
+            };
+            let ml = stages.wrap(StagePoint::AfterMakeGeometries, ml, true);
+            let mg = stages.wrap(StagePoint::AfterMultiPolygons, Box::new(Callback::new(Box::new(MakeGeometries::new(
+                ml,
                 style.clone(),
                 true,
-            ))));
+            )))), true);
             let mm: CallFinishWorkingBlock = if style.multipolygons || style.boundary_relations {
                 Box::new(Callback::new(Box::new(ProcessMultiPolygons::new(
                     style.clone(),
+                    check_assembled,
                     mg,
                 ))))
             } else {
                 mg
             };
+            let mm = stages.wrap(StagePoint::AfterRelationTags, mm, true);
             let rt: CallFinishWorkingBlock = if !style.relation_tag_spec.is_empty() {
                 Box::new(Callback::new(Box::new(AddRelationTags::new(
                     mm,
@@ -489,6 +557,7 @@ pub fn process_geometry_call(
             } else {
                 mm
             };
+            let rt = stages.wrap(StagePoint::AfterParentTags, rt, true);
             let ap: CallFinishWorkingBlock = if !style.parent_tags.is_empty() {
                 Box::new(Callback::new(Box::new(AddParentTag::new(
                     rt,
@@ -498,6 +567,7 @@ pub fn process_geometry_call(
                 rt
             };
 
+            let ap = stages.wrap(StagePoint::AfterWayNodes, ap, true);
             let ww = CallbackSync::new(Box::new(CollectWayNodes::new(ap, style.clone())), numchan);
 
             let mut pps: Vec<
@@ -514,16 +584,25 @@ pub fn process_geometry_call(
 
     let msg = format!("process_geometry, numchan={}", numchan);
     
-    read_all_blocks_parallel_with_progbar(
-        &mut pfilelocs.0,
-        &pfilelocs.1,
-        pp,
-        &msg,
-        pfilelocs.2,
-    )
+    read_all_blocks_result(pfilelocs, pp, &msg)
 }
 
 
+//optional output settings for process_geometry. Use
+//ProcessGeometryOptions::default() to keep the defaults.
+#[derive(Debug, Default)]
+pub struct ProcessGeometryOptions {
+    //file to write multipolygon errors to, as csv if it ends with .csv,
+    //otherwise as geojson
+    pub error_report: Option<String>,
+    pub validate: Option<ValidateMode>,
+    //pack options and block compression for pbf file outputs
+    pub pack_options: Option<GeometryPackOptions>,
+    pub compression: Option<Compression>,
+    //custom stages added to the fixed stages of process_geometry_call
+    pub stages: CustomStages,
+}
+
 pub fn process_geometry(
     prfx: &str,
     outfn: OutputType,
@@ -533,7 +612,9 @@ pub fn process_geometry(
     style_name: Option<&str>,
     max_minzoom: Option<i64>,
     numchan: usize,
+    options: ProcessGeometryOptions,
 ) -> Result<Option<Vec<GeometryBlock>>> {
+    let ProcessGeometryOptions { error_report, validate, pack_options, compression, stages } = options;
     let mut tx = LogTimes::new();
     let (bbox, poly) = read_filter(filter)?;
 
@@ -567,41 +648,68 @@ pub fn process_geometry(
     };
     tx.add("load_minzoom");
     
+    let mut header = GeometryHeader::new(bbox.clone(), Some(&style), minzoom.as_ref(), timestamp)?;
+    if let Some(p) = pack_options {
+        header.pack_options = p;
+    }
+    if let Some(c) = compression {
+        header.compression = c;
+    }
     
-    let mut groups: Option<Arc<QuadtreeTree>> = None;
+    let (out, groups) = make_output(&outfn, &pfilelocs, &bbox, &header, &max_minzoom, numchan)?;
     
+    let tm = process_geometry_call(&mut pfilelocs, out, style, minzoom, numchan, validate, error_report.is_some(), stages)?;
+
+    tx.add("process_geometry");
+
+    let (all_tiles, tempdata, mut multipolygon_errors) = collect_output_others(tm);
+    tx.add("finish process_geometry");
     
-    let out: Option<Box<dyn CallFinish<CallType = GeometryBlock, ReturnType = Timings, ErrorType=Error>>> =
-        match &outfn {
-            OutputType::None => None,
-            OutputType::Collect | OutputType::Json(_) | OutputType::TiledJson(_) => {
-                let mut qq = Vec::new();
-                for a in &pfilelocs.1 {
-                    qq.push(a.0.clone());
-                }
-                Some(Box::new(StoreBlocks::new(qq)))
-            },
-            OutputType::PbfFile(ofn) => {
-                Some(prep_write_geometry_pbffile(ofn, &bbox, numchan)?)
-            },
-            OutputType::PbfFileSorted(ofn) => {
-                let (pp,gg) = make_write_temp_geometry(ofn, &pfilelocs, &max_minzoom, numchan)?;
-                groups = Some(gg);
-                Some(pp)
-            },
-                
-            OutputType::Postgresql(options) => {
-                Some(make_write_postgresql_geometry(&options, numchan)?)
-            }
-        };
+    if let Some(fname) = error_report {
+        multipolygon_errors.sort_by_key(|e| e.relation_id);
+        message!("write {} multipolygon errors to {}", multipolygon_errors.len(), fname);
+        write_multipolygon_errors(&fname, &multipolygon_errors)?;
+        tx.add("write multipolygon errors");
+    }
     
-    let tm = process_geometry_call(&mut pfilelocs, out, style, minzoom, numchan);    
+    let out = finish_output(outfn, all_tiles, &bbox, &header, tempdata, groups, numchan, &mut tx)?;
 
-    tx.add("process_geometry");
+    message!("{}", tx);
+    Ok(out)
+        
+}
+
+fn make_output(outfn: &OutputType, pfilelocs: &ParallelFileLocs, bbox: &Bbox, header: &GeometryHeader, max_minzoom: &Option<i64>, numchan: usize)
+    -> Result<(Option<CallFinishGeometryBlock>, Option<Arc<QuadtreeTree>>)> {
+    
+    match outfn {
+        OutputType::None => Ok((None, None)),
+        OutputType::Collect | OutputType::Json(_) | OutputType::TiledJson(_) => {
+            let mut qq = Vec::new();
+            for a in &pfilelocs.1 {
+                qq.push(a.0.clone());
+            }
+            Ok((Some(Box::new(StoreBlocks::new(qq))), None))
+        },
+        OutputType::PbfFile(ofn) => {
+            Ok((Some(prep_write_geometry_pbffile(ofn, bbox, header, numchan)?), None))
+        },
+        OutputType::PbfFileSorted(ofn) => {
+            let (pp,gg) = make_write_temp_geometry(ofn, pfilelocs, max_minzoom, numchan)?;
+            Ok((Some(pp), Some(gg)))
+        },
+            
+        OutputType::Postgresql(options) => {
+            Ok((Some(make_write_postgresql_geometry(options, numchan)?), None))
+        }
+    }
+}
 
+fn collect_output_others(tm: Timings) -> (BTreeMap<Quadtree, GeometryBlock>, Option<TempData>, Vec<MultiPolygonError>) {
     message!("{}", tm);
     let mut all_tiles = BTreeMap::new();
     let mut tempdata: Option<TempData> = None;
+    let mut multipolygon_errors = Vec::new();
     for (w, x) in tm.others {
         match x {
             OtherData::Messages(mm) => {
@@ -616,36 +724,104 @@ pub fn process_geometry(
                 all_tiles.extend(tiles);
             },
             OtherData::TempData(td) => { tempdata = Some(td); },
+            OtherData::MultiPolygonErrors(ee) => {
+                multipolygon_errors.extend(ee);
+            }
+            OtherData::IndexEntries(_) => {}
         }
     }
-    tx.add("finish process_geometry");
+    (all_tiles, tempdata, multipolygon_errors)
+}
+
+fn finish_output(outfn: OutputType, all_tiles: BTreeMap<Quadtree, GeometryBlock>, bbox: &Bbox, header: &GeometryHeader, tempdata: Option<TempData>,
+    groups: Option<Arc<QuadtreeTree>>, numchan: usize, tx: &mut LogTimes) -> Result<Option<Vec<GeometryBlock>>> {
     
-    let out = match outfn {
-        OutputType::None | OutputType::PbfFile(_) | OutputType::Postgresql(_) => { None },
-        OutputType::Collect => Some(all_tiles.into_values().collect()),
+    match outfn {
+        OutputType::None | OutputType::PbfFile(_) | OutputType::Postgresql(_) => Ok(None),
+        OutputType::Collect => Ok(Some(all_tiles.into_values().collect())),
         OutputType::PbfFileSorted(outfn) => {
-            write_temp_geometry(&outfn, &bbox, tempdata.unwrap(), groups.unwrap(), numchan)?;
-            tx.add("write final pbf");
-            None
+            match (tempdata, groups) {
+                (Some(td), Some(gg)) => {
+                    write_temp_geometry(&outfn, bbox, header, td, gg, numchan)?;
+                    tx.add("write final pbf");
+                    write_geometry_index(&outfn, numchan)?;
+                    tx.add("write index");
+                    Ok(None)
+                },
+                _ => Err(Error::InvalidDataError(format!("no temp data to write to {}", outfn)))
+            }
         },
         OutputType::Json(ofn) => {
             if !all_tiles.is_empty() {
                 write_geojson_flat(all_tiles, &ofn)?;
                 tx.add("write json");
             }
-            None
+            Ok(None)
         }
         OutputType::TiledJson(ofn) => {
             if !all_tiles.is_empty() {
                 write_geojson_tiles(&all_tiles, &ofn)?;
                 tx.add("write json");
             }
-            Some(all_tiles.into_values().collect())
+            Ok(Some(all_tiles.into_values().collect()))
         }
+    }
+}
+
+//reads a geometry pbf file, as written by OutputType::PbfFile or
+//OutputType::PbfFileSorted, and writes it to another output type
+pub fn convert_geometry(
+    infn: &str,
+    outfn: OutputType,
+    filter: Option<&str>,
+    max_minzoom: Option<i64>,
+    numchan: usize,
+    pack_options: Option<GeometryPackOptions>,
+    compression: Option<Compression>,
+    tag_filter: Option<&str>,
+    geometry_types: Option<&str>,
+) -> Result<Option<Vec<GeometryBlock>>> {
+    let mut tx = LogTimes::new();
+    let (bbox, _) = read_filter(filter)?;
+    let object_filter = ObjectFilter::new(tag_filter, geometry_types)?;
+    
+    let max_depth = match max_minzoom {
+        None => None,
+        Some(md) => Some(md as usize),
     };
+    let pfilelocs = geometry_file_locs(infn, Some(bbox.clone()), max_depth)?;
+    tx.add("get_file_locs");
+    
+    //keep the style, minzoom spec and timestamp of the input file
+    let mut header = match check_geometry_file(infn)? {
+        Some(mut h) => {
+            h.generator = GeometryHeader::new(bbox.clone(), None, None, None)?.generator;
+            h
+        },
+        None => GeometryHeader::new(bbox.clone(), None, None, None)?,
+    };
+    if let Some(p) = pack_options {
+        header.pack_options = p;
+    }
+    if let Some(c) = compression {
+        header.compression = c;
+    }
+    
+    let (out, groups) = make_output(&outfn, &pfilelocs, &bbox, &header, &max_minzoom, numchan)?;
+    let out = match out {
+        Some(o) => o,
+        None => {
+            return Err(Error::UserSelectionError(format!("convert_geometry needs an output")));
+        }
+    };
+    
+    let tm = read_geometry_blocks(infn, out, filter, max_minzoom, Some(object_filter), numchan)?;
+    tx.add("convert_geometry");
+    
+    let (all_tiles, tempdata, _) = collect_output_others(tm);
+    
+    let out = finish_output(outfn, all_tiles, &bbox, &header, tempdata, groups, numchan, &mut tx)?;
     
-
     message!("{}", tx);
     Ok(out)
-        
 }
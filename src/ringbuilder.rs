@@ -0,0 +1,697 @@
+use crate::elements::{PolygonPart, Ring, RingPart};
+use crate::position::{on_segment_interior, orient, point_in_ring_exact};
+use crate::{Error, LonLat, Result};
+
+use osmquadtree::elements::Bbox;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+
+struct Piece {
+    orig_id: i64,
+    is_inner: bool,
+    refs: Vec<i64>,
+    lonlats: Vec<LonLat>,
+}
+
+impl Piece {
+    fn first(&self) -> i64 {
+        self.refs[0]
+    }
+    fn last(&self) -> i64 {
+        self.refs[self.refs.len() - 1]
+    }
+    fn is_loop(&self) -> bool {
+        self.first() == self.last()
+    }
+}
+
+fn prep_pieces(outer: Vec<RingPart>, inner: Vec<RingPart>) -> Vec<Piece> {
+    let mut seen = BTreeSet::new();
+
+    //nodes at the same location are treated as the same node
+    let mut locs: BTreeMap<LonLat, i64> = BTreeMap::new();
+
+    let mut res = Vec::new();
+    for (rp, is_inner) in outer
+        .into_iter()
+        .map(|r| (r, false))
+        .chain(inner.into_iter().map(|r| (r, true)))
+    {
+        if !seen.insert(rp.orig_id) {
+            continue;
+        }
+        let mut pc = Piece {
+            orig_id: rp.orig_id,
+            is_inner: is_inner,
+            refs: Vec::with_capacity(rp.refs.len()),
+            lonlats: Vec::with_capacity(rp.refs.len()),
+        };
+        for (r, ll) in rp.refs.into_iter().zip(rp.lonlats.into_iter()) {
+            let r = *locs.entry(ll.clone()).or_insert(r);
+            if pc.refs.last() == Some(&r) {
+                continue;
+            }
+            pc.refs.push(r);
+            pc.lonlats.push(ll);
+        }
+        if pc.refs.len() > 1 {
+            res.push(pc);
+        }
+    }
+    res
+}
+
+fn seg_box(a: &LonLat, b: &LonLat) -> Bbox {
+    Bbox::new(
+        i32::min(a.lon, b.lon),
+        i32::min(a.lat, b.lat),
+        i32::max(a.lon, b.lon),
+        i32::max(a.lat, b.lat),
+    )
+}
+
+fn crossing_point(p1: &LonLat, p2: &LonLat, q1: &LonLat, q2: &LonLat) -> LonLat {
+    let (px, py) = (p1.lon as f64, p1.lat as f64);
+    let (rx, ry) = (p2.lon as f64 - px, p2.lat as f64 - py);
+    let (sx, sy) = (q2.lon as f64 - q1.lon as f64, q2.lat as f64 - q1.lat as f64);
+    let t = ((q1.lon as f64 - px) * sy - (q1.lat as f64 - py) * sx) / (rx * sy - ry * sx);
+    LonLat::new(
+        f64::round(px + t * rx) as i32,
+        f64::round(py + t * ry) as i32,
+    )
+}
+
+fn dist2(a: &LonLat, b: &LonLat) -> i64 {
+    let dx = a.lon as i64 - b.lon as i64;
+    let dy = a.lat as i64 - b.lat as i64;
+    dx * dx + dy * dy
+}
+
+type Insertions = BTreeMap<(usize, usize), Vec<(i64, LonLat)>>;
+
+fn add_insertion(ins: &mut Insertions, pc: &[Piece], key: (usize, usize), r: i64, ll: &LonLat) {
+    let (a, b) = (&pc[key.0].lonlats[key.1], &pc[key.0].lonlats[key.1 + 1]);
+    if !on_segment_interior(a, b, ll) {
+        return;
+    }
+    ins.entry(key).or_insert_with(Vec::new).push((r, ll.clone()));
+}
+
+//the crossing point of two segments is rounded to the integer grid, so is
+//usually not exactly on either segment: it is inserted anyway (snap rounding)
+fn add_snapped_insertion(ins: &mut Insertions, pc: &[Piece], key: (usize, usize), r: i64, ll: &LonLat) {
+    let (a, b) = (&pc[key.0].lonlats[key.1], &pc[key.0].lonlats[key.1 + 1]);
+    if ll == a || ll == b {
+        return;
+    }
+    ins.entry(key).or_insert_with(Vec::new).push((r, ll.clone()));
+}
+
+//snap rounding can very rarely keep creating new crossings
+const MAX_SPLIT_PASSES: usize = 16;
+
+//add a vertex wherever two segments cross, or where a vertex touches another
+//segment. The snapped vertices can make new crossings with other segments,
+//so this repeats until no more are found.
+fn split_crossings(pieces: &mut Vec<Piece>, next_id: &mut i64) -> usize {
+    let mut num_crossings = 0;
+    for _ in 0..MAX_SPLIT_PASSES {
+        match split_crossings_pass(pieces, next_id) {
+            None => {
+                break;
+            }
+            Some(n) => {
+                num_crossings += n;
+            }
+        }
+    }
+    num_crossings
+}
+
+//returns None if no vertices were added, otherwise the number of crossings
+fn split_crossings_pass(pieces: &mut Vec<Piece>, next_id: &mut i64) -> Option<usize> {
+    let mut locs: BTreeMap<LonLat, i64> = BTreeMap::new();
+    let mut segs = Vec::new();
+    for (i, pc) in pieces.iter().enumerate() {
+        for j in 0..pc.refs.len() - 1 {
+            locs.insert(pc.lonlats[j].clone(), pc.refs[j]);
+            segs.push((seg_box(&pc.lonlats[j], &pc.lonlats[j + 1]), i, j));
+        }
+        locs.insert(pc.lonlats[pc.refs.len() - 1].clone(), pc.last());
+    }
+    segs.sort_by_key(|s| s.0.minlon);
+
+    let mut ins = Insertions::new();
+    let mut num_crossings = 0;
+    for x in 0..segs.len() {
+        let (bx, i, j) = &segs[x];
+        for y in x + 1..segs.len() {
+            let (by, k, l) = &segs[y];
+            if by.minlon > bx.maxlon {
+                break;
+            }
+            if by.minlat > bx.maxlat || by.maxlat < bx.minlat {
+                continue;
+            }
+            let (p1, p2) = (&pieces[*i].lonlats[*j], &pieces[*i].lonlats[*j + 1]);
+            let (q1, q2) = (&pieces[*k].lonlats[*l], &pieces[*k].lonlats[*l + 1]);
+
+            let o1 = orient(p1, p2, q1);
+            let o2 = orient(p1, p2, q2);
+            let o3 = orient(q1, q2, p1);
+            let o4 = orient(q1, q2, p2);
+
+            if o1 * o2 < 0 && o3 * o4 < 0 {
+                num_crossings += 1;
+                let ll = crossing_point(p1, p2, q1, q2);
+                let r = match locs.get(&ll) {
+                    Some(r) => *r,
+                    None => {
+                        *next_id -= 1;
+                        locs.insert(ll.clone(), *next_id);
+                        *next_id
+                    }
+                };
+                add_snapped_insertion(&mut ins, pieces, (*i, *j), r, &ll);
+                add_snapped_insertion(&mut ins, pieces, (*k, *l), r, &ll);
+            } else {
+                let (r1, r2) = (pieces[*k].refs[*l], pieces[*k].refs[*l + 1]);
+                let (s1, s2) = (pieces[*i].refs[*j], pieces[*i].refs[*j + 1]);
+                add_insertion(&mut ins, pieces, (*i, *j), r1, q1);
+                add_insertion(&mut ins, pieces, (*i, *j), r2, q2);
+                add_insertion(&mut ins, pieces, (*k, *l), s1, p1);
+                add_insertion(&mut ins, pieces, (*k, *l), s2, p2);
+            }
+        }
+    }
+    if ins.is_empty() {
+        return None;
+    }
+
+    let mut by_piece: BTreeMap<usize, BTreeMap<usize, Vec<(i64, LonLat)>>> = BTreeMap::new();
+    for ((i, j), vv) in ins {
+        by_piece.entry(i).or_insert_with(BTreeMap::new).insert(j, vv);
+    }
+    for (i, segins) in by_piece {
+        let pc = &mut pieces[i];
+        let mut refs = Vec::with_capacity(pc.refs.len());
+        let mut lonlats = Vec::with_capacity(pc.refs.len());
+        for j in 0..pc.refs.len() {
+            refs.push(pc.refs[j]);
+            lonlats.push(pc.lonlats[j].clone());
+            if let Some(vv) = segins.get(&j) {
+                let a = &pc.lonlats[j];
+                let mut vv = vv.clone();
+                vv.sort_by_key(|(_, ll)| dist2(a, ll));
+                vv.dedup_by(|x, y| x.1 == y.1);
+                for (r, ll) in vv {
+                    refs.push(r);
+                    lonlats.push(ll);
+                }
+            }
+        }
+        pc.refs = refs;
+        pc.lonlats = lonlats;
+    }
+    Some(num_crossings)
+}
+
+//split pieces at every node shared with another piece, or repeated within a piece
+fn split_at_junctions(pieces: Vec<Piece>) -> Vec<Piece> {
+    let mut counts: BTreeMap<i64, usize> = BTreeMap::new();
+    for pc in &pieces {
+        let n = if pc.is_loop() { pc.refs.len() - 1 } else { pc.refs.len() };
+        for r in &pc.refs[..n] {
+            *counts.entry(*r).or_insert(0) += 1;
+        }
+        if !pc.is_loop() {
+            *counts.get_mut(&pc.first()).unwrap() += 1;
+            *counts.get_mut(&pc.last()).unwrap() += 1;
+        }
+    }
+
+    let mut res = Vec::new();
+    for pc in pieces {
+        let mut st = 0;
+        for i in 1..pc.refs.len() - 1 {
+            if counts[&pc.refs[i]] > 1 {
+                res.push(Piece {
+                    orig_id: pc.orig_id,
+                    is_inner: pc.is_inner,
+                    refs: pc.refs[st..i + 1].to_vec(),
+                    lonlats: pc.lonlats[st..i + 1].to_vec(),
+                });
+                st = i;
+            }
+        }
+        if st == 0 {
+            res.push(pc);
+        } else {
+            res.push(Piece {
+                orig_id: pc.orig_id,
+                is_inner: pc.is_inner,
+                refs: pc.refs[st..].to_vec(),
+                lonlats: pc.lonlats[st..].to_vec(),
+            });
+        }
+    }
+    res
+}
+
+//pieces repeated an even number of times (i.e. boundaries shared between two rings) cancel out
+fn drop_shared_pieces(pieces: Vec<Piece>) -> Vec<Piece> {
+    let mut keyed: BTreeMap<Vec<i64>, Vec<Piece>> = BTreeMap::new();
+    for pc in pieces {
+        let mut rev = pc.refs.clone();
+        rev.reverse();
+        let key = if rev < pc.refs { rev } else { pc.refs.clone() };
+        keyed.entry(key).or_insert_with(Vec::new).push(pc);
+    }
+    let mut res = Vec::new();
+    for (_, mut pp) in keyed {
+        if pp.len() % 2 == 1 {
+            res.push(pp.swap_remove(0));
+        }
+    }
+    res
+}
+
+fn direction(from: &LonLat, to: &LonLat) -> (i64, i64) {
+    (
+        to.lon as i64 - from.lon as i64,
+        to.lat as i64 - from.lat as i64,
+    )
+}
+
+fn cross(a: (i64, i64), b: (i64, i64)) -> i128 {
+    (a.0 as i128) * (b.1 as i128) - (a.1 as i128) * (b.0 as i128)
+}
+
+fn dot(a: (i64, i64), b: (i64, i64)) -> i128 {
+    (a.0 as i128) * (b.0 as i128) + (a.1 as i128) * (b.1 as i128)
+}
+
+//which part of the turn (0, 2pi] anticlockwise from rf the direction d lies in
+fn turn_class(rf: (i64, i64), d: (i64, i64)) -> i32 {
+    let c = cross(rf, d);
+    if c > 0 {
+        0
+    } else if c == 0 && dot(rf, d) < 0 {
+        1
+    } else if c < 0 {
+        2
+    } else {
+        3
+    }
+}
+
+fn compare_turn(rf: (i64, i64), a: (i64, i64), b: (i64, i64)) -> Ordering {
+    let (ca, cb) = (turn_class(rf, a), turn_class(rf, b));
+    if ca != cb {
+        return ca.cmp(&cb);
+    }
+    let c = cross(a, b);
+    if c > 0 {
+        Ordering::Less
+    } else if c < 0 {
+        Ordering::Greater
+    } else {
+        Ordering::Equal
+    }
+}
+
+fn leaving_direction(pc: &Piece, from_first: bool) -> (i64, i64) {
+    let n = pc.lonlats.len();
+    if from_first {
+        direction(&pc.lonlats[0], &pc.lonlats[1])
+    } else {
+        direction(&pc.lonlats[n - 1], &pc.lonlats[n - 2])
+    }
+}
+
+fn trace_rings(pieces: &[Piece]) -> (Vec<Vec<(usize, bool)>>, Vec<usize>) {
+    let mut rings = Vec::new();
+    let mut left = Vec::new();
+
+    let mut ends: BTreeMap<i64, Vec<usize>> = BTreeMap::new();
+    let mut used = vec![false; pieces.len()];
+    for (i, pc) in pieces.iter().enumerate() {
+        if pc.is_loop() {
+            used[i] = true;
+            rings.push(vec![(i, false)]);
+        } else {
+            ends.entry(pc.first()).or_insert_with(Vec::new).push(i);
+            ends.entry(pc.last()).or_insert_with(Vec::new).push(i);
+        }
+    }
+
+    //repeatedly remove dangling pieces, which can't be part of any ring
+    let mut queue: Vec<i64> = ends
+        .iter()
+        .filter(|(_, v)| v.len() == 1)
+        .map(|(k, _)| *k)
+        .collect();
+    while let Some(n) = queue.pop() {
+        let i = match ends.get(&n) {
+            Some(v) if v.len() == 1 => v[0],
+            _ => continue,
+        };
+        used[i] = true;
+        left.push(i);
+        for m in &[pieces[i].first(), pieces[i].last()] {
+            if let Some(v) = ends.get_mut(m) {
+                v.retain(|x| *x != i);
+                if v.len() == 1 {
+                    queue.push(*m);
+                }
+            }
+        }
+    }
+
+    for s in 0..pieces.len() {
+        if used[s] {
+            continue;
+        }
+        used[s] = true;
+        let start = pieces[s].first();
+        let mut path = vec![(s, false)];
+        let mut curr = pieces[s].last();
+        let mut incoming = leaving_direction(&pieces[s], false);
+        let mut closed = true;
+        while curr != start {
+            let cands: Vec<usize> = ends[&curr].iter().filter(|i| !used[**i]).cloned().collect();
+            if cands.is_empty() {
+                closed = false;
+                break;
+            }
+            //take the next piece anticlockwise from the one we arrived along
+            let mut best = cands[0];
+            let mut best_dir = leaving_direction(&pieces[best], pieces[best].first() == curr);
+            for c in &cands[1..] {
+                let d = leaving_direction(&pieces[*c], pieces[*c].first() == curr);
+                if compare_turn(incoming, d, best_dir) == Ordering::Less {
+                    best = *c;
+                    best_dir = d;
+                }
+            }
+            used[best] = true;
+            let fwd = pieces[best].first() == curr;
+            path.push((best, !fwd));
+            curr = if fwd { pieces[best].last() } else { pieces[best].first() };
+            incoming = leaving_direction(&pieces[best], !fwd);
+        }
+        if !closed {
+            left.extend(path.into_iter().map(|(i, _)| i));
+            continue;
+        }
+
+        //split walks which revisit a node into separate rings
+        let mut nodes = vec![start];
+        let mut parts: Vec<(usize, bool)> = Vec::new();
+        for (i, rev) in path {
+            parts.push((i, rev));
+            let m = if rev { pieces[i].first() } else { pieces[i].last() };
+            match nodes.iter().position(|x| *x == m) {
+                Some(pos) => {
+                    rings.push(parts.split_off(pos));
+                    nodes.truncate(pos + 1);
+                }
+                None => {
+                    nodes.push(m);
+                }
+            }
+        }
+    }
+    (rings, left)
+}
+
+fn make_ring(pieces: &[Piece], path: &[(usize, bool)]) -> Ring {
+    let mut ring = Ring::new();
+    for (i, rev) in path {
+        let pc = &pieces[*i];
+        match ring.parts.last_mut() {
+            Some(p) if p.orig_id == pc.orig_id && p.is_reversed == *rev => {
+                if *rev && p.refs[0] == pc.last() {
+                    let mut refs = pc.refs.clone();
+                    let mut lonlats = pc.lonlats.clone();
+                    refs.extend(p.refs.iter().skip(1).cloned());
+                    lonlats.extend(p.lonlats.iter().skip(1).cloned());
+                    p.refs = refs;
+                    p.lonlats = lonlats;
+                    continue;
+                } else if !*rev && p.refs[p.refs.len() - 1] == pc.first() {
+                    p.refs.extend(pc.refs.iter().skip(1).cloned());
+                    p.lonlats.extend(pc.lonlats.iter().skip(1).cloned());
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        ring.parts.push(RingPart::new(
+            pc.orig_id,
+            *rev,
+            pc.refs.clone(),
+            pc.lonlats.clone(),
+        ));
+    }
+    ring
+}
+
+//false if inner lies entirely on the boundary of outer
+pub(crate) fn ring_within(outer: &[LonLat], inner: &[LonLat]) -> bool {
+    for l in inner {
+        match point_in_ring_exact(outer, l) {
+            1 => {
+                return true;
+            }
+            -1 => {
+                return false;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+fn to_ringparts(pieces: &[Piece], idx: &[usize]) -> Vec<RingPart> {
+    idx.iter()
+        .map(|i| {
+            let pc = &pieces[*i];
+            RingPart::new(pc.orig_id, false, pc.refs.clone(), pc.lonlats.clone())
+        })
+        .collect()
+}
+
+pub struct AssembledRings {
+    rings: Vec<(Ring, Vec<LonLat>, bool)>,
+    pub left: Vec<RingPart>,
+    pub num_crossings: usize,
+}
+
+//assemble closed rings from way parts, splitting rings where they touch or cross and
+//removing boundaries shared between rings
+pub fn assemble_rings(outer: Vec<RingPart>, inner: Vec<RingPart>) -> Result<AssembledRings> {
+    let mut pieces = prep_pieces(outer, inner);
+    if pieces.is_empty() {
+        return Err(Error::InvalidDataError(format!("no ring parts")));
+    }
+    let mut next_id = 0;
+    let num_crossings = split_crossings(&mut pieces, &mut next_id);
+    let pieces = drop_shared_pieces(split_at_junctions(pieces));
+
+    let (paths, left) = trace_rings(&pieces);
+
+    let mut rings = Vec::new();
+    for path in paths {
+        let mut r = make_ring(&pieces, &path);
+        let ll = r.lonlats()?;
+        if ll.len() < 4 {
+            continue;
+        }
+        r.calc_area_bbox()?;
+        if r.area == 0.0 {
+            continue;
+        }
+        let all_inner = path.iter().all(|(i, _)| pieces[*i].is_inner);
+        rings.push((r, ll, all_inner));
+    }
+    Ok(AssembledRings {
+        rings: rings,
+        left: to_ringparts(&pieces, &left),
+        num_crossings: num_crossings,
+    })
+}
+
+//nest rings by containment: rings at even depth are exteriors, at odd depth interiors
+pub fn nest_rings(assembled: AssembledRings) -> (Vec<PolygonPart>, Vec<Ring>) {
+    let mut rings = assembled.rings;
+    rings.sort_by(|p, q| {
+        f64::abs(q.0.area)
+            .partial_cmp(&f64::abs(p.0.area))
+            .unwrap()
+    });
+
+    let mut res: Vec<PolygonPart> = Vec::new();
+    let mut placed: Vec<(Vec<LonLat>, Bbox, usize, usize)> = Vec::new();
+    let mut rem = Vec::new();
+
+    for (r, ll, all_inner) in rings {
+        let mut parent = None;
+        for (i, (pll, pbox, _, _)) in placed.iter().enumerate().rev() {
+            if pbox.contains(&r.bbox) && ring_within(pll, &ll) {
+                parent = Some(i);
+                break;
+            }
+        }
+        let depth = match parent {
+            None => 0,
+            Some(i) => placed[i].2 + 1,
+        };
+        let bbox = r.bbox.clone();
+        if depth % 2 == 0 {
+            if depth == 0 && all_inner {
+                rem.push(r);
+                continue;
+            }
+            res.push(PolygonPart::new(r));
+            placed.push((ll, bbox, depth, res.len() - 1));
+        } else {
+            let pi = placed[parent.unwrap()].3;
+            res[pi].add_interior(r);
+            placed.push((ll, bbox, depth, pi));
+        }
+    }
+    (res, rem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(id: i64, pts: &[(i64, i32, i32)]) -> RingPart {
+        RingPart::new(
+            id,
+            false,
+            pts.iter().map(|(r, _, _)| *r).collect(),
+            pts.iter().map(|(_, x, y)| LonLat::new(*x, *y)).collect(),
+        )
+    }
+
+    fn square(id: i64, first_ref: i64, x0: i32, y0: i32, x1: i32, y1: i32) -> RingPart {
+        part(id, &[
+            (first_ref, x0, y0),
+            (first_ref + 1, x1, y0),
+            (first_ref + 2, x1, y1),
+            (first_ref + 3, x0, y1),
+            (first_ref, x0, y0),
+        ])
+    }
+
+    fn any_proper_crossing(rings: &[Vec<LonLat>]) -> bool {
+        let segs: Vec<(&LonLat, &LonLat)> = rings
+            .iter()
+            .flat_map(|ll| ll.iter().zip(ll.iter().skip(1)))
+            .collect();
+        for (x, (p1, p2)) in segs.iter().enumerate() {
+            for (q1, q2) in &segs[x + 1..] {
+                let (o1, o2) = (orient(p1, p2, q1), orient(p1, p2, q2));
+                let (o3, o4) = (orient(q1, q2, p1), orient(q1, q2, p2));
+                if o1 * o2 < 0 && o3 * o4 < 0 {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn bow_tie_split_off_grid() {
+        //(0,0)-(7,10) crosses (7,0)-(0,10) at (3.5,5)
+        let lonlats = vec![
+            LonLat::new(0, 0),
+            LonLat::new(7, 10),
+            LonLat::new(7, 0),
+            LonLat::new(0, 10),
+            LonLat::new(0, 0),
+        ];
+        let part = RingPart::new(1, false, vec![1, 2, 3, 4, 1], lonlats);
+        let assembled = assemble_rings(vec![part], Vec::new()).unwrap();
+        assert_eq!(assembled.num_crossings, 1);
+        assert!(assembled.left.is_empty());
+        assert_eq!(assembled.rings.len(), 2);
+        for (_, ll, _) in &assembled.rings {
+            assert_eq!(ll.len(), 4);
+            assert!(ll.contains(&LonLat::new(4, 5)));
+        }
+    }
+
+    #[test]
+    fn snapped_crossings_rechecked() {
+        //a star with all its crossings off the integer grid, close enough
+        //together that the snapped vertices can make new crossings
+        let pts = [(0, 0), (13, 37), (26, 1), (-4, 24), (31, 23), (0, 0)];
+        let rp = part(1, &[
+            (1, pts[0].0, pts[0].1),
+            (2, pts[1].0, pts[1].1),
+            (3, pts[2].0, pts[2].1),
+            (4, pts[3].0, pts[3].1),
+            (5, pts[4].0, pts[4].1),
+            (1, pts[5].0, pts[5].1),
+        ]);
+        let assembled = assemble_rings(vec![rp], Vec::new()).unwrap();
+        assert!(assembled.num_crossings >= 5);
+        let rings: Vec<Vec<LonLat>> = assembled.rings.iter().map(|(_, ll, _)| ll.clone()).collect();
+        assert!(!rings.is_empty());
+        assert!(!any_proper_crossing(&rings));
+    }
+
+    #[test]
+    fn touching_rings() {
+        //two squares touching at a single node, as one way and as two
+        let figure_eight = part(1, &[
+            (1, 0, 0), (2, 10, 0), (3, 10, 10), (4, 20, 10), (5, 20, 20),
+            (6, 10, 20), (3, 10, 10), (7, 0, 10), (1, 0, 0),
+        ]);
+        let assembled = assemble_rings(vec![figure_eight], Vec::new()).unwrap();
+        assert!(assembled.left.is_empty());
+        assert_eq!(assembled.rings.len(), 2);
+        let (polys, rem) = nest_rings(assembled);
+        assert_eq!(polys.len(), 2);
+        assert!(rem.is_empty());
+
+        let assembled = assemble_rings(vec![square(1, 1, 0, 0, 10, 10), square(2, 10, 10, 10, 20, 20)], Vec::new()).unwrap();
+        assert_eq!(assembled.rings.len(), 2);
+        assert_eq!(nest_rings(assembled).0.len(), 2);
+    }
+
+    #[test]
+    fn shared_segment() {
+        //two squares sharing an edge are merged into one ring
+        let a = part(1, &[(1, 0, 0), (2, 10, 0), (3, 10, 10), (4, 0, 10), (1, 0, 0)]);
+        let b = part(2, &[(2, 10, 0), (5, 20, 0), (6, 20, 10), (3, 10, 10), (2, 10, 0)]);
+        let assembled = assemble_rings(vec![a, b], Vec::new()).unwrap();
+        assert!(assembled.left.is_empty());
+        assert_eq!(assembled.rings.len(), 1);
+        let ll = &assembled.rings[0].1;
+        assert_eq!(ll.len(), 7);
+        assert!(ll.contains(&LonLat::new(20, 0)));
+        assert!(ll.contains(&LonLat::new(0, 10)));
+    }
+
+    #[test]
+    fn nested_islands() {
+        //an outer ring with a hole, and an island inside the hole
+        let outer = square(1, 1, 0, 0, 100, 100);
+        let hole = square(2, 10, 20, 20, 80, 80);
+        let island = square(3, 20, 40, 40, 60, 60);
+        let assembled = assemble_rings(vec![outer, island], vec![hole]).unwrap();
+        assert_eq!(assembled.rings.len(), 3);
+        let (polys, rem) = nest_rings(assembled);
+        assert!(rem.is_empty());
+        assert_eq!(polys.len(), 2);
+        assert_eq!(polys[0].interiors.len(), 1);
+        assert!(polys[1].interiors.is_empty());
+        assert!(polys[0].area.abs() > polys[1].area.abs());
+    }
+}
@@ -0,0 +1,343 @@
+mod mvt;
+mod tilecache;
+
+pub use crate::serve::mvt::{make_mvt_tile, MvtValue, TileTransform, EXTENT};
+pub use crate::serve::tilecache::{TileCache, TileFormat, TileKey};
+
+use crate::antimeridian::bounds_union;
+use crate::elements::GeoJsonable;
+use crate::postgresql::{AllocFunc, PostgresqlConnection, PostgresqlOptions, TableSpec};
+use crate::{Error, GeometryBlock, GeometryFileReader, GeometryStyle, Result};
+
+use osmquadtree::elements::{coordinate_as_float, Bbox};
+use osmquadtree::message;
+
+use serde_json::{json, Map, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//the highest zoom ServeOptions.max_zoom may be set to
+pub const MAX_ZOOM: u32 = 24;
+
+//connections waiting for a worker, for each worker, before new
+//connections are turned away
+const QUEUE_PER_WORKER: usize = 4;
+
+pub struct ServeOptions {
+    pub addr: String,
+    pub name: String,
+    pub cache_size: usize,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub table_spec: Vec<TableSpec>,
+    pub table_alloc: AllocFunc,
+    //number of threads answering requests, each with its own file reader
+    pub num_workers: usize,
+    //read and write timeout for each connection
+    pub timeout: Duration,
+    //for files without minzoom values, tiles whose blocks hold more
+    //objects than this are refused rather than read into memory
+    pub max_tile_objects: usize,
+}
+
+impl ServeOptions {
+    //layers and properties follow the postgresql table spec for style
+    pub fn new(addr: &str, name: &str, style: &GeometryStyle, extended: bool) -> ServeOptions {
+        let po = if extended {
+            PostgresqlOptions::extended(PostgresqlConnection::Null, style)
+        } else {
+            PostgresqlOptions::osm2pgsql(PostgresqlConnection::Null, style)
+        };
+        ServeOptions {
+            addr: String::from(addr),
+            name: String::from(name),
+            cache_size: 1000,
+            min_zoom: 0,
+            max_zoom: 14,
+            table_spec: po.table_spec,
+            table_alloc: po.table_alloc,
+            num_workers: 4,
+            timeout: Duration::from_secs(30),
+            max_tile_objects: 1000000,
+        }
+    }
+}
+
+//each thread serving tiles needs its own reader, from reader().try_clone()
+pub struct TileServer {
+    reader: GeometryFileReader,
+    cache: Mutex<TileCache>,
+    options: ServeOptions,
+    bounds: Bbox,
+    has_minzoom: bool,
+}
+
+fn http_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn parse_tile_path(path: &str, max_zoom: u32) -> Option<(u32, u32, u32, TileFormat)> {
+    let (rest, format) = if let Some(r) = path.strip_suffix(".mvt") {
+        (r, TileFormat::Mvt)
+    } else if let Some(r) = path.strip_suffix(".pbf") {
+        (r, TileFormat::Mvt)
+    } else if let Some(r) = path.strip_suffix(".geojson") {
+        (r, TileFormat::GeoJson)
+    } else {
+        return None;
+    };
+    let parts: Vec<&str> = rest.trim_start_matches('/').split('/').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let z: u32 = parts[0].parse().ok()?;
+    let x: u32 = parts[1].parse().ok()?;
+    let y: u32 = parts[2].parse().ok()?;
+    if z > max_zoom || x >= (1 << z) || y >= (1 << z) {
+        return None;
+    }
+    Some((z, x, y, format))
+}
+
+fn pack_geojson_tile(bl: &GeometryBlock) -> Result<Vec<u8>> {
+    let mut feats = Vec::with_capacity(bl.points.len() + bl.linestrings.len() + bl.simple_polygons.len() + bl.complicated_polygons.len());
+    for p in &bl.points {
+        feats.push(p.to_geojson(false)?);
+    }
+    for p in &bl.linestrings {
+        feats.push(p.to_geojson(false)?);
+    }
+    for p in &bl.simple_polygons {
+        feats.push(p.to_geojson(false)?);
+    }
+    for p in &bl.complicated_polygons {
+        feats.push(p.to_geojson(false)?);
+    }
+    Ok(serde_json::to_vec(&json!({"type": "FeatureCollection", "features": feats}))?)
+}
+
+impl TileServer {
+    pub fn new(fname: &str, options: ServeOptions, numchan: usize) -> Result<TileServer> {
+        if options.max_zoom > MAX_ZOOM {
+            return Err(Error::UserSelectionError(format!("max_zoom {} is greater than {}", options.max_zoom, MAX_ZOOM)));
+        }
+        let reader = GeometryFileReader::open(fname, numchan)?;
+        let bxs: Vec<Bbox> = reader.index().entries.iter().filter(|e| e.num_objects > 0).map(|e| e.bounds()).collect();
+        let bounds = bounds_union(&bxs);
+        let has_minzoom = reader.index().entries.iter().any(|e| !e.minzoom.is_none());
+        if !has_minzoom {
+            message!("{} has no minzoom values: all objects will be included in every tile", fname);
+        }
+        Ok(TileServer {
+            reader: reader,
+            cache: Mutex::new(TileCache::new(options.cache_size)),
+            options: options,
+            bounds: bounds,
+            has_minzoom: has_minzoom,
+        })
+    }
+
+    pub fn reader(&self) -> &GeometryFileReader {
+        &self.reader
+    }
+
+    fn make_tile(&self, reader: &mut GeometryFileReader, z: u32, x: u32, y: u32, format: TileFormat) -> Result<Vec<u8>> {
+        let tf = TileTransform::new(z, x, y);
+        let max_minzoom = if self.has_minzoom { Some(z as i64) } else { None };
+        if !self.has_minzoom {
+            let bbox = tf.bbox();
+            let num_objects: usize = reader.index().entries.iter().filter(|e| e.check(&bbox, &None)).map(|e| e.num_objects).sum();
+            if num_objects > self.options.max_tile_objects {
+                return Err(Error::UserSelectionError(format!(
+                    "tile {}/{}/{} covers {} objects, more than {}: use a file with minzoom values",
+                    z, x, y, num_objects, self.options.max_tile_objects
+                )));
+            }
+        }
+        let bl = reader.query(&tf.bbox(), max_minzoom)?;
+        match format {
+            TileFormat::Mvt => make_mvt_tile(&bl, &tf, &self.options.table_spec, &self.options.table_alloc),
+            TileFormat::GeoJson => pack_geojson_tile(&bl),
+        }
+    }
+
+    pub fn tile(&self, reader: &mut GeometryFileReader, z: u32, x: u32, y: u32, format: TileFormat) -> Result<Arc<Vec<u8>>> {
+        let key = (z, x, y, format);
+        if let Some(t) = self.cache.lock().unwrap().get(&key) {
+            return Ok(t);
+        }
+        let t = Arc::new(self.make_tile(reader, z, x, y, format)?);
+        self.cache.lock().unwrap().insert(key, t.clone());
+        Ok(t)
+    }
+
+    pub fn tilejson(&self, host: &str) -> Value {
+        let mut layers = Vec::new();
+        for t in &self.options.table_spec {
+            let mut fields = Map::new();
+            for (n, src, _) in &t.columns {
+                if let Some(ft) = mvt::column_field_type(src) {
+                    fields.insert(n.clone(), json!(ft));
+                }
+            }
+            layers.push(json!({"id": t.name, "fields": fields}));
+        }
+        let b = &self.bounds;
+        let bounds = (
+            coordinate_as_float(b.minlon),
+            coordinate_as_float(b.minlat),
+            coordinate_as_float(b.maxlon),
+            coordinate_as_float(b.maxlat),
+        );
+        json!({
+            "tilejson": "2.2.0",
+            "name": self.options.name,
+            "scheme": "xyz",
+            "format": "pbf",
+            "tiles": [format!("http://{}/{{z}}/{{x}}/{{y}}.mvt", host)],
+            "minzoom": self.options.min_zoom,
+            "maxzoom": self.options.max_zoom,
+            "bounds": bounds,
+            "vector_layers": layers,
+        })
+    }
+
+    fn handle(&self, reader: &mut GeometryFileReader, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        let mut host = self.options.addr.clone();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                if k.trim().eq_ignore_ascii_case("host") {
+                    host = String::from(v.trim());
+                }
+            }
+        }
+
+        let parts: Vec<&str> = request.split_whitespace().collect();
+        if parts.len() < 2 || parts[0] != "GET" {
+            return http_response(&mut stream, "405 Method Not Allowed", "text/plain", b"only GET is supported");
+        }
+        let path = parts[1].split('?').next().unwrap_or("");
+
+        if path == "/" || path == "/tilejson.json" {
+            let tj = serde_json::to_vec(&self.tilejson(&host))?;
+            return http_response(&mut stream, "200 OK", "application/json", &tj);
+        }
+        match parse_tile_path(path, self.options.max_zoom) {
+            None => http_response(&mut stream, "404 Not Found", "text/plain", b"not found"),
+            Some((z, x, y, format)) => match self.tile(reader, z, x, y, format) {
+                Ok(t) => {
+                    let ct = match format {
+                        TileFormat::Mvt => "application/vnd.mapbox-vector-tile",
+                        TileFormat::GeoJson => "application/geo+json",
+                    };
+                    http_response(&mut stream, "200 OK", ct, &t)
+                }
+                Err(e) => {
+                    message!("tile {}/{}/{} failed: {}", z, x, y, e);
+                    http_response(&mut stream, "500 Internal Server Error", "text/plain", format!("{}", e).as_bytes())
+                }
+            },
+        }
+    }
+}
+
+fn run_worker(server: Arc<TileServer>, mut reader: GeometryFileReader, streams: Arc<Mutex<Receiver<TcpStream>>>) {
+    loop {
+        let stream = match streams.lock().unwrap().recv() {
+            Ok(s) => s,
+            Err(_) => {
+                return;
+            }
+        };
+        if let Err(e) = server.handle(&mut reader, stream) {
+            message!("request failed: {}", e);
+        }
+    }
+}
+
+//serves /{z}/{x}/{y}.mvt, /{z}/{x}/{y}.geojson and /tilejson.json from a
+//sorted geometry pbffile until the process is stopped. Requests are
+//answered by options.num_workers threads, and connections arriving while
+//too many are waiting are refused.
+pub fn serve_geometry(fname: &str, options: ServeOptions, numchan: usize) -> Result<()> {
+    let addr = options.addr.clone();
+    let timeout = options.timeout;
+    let num_workers = usize::max(options.num_workers, 1);
+    let server = Arc::new(TileServer::new(fname, options, numchan)?);
+    let listener = TcpListener::bind(&addr)
+        .or_else(|e| Err(Error::UserSelectionError(format!("can't listen on {}: {}", addr, e))))?;
+
+    let (sender, receiver) = sync_channel(num_workers * QUEUE_PER_WORKER);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..num_workers {
+        let reader = server.reader().try_clone()?;
+        let (sv, rv) = (server.clone(), receiver.clone());
+        std::thread::spawn(move || run_worker(sv, reader, rv));
+    }
+
+    message!("serving {} at http://{}/tilejson.json, with {} workers", fname, addr, num_workers);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))?;
+                match sender.try_send(s) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(mut s)) => {
+                        let _ = http_response(&mut s, "503 Service Unavailable", "text/plain", b"too many requests");
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        return Err(Error::InvalidDataError(String::from("tile workers stopped")));
+                    }
+                }
+            }
+            Err(e) => {
+                message!("connection failed: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_paths() {
+        assert_eq!(parse_tile_path("/3/2/5.mvt", 14), Some((3, 2, 5, TileFormat::Mvt)));
+        assert_eq!(parse_tile_path("/3/2/5.pbf", 14), Some((3, 2, 5, TileFormat::Mvt)));
+        assert_eq!(parse_tile_path("/0/0/0.geojson", 14), Some((0, 0, 0, TileFormat::GeoJson)));
+        assert_eq!(parse_tile_path("/3/2/5.png", 14), None);
+        assert_eq!(parse_tile_path("/3/2.mvt", 14), None);
+        assert_eq!(parse_tile_path("/a/2/5.mvt", 14), None);
+        assert_eq!(parse_tile_path("/3/2/5/1.mvt", 14), None);
+    }
+
+    #[test]
+    fn tile_paths_out_of_range() {
+        assert_eq!(parse_tile_path("/15/0/0.mvt", 14), None);
+        assert_eq!(parse_tile_path("/3/8/0.mvt", 14), None);
+        assert_eq!(parse_tile_path("/3/0/8.mvt", 14), None);
+        assert_eq!(parse_tile_path("/3/7/7.mvt", 14), Some((3, 7, 7, TileFormat::Mvt)));
+    }
+}
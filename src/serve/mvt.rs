@@ -0,0 +1,541 @@
+use crate::antimeridian::{
+    crosses_antimeridian, polygon_crosses_antimeridian, split_line_at_antimeridian,
+    split_polygon_at_antimeridian,
+};
+use crate::postgresql::{AllocFunc, ColumnSource, GeometryType, TableSpec};
+use crate::{resolve_localized_name, GeometryBlock, LonLat, Result, XY};
+
+use osmquadtree::elements::{Bbox, Tag, EARTH_WIDTH};
+use simple_protocolbuffers::{pack_data, pack_value, zig_zag};
+
+use std::collections::BTreeMap;
+
+//mapbox vector tile encoding, following
+//https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+
+pub const EXTENT: u32 = 4096;
+const BUFFER: f64 = 64.0;
+
+pub struct TileTransform {
+    x0: f64,
+    y0: f64,
+    size: f64,
+}
+
+impl TileTransform {
+    pub fn new(z: u32, x: u32, y: u32) -> TileTransform {
+        let size = 2.0 * EARTH_WIDTH / f64::powi(2.0, z as i32);
+        TileTransform {
+            x0: -EARTH_WIDTH + (x as f64) * size,
+            y0: EARTH_WIDTH - (y as f64) * size,
+            size: size,
+        }
+    }
+
+    //tile area, plus a buffer of BUFFER pixels
+    pub fn bbox(&self) -> Bbox {
+        let b = self.size * BUFFER / (EXTENT as f64);
+        let clamp = |v: f64| f64::max(-EARTH_WIDTH, f64::min(EARTH_WIDTH, v));
+        let a = LonLat::backward(&XY::from((clamp(self.x0 - b), clamp(self.y0 - self.size - b))));
+        let c = LonLat::backward(&XY::from((clamp(self.x0 + self.size + b), clamp(self.y0 + b))));
+        Bbox::new(a.lon, a.lat, c.lon, c.lat)
+    }
+
+    fn tile_coords(&self, l: &LonLat) -> (f64, f64) {
+        let xy = l.forward();
+        let s = (EXTENT as f64) / self.size;
+        ((xy.x - self.x0) * s, (self.y0 - xy.y) * s)
+    }
+
+    fn apply(&self, l: &LonLat) -> (i64, i64) {
+        round_point(&self.tile_coords(l))
+    }
+
+    //lines are clipped to the tile area plus buffer, which may split them
+    fn apply_line(&self, lonlats: &[LonLat]) -> Vec<Vec<(i64, i64)>> {
+        let pts: Vec<(f64, f64)> = lonlats.iter().map(|l| self.tile_coords(l)).collect();
+        clip_line(&pts).iter().map(|p| round_points(p)).collect()
+    }
+
+    fn apply_ring(&self, lonlats: &[LonLat]) -> Vec<(i64, i64)> {
+        let mut pts: Vec<(f64, f64)> = lonlats.iter().map(|l| self.tile_coords(l)).collect();
+        if pts.len() > 1 && pts[0] == pts[pts.len() - 1] {
+            pts.pop();
+        }
+        round_points(&clip_ring(&pts))
+    }
+}
+
+fn round_point(p: &(f64, f64)) -> (i64, i64) {
+    (f64::round(p.0) as i64, f64::round(p.1) as i64)
+}
+
+fn round_points(pts: &[(f64, f64)]) -> Vec<(i64, i64)> {
+    let mut res: Vec<(i64, i64)> = pts.iter().map(round_point).collect();
+    res.dedup();
+    res
+}
+
+const CLIP_MIN: f64 = -BUFFER;
+const CLIP_MAX: f64 = EXTENT as f64 + BUFFER;
+
+//liang-barsky. Returns the clipped segment, and whether it leaves the clip
+//area before b.
+fn clip_segment(a: (f64, f64), b: (f64, f64)) -> Option<((f64, f64), (f64, f64), bool)> {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let (mut t0, mut t1) = (0.0, 1.0);
+    for (p, q) in [(-dx, a.0 - CLIP_MIN), (dx, CLIP_MAX - a.0), (-dy, a.1 - CLIP_MIN), (dy, CLIP_MAX - a.1)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let r = q / p;
+            if p < 0.0 {
+                if r > t1 {
+                    return None;
+                }
+                t0 = f64::max(t0, r);
+            } else {
+                if r < t0 {
+                    return None;
+                }
+                t1 = f64::min(t1, r);
+            }
+        }
+    }
+    Some(((a.0 + t0 * dx, a.1 + t0 * dy), (a.0 + t1 * dx, a.1 + t1 * dy), t1 < 1.0))
+}
+
+fn clip_line(pts: &[(f64, f64)]) -> Vec<Vec<(f64, f64)>> {
+    let mut res = Vec::new();
+    let mut curr: Vec<(f64, f64)> = Vec::new();
+    for w in pts.windows(2) {
+        match clip_segment(w[0], w[1]) {
+            None => {
+                if curr.len() > 1 {
+                    res.push(std::mem::take(&mut curr));
+                } else {
+                    curr.clear();
+                }
+            }
+            Some((a, b, leaves)) => {
+                if curr.is_empty() {
+                    curr.push(a);
+                }
+                curr.push(b);
+                if leaves {
+                    res.push(std::mem::take(&mut curr));
+                }
+            }
+        }
+    }
+    if curr.len() > 1 {
+        res.push(curr);
+    }
+    res
+}
+
+//sutherland-hodgman, against each edge of the clip area in turn
+fn clip_ring(pts: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let mut res = pts.to_vec();
+    for edge in 0..4 {
+        if res.is_empty() {
+            break;
+        }
+        let inside = |p: &(f64, f64)| match edge {
+            0 => p.0 >= CLIP_MIN,
+            1 => p.0 <= CLIP_MAX,
+            2 => p.1 >= CLIP_MIN,
+            _ => p.1 <= CLIP_MAX,
+        };
+        let crossing = |a: &(f64, f64), b: &(f64, f64)| {
+            let c = if edge % 2 == 0 { CLIP_MIN } else { CLIP_MAX };
+            if edge < 2 {
+                (c, a.1 + (c - a.0) * (b.1 - a.1) / (b.0 - a.0))
+            } else {
+                (a.0 + (c - a.1) * (b.0 - a.0) / (b.1 - a.1), c)
+            }
+        };
+        let input = std::mem::take(&mut res);
+        let mut prev = input[input.len() - 1];
+        for p in &input {
+            if inside(p) {
+                if !inside(&prev) {
+                    res.push(crossing(&prev, p));
+                }
+                res.push(*p);
+            } else if inside(&prev) {
+                res.push(crossing(&prev, p));
+            }
+            prev = *p;
+        }
+    }
+    res
+}
+
+fn command(id: u32, count: usize) -> u32 {
+    (id & 7) | ((count as u32) << 3)
+}
+
+fn zz(v: i64) -> u32 {
+    ((v << 1) ^ (v >> 63)) as u32
+}
+
+struct GeometryEncoder {
+    cmds: Vec<u32>,
+    cursor: (i64, i64),
+}
+
+impl GeometryEncoder {
+    fn new() -> GeometryEncoder {
+        GeometryEncoder {
+            cmds: Vec::new(),
+            cursor: (0, 0),
+        }
+    }
+
+    fn add_coordinate(&mut self, p: &(i64, i64)) {
+        self.cmds.push(zz(p.0 - self.cursor.0));
+        self.cmds.push(zz(p.1 - self.cursor.1));
+        self.cursor = *p;
+    }
+
+    fn add_point(&mut self, p: &(i64, i64)) {
+        self.cmds.push(command(1, 1));
+        self.add_coordinate(p);
+    }
+
+    fn add_line(&mut self, pts: &[(i64, i64)]) {
+        if pts.len() < 2 {
+            return;
+        }
+        self.cmds.push(command(1, 1));
+        self.add_coordinate(&pts[0]);
+        self.cmds.push(command(2, pts.len() - 1));
+        for p in &pts[1..] {
+            self.add_coordinate(p);
+        }
+    }
+
+    //exterior rings must have positive area in tile coordinates, interior
+    //rings negative area
+    fn add_ring(&mut self, pts: &[(i64, i64)], exterior: bool) -> bool {
+        let mut pts = pts.to_vec();
+        if pts.len() > 1 && pts[0] == pts[pts.len() - 1] {
+            pts.pop();
+        }
+        if pts.len() < 3 {
+            return false;
+        }
+        let a = ring_area(&pts);
+        if a == 0 {
+            return false;
+        }
+        if (a > 0) != exterior {
+            pts.reverse();
+        }
+        self.cmds.push(command(1, 1));
+        self.add_coordinate(&pts[0]);
+        self.cmds.push(command(2, pts.len() - 1));
+        for p in &pts[1..] {
+            self.add_coordinate(p);
+        }
+        self.cmds.push(command(7, 1));
+        true
+    }
+
+    fn add_polygon(&mut self, tf: &TileTransform, rings: &[Vec<LonLat>]) {
+        if rings.is_empty() {
+            return;
+        }
+        if !self.add_ring(&tf.apply_ring(&rings[0]), true) {
+            return;
+        }
+        for ii in &rings[1..] {
+            self.add_ring(&tf.apply_ring(ii), false);
+        }
+    }
+}
+
+fn ring_area(pts: &[(i64, i64)]) -> i64 {
+    let mut a = 0;
+    for i in 0..pts.len() {
+        let p = pts[i];
+        let q = pts[(i + 1) % pts.len()];
+        a += p.0 * q.1 - q.0 * p.1;
+    }
+    a
+}
+
+fn write_varint(res: &mut Vec<u8>, mut v: u64) {
+    while v >= 128 {
+        res.push(((v & 127) | 128) as u8);
+        v >>= 7;
+    }
+    res.push(v as u8);
+}
+
+fn pack_packed(vals: &[u32]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(vals.len() * 2);
+    for v in vals {
+        write_varint(&mut res, *v as u64);
+    }
+    res
+}
+
+#[derive(Debug, Clone)]
+pub enum MvtValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+}
+
+impl MvtValue {
+    fn pack(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        match self {
+            MvtValue::String(s) => pack_data(&mut res, 1, s.as_bytes()),
+            MvtValue::Double(d) => {
+                //field 3, wire type 1 (64 bit)
+                res.push(25);
+                res.extend(d.to_bits().to_le_bytes());
+            }
+            MvtValue::Int(i) => pack_value(&mut res, 6, zig_zag(*i)),
+        }
+        res
+    }
+}
+
+pub fn column_field_type(src: &ColumnSource) -> Option<&'static str> {
+    match src {
+        ColumnSource::Tag | ColumnSource::LocalizedName { .. } => Some("String"),
+        ColumnSource::OsmId
+        | ColumnSource::Layer
+        | ColumnSource::ZOrder
+        | ColumnSource::MinZoom
+        | ColumnSource::Length
+        | ColumnSource::Area => Some("Number"),
+        _ => None,
+    }
+}
+
+struct FeatureProps<'a> {
+    id: i64,
+    tags: &'a [Tag],
+    layer: Option<i64>,
+    z_order: Option<i64>,
+    minzoom: Option<i64>,
+    length: Option<f64>,
+    area: Option<f64>,
+}
+
+fn feature_properties(spec: &TableSpec, fp: &FeatureProps) -> Vec<(String, MvtValue)> {
+    let mut res = Vec::new();
+    for (name, src, _) in &spec.columns {
+        let v = match src {
+            ColumnSource::OsmId => Some(MvtValue::Int(fp.id)),
+            ColumnSource::Tag => fp
+                .tags
+                .iter()
+                .find(|t| &t.key == name)
+                .map(|t| MvtValue::String(t.val.clone())),
+            ColumnSource::LocalizedName { lang, fallback, transliterate } => {
+                resolve_localized_name(lang, fallback, *transliterate, fp.tags).map(MvtValue::String)
+            }
+            ColumnSource::Layer => fp.layer.map(MvtValue::Int),
+            ColumnSource::ZOrder => fp.z_order.map(MvtValue::Int),
+            ColumnSource::MinZoom => fp.minzoom.map(MvtValue::Int),
+            ColumnSource::Length => fp.length.map(MvtValue::Double),
+            ColumnSource::Area => fp.area.map(MvtValue::Double),
+            _ => None,
+        };
+        match v {
+            None => {}
+            Some(v) => {
+                res.push((name.clone(), v));
+            }
+        }
+    }
+    res
+}
+
+struct LayerBuilder {
+    keys: Vec<String>,
+    key_idx: BTreeMap<String, u32>,
+    values: Vec<Vec<u8>>,
+    value_idx: BTreeMap<Vec<u8>, u32>,
+    features: Vec<Vec<u8>>,
+}
+
+impl LayerBuilder {
+    fn new() -> LayerBuilder {
+        LayerBuilder {
+            keys: Vec::new(),
+            key_idx: BTreeMap::new(),
+            values: Vec::new(),
+            value_idx: BTreeMap::new(),
+            features: Vec::new(),
+        }
+    }
+
+    fn add_feature(&mut self, id: i64, geom_type: u64, geom: GeometryEncoder, props: Vec<(String, MvtValue)>) {
+        if geom.cmds.is_empty() {
+            return;
+        }
+        let mut tags = Vec::with_capacity(props.len() * 2);
+        for (k, v) in props {
+            let ki = match self.key_idx.get(&k) {
+                Some(i) => *i,
+                None => {
+                    let i = self.keys.len() as u32;
+                    self.key_idx.insert(k.clone(), i);
+                    self.keys.push(k);
+                    i
+                }
+            };
+            let pv = v.pack();
+            let vi = match self.value_idx.get(&pv) {
+                Some(i) => *i,
+                None => {
+                    let i = self.values.len() as u32;
+                    self.value_idx.insert(pv.clone(), i);
+                    self.values.push(pv);
+                    i
+                }
+            };
+            tags.push(ki);
+            tags.push(vi);
+        }
+
+        let mut res = Vec::new();
+        if id > 0 {
+            pack_value(&mut res, 1, id as u64);
+        }
+        if !tags.is_empty() {
+            pack_data(&mut res, 2, &pack_packed(&tags));
+        }
+        pack_value(&mut res, 3, geom_type);
+        pack_data(&mut res, 4, &pack_packed(&geom.cmds));
+        self.features.push(res);
+    }
+
+    fn pack(&self, name: &str) -> Vec<u8> {
+        let mut res = Vec::new();
+        pack_value(&mut res, 15, 2);
+        pack_data(&mut res, 1, name.as_bytes());
+        for f in &self.features {
+            pack_data(&mut res, 2, f);
+        }
+        for k in &self.keys {
+            pack_data(&mut res, 3, k.as_bytes());
+        }
+        for v in &self.values {
+            pack_data(&mut res, 4, v);
+        }
+        pack_value(&mut res, 5, EXTENT as u64);
+        res
+    }
+}
+
+fn line_parts(lonlats: &[LonLat]) -> Vec<Vec<LonLat>> {
+    if crosses_antimeridian(lonlats) {
+        split_line_at_antimeridian(lonlats)
+    } else {
+        vec![lonlats.to_vec()]
+    }
+}
+
+fn polygon_parts(rings: Vec<Vec<LonLat>>) -> Vec<Vec<Vec<LonLat>>> {
+    if polygon_crosses_antimeridian(&rings) {
+        split_polygon_at_antimeridian(&rings)
+    } else {
+        vec![rings]
+    }
+}
+
+//one layer for each table in table_spec, with objects allocated to tables
+//as for the postgresql output
+pub fn make_mvt_tile(
+    bl: &GeometryBlock,
+    tf: &TileTransform,
+    table_spec: &Vec<TableSpec>,
+    table_alloc: &AllocFunc,
+) -> Result<Vec<u8>> {
+    let mut layers: Vec<LayerBuilder> = table_spec.iter().map(|_| LayerBuilder::new()).collect();
+
+    for p in &bl.points {
+        for i in table_alloc(&GeometryType::Point(p)) {
+            let mut ge = GeometryEncoder::new();
+            ge.add_point(&tf.apply(&p.lonlat));
+            let fp = FeatureProps { id: p.id, tags: &p.tags, layer: p.layer, z_order: None, minzoom: p.minzoom, length: None, area: None };
+            layers[i].add_feature(p.id, 1, ge, feature_properties(&table_spec[i], &fp));
+        }
+    }
+    for p in &bl.linestrings {
+        for i in table_alloc(&GeometryType::Linestring(p)) {
+            let mut ge = GeometryEncoder::new();
+            for ll in line_parts(&p.lonlats) {
+                for pp in tf.apply_line(&ll) {
+                    ge.add_line(&pp);
+                }
+            }
+            let fp = FeatureProps { id: p.id, tags: &p.tags, layer: p.layer, z_order: p.z_order, minzoom: p.minzoom, length: Some(p.length), area: None };
+            layers[i].add_feature(p.id, 2, ge, feature_properties(&table_spec[i], &fp));
+        }
+    }
+    for p in &bl.simple_polygons {
+        for i in table_alloc(&GeometryType::SimplePolygon(p)) {
+            let mut ge = GeometryEncoder::new();
+            for rings in polygon_parts(vec![p.lonlats.clone()]) {
+                ge.add_polygon(tf, &rings);
+            }
+            let fp = FeatureProps { id: p.id, tags: &p.tags, layer: p.layer, z_order: p.z_order, minzoom: p.minzoom, length: None, area: Some(p.area) };
+            layers[i].add_feature(p.id, 3, ge, feature_properties(&table_spec[i], &fp));
+        }
+    }
+    for p in &bl.complicated_polygons {
+        for i in table_alloc(&GeometryType::ComplicatedPolygon(p)) {
+            let mut ge = GeometryEncoder::new();
+            for pp in &p.parts {
+                for rings in polygon_parts(pp.rings()?) {
+                    ge.add_polygon(tf, &rings);
+                }
+            }
+            //relations are written with negative ids, as for the postgresql output
+            let fp = FeatureProps { id: -p.id, tags: &p.tags, layer: p.layer, z_order: p.z_order, minzoom: p.minzoom, length: None, area: Some(p.area) };
+            layers[i].add_feature(-p.id, 3, ge, feature_properties(&table_spec[i], &fp));
+        }
+    }
+
+    let mut res = Vec::new();
+    for (l, t) in layers.iter().zip(table_spec) {
+        if !l.features.is_empty() {
+            pack_data(&mut res, 3, &l.pack(&t.name));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_line_splits_at_tile_edge() {
+        let c = CLIP_MAX;
+        let parts = clip_line(&[(c - 64.0, 100.0), (c + 64.0, 100.0), (c + 64.0, 200.0), (c - 64.0, 200.0)]);
+        assert_eq!(parts, vec![vec![(c - 64.0, 100.0), (c, 100.0)], vec![(c, 200.0), (c - 64.0, 200.0)]]);
+    }
+
+    #[test]
+    fn clip_ring_to_tile() {
+        let ring = clip_ring(&[(-1e9, -1e9), (1e9, -1e9), (1e9, 1e9), (-1e9, 1e9)]);
+        assert_eq!(ring.len(), 4);
+        for p in &ring {
+            assert!(p.0 == CLIP_MIN || p.0 == CLIP_MAX);
+            assert!(p.1 == CLIP_MIN || p.1 == CLIP_MAX);
+        }
+        assert!(clip_ring(&[(-1e9, 0.0), (-1e9 + 1.0, 0.0), (-1e9, 1.0)]).is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TileFormat {
+    Mvt,
+    GeoJson,
+}
+
+pub type TileKey = (u32, u32, u32, TileFormat);
+
+//least recently used tiles are dropped once capacity is reached
+pub struct TileCache {
+    capacity: usize,
+    tiles: HashMap<TileKey, (u64, Arc<Vec<u8>>)>,
+    ages: BTreeMap<u64, TileKey>,
+    tick: u64,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl TileCache {
+    pub fn new(capacity: usize) -> TileCache {
+        TileCache {
+            capacity: capacity,
+            tiles: HashMap::new(),
+            ages: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn get(&mut self, key: &TileKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        match self.tiles.get_mut(key) {
+            None => {
+                self.misses += 1;
+                None
+            }
+            Some((age, data)) => {
+                self.ages.remove(age);
+                *age = self.tick;
+                self.ages.insert(self.tick, key.clone());
+                self.hits += 1;
+                Some(data.clone())
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: TileKey, data: Arc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((age, _)) = self.tiles.remove(&key) {
+            self.ages.remove(&age);
+        }
+        while self.tiles.len() >= self.capacity {
+            let oldest = match self.ages.keys().next() {
+                Some(a) => *a,
+                None => break,
+            };
+            if let Some(k) = self.ages.remove(&oldest) {
+                self.tiles.remove(&k);
+            }
+        }
+        self.ages.insert(self.tick, key.clone());
+        self.tiles.insert(key, (self.tick, data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(x: u32) -> TileKey {
+        (10, x, 0, TileFormat::Mvt)
+    }
+
+    fn tile(x: u8) -> Arc<Vec<u8>> {
+        Arc::new(vec![x])
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TileCache::new(2);
+        cache.insert(key(1), tile(1));
+        cache.insert(key(2), tile(2));
+        //key(1) is now more recently used than key(2)
+        assert_eq!(cache.get(&key(1)), Some(tile(1)));
+        cache.insert(key(3), tile(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key(2)), None);
+        assert_eq!(cache.get(&key(1)), Some(tile(1)));
+        assert_eq!(cache.get(&key(3)), Some(tile(3)));
+        assert_eq!((cache.hits, cache.misses), (3, 1));
+    }
+
+    #[test]
+    fn replacing_a_tile_keeps_one_copy() {
+        let mut cache = TileCache::new(2);
+        cache.insert(key(1), tile(1));
+        cache.insert(key(1), tile(5));
+        cache.insert(key(2), tile(2));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key(1)), Some(tile(5)));
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut cache = TileCache::new(0);
+        cache.insert(key(1), tile(1));
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.get(&key(1)), None);
+    }
+}
@@ -1,11 +1,17 @@
 use osmquadtree::elements::Tag;
 use crate::default_style::DEFAULT_GEOMETRY_STYLE;
+use crate::keymatch::KeyPatterns;
+use crate::localizednames::LocalizedNameSpec;
+use crate::transform::TagTransform;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use std::collections::{BTreeMap, BTreeSet};
 
 use std::fs::File;
 use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::OnceLock;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -175,25 +181,334 @@ fn get_zorder_value(t: &Tag) -> Option<i64> {
     return None;
 }*/
 
+//limits chains of extends, which also catches cycles
+const MAX_EXTENDS_DEPTH: usize = 16;
+
+const MERGE_SET_FIELDS: [&str; 3] = ["feature_keys", "other_keys", "drop_keys"];
+const MERGE_MAP_FIELDS: [&str; 3] = ["polygon_tags", "z_order_spec", "parent_tags"];
+
+fn style_error(msg: String) -> Error {
+    Error::new(ErrorKind::Other, msg)
+}
+
+//a field given as {"add": .., "remove": [..]} is merged with the base
+//value, rather than replacing it
+fn is_merge_spec(v: &Value) -> bool {
+    match v.as_object() {
+        Some(o) => !o.is_empty() && o.keys().all(|k| k == "add" || k == "remove"),
+        None => false,
+    }
+}
+
+fn removed_strings(field: &str, spec: &Value) -> Result<Vec<String>> {
+    match spec.get("remove") {
+        None => Ok(Vec::new()),
+        Some(r) => serde_json::from_value(r.clone())
+            .or_else(|_| Err(style_error(format!("{}.remove must be a list of strings", field)))),
+    }
+}
+
+fn merge_field(field: &str, base: Value, spec: &Value) -> Result<Value> {
+    let remove = removed_strings(field, spec)?;
+    let add = spec.get("add");
+
+    if MERGE_SET_FIELDS.contains(&field) {
+        let mut vals: Vec<Value> = match base {
+            Value::Array(a) => a,
+            Value::Null => {
+                return Err(style_error(format!("can't merge {} with null (all keys) in base style", field)));
+            }
+            _ => { return Err(style_error(format!("base {} is not a list", field))); }
+        };
+        vals.retain(|v| !v.as_str().map_or(false, |s| remove.iter().any(|r| r == s)));
+        match add {
+            None => {}
+            Some(Value::Array(a)) => {
+                for v in a {
+                    if !vals.contains(v) {
+                        vals.push(v.clone());
+                    }
+                }
+            }
+            Some(_) => { return Err(style_error(format!("{}.add must be a list", field))); }
+        }
+        Ok(Value::Array(vals))
+    } else if MERGE_MAP_FIELDS.contains(&field) {
+        let mut vals = match base {
+            Value::Object(o) => o,
+            _ => { return Err(style_error(format!("base {} is not a map", field))); }
+        };
+        for r in &remove {
+            vals.remove(r);
+        }
+        match add {
+            None => {}
+            Some(Value::Object(a)) => {
+                for (k, v) in a {
+                    vals.insert(k.clone(), v.clone());
+                }
+            }
+            Some(_) => { return Err(style_error(format!("{}.add must be a map", field))); }
+        }
+        Ok(Value::Object(vals))
+    } else if field == "relation_tag_spec" {
+        //entries are removed by target_key
+        let mut vals = match base {
+            Value::Array(a) => a,
+            _ => { return Err(style_error(format!("base {} is not a list", field))); }
+        };
+        vals.retain(|v| {
+            !v.get("target_key")
+                .and_then(|t| t.as_str())
+                .map_or(false, |s| remove.iter().any(|r| r == s))
+        });
+        match add {
+            None => {}
+            Some(Value::Array(a)) => vals.extend(a.iter().cloned()),
+            Some(_) => { return Err(style_error(format!("{}.add must be a list", field))); }
+        }
+        Ok(Value::Array(vals))
+    } else {
+        Err(style_error(format!("{} can't be merged, only replaced", field)))
+    }
+}
+
+//replaces a style with an "extends" field (either "default" or the path of
+//another style file, relative to dir) with the merged base style
+fn resolve_style(value: Value, dir: &Path, depth: usize) -> Result<Value> {
+    let mut obj = match value {
+        Value::Object(o) => o,
+        _ => { return Err(style_error(String::from("style must be a json object"))); }
+    };
+    let base = match obj.remove("extends") {
+        None => { return Ok(Value::Object(obj)); }
+        Some(Value::String(b)) => b,
+        Some(_) => { return Err(style_error(String::from("extends must be \"default\" or a filename"))); }
+    };
+    if depth >= MAX_EXTENDS_DEPTH {
+        return Err(style_error(format!("more than {} levels of extends", MAX_EXTENDS_DEPTH)));
+    }
+    let base = if base == "default" {
+        serde_json::from_str::<Value>(&DEFAULT_GEOMETRY_STYLE).expect("!!")
+    } else {
+        read_style_file_depth(&dir.join(&base), depth + 1)?
+    };
+    let mut res = match base {
+        Value::Object(o) => o,
+        _ => { return Err(style_error(String::from("base style must be a json object"))); }
+    };
+    for (k, v) in obj {
+        let merged = if is_merge_spec(&v) {
+            merge_field(&k, res.remove(&k).unwrap_or(Value::Null), &v)?
+        } else {
+            v
+        };
+        res.insert(k, merged);
+    }
+    Ok(Value::Object(res))
+}
+
+fn read_style_file_depth(infn: &Path, depth: usize) -> Result<Value> {
+    let ff = File::open(infn).or_else(|e| Err(style_error(format!("{}: {}", infn.display(), e))))?;
+    let mut fbuf = BufReader::new(ff);
+    let mut value = serde_json::from_reader::<_, Value>(&mut fbuf)
+        .or_else(|e| Err(style_error(format!("{}: {}", infn.display(), e))))?;
+    let dir = infn.parent().unwrap_or(Path::new("."));
+
+    //the script file is relative to the style file, as extends is
+    if let Some(Value::String(f)) = value.get_mut("transform_script_file") {
+        if Path::new(f.as_str()).is_relative() {
+            *f = dir.join(f.as_str()).to_string_lossy().into_owned();
+        }
+    }
+    resolve_style(value, dir, depth)
+}
+
+//a transform script which fails to compile or run, as opposed to one
+//dropping the object or the object not being a feature
+pub fn is_transform_failure(e: &Error) -> bool {
+    e.kind() == ErrorKind::InvalidData
+}
+
+//reads a style file, resolving any extends, without checking it is a
+//valid GeometryStyle
+pub(crate) fn read_style_file(infn: &str) -> Result<Value> {
+    read_style_file_depth(Path::new(infn), 0)
+}
+
+//writes the style json to outfn, with any extends resolved. Without
+//style_name the default style is written.
+pub fn dump_geometry_style(outfn: &str, style_name: Option<&str>) -> Result<()> {
+    let style = match style_name {
+        None => GeometryStyle::default(),
+        Some(fname) => GeometryStyle::from_file(fname)?,
+    };
+    let mut f = File::create(outfn)?;
+    serde_json::to_writer_pretty(&mut f, &style)?;
+    Ok(())
+}
+
+//the label point search only ends once cells are smaller than precision
+fn positive_precision<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<f64>, D::Error> {
+    match Option::<f64>::deserialize(d)? {
+        Some(p) if p.is_nan() || p <= 0.0 => Err(serde::de::Error::custom(format!(
+            "$.label_point_precision: {} must be greater than zero",
+            p
+        ))),
+        p => Ok(p),
+    }
+}
+
+//feature_keys, other_keys, polygon_tags and drop_keys are no longer
+//public fields: read them with the accessors of the same name, and change
+//them with the set_ methods, so that their patterns are always valid
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GeometryStyle {
-    pub feature_keys: BTreeSet<String>,
-    pub other_keys: Option<BTreeSet<String>>,
-    pub polygon_tags: BTreeMap<String, PolyTagSpec>,
+    #[serde(flatten)]
+    keys: StyleKeys,
     pub parent_tags: BTreeMap<String, ParentTagSpec>,
     pub relation_tag_spec: Vec<RelationTagSpec>,
     pub z_order_spec: BTreeMap<String,BTreeMap<String,i64>>,
     pub all_objs: bool,
-    pub drop_keys: BTreeSet<String>,
     pub multipolygons: bool,
     pub boundary_relations: bool,
+    //join connected lines with the same tags, within each quadtree block only:
+    //lines which continue into another block are left as separate pieces,
+    //split where they meet at a node on the block edge
+    #[serde(default)]
+    pub merge_lines: bool,
+    #[serde(default)]
+    pub multipolygon_outer_tags: bool,
+    #[serde(default)]
+    pub suppress_consumed_outers: bool,
+    #[serde(default, deserialize_with = "positive_precision")]
+    pub label_point_precision: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform_script: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform_script_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub localized_names: Option<LocalizedNameSpec>,
+    
+    #[serde(skip)]
+    transform: OnceLock<std::result::Result<Option<TagTransform>, String>>,
+}
+
+#[derive(Deserialize, Clone)]
+struct StyleKeysSpec {
+    feature_keys: BTreeSet<String>,
+    other_keys: Option<BTreeSet<String>>,
+    polygon_tags: BTreeMap<String, PolyTagSpec>,
+    drop_keys: BTreeSet<String>,
+}
+
+//the glob and regex keys are compiled when the style is deserialised, so
+//a style with an invalid pattern can't be made
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(try_from = "StyleKeysSpec")]
+struct StyleKeys {
+    feature_keys: BTreeSet<String>,
+    other_keys: Option<BTreeSet<String>>,
+    polygon_tags: BTreeMap<String, PolyTagSpec>,
+    drop_keys: BTreeSet<String>,
+    #[serde(skip)]
+    patterns: StyleKeyPatterns,
+}
+
+#[derive(Debug)]
+struct StyleKeyPatterns {
+    feature: KeyPatterns,
+    other: Option<KeyPatterns>,
+    drop: KeyPatterns,
+    polygon: KeyPatterns,
+}
+
+//errors give the json path of the invalid pattern
+impl TryFrom<StyleKeysSpec> for StyleKeys {
+    type Error = String;
+
+    fn try_from(spec: StyleKeysSpec) -> std::result::Result<StyleKeys, String> {
+        let compile = |field: &str, keys: &mut dyn Iterator<Item = &String>| {
+            KeyPatterns::new(keys).or_else(|(k, e)| {
+                Err(format!("$.{}[{}]: invalid key pattern: {}", field, serde_json::json!(k), e))
+            })
+        };
+        let patterns = StyleKeyPatterns {
+            feature: compile("feature_keys", &mut spec.feature_keys.iter())?,
+            other: match &spec.other_keys {
+                None => None,
+                Some(o) => Some(compile("other_keys", &mut o.iter())?),
+            },
+            drop: compile("drop_keys", &mut spec.drop_keys.iter())?,
+            polygon: compile("polygon_tags", &mut spec.polygon_tags.keys())?,
+        };
+        Ok(StyleKeys {
+            feature_keys: spec.feature_keys,
+            other_keys: spec.other_keys,
+            polygon_tags: spec.polygon_tags,
+            drop_keys: spec.drop_keys,
+            patterns: patterns,
+        })
+    }
 }
 
 impl GeometryStyle {
+    pub fn feature_keys(&self) -> &BTreeSet<String> {
+        &self.keys.feature_keys
+    }
+
+    pub fn other_keys(&self) -> Option<&BTreeSet<String>> {
+        self.keys.other_keys.as_ref()
+    }
+
+    pub fn polygon_tags(&self) -> &BTreeMap<String, PolyTagSpec> {
+        &self.keys.polygon_tags
+    }
+
+    pub fn drop_keys(&self) -> &BTreeSet<String> {
+        &self.keys.drop_keys
+    }
+
+    //each of these recompiles the key patterns, and leaves the style
+    //unchanged if any are invalid
+    pub fn set_feature_keys(&mut self, feature_keys: BTreeSet<String>) -> Result<()> {
+        self.update_keys(|s| s.feature_keys = feature_keys)
+    }
+
+    pub fn set_other_keys(&mut self, other_keys: Option<BTreeSet<String>>) -> Result<()> {
+        self.update_keys(|s| s.other_keys = other_keys)
+    }
+
+    pub fn set_polygon_tags(&mut self, polygon_tags: BTreeMap<String, PolyTagSpec>) -> Result<()> {
+        self.update_keys(|s| s.polygon_tags = polygon_tags)
+    }
+
+    pub fn set_drop_keys(&mut self, drop_keys: BTreeSet<String>) -> Result<()> {
+        self.update_keys(|s| s.drop_keys = drop_keys)
+    }
+
+    fn update_keys<F: FnOnce(&mut StyleKeysSpec)>(&mut self, update: F) -> Result<()> {
+        let mut spec = StyleKeysSpec {
+            feature_keys: self.keys.feature_keys.clone(),
+            other_keys: self.keys.other_keys.clone(),
+            polygon_tags: self.keys.polygon_tags.clone(),
+            drop_keys: self.keys.drop_keys.clone(),
+        };
+        update(&mut spec);
+        self.keys = StyleKeys::try_from(spec).or_else(|e| Err(Error::new(ErrorKind::InvalidInput, e)))?;
+        Ok(())
+    }
     
+    //a style with extends is resolved against its base, with relative
+    //paths taken from the current directory
     pub fn from_json(input_str: &str) -> Result<GeometryStyle> {
-        match serde_json::from_str(input_str) {
-            Ok(g) => Ok(g),
+        let value = serde_json::from_str::<Value>(input_str).or_else(|e| Err(Error::new(ErrorKind::Other, e.to_string())))?;
+        let value = resolve_style(value, Path::new("."), 0)?;
+        match serde_json::from_value::<GeometryStyle>(value) {
+            Ok(g) => {
+                g.transform()?;
+                Ok(g)
+            },
             Err(e) => Err(Error::new(ErrorKind::Other, e.to_string()))
         }
     }
@@ -203,23 +518,77 @@ impl GeometryStyle {
     }
 
     pub fn from_file(infn: &str) -> Result<GeometryStyle> {
-        let ff = File::open(infn)?;
-        let mut fbuf = BufReader::new(ff);
-        match serde_json::from_reader(&mut fbuf) {
-            Ok(p) => Ok(p),
+        match serde_json::from_value::<GeometryStyle>(read_style_file(infn)?) {
+            Ok(p) => {
+                p.transform()?;
+                Ok(p)
+            },
             Err(e) => Err(Error::new(ErrorKind::Other, e.to_string())),
         }
     }
-    fn has_feature_key(&self, tags: &[Tag]) -> bool {
+    
+    fn patterns(&self) -> &StyleKeyPatterns {
+        &self.keys.patterns
+    }
+
+    //the transform script is compiled the first time it is needed
+    pub fn transform(&self) -> Result<Option<&TagTransform>> {
+        let t = self.transform.get_or_init(|| {
+            let script = match (&self.transform_script, &self.transform_script_file) {
+                (None, None) => { return Ok(None); },
+                (Some(s), None) => s.clone(),
+                (None, Some(f)) => std::fs::read_to_string(f).or_else(|e| Err(format!("{}: {}", f, e)))?,
+                (Some(_), Some(_)) => {
+                    return Err(String::from("only one of transform_script and transform_script_file can be given"));
+                }
+            };
+            TagTransform::new(&script).map(Some).or_else(|e| Err(e.to_string()))
+        });
+        match t {
+            Ok(t) => Ok(t.as_ref()),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e.clone())),
+        }
+    }
+    
+    //Ok(None) when there is no transform, so the tags are used unchanged
+    fn apply_transform<F>(&self, apply: F) -> Result<Option<Vec<Tag>>>
+    where
+        F: Fn(&TagTransform) -> crate::Result<Option<Vec<Tag>>>,
+    {
+        match self.transform()? {
+            None => Ok(None),
+            Some(t) => match apply(t) {
+                Ok(Some(tags)) => Ok(Some(tags)),
+                Ok(None) => Err(Error::new(ErrorKind::Other, "dropped by transform")),
+                Err(e) => Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+            },
+        }
+    }
+    pub fn has_feature_key(&self, tags: &[Tag]) -> bool {
         for t in tags {
-            if self.feature_keys.contains(&t.key) {
+            if self.is_feature_key(&t.key) {
                 return true;
             }
         }
         false
     }
+
+    pub fn is_feature_key(&self, k: &str) -> bool {
+        self.keys.feature_keys.contains(k) || self.patterns().feature.matches(k)
+    }
+
+    //true if tags with key k are passed through to the output
+    pub(crate) fn keeps_key(&self, k: &str) -> bool {
+        if let Some(ln) = &self.localized_names {
+            if ln.uses_key(k) {
+                return true;
+            }
+        }
+        self.has_key(k) && !self.is_drop(k)
+    }
+
     fn has_key(&self, k: &str) -> bool {
-        match &self.other_keys {
+        match &self.keys.other_keys {
             None => {
                 return true;
             }
@@ -230,22 +599,27 @@ impl GeometryStyle {
             }
         }
 
-        self.feature_keys.contains(k)
+        if self.keys.feature_keys.contains(k) {
+            return true;
+        }
+        let p = self.patterns();
+        p.other.as_ref().map_or(false, |o| o.matches(k)) || p.feature.matches(k)
     }
 
     fn is_drop(&self, k: &str) -> bool {
-        if self.drop_keys.is_empty() {
+        if self.keys.drop_keys.is_empty() {
             return false;
         }
 
-        if self.drop_keys.contains(k) {
+        if self.keys.drop_keys.contains(k) {
             return true;
         }
 
-        match k.find(':') {
+        let prefix_drop = match k.find(':') {
             None => false,
-            Some(x) => self.drop_keys.contains(&k[0..x + 1]),
-        }
+            Some(x) => self.keys.drop_keys.contains(&k[0..x + 1]),
+        };
+        prefix_drop || self.patterns().drop.matches(k)
     }
     fn get_zorder_value(&self, t: &Tag) -> Option<i64> {
         if let Some(p) = self.z_order_spec.get(&t.key) {
@@ -263,10 +637,8 @@ impl GeometryStyle {
         let mut z_order: Option<i64> = None;
         let mut layer: Option<i64> = None;
         for t in tags {
-            if self.has_key(&t.key) {
-                if !self.is_drop(&t.key) {
-                    res.push(t.clone());
-                }
+            if self.keeps_key(&t.key) {
+                res.push(t.clone());
             }
 
             if t.key == "layer" {
@@ -292,8 +664,13 @@ impl GeometryStyle {
     }
 
     fn check_polygon_tags(&self, tags: &[Tag]) -> bool {
+        let patterns = &self.patterns().polygon;
         for t in tags {
-            match self.polygon_tags.get(&t.key) {
+            let spec = match self.keys.polygon_tags.get(&t.key) {
+                Some(pt) => Some(pt),
+                None => patterns.first_match(&t.key).and_then(|p| self.keys.polygon_tags.get(p)),
+            };
+            match spec {
                 None => {}
                 Some(pt) => match pt {
                     PolyTagSpec::All => {
@@ -319,6 +696,9 @@ impl GeometryStyle {
         &self,
         tags: &[Tag],
     ) -> Result<(Vec<Tag>, Option<i64>, Option<i64>)> {
+        let transformed = self.apply_transform(|t| t.relation(tags))?;
+        let tags = transformed.as_deref().unwrap_or(tags);
+        
         if !self.all_objs && !self.has_feature_key(&tags) {
             return Err(Error::new(ErrorKind::Other, "not a feature"));
         }
@@ -330,11 +710,28 @@ impl GeometryStyle {
         Ok(self.filter_tags(tags))
     }
 
+    pub fn process_multipolygon_outer_tags(
+        &self,
+        rel_tags: &[Tag],
+        outer_tags: &[Tag],
+    ) -> Result<(Vec<Tag>, Option<i64>, Option<i64>)> {
+        let mut tags = rel_tags.to_vec();
+        for t in outer_tags {
+            if !tags.iter().any(|x| x.key == t.key) {
+                tags.push(t.clone());
+            }
+        }
+        self.process_multipolygon_relation(&tags)
+    }
+
     pub fn process_way(
         &self,
         tags: &[Tag],
         is_ring: bool,
     ) -> Result<(bool, Vec<Tag>, Option<i64>, Option<i64>)> {
+        let transformed = self.apply_transform(|t| t.way(tags, is_ring))?;
+        let tags = transformed.as_deref().unwrap_or(tags);
+        
         if !self.all_objs && !self.has_feature_key(&tags) {
             return Err(Error::new(ErrorKind::Other, "not a feature"));
         }
@@ -345,6 +742,9 @@ impl GeometryStyle {
     }
 
     pub fn process_node(&self, tags: &[Tag]) -> Result<(Vec<Tag>, Option<i64>)> {
+        let transformed = self.apply_transform(|t| t.node(tags))?;
+        let tags = transformed.as_deref().unwrap_or(tags);
+        
         if !self.all_objs && !self.has_feature_key(&tags) {
             return Err(Error::new(ErrorKind::Other, "not a feature"));
         }
@@ -353,3 +753,156 @@ impl GeometryStyle {
         Ok((t, lyr))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style_with_precision(p: Value) -> Value {
+        let mut v: Value = serde_json::from_str(DEFAULT_GEOMETRY_STYLE).unwrap();
+        v["label_point_precision"] = p;
+        v
+    }
+
+    #[test]
+    fn label_point_precision_must_be_positive() {
+        for p in [serde_json::json!(0.0), serde_json::json!(-1.0)] {
+            let v = style_with_precision(p);
+            assert!(serde_json::from_value::<GeometryStyle>(v.clone()).is_err());
+            let e = GeometryStyle::from_json(&v.to_string()).unwrap_err();
+            assert!(e.to_string().contains("$.label_point_precision"));
+        }
+    }
+
+    #[test]
+    fn label_point_precision_read() {
+        let g = serde_json::from_value::<GeometryStyle>(style_with_precision(serde_json::json!(2.5))).unwrap();
+        assert_eq!(g.label_point_precision, Some(2.5));
+        let g = serde_json::from_value::<GeometryStyle>(style_with_precision(Value::Null)).unwrap();
+        assert_eq!(g.label_point_precision, None);
+    }
+
+    #[test]
+    fn invalid_key_pattern_rejected() {
+        let mut v: Value = serde_json::from_str(DEFAULT_GEOMETRY_STYLE).unwrap();
+        v["drop_keys"] = serde_json::json!(["^(a"]);
+        let e = serde_json::from_value::<GeometryStyle>(v).unwrap_err();
+        assert!(e.to_string().contains("$.drop_keys[\"^(a\"]"));
+    }
+
+    #[test]
+    fn set_keys_checks_patterns() {
+        let mut g = GeometryStyle::default();
+        let keys: BTreeSet<String> = [String::from("^(a")].into_iter().collect();
+        assert!(g.set_feature_keys(keys).is_err());
+        assert!(g.feature_keys().contains("highway"));
+
+        let keys: BTreeSet<String> = [String::from("name:*")].into_iter().collect();
+        g.set_feature_keys(keys).unwrap();
+        assert!(g.is_feature_key("name:en"));
+        assert!(!g.is_feature_key("highway"));
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let d = std::env::temp_dir().join(format!("osmquadtree-geometry-style-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(d.join("base")).unwrap();
+        d
+    }
+
+    fn write_json(path: &Path, v: Value) {
+        std::fs::write(path, v.to_string()).unwrap();
+    }
+
+    #[test]
+    fn merge_set_add_remove() {
+        let spec = serde_json::json!({"add": ["c", "a"], "remove": ["b"]});
+        let res = merge_field("feature_keys", serde_json::json!(["a", "b"]), &spec).unwrap();
+        assert_eq!(res, serde_json::json!(["a", "c"]));
+    }
+
+    #[test]
+    fn merge_with_null_other_keys() {
+        let spec = serde_json::json!({"add": ["name"]});
+        let e = merge_field("other_keys", Value::Null, &spec).unwrap_err();
+        assert!(e.to_string().contains("can't merge other_keys with null"));
+    }
+
+    #[test]
+    fn merge_map_add_remove() {
+        let spec = serde_json::json!({"add": {"c": "all"}, "remove": ["a"]});
+        let res = merge_field("polygon_tags", serde_json::json!({"a": "all", "b": "all"}), &spec).unwrap();
+        assert_eq!(res, serde_json::json!({"b": "all", "c": "all"}));
+
+        let base = serde_json::json!({"highway": {"motorway": 380}, "railway": {"rail": 440}});
+        let spec = serde_json::json!({"add": {"highway": {"primary": 360}}, "remove": ["railway"]});
+        let res = merge_field("z_order_spec", base, &spec).unwrap();
+        assert_eq!(res, serde_json::json!({"highway": {"primary": 360}}));
+    }
+
+    #[test]
+    fn merge_relation_tag_spec_by_target_key() {
+        let base = serde_json::json!([{"target_key": "bus_routes"}, {"target_key": "bicycle_routes"}]);
+        let spec = serde_json::json!({"add": [{"target_key": "hiking_routes"}], "remove": ["bus_routes"]});
+        let res = merge_field("relation_tag_spec", base, &spec).unwrap();
+        assert_eq!(res, serde_json::json!([{"target_key": "bicycle_routes"}, {"target_key": "hiking_routes"}]));
+    }
+
+    #[test]
+    fn extends_chain_relative_paths() {
+        let d = temp_dir("chain");
+        //base/mid.json extends base/base.json, relative to its own directory
+        write_json(&d.join("base").join("base.json"), serde_json::json!({
+            "extends": "default",
+            "feature_keys": {"add": ["foo"]}
+        }));
+        write_json(&d.join("base").join("mid.json"), serde_json::json!({
+            "extends": "base.json",
+            "feature_keys": {"add": ["bar"], "remove": ["highway"]}
+        }));
+        let top = d.join("top.json");
+        write_json(&top, serde_json::json!({"extends": "base/mid.json", "all_objs": true}));
+
+        let g = GeometryStyle::from_file(top.to_str().unwrap()).unwrap();
+        assert!(g.feature_keys().contains("foo"));
+        assert!(g.feature_keys().contains("bar"));
+        assert!(!g.feature_keys().contains("highway"));
+        assert!(g.feature_keys().contains("building"));
+        assert!(g.all_objs);
+
+        let _ = std::fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn extends_cycle_detected() {
+        let d = temp_dir("cycle");
+        write_json(&d.join("a.json"), serde_json::json!({"extends": "b.json"}));
+        write_json(&d.join("b.json"), serde_json::json!({"extends": "a.json"}));
+
+        let e = read_style_file(d.join("a.json").to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().contains(&format!("more than {} levels of extends", MAX_EXTENDS_DEPTH)));
+
+        let _ = std::fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn dump_resolved_style() {
+        let d = temp_dir("dump");
+        let style = d.join("style.json");
+        write_json(&style, serde_json::json!({
+            "extends": "default",
+            "drop_keys": {"add": ["note"]},
+            "merge_lines": true
+        }));
+        let outfn = d.join("out.json");
+        dump_geometry_style(outfn.to_str().unwrap(), Some(style.to_str().unwrap())).unwrap();
+
+        let v: Value = serde_json::from_str(&std::fs::read_to_string(&outfn).unwrap()).unwrap();
+        assert!(v.get("extends").is_none());
+        assert!(v["drop_keys"].as_array().unwrap().contains(&serde_json::json!("note")));
+        assert_eq!(v["merge_lines"], serde_json::json!(true));
+        let g = serde_json::from_value::<GeometryStyle>(v).unwrap();
+        assert!(g.drop_keys().contains("note"));
+
+        let _ = std::fs::remove_dir_all(&d);
+    }
+}
@@ -0,0 +1,485 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::elements::{PrimitiveBlock, Quadtree, Tag};
+use osmquadtree::pbfformat::{get_file_locs, make_read_primitive_blocks_combine_call_all, read_all_blocks_parallel_with_progbar};
+
+use crate::postgresql::{make_table_spec, ColumnSource, TableSpec};
+use crate::style::read_style_file;
+use crate::{is_key_pattern, Error, GeometryStyle, Result, Timings};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueLevel {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StyleIssue {
+    pub level: IssueLevel,
+    //json path into the style, or into the table spec when starting
+    //$.table_spec (or $.default_table_spec when none was given)
+    pub path: String,
+    pub message: String,
+}
+
+impl StyleIssue {
+    fn warning(path: String, message: String) -> StyleIssue {
+        StyleIssue { level: IssueLevel::Warning, path: path, message: message }
+    }
+    fn error(path: String, message: String) -> StyleIssue {
+        StyleIssue { level: IssueLevel::Error, path: path, message: message }
+    }
+}
+
+fn key_path(field: &str, key: &str) -> String {
+    format!("$.{}[{}]", field, json!(key))
+}
+
+//loads a style without compiling the transform script, so that script
+//errors are reported by validate_style rather than failing the load
+pub fn read_style_unchecked(fname: &str) -> Result<GeometryStyle> {
+    serde_json::from_value(read_style_file(fname)?)
+        .or_else(|e| Err(Error::InvalidDataError(format!("{}: {}", fname, e))))
+}
+
+fn tag_columns(spec: &TableSpec) -> BTreeSet<String> {
+    spec.columns
+        .iter()
+        .filter(|(_, s, _)| *s == ColumnSource::Tag)
+        .map(|(n, _, _)| n.clone())
+        .collect()
+}
+
+//checks a style for keys which can't have the intended effect. The table
+//spec defaults to make_table_spec(style, false).
+pub fn validate_style(style: &GeometryStyle, table_spec: Option<&Vec<TableSpec>>) -> Vec<StyleIssue> {
+    let mut res = Vec::new();
+
+    for k in style.feature_keys().iter().filter(|k| !is_key_pattern(k)) {
+        if !style.keeps_key(k) {
+            res.push(StyleIssue::error(
+                key_path("feature_keys", k),
+                format!("feature key {} is dropped, so never appears in the output", k),
+            ));
+        }
+    }
+
+    for k in style.polygon_tags().keys().filter(|k| !is_key_pattern(k)) {
+        if !style.is_feature_key(k) {
+            res.push(StyleIssue::warning(
+                key_path("polygon_tags", k),
+                format!("{} is not a feature key, so only closed ways with another feature key become polygons", k),
+            ));
+        }
+    }
+
+    for k in style.z_order_spec.keys() {
+        if !style.keeps_key(k) {
+            res.push(StyleIssue::warning(
+                key_path("z_order_spec", k),
+                format!("{} is never kept, so objects are ordered by a tag which isn't in the output", k),
+            ));
+        }
+    }
+
+    let default_spec;
+    let (table_spec, prfx) = match table_spec {
+        Some(t) => (t, "$.table_spec"),
+        None => {
+            default_spec = make_table_spec(style, false);
+            (&default_spec, "$.default_table_spec")
+        }
+    };
+    //tag columns given more than once in a table, such as a kept key which
+    //is also a relation or parent tag
+    let mut duplicate_columns = BTreeMap::new();
+    for spec in table_spec {
+        let mut counts = BTreeMap::new();
+        for (n, s, _) in &spec.columns {
+            if *s == ColumnSource::Tag {
+                *counts.entry(n.clone()).or_insert(0) += 1;
+            }
+        }
+        for (n, c) in counts {
+            if c > 1 {
+                duplicate_columns.entry(n).or_insert(spec.name.clone());
+            }
+        }
+    }
+
+    let mut targets = BTreeMap::new();
+    for (i, r) in style.relation_tag_spec.iter().enumerate() {
+        let path = format!("$.relation_tag_spec[{}].target_key", i);
+        if let Some(t) = duplicate_columns.get(&r.target_key) {
+            res.push(StyleIssue::error(
+                path.clone(),
+                format!("target key {} is also a tag column of table {}", r.target_key, t),
+            ));
+        }
+        if let Some(j) = targets.insert(r.target_key.clone(), i) {
+            res.push(StyleIssue::warning(
+                path.clone(),
+                format!("target key {} is also set by relation_tag_spec[{}]", r.target_key, j),
+            ));
+        }
+        if r.source_key == r.target_key {
+            res.push(StyleIssue::warning(
+                path,
+                format!("target key {} is the same as the source key", r.target_key),
+            ));
+        }
+    }
+
+    for (k, p) in &style.parent_tags {
+        let path = key_path("parent_tags", k);
+        if let Some(t) = duplicate_columns.get(k) {
+            res.push(StyleIssue::error(
+                path.clone(),
+                format!("parent tag {} is also a tag column of table {}", k, t),
+            ));
+        }
+        if let Some(i) = targets.get(k) {
+            res.push(StyleIssue::error(
+                path.clone(),
+                format!("parent tag {} is also the target of relation_tag_spec[{}]", k, i),
+            ));
+        }
+        for nk in &p.node_keys {
+            if !style.is_feature_key(nk) && !style.all_objs {
+                res.push(StyleIssue::warning(
+                    format!("{}.node_keys", path),
+                    format!("{} is not a feature key, so nodes with only this key are never output", nk),
+                ));
+            }
+        }
+    }
+
+    if let Err(e) = style.transform() {
+        let path = if style.transform_script_file.is_some() && style.transform_script.is_none() {
+            "$.transform_script_file"
+        } else {
+            "$.transform_script"
+        };
+        res.push(StyleIssue::error(String::from(path), e.to_string()));
+    }
+
+    for (i, spec) in table_spec.iter().enumerate() {
+        let mut seen = BTreeMap::new();
+        for (j, (n, s, _)) in spec.columns.iter().enumerate() {
+            let path = format!("{}[{}].columns[{}]", prfx, i, j);
+            let reported = targets.contains_key(n) || style.parent_tags.contains_key(n);
+            if let Some(k) = seen.insert(n.clone(), j).filter(|_| !reported) {
+                res.push(StyleIssue::error(
+                    path.clone(),
+                    format!("table {} column {} is also columns[{}]", spec.name, n, k),
+                ));
+            }
+            if *s == ColumnSource::Tag
+                && !style.keeps_key(n)
+                && !style.parent_tags.contains_key(n)
+                && !targets.contains_key(n)
+            {
+                res.push(StyleIssue::warning(
+                    path.clone(),
+                    format!("table {} column {} is never filled, as the style doesn't keep {}", spec.name, n, n),
+                ));
+            }
+            if let ColumnSource::LocalizedName { lang, fallback, .. } = s {
+                if !style.keeps_key(&format!("name:{}", lang)) && !fallback.iter().any(|f| style.keeps_key(f)) {
+                    res.push(StyleIssue::warning(
+                        path,
+                        format!("table {} column {} is never filled, as the style keeps none of name:{} or its fallback keys", spec.name, n, lang),
+                    ));
+                }
+            }
+        }
+    }
+
+    res
+}
+
+pub fn issues_to_text(issues: &[StyleIssue]) -> String {
+    let mut res = String::new();
+    for i in issues {
+        let level = match i.level {
+            IssueLevel::Warning => "warning",
+            IssueLevel::Error => "error",
+        };
+        writeln!(res, "{}: {}: {}", level, i.path, i.message).unwrap();
+    }
+    res
+}
+
+//which column each source tag ends up in, for the objects of a sample of
+//blocks. Parent and relation tags are added by earlier stages, so don't
+//appear here.
+#[derive(Debug, Default)]
+pub struct StyleDryRun {
+    pub num_objects: BTreeMap<&'static str, (usize, usize)>,
+    pub tags: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+impl StyleDryRun {
+    fn add_object(&mut self, obj_type: &'static str, kept: bool) {
+        let n = self.num_objects.entry(obj_type).or_insert((0, 0));
+        n.0 += 1;
+        if kept {
+            n.1 += 1;
+        }
+    }
+
+    fn add_tags(&mut self, columns: &BTreeMap<String, (BTreeSet<String>, Option<String>)>, table: &str, source: &[Tag], kept: &[Tag]) {
+        let kept_keys: BTreeSet<&str> = kept.iter().map(|t| t.key.as_str()).collect();
+        for t in source {
+            if !kept_keys.contains(t.key.as_str()) {
+                *self.tags.entry(t.key.clone()).or_default().entry(String::from("dropped")).or_insert(0) += 1;
+            }
+        }
+        for t in kept {
+            let dest = match columns.get(table) {
+                None => format!("{}: no table", table),
+                Some((cols, other)) => {
+                    if cols.contains(&t.key) {
+                        format!("{}.{}", table, t.key)
+                    } else if let Some(o) = other {
+                        format!("{}.{}", table, o)
+                    } else {
+                        format!("{}: no column", table)
+                    }
+                }
+            };
+            *self.tags.entry(t.key.clone()).or_default().entry(dest).or_insert(0) += 1;
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut objs = serde_json::Map::new();
+        for (k, (n, m)) in &self.num_objects {
+            objs.insert(String::from(*k), json!({"count": n, "kept": m}));
+        }
+        json!({"objects": objs, "tags": self.tags})
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut res = String::new();
+        for (k, (n, m)) in &self.num_objects {
+            writeln!(res, "{}: {} of {} kept", k, m, n).unwrap();
+        }
+        for (k, dd) in &self.tags {
+            let dests: Vec<String> = dd.iter().map(|(d, n)| format!("{} [{}]", d, n)).collect();
+            writeln!(res, "{} -> {}", k, dests.join(", ")).unwrap();
+        }
+        res
+    }
+}
+
+struct DryRunStyle {
+    style: Arc<GeometryStyle>,
+    columns: BTreeMap<String, (BTreeSet<String>, Option<String>)>,
+    result: Arc<Mutex<StyleDryRun>>,
+}
+
+impl CallFinish for DryRunStyle {
+    type CallType = PrimitiveBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, pb: PrimitiveBlock) {
+        let mut res = self.result.lock().unwrap();
+        for n in &pb.nodes {
+            match self.style.process_node(&n.tags) {
+                Ok((tags, _)) => {
+                    res.add_object("node", true);
+                    res.add_tags(&self.columns, "point", &n.tags, &tags);
+                }
+                Err(_) => res.add_object("node", false),
+            }
+        }
+        for w in &pb.ways {
+            let is_ring = w.refs.len() > 3 && w.refs[0] == w.refs[w.refs.len() - 1];
+            match self.style.process_way(&w.tags, is_ring) {
+                Ok((is_poly, tags, _, _)) => {
+                    res.add_object("way", true);
+                    res.add_tags(&self.columns, if is_poly { "polygon" } else { "line" }, &w.tags, &tags);
+                }
+                Err(_) => res.add_object("way", false),
+            }
+        }
+        for r in &pb.relations {
+            let is_mp = r.tags.iter().any(|t| {
+                t.key == "type"
+                    && ((self.style.multipolygons && t.val == "multipolygon")
+                        || (self.style.boundary_relations && t.val == "boundary"))
+            });
+            if !is_mp {
+                continue;
+            }
+            match self.style.process_multipolygon_relation(&r.tags) {
+                Ok((tags, _, _)) => {
+                    res.add_object("relation", true);
+                    res.add_tags(&self.columns, "polygon", &r.tags, &tags);
+                }
+                Err(_) => res.add_object("relation", false),
+            }
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        Ok(Timings::new())
+    }
+}
+
+//the length of the first num_blocks blocks of locs, for the progress bar.
+//blocks end at the next block in the same file. The last block of a file
+//ends at total_len when there is one file, and otherwise is taken to be the
+//mean size of the other blocks.
+fn kept_blocks_len(locs: &[(Quadtree, Vec<(usize, u64)>)], num_blocks: usize, num_files: usize, total_len: u64) -> u64 {
+    if num_blocks >= locs.len() {
+        return total_len;
+    }
+    let mut offsets: BTreeMap<usize, BTreeSet<u64>> = BTreeMap::new();
+    for (_, ll) in locs {
+        for (f, p) in ll {
+            offsets.entry(*f).or_insert_with(BTreeSet::new).insert(*p);
+        }
+    }
+    let block_end = |f: &usize, p: &u64| -> Option<u64> {
+        match offsets[f].range(p + 1..).next() {
+            Some(e) => Some(*e),
+            None if num_files == 1 => Some(total_len),
+            None => None,
+        }
+    };
+
+    let (mut known, mut num_known, mut num_unknown) = (0, 0, 0);
+    for (_, ll) in &locs[..num_blocks] {
+        for (f, p) in ll {
+            match block_end(f, p) {
+                Some(e) => {
+                    known += e - p;
+                    num_known += 1;
+                }
+                None => {
+                    num_unknown += 1;
+                }
+            }
+        }
+    }
+    if num_unknown == 0 || num_known == 0 {
+        return known;
+    }
+    known + num_unknown * known / num_known
+}
+
+//runs the style's tag processing over the first num_blocks blocks of prfx
+pub fn style_dry_run(
+    style: GeometryStyle,
+    table_spec: Option<&Vec<TableSpec>>,
+    prfx: &str,
+    num_blocks: usize,
+) -> Result<StyleDryRun> {
+    let default_spec;
+    let table_spec = match table_spec {
+        Some(t) => t,
+        None => {
+            default_spec = make_table_spec(&style, false);
+            &default_spec
+        }
+    };
+    let mut columns = BTreeMap::new();
+    for spec in table_spec {
+        let other = spec
+            .columns
+            .iter()
+            .find(|(_, s, _)| *s == ColumnSource::OtherTags)
+            .map(|(n, _, _)| n.clone());
+        columns.insert(spec.name.clone(), (tag_columns(spec), other));
+    }
+
+    let mut pfilelocs = get_file_locs(prfx, None, None)?;
+    let kept_len = kept_blocks_len(&pfilelocs.1, num_blocks, pfilelocs.0.len(), pfilelocs.2);
+    pfilelocs.1.truncate(num_blocks);
+
+    let result = Arc::new(Mutex::new(StyleDryRun::default()));
+    let dr = Box::new(DryRunStyle { style: Arc::new(style), columns: columns, result: result.clone() });
+
+    read_all_blocks_parallel_with_progbar(
+        &mut pfilelocs.0,
+        &pfilelocs.1,
+        make_read_primitive_blocks_combine_call_all(dr),
+        &format!("style dry run, {} blocks", num_blocks),
+        kept_len,
+    );
+
+    let res = std::mem::take(&mut *result.lock().unwrap());
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_style_has_no_errors() {
+        let style = GeometryStyle::default();
+        let issues = validate_style(&style, None);
+        let errors: Vec<&StyleIssue> = issues.iter().filter(|i| i.level == IssueLevel::Error).collect();
+        assert!(errors.is_empty(), "{}", issues_to_text(&issues));
+    }
+
+    #[test]
+    fn relation_target_collides_with_tag_column() {
+        let mut style = GeometryStyle::default();
+        let mut feature_keys = style.feature_keys().clone();
+        feature_keys.insert(String::from("bus_routes"));
+        style.set_feature_keys(feature_keys).unwrap();
+        let issues = validate_style(&style, None);
+        assert!(issues
+            .iter()
+            .any(|i| i.level == IssueLevel::Error && i.path == "$.relation_tag_spec[2].target_key"));
+    }
+
+    #[test]
+    fn table_spec_paths() {
+        //a dropped key which still has a column is never filled
+        let mut style = GeometryStyle::default();
+        let mut drop_keys = style.drop_keys().clone();
+        drop_keys.insert(String::from("highway"));
+        drop_keys.insert(String::from("dropped_key"));
+        style.set_drop_keys(drop_keys).unwrap();
+
+        let mut table_spec = make_table_spec(&style, false);
+        table_spec[0].columns.push((String::from("dropped_key"), ColumnSource::Tag, crate::postgresql::ColumnType::Text));
+        let j = table_spec[0].columns.len() - 1;
+        let issues = validate_style(&style, Some(&table_spec));
+        let path = format!("$.table_spec[0].columns[{}]", j);
+        assert!(issues.iter().any(|i| i.level == IssueLevel::Warning && i.path == path), "{}", issues_to_text(&issues));
+
+        //columns of the default table spec are reported as such
+        let issues = validate_style(&style, None);
+        assert!(issues.iter().any(|i| i.path.starts_with("$.default_table_spec[0].columns[")), "{}", issues_to_text(&issues));
+        assert!(!issues.iter().any(|i| i.path.starts_with("$.table_spec")));
+    }
+
+    #[test]
+    fn kept_blocks_length() {
+        let locs: Vec<(Quadtree, Vec<(usize, u64)>)> = vec![
+            (Quadtree::new(0), vec![(0, 0)]),
+            (Quadtree::new(1), vec![(0, 100)]),
+            (Quadtree::new(2), vec![(0, 250), (1, 0)]),
+            (Quadtree::new(3), vec![(1, 50)]),
+        ];
+        assert_eq!(kept_blocks_len(&locs[..3], 1, 1, 400), 100);
+        assert_eq!(kept_blocks_len(&locs[..3], 2, 1, 400), 250);
+        assert_eq!(kept_blocks_len(&locs[..3], 3, 1, 400), 400);
+        //the last block in file 0 is taken to be the mean size of the others
+        assert_eq!(kept_blocks_len(&locs, 3, 2, 500), 100 + 150 + 50 + 100);
+        assert_eq!(kept_blocks_len(&locs, 4, 2, 500), 500);
+    }
+}
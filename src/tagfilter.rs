@@ -0,0 +1,384 @@
+use crate::{Error, Result};
+
+use osmquadtree::elements::Tag;
+use simple_protocolbuffers::{read_packed_int, IterTags, PbfTag};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagFilter {
+    Has(String),
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    Not(Box<TagFilter>),
+    And(Box<TagFilter>, Box<TagFilter>),
+    Or(Box<TagFilter>, Box<TagFilter>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Equals,
+    NotEquals,
+    Open,
+    Close,
+    Comma,
+}
+
+fn is_word_char(c: char) -> bool {
+    !(c.is_whitespace() || c == '=' || c == '!' || c == '(' || c == ')' || c == ',' || c == '"' || c == '\'')
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut res = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '=' => res.push(Token::Equals),
+            '!' => match chars.next() {
+                Some('=') => res.push(Token::NotEquals),
+                _ => {
+                    return Err(Error::UserSelectionError(format!("expected != in tag filter {}", s)));
+                }
+            },
+            '(' => res.push(Token::Open),
+            ')' => res.push(Token::Close),
+            ',' => res.push(Token::Comma),
+            '"' | '\'' => {
+                let mut w = String::new();
+                loop {
+                    match chars.next() {
+                        None => {
+                            return Err(Error::UserSelectionError(format!("unterminated string in tag filter {}", s)));
+                        }
+                        Some(d) if d == c => break,
+                        Some(d) => w.push(d),
+                    }
+                }
+                res.push(Token::Quoted(w));
+            }
+            c => {
+                let mut w = String::from(c);
+                while let Some(d) = chars.peek() {
+                    if !is_word_char(*d) {
+                        break;
+                    }
+                    w.push(*d);
+                    chars.next();
+                }
+                res.push(Token::Word(w));
+            }
+        }
+    }
+    Ok(res)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn is_keyword(&self, kw: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) => w == kw,
+            _ => false,
+        }
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error::UserSelectionError(format!("tag filter: {} at token {}", msg, self.pos))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(w)) | Some(Token::Quoted(w)) => Ok(w),
+            _ => Err(self.error("expected key or value")),
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<TagFilter> {
+        let mut res = self.and_expr()?;
+        while self.is_keyword("or") {
+            self.pos += 1;
+            res = TagFilter::Or(Box::new(res), Box::new(self.and_expr()?));
+        }
+        Ok(res)
+    }
+
+    fn and_expr(&mut self) -> Result<TagFilter> {
+        let mut res = self.not_expr()?;
+        while self.is_keyword("and") {
+            self.pos += 1;
+            res = TagFilter::And(Box::new(res), Box::new(self.not_expr()?));
+        }
+        Ok(res)
+    }
+
+    fn not_expr(&mut self) -> Result<TagFilter> {
+        if self.is_keyword("not") {
+            self.pos += 1;
+            return Ok(TagFilter::Not(Box::new(self.not_expr()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<TagFilter> {
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let res = self.or_expr()?;
+            if self.next() != Some(Token::Close) {
+                return Err(self.error("expected )"));
+            }
+            return Ok(res);
+        }
+        let key = self.string()?;
+        match self.peek() {
+            Some(Token::Equals) => {
+                self.pos += 1;
+                let val = self.string()?;
+                if val == "*" {
+                    Ok(TagFilter::Has(key))
+                } else {
+                    Ok(TagFilter::Equals(key, val))
+                }
+            }
+            Some(Token::NotEquals) => {
+                self.pos += 1;
+                let val = self.string()?;
+                if val == "*" {
+                    Ok(TagFilter::Not(Box::new(TagFilter::Has(key))))
+                } else {
+                    Ok(TagFilter::NotEquals(key, val))
+                }
+            }
+            Some(Token::Word(w)) if w == "in" => {
+                self.pos += 1;
+                if self.next() != Some(Token::Open) {
+                    return Err(self.error("expected ( after in"));
+                }
+                let mut vals = vec![self.string()?];
+                loop {
+                    match self.next() {
+                        Some(Token::Comma) => vals.push(self.string()?),
+                        Some(Token::Close) => break,
+                        _ => {
+                            return Err(self.error("expected , or )"));
+                        }
+                    }
+                }
+                Ok(TagFilter::In(key, vals))
+            }
+            _ => Ok(TagFilter::Has(key)),
+        }
+    }
+}
+
+impl TagFilter {
+    //expressions such as "highway in (primary,secondary) and not tunnel=yes".
+    //A key on its own (or key=*) tests the key is present; key!=val is true
+    //if the key is missing.
+    pub fn from_str(s: &str) -> Result<TagFilter> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0 };
+        let res = parser.or_expr()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(parser.error("unexpected token"));
+        }
+        Ok(res)
+    }
+
+    pub fn check_with<'a, F: Fn(&str) -> Option<&'a str>>(&self, get: &F) -> bool {
+        match self {
+            TagFilter::Has(k) => get(k).is_some(),
+            TagFilter::Equals(k, v) => get(k) == Some(v.as_str()),
+            TagFilter::NotEquals(k, v) => get(k) != Some(v.as_str()),
+            TagFilter::In(k, vv) => match get(k) {
+                None => false,
+                Some(x) => vv.iter().any(|v| v == x),
+            },
+            TagFilter::Not(f) => !f.check_with(get),
+            TagFilter::And(a, b) => a.check_with(get) && b.check_with(get),
+            TagFilter::Or(a, b) => a.check_with(get) || b.check_with(get),
+        }
+    }
+
+    pub fn check(&self, tags: &[Tag]) -> bool {
+        self.check_with(&|k: &str| tags.iter().find(|t| t.key == k).map(|t| t.val.as_str()))
+    }
+}
+
+//"point", "linestring", "simple_polygon", "complicated_polygon", or
+//"polygon" for both kinds of polygon
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeometryTypes {
+    pub point: bool,
+    pub linestring: bool,
+    pub simple_polygon: bool,
+    pub complicated_polygon: bool,
+}
+
+impl GeometryTypes {
+    pub fn all() -> GeometryTypes {
+        GeometryTypes { point: true, linestring: true, simple_polygon: true, complicated_polygon: true }
+    }
+
+    pub fn from_list(types: &str) -> Result<GeometryTypes> {
+        if types == "all" {
+            return Ok(GeometryTypes::all());
+        }
+        let mut res = GeometryTypes { point: false, linestring: false, simple_polygon: false, complicated_polygon: false };
+        for t in types.split(',') {
+            match t.trim() {
+                "point" => { res.point = true; },
+                "linestring" => { res.linestring = true; },
+                "simple_polygon" => { res.simple_polygon = true; },
+                "complicated_polygon" => { res.complicated_polygon = true; },
+                "polygon" => { res.simple_polygon = true; res.complicated_polygon = true; },
+                x => {
+                    return Err(Error::UserSelectionError(format!(
+                        "unknown geometry type {}, expected point, linestring, polygon, simple_polygon or complicated_polygon", x)));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.point && self.linestring && self.simple_polygon && self.complicated_polygon
+    }
+}
+
+//applied to each object as a geometry block is unpacked
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectFilter {
+    pub types: GeometryTypes,
+    pub tags: Option<TagFilter>,
+}
+
+impl ObjectFilter {
+    pub fn all() -> ObjectFilter {
+        ObjectFilter { types: GeometryTypes::all(), tags: None }
+    }
+
+    pub fn new(tag_filter: Option<&str>, geometry_types: Option<&str>) -> Result<ObjectFilter> {
+        Ok(ObjectFilter {
+            types: match geometry_types {
+                None => GeometryTypes::all(),
+                Some(t) => GeometryTypes::from_list(t)?,
+            },
+            tags: match tag_filter {
+                None => None,
+                Some(t) => Some(TagFilter::from_str(t)?),
+            },
+        })
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.types.is_all() && self.tags.is_none()
+    }
+
+    pub fn check_tags(&self, tags: &[Tag]) -> bool {
+        match &self.tags {
+            None => true,
+            Some(t) => t.check(tags),
+        }
+    }
+
+    //checks the keys and vals fields of a packed object, without unpacking
+    //the rest of it
+    pub fn check_packed(&self, strings: &Vec<String>, data: &[u8]) -> Result<bool> {
+        let tf = match &self.tags {
+            None => {
+                return Ok(true);
+            }
+            Some(t) => t,
+        };
+        let mut keys = Vec::new();
+        let mut vals = Vec::new();
+        for tg in IterTags::new(data) {
+            match tg {
+                PbfTag::Data(2, d) => { keys = read_packed_int(d); },
+                PbfTag::Data(3, d) => { vals = read_packed_int(d); },
+                _ => {}
+            }
+        }
+        if keys.len() != vals.len() {
+            return Err(Error::InvalidDataError(format!("keys.len()!=vals.len()")));
+        }
+        let mut tags = Vec::with_capacity(keys.len());
+        for (k, v) in keys.iter().zip(vals.iter()) {
+            match (strings.get(*k as usize), strings.get(*v as usize)) {
+                (Some(k), Some(v)) => tags.push((k.as_str(), v.as_str())),
+                _ => {
+                    return Err(Error::InvalidDataError(format!("string index {} or {} out of range", k, v)));
+                }
+            }
+        }
+        Ok(tf.check_with(&|k: &str| tags.iter().find(|(tk, _)| *tk == k).map(|(_, tv)| *tv)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has(k: &str) -> Box<TagFilter> {
+        Box::new(TagFilter::Has(String::from(k)))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            TagFilter::from_str("a or b and c").unwrap(),
+            TagFilter::Or(has("a"), Box::new(TagFilter::And(has("b"), has("c"))))
+        );
+        assert_eq!(
+            TagFilter::from_str("not a and b").unwrap(),
+            TagFilter::And(Box::new(TagFilter::Not(has("a"))), has("b"))
+        );
+        assert_eq!(
+            TagFilter::from_str("(a or b) and c").unwrap(),
+            TagFilter::And(Box::new(TagFilter::Or(has("a"), has("b"))), has("c"))
+        );
+        assert_eq!(
+            TagFilter::from_str("a or b or c").unwrap(),
+            TagFilter::Or(Box::new(TagFilter::Or(has("a"), has("b"))), has("c"))
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(TagFilter::from_str("a=*").unwrap(), *has("a"));
+        assert_eq!(TagFilter::from_str("a!=*").unwrap(), TagFilter::Not(has("a")));
+        assert_eq!(
+            TagFilter::from_str("name=\"St Mary's\"").unwrap(),
+            TagFilter::Equals(String::from("name"), String::from("St Mary's"))
+        );
+        assert_eq!(
+            TagFilter::from_str("highway in (primary, 'secondary')").unwrap(),
+            TagFilter::In(String::from("highway"), vec![String::from("primary"), String::from("secondary")])
+        );
+
+        let f = TagFilter::from_str("tunnel!=yes").unwrap();
+        assert!(f.check(&[]));
+        assert!(!f.check(&[Tag::new(String::from("tunnel"), String::from("yes"))]));
+    }
+
+    #[test]
+    fn errors() {
+        for s in ["", "a !b", "name='x", "(a or b", "a in b", "a in (b c)", "a b", "a =", "a and"] {
+            assert!(TagFilter::from_str(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -3,7 +3,10 @@ use channelled_callbacks::{CallFinish, Callback, CallbackMerge, CallbackSync, Ca
 use osmquadtree::utils::ThreadTimer;
 use osmquadtree::pbfformat::{HeaderType, FileBlock, WriteFile, pack_file_block,ParallelFileLocs,CompressionType};
 
-use crate::{GeometryBlock,CallFinishGeometryBlock,Timings,OtherData, Error, Result};
+use crate::compression::Compression;
+use crate::mergesplit::dedup_sorted_block;
+use crate::pack_geometry::{pack_geometry_block_with_options, GeometryPackOptions};
+use crate::{GeometryBlock,GeometryHeader,CallFinishGeometryBlock,Timings,OtherData, Error, Result};
 use osmquadtree::elements::{Bbox,};
 use osmquadtree::message;
 
@@ -11,9 +14,9 @@ use std::sync::Arc;
 
 
 
-fn pack_geom(bl: GeometryBlock) -> Vec<(i64, Vec<u8>)> {
-    let p = bl.pack().expect("!");
-    let q = pack_file_block("OSMData", &p, &CompressionType::Zlib).expect("?");
+fn pack_geom(bl: GeometryBlock, opts: &GeometryPackOptions, compression: &Compression) -> Vec<(i64, Vec<u8>)> {
+    let p = pack_geometry_block_with_options(&bl, opts).expect("!");
+    let q = compression.pack_file_block("OSMData", &p).expect("?");
 
     vec![(bl.quadtree.as_int(), q)]
 }
@@ -39,17 +42,18 @@ impl CallFinish for WrapWriteFile {
     }
 }
 
-pub fn prep_write_geometry_pbffile(ofn: &str, bbox: &Bbox, numchan: usize) -> Result<CallFinishGeometryBlock> {
+pub fn prep_write_geometry_pbffile(ofn: &str, bbox: &Bbox, header: &GeometryHeader, numchan: usize) -> Result<CallFinishGeometryBlock> {
     
-    let wf = Box::new(WrapWriteFile(WriteFile::with_bbox(
+    let mut wf = Box::new(WrapWriteFile(WriteFile::with_bbox(
             &ofn,
             HeaderType::NoLocs,
             Some(&bbox),
         )));
+    wf.call(vec![(-1, header.pack_file_block()?)]);
                 
     if numchan == 0 {
-        
-        Ok(Box::new(CallAll::new(wf, "pack_geometry", Box::new(pack_geom))))
+        let (opts, compression) = (header.pack_options.clone(), header.compression);
+        Ok(Box::new(CallAll::new(wf, "pack_geometry", Box::new(move |bl| pack_geom(bl, &opts, &compression)))))
     } else {
         let wts = CallbackSync::new(wf,numchan);
         let mut pps: Vec<
@@ -57,10 +61,11 @@ pub fn prep_write_geometry_pbffile(ofn: &str, bbox: &Bbox, numchan: usize) -> Re
         > = Vec::new();
         for wt in wts {
             let wt2 = Box::new(ReplaceNoneWithTimings::new(wt));
+            let (opts, compression) = (header.pack_options.clone(), header.compression);
             pps.push(Box::new(Callback::new(Box::new(CallAll::new(
                 wt2,
                 "pack_geometry",
-                Box::new(pack_geom),
+                Box::new(move |bl| pack_geom(bl, &opts, &compression)),
             )))));
         }
         Ok(Box::new(CallbackMerge::new(pps, Box::new(MergeTimings::new()))))
@@ -74,6 +79,10 @@ fn prep_groups_from_filelocs(filelocs: &ParallelFileLocs, max_minzoom: &Option<i
     
     for p in &filelocs.1 {
         let q = p.0.clone();
+        if q.as_int() < 0 {
+            //header blocks of geometry pbffiles
+            continue;
+        }
         match max_minzoom {
             None => { groups.add(&q,1); },
             Some(m) => {
@@ -241,7 +250,7 @@ pub fn make_write_temp_geometry(outfn: &str, filelocs: &ParallelFileLocs, max_mi
 
 
 
-fn sort_temp_geoms(data: Vec<FileBlock>, groups: Arc<QuadtreeTree>) -> Result<Vec<(i64, Vec<u8>)>> {
+fn sort_temp_geoms(data: Vec<FileBlock>, groups: Arc<QuadtreeTree>, opts: &GeometryPackOptions, compression: &Compression, dedup: bool) -> Result<(Vec<(i64, Vec<u8>)>, usize)> {
     
     let mut blocks = SortBlocks::<GeometryBlock>::new(groups);
     
@@ -253,24 +262,36 @@ fn sort_temp_geoms(data: Vec<FileBlock>, groups: Arc<QuadtreeTree>) -> Result<Ve
     
 
     let mut res = Vec::new();
+    let mut dups = 0;
     for p in blocks.finish() {
-        res.extend(pack_geom(p))
+        let p = if dedup {
+            let (p, d) = dedup_sorted_block(p);
+            dups += d;
+            p
+        } else {
+            p
+        };
+        res.extend(pack_geom(p, opts, compression));
     }
-    Ok(res)
+    Ok((res, dups))
 }
 
 struct SortBlocksTempGeometry<T> {
     
     out: Box<T>,
     groups: Arc<QuadtreeTree>,
+    opts: GeometryPackOptions,
+    compression: Compression,
+    dedup: bool,
+    dups: usize,
     tm: f64
 }
     
 impl<T> SortBlocksTempGeometry<T>
     where T: CallFinish<CallType=Vec<(i64, Vec<u8>)>, ReturnType=Timings>
 {    
-    pub fn new(out: Box<T>, groups: Arc<QuadtreeTree>) -> SortBlocksTempGeometry<T> {
-        SortBlocksTempGeometry{out: out, groups: groups, tm:0.0}
+    pub fn new(out: Box<T>, groups: Arc<QuadtreeTree>, opts: GeometryPackOptions, compression: Compression, dedup: bool) -> SortBlocksTempGeometry<T> {
+        SortBlocksTempGeometry{out: out, groups: groups, opts: opts, compression: compression, dedup: dedup, dups: 0, tm:0.0}
     }
     
 }
@@ -284,7 +305,8 @@ impl<T> CallFinish for SortBlocksTempGeometry<T>
     
     fn call(&mut self, d: (i64,Vec<FileBlock>)) {
         let tx=ThreadTimer::new();
-        let res = sort_temp_geoms(d.1, self.groups.clone()).expect("?");
+        let (res, dups) = sort_temp_geoms(d.1, self.groups.clone(), &self.opts, &self.compression, self.dedup).expect("?");
+        self.dups += dups;
         self.tm += tx.since();
         self.out.call(res);
     }
@@ -293,21 +315,33 @@ impl<T> CallFinish for SortBlocksTempGeometry<T>
         
         let mut tt = self.out.finish()?;
         tt.add("CollectBlocksTempGeometry", self.tm);
+        if self.dedup {
+            tt.add_other("CollectBlocksTempGeometry", OtherData::Messages(vec![format!("dropped {} duplicate objects", self.dups)]));
+        }
         Ok(tt)
     }
 }
         
 
 
-pub fn write_temp_geometry(outfn: &str, bbox: &Bbox, tempdata: TempData, groups: Arc<QuadtreeTree>, numchan: usize) -> Result<()> {
-    let wf = Box::new(WrapWriteFile(WriteFile::with_bbox(
+pub fn write_temp_geometry(outfn: &str, bbox: &Bbox, header: &GeometryHeader, tempdata: TempData, groups: Arc<QuadtreeTree>, numchan: usize) -> Result<()> {
+    let t = write_temp_geometry_call(outfn, bbox, header, tempdata, groups, numchan, false)?;
+    message!("{}", t);
+    Ok(())
+}
+
+//with dedup, repeated objects in each sorted block are dropped, as when
+//merging files which overlap
+pub(crate) fn write_temp_geometry_call(outfn: &str, bbox: &Bbox, header: &GeometryHeader, tempdata: TempData, groups: Arc<QuadtreeTree>, numchan: usize, dedup: bool) -> Result<Timings> {
+    let mut wf = Box::new(WrapWriteFile(WriteFile::with_bbox(
             &outfn,
             HeaderType::ExternalLocs,
             Some(&bbox),
         )));
+    wf.call(vec![(-1, header.pack_file_block()?)]);
 
     let cq: Box<dyn CallFinish< CallType=(i64,Vec<FileBlock>), ReturnType=Timings, ErrorType=Error>> = if numchan == 0 {
-        Box::new(SortBlocksTempGeometry::new(wf, groups))
+        Box::new(SortBlocksTempGeometry::new(wf, groups, header.pack_options.clone(), header.compression, dedup))
     } else {
         let wfs = CallbackSync::new(wf, numchan);
         let mut cqs: Vec<
@@ -318,15 +352,14 @@ pub fn write_temp_geometry(outfn: &str, bbox: &Bbox, tempdata: TempData, groups:
             
             cqs.push(Box::new(Callback::new(Box::new(SortBlocksTempGeometry::new(
                 w2,
-                groups.clone()
+                groups.clone(),
+                header.pack_options.clone(),
+                header.compression,
+                dedup,
             )))));
         }
         Box::new(CallbackMerge::new(cqs, Box::new(MergeTimings::new())))
     };
     
-    let t = read_temp_data(tempdata, cq, true)?;
-    
-    message!("{}", t);
-    Ok(())
-    
+    read_temp_data(tempdata, cq, true)
 }
@@ -0,0 +1,213 @@
+use crate::{Error, Result};
+
+use osmquadtree::elements::Tag;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use std::fmt;
+
+//limits on each call, so a bad script can't hang processing
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 16;
+
+//a rhai script defining any of
+//    fn node(tags) { .. }
+//    fn way(tags, is_ring) { .. }
+//    fn relation(tags) { .. }
+//each given the object's tags as a map, and returning the new tags or ()
+//to drop the object. Scripts have no access to files or the network.
+pub struct TagTransform {
+    engine: Engine,
+    ast: AST,
+    has_node: bool,
+    has_way: bool,
+    has_relation: bool,
+}
+
+impl fmt::Debug for TagTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TagTransform[node={}, way={}, relation={}]",
+            self.has_node, self.has_way, self.has_relation
+        )
+    }
+}
+
+fn number(s: &str) -> Dynamic {
+    match s.trim().parse::<f64>() {
+        Ok(f) => Dynamic::from(f),
+        Err(_) => Dynamic::UNIT,
+    }
+}
+
+fn tags_to_map(tags: &[Tag]) -> Map {
+    let mut res = Map::new();
+    for t in tags {
+        res.insert(t.key.as_str().into(), Dynamic::from(t.val.clone()));
+    }
+    res
+}
+
+fn map_to_tags(name: &str, res: Dynamic) -> Result<Option<Vec<Tag>>> {
+    if res.is_unit() {
+        return Ok(None);
+    }
+    let type_name = res.type_name();
+    match res.try_cast::<Map>() {
+        None => Err(Error::InvalidDataError(format!(
+            "transform {} returned {}, expected a map or ()",
+            name, type_name
+        ))),
+        Some(m) => {
+            let mut tags = Vec::with_capacity(m.len());
+            for (k, v) in m {
+                if v.is_unit() {
+                    continue;
+                }
+                tags.push(Tag::new(k.to_string(), v.to_string()));
+            }
+            Ok(Some(tags))
+        }
+    }
+}
+
+impl TagTransform {
+    pub fn new(script: &str) -> Result<TagTransform> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.on_print(|_| {});
+        engine.on_debug(|_, _, _| {});
+        engine.register_fn("number", number);
+
+        let ast = engine
+            .compile(script)
+            .or_else(|e| Err(Error::UserSelectionError(format!("transform script: {}", e))))?;
+
+        let has_fn = |name: &str, nparams: usize| {
+            ast.iter_functions().any(|f| f.name == name && f.params.len() == nparams)
+        };
+        let has_node = has_fn("node", 1);
+        let has_way = has_fn("way", 2);
+        let has_relation = has_fn("relation", 1);
+        if !has_node && !has_way && !has_relation {
+            return Err(Error::UserSelectionError(String::from(
+                "transform script defines none of node(tags), way(tags, is_ring) or relation(tags)",
+            )));
+        }
+        Ok(TagTransform {
+            engine: engine,
+            ast: ast,
+            has_node: has_node,
+            has_way: has_way,
+            has_relation: has_relation,
+        })
+    }
+
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<Option<Vec<Tag>>> {
+        let res = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+            .or_else(|e| Err(Error::InvalidDataError(format!("transform {} failed: {}", name, e))))?;
+        map_to_tags(name, res)
+    }
+
+    //each returns Some(tags) unchanged if the hook isn't defined
+    pub fn node(&self, tags: &[Tag]) -> Result<Option<Vec<Tag>>> {
+        if !self.has_node {
+            return Ok(Some(tags.to_vec()));
+        }
+        self.call("node", (tags_to_map(tags),))
+    }
+
+    pub fn way(&self, tags: &[Tag], is_ring: bool) -> Result<Option<Vec<Tag>>> {
+        if !self.has_way {
+            return Ok(Some(tags.to_vec()));
+        }
+        self.call("way", (tags_to_map(tags), is_ring))
+    }
+
+    pub fn relation(&self, tags: &[Tag]) -> Result<Option<Vec<Tag>>> {
+        if !self.has_relation {
+            return Ok(Some(tags.to_vec()));
+        }
+        self.call("relation", (tags_to_map(tags),))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(kv: &[(&str, &str)]) -> Vec<Tag> {
+        kv.iter().map(|(k, v)| Tag::new(String::from(*k), String::from(*v))).collect()
+    }
+
+    const SCRIPT: &str = r#"
+        fn node(tags) {
+            if "name:en" in tags {
+                tags.name = tags["name:en"];
+            }
+            tags
+        }
+        fn way(tags, is_ring) {
+            if "highway" in tags && tags.highway == "footway" {
+                return ();
+            }
+            if "maxspeed" in tags {
+                let s = number(tags.maxspeed);
+                tags.maxspeed = if type_of(s) == "()" { () } else { (s * 2.0).to_int() };
+            }
+            tags.is_ring = if is_ring { "yes" } else { "no" };
+            tags
+        }
+    "#;
+
+    #[test]
+    fn node_hook() {
+        let tt = TagTransform::new(SCRIPT).unwrap();
+        let res = tt.node(&tags(&[("name", "Köln"), ("name:en", "Cologne")])).unwrap();
+        assert_eq!(res, Some(tags(&[("name", "Cologne"), ("name:en", "Cologne")])));
+    }
+
+    #[test]
+    fn way_hook() {
+        let tt = TagTransform::new(SCRIPT).unwrap();
+        let res = tt.way(&tags(&[("highway", "primary"), ("maxspeed", "30")]), false).unwrap();
+        assert_eq!(res, Some(tags(&[("highway", "primary"), ("is_ring", "no"), ("maxspeed", "60")])));
+
+        //unset values drop the tag
+        let res = tt.way(&tags(&[("building", "yes"), ("maxspeed", "fast")]), true).unwrap();
+        assert_eq!(res, Some(tags(&[("building", "yes"), ("is_ring", "yes")])));
+    }
+
+    #[test]
+    fn unit_drops_object() {
+        let tt = TagTransform::new(SCRIPT).unwrap();
+        assert_eq!(tt.way(&tags(&[("highway", "footway")]), false).unwrap(), None);
+    }
+
+    #[test]
+    fn missing_hook_keeps_tags() {
+        let tt = TagTransform::new(SCRIPT).unwrap();
+        let tgs = tags(&[("type", "multipolygon"), ("natural", "water")]);
+        assert_eq!(tt.relation(&tgs).unwrap(), Some(tgs));
+    }
+
+    #[test]
+    fn invalid_scripts() {
+        assert!(TagTransform::new("fn node(tags) { tags ").is_err());
+        assert!(TagTransform::new("fn other(tags) { tags }").is_err());
+        assert!(TagTransform::new("fn node(tags, extra) { tags }").is_err());
+    }
+
+    #[test]
+    fn failures_are_errors() {
+        let tt = TagTransform::new(r#"fn node(tags) { "name" }"#).unwrap();
+        let e = tt.node(&tags(&[("name", "x")])).unwrap_err();
+        assert!(e.to_string().contains("expected a map"));
+
+        let tt = TagTransform::new("fn node(tags) { loop { } }").unwrap();
+        assert!(tt.node(&tags(&[("name", "x")])).is_err());
+    }
+}
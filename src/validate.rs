@@ -0,0 +1,566 @@
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::elements::{Quadtree, Tag};
+use osmquadtree::utils::ThreadTimer;
+
+use crate::antimeridian::quadtree_bounds;
+use crate::elements::WithBounds;
+use crate::multipolygonerrors::MultiPolygonError;
+use crate::multipolygons::MAX_MP_ERRORS;
+use crate::position::{calc_ring_area, on_segment_interior, orient};
+use crate::ringbuilder::{assemble_rings, nest_rings, ring_within};
+use crate::{
+    ComplicatedPolygonGeometry, Error, LonLat, OtherData, PolygonPart, Result, RingPart,
+    SimplePolygonGeometry, Timings, WorkingBlock,
+};
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValidationIssue {
+    TooFewPoints,
+    DuplicatePoints,
+    SelfIntersection,
+    RingOrientation,
+    HoleOutsideShell,
+    OverlappingParts,
+}
+
+impl ValidationIssue {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidationIssue::TooFewPoints => "too_few_points",
+            ValidationIssue::DuplicatePoints => "duplicate_points",
+            ValidationIssue::SelfIntersection => "self_intersection",
+            ValidationIssue::RingOrientation => "ring_orientation",
+            ValidationIssue::HoleOutsideShell => "hole_outside_shell",
+            ValidationIssue::OverlappingParts => "overlapping_parts",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidateMode {
+    Repair,
+    Flag,
+}
+
+impl ValidateMode {
+    pub fn from_str(s: &str) -> Result<ValidateMode> {
+        match s {
+            "repair" => Ok(ValidateMode::Repair),
+            "flag" => Ok(ValidateMode::Flag),
+            _ => Err(Error::UserSelectionError(format!(
+                "unknown validate mode {}, expected repair or flag",
+                s
+            ))),
+        }
+    }
+}
+
+fn add_issue(issues: &mut Vec<ValidationIssue>, i: ValidationIssue) {
+    if !issues.contains(&i) {
+        issues.push(i);
+    }
+}
+
+fn check_ring_points(ll: &[LonLat], issues: &mut Vec<ValidationIssue>) {
+    if ll.len() < 4 || ll[0] != ll[ll.len() - 1] {
+        add_issue(issues, ValidationIssue::TooFewPoints);
+    }
+    for i in 1..ll.len() {
+        if ll[i] == ll[i - 1] {
+            add_issue(issues, ValidationIssue::DuplicatePoints);
+            return;
+        }
+    }
+}
+
+fn segments_conflict(
+    p1: &LonLat,
+    p2: &LonLat,
+    q1: &LonLat,
+    q2: &LonLat,
+    adjacent: bool,
+    same_ring: bool,
+) -> bool {
+    let o1 = orient(p1, p2, q1);
+    let o2 = orient(p1, p2, q2);
+    let o3 = orient(q1, q2, p1);
+    let o4 = orient(q1, q2, p2);
+    if o1 * o2 < 0 && o3 * o4 < 0 {
+        return true;
+    }
+    let touches = on_segment_interior(p1, p2, q1)
+        || on_segment_interior(p1, p2, q2)
+        || on_segment_interior(q1, q2, p1)
+        || on_segment_interior(q1, q2, p2);
+
+    if adjacent {
+        //adjacent segments which double back on themselves
+        return touches;
+    }
+    if same_ring {
+        return touches || p1 == q1 || p1 == q2 || p2 == q1 || p2 == q2;
+    }
+    //rings may touch at a point, but not share a segment
+    let same_segment = (p1 == q1 && p2 == q2) || (p1 == q2 && p2 == q1);
+    (touches || same_segment) && o1 == 0 && o2 == 0
+}
+
+//checks for segments crossing within or between rings
+pub fn rings_intersect(rings: &[&[LonLat]]) -> bool {
+    segments_intersect(rings, None)
+}
+
+//if groups is given, only segments from rings in different groups are compared
+fn segments_intersect(rings: &[&[LonLat]], groups: Option<&[usize]>) -> bool {
+    let mut segs = Vec::new();
+    for (r, ll) in rings.iter().enumerate() {
+        for i in 1..ll.len() {
+            if ll[i] == ll[i - 1] {
+                continue;
+            }
+            let minlon = i32::min(ll[i - 1].lon, ll[i].lon);
+            let maxlon = i32::max(ll[i - 1].lon, ll[i].lon);
+            segs.push((minlon, maxlon, r, i - 1));
+        }
+    }
+    segs.sort();
+
+    for x in 0..segs.len() {
+        let (_, maxlon, r, i) = segs[x];
+        let (p1, p2) = (&rings[r][i], &rings[r][i + 1]);
+        for y in x + 1..segs.len() {
+            let (minlon2, _, s, j) = segs[y];
+            if minlon2 > maxlon {
+                break;
+            }
+            if groups.map_or(false, |g| g[r] == g[s]) {
+                continue;
+            }
+            let (q1, q2) = (&rings[s][j], &rings[s][j + 1]);
+            if i32::max(p1.lat, p2.lat) < i32::min(q1.lat, q2.lat)
+                || i32::max(q1.lat, q2.lat) < i32::min(p1.lat, p2.lat)
+            {
+                continue;
+            }
+            let same_ring = r == s;
+            let nsegs = rings[r].len() - 1;
+            let adjacent = same_ring
+                && (i + 1 == j || j + 1 == i || (i == 0 && j == nsegs - 1) || (j == 0 && i == nsegs - 1));
+            if segments_conflict(p1, p2, q1, q2, adjacent, same_ring) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+pub fn validate_simple_polygon(sp: &SimplePolygonGeometry) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    check_ring_points(&sp.lonlats, &mut issues);
+    if issues.contains(&ValidationIssue::TooFewPoints) {
+        return issues;
+    }
+    if (calc_ring_area(&sp.lonlats) < 0.0) != sp.reversed {
+        add_issue(&mut issues, ValidationIssue::RingOrientation);
+    }
+    if rings_intersect(&[sp.lonlats.as_slice()]) {
+        add_issue(&mut issues, ValidationIssue::SelfIntersection);
+    }
+    issues
+}
+
+//returns the part's exterior and interior rings, if they could be read
+fn validate_polygon_part(
+    part: &PolygonPart,
+    issues: &mut Vec<ValidationIssue>,
+) -> Option<(Vec<LonLat>, Vec<Vec<LonLat>>)> {
+    let ext = match part.exterior.lonlats() {
+        Ok(e) => e,
+        Err(_) => {
+            add_issue(issues, ValidationIssue::TooFewPoints);
+            return None;
+        }
+    };
+    check_ring_points(&ext, issues);
+    if calc_ring_area(&ext) < 0.0 {
+        add_issue(issues, ValidationIssue::RingOrientation);
+    }
+
+    let mut ints = Vec::new();
+    for ii in &part.interiors {
+        match ii.lonlats() {
+            Ok(ll) => {
+                check_ring_points(&ll, issues);
+                if calc_ring_area(&ll) > 0.0 {
+                    add_issue(issues, ValidationIssue::RingOrientation);
+                }
+                if !ring_within(&ext, &ll) {
+                    add_issue(issues, ValidationIssue::HoleOutsideShell);
+                }
+                ints.push(ll);
+            }
+            Err(_) => {
+                add_issue(issues, ValidationIssue::TooFewPoints);
+            }
+        }
+    }
+
+    let mut rings: Vec<&[LonLat]> = vec![ext.as_slice()];
+    for ll in &ints {
+        rings.push(ll.as_slice());
+    }
+    if rings_intersect(&rings) {
+        add_issue(issues, ValidationIssue::SelfIntersection);
+    }
+    Some((ext, ints))
+}
+
+fn ring_box(ll: &[LonLat]) -> (i32, i32, i32, i32) {
+    let mut res = (i32::MAX, i32::MAX, i32::MIN, i32::MIN);
+    for l in ll {
+        res = (
+            i32::min(res.0, l.lon),
+            i32::min(res.1, l.lat),
+            i32::max(res.2, l.lon),
+            i32::max(res.3, l.lat),
+        );
+    }
+    res
+}
+
+fn box_contains(a: &(i32, i32, i32, i32), b: &(i32, i32, i32, i32)) -> bool {
+    a.0 <= b.0 && a.1 <= b.1 && a.2 >= b.2 && a.3 >= b.3
+}
+
+//separate parts may touch at a point, or one may sit in a hole of another,
+//but they may not cross, share a segment or lie one inside the other
+fn parts_overlap(parts: &[(Vec<LonLat>, Vec<Vec<LonLat>>)]) -> bool {
+    let mut rings: Vec<&[LonLat]> = Vec::new();
+    let mut groups = Vec::new();
+    for (i, (ext, ints)) in parts.iter().enumerate() {
+        rings.push(ext.as_slice());
+        groups.push(i);
+        for ll in ints {
+            rings.push(ll.as_slice());
+            groups.push(i);
+        }
+    }
+    if segments_intersect(&rings, Some(&groups)) {
+        return true;
+    }
+
+    let boxes: Vec<_> = parts.iter().map(|(ext, _)| ring_box(ext)).collect();
+    for (i, (ext, ints)) in parts.iter().enumerate() {
+        for (j, (other, _)) in parts.iter().enumerate() {
+            if i == j || !box_contains(&boxes[i], &boxes[j]) || !ring_within(ext, other) {
+                continue;
+            }
+            if !ints.iter().any(|ll| ring_within(ll, other)) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+pub fn validate_complicated_polygon(cp: &ComplicatedPolygonGeometry) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    if cp.parts.is_empty() {
+        add_issue(&mut issues, ValidationIssue::TooFewPoints);
+    }
+    let mut parts = Vec::with_capacity(cp.parts.len());
+    for p in &cp.parts {
+        if let Some(rings) = validate_polygon_part(p, &mut issues) {
+            parts.push(rings);
+        }
+    }
+    if parts.len() > 1 && parts_overlap(&parts) {
+        add_issue(&mut issues, ValidationIssue::OverlappingParts);
+    }
+    issues.sort();
+    issues
+}
+
+//returns false if the polygon can't be repaired as a single ring. If keep_largest is set,
+//the largest ring is kept instead. Points added where the ring crosses itself have no
+//node: their refs are synthetic negative ids (-1, -2, ...), numbered afresh for each
+//repair, so they are not unique between polygons.
+pub fn repair_simple_polygon(sp: &mut SimplePolygonGeometry, keep_largest: bool) -> bool {
+    let rp = RingPart::new(sp.id, false, sp.refs.clone(), sp.lonlats.clone());
+    let assembled = match assemble_rings(vec![rp], Vec::new()) {
+        Ok(a) => a,
+        Err(_) => {
+            return false;
+        }
+    };
+    let (mut parts, _) = nest_rings(assembled);
+    if parts.is_empty() {
+        return false;
+    }
+    if parts.len() > 1 || !parts[0].interiors.is_empty() {
+        if !keep_largest {
+            return false;
+        }
+    }
+    let part = parts.swap_remove(0);
+    let (refs, lonlats) = match (part.exterior.refs(), part.exterior.lonlats()) {
+        (Ok(r), Ok(l)) => (r, l),
+        _ => {
+            return false;
+        }
+    };
+    let area = calc_ring_area(&lonlats);
+    sp.refs = refs;
+    sp.lonlats = lonlats;
+    sp.reversed = area < 0.0;
+    sp.area = f64::abs(area);
+    true
+}
+
+pub fn repair_complicated_polygon(cp: &mut ComplicatedPolygonGeometry) -> bool {
+    let mut outer = Vec::new();
+    let mut inner = Vec::new();
+    for p in std::mem::take(&mut cp.parts) {
+        outer.extend(p.exterior.parts);
+        for ii in p.interiors {
+            inner.extend(ii.parts);
+        }
+    }
+    let assembled = match assemble_rings(outer, inner) {
+        Ok(a) => a,
+        Err(_) => {
+            return false;
+        }
+    };
+    let (parts, _) = nest_rings(assembled);
+    if parts.is_empty() {
+        return false;
+    }
+    cp.area = parts.iter().map(|p| p.area).sum();
+    cp.parts = parts;
+    true
+}
+
+fn flag_tag(issues: &[ValidationIssue]) -> Tag {
+    let vv: Vec<&str> = issues.iter().map(|i| i.as_str()).collect();
+    Tag::new(String::from("invalid"), vv.join(";"))
+}
+
+pub struct ValidateGeometries<T: ?Sized> {
+    out: Box<T>,
+    mode: ValidateMode,
+    tm: f64,
+    counts: BTreeMap<ValidationIssue, usize>,
+    num_repaired: usize,
+    num_reduced: usize,
+    num_dropped: usize,
+    num_invalid_after_repair: usize,
+    mp_errors: Option<Vec<MultiPolygonError>>,
+}
+
+impl<T> ValidateGeometries<T>
+where
+    T: CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error> + ?Sized,
+{
+    //with report_errors, invalid multipolygons are also returned as
+    //InvalidAfterAssembly errors, so they are only checked once
+    pub fn new(out: Box<T>, mode: ValidateMode, report_errors: bool) -> ValidateGeometries<T> {
+        ValidateGeometries {
+            out: out,
+            mode: mode,
+            tm: 0.0,
+            counts: BTreeMap::new(),
+            num_repaired: 0,
+            num_reduced: 0,
+            num_dropped: 0,
+            num_invalid_after_repair: 0,
+            mp_errors: if report_errors { Some(Vec::new()) } else { None },
+        }
+    }
+
+    fn count(&mut self, issues: &[ValidationIssue]) {
+        for i in issues {
+            *self.counts.entry(*i).or_insert(0) += 1;
+        }
+    }
+
+    fn process_block(&mut self, bl: &mut WorkingBlock) {
+        let mut sps = Vec::with_capacity(bl.geometry_block.simple_polygons.len());
+        for mut sp in std::mem::take(&mut bl.geometry_block.simple_polygons) {
+            let issues = validate_simple_polygon(&sp);
+            if issues.is_empty() {
+                sps.push(sp);
+                continue;
+            }
+            self.count(&issues);
+            match self.mode {
+                ValidateMode::Flag => {
+                    sp.tags.push(flag_tag(&issues));
+                    sps.push(sp);
+                }
+                ValidateMode::Repair => {
+                    if repair_simple_polygon(&mut sp, false) {
+                        self.num_repaired += 1;
+                    } else if repair_simple_polygon(&mut sp, true) {
+                        self.num_reduced += 1;
+                    } else {
+                        self.num_dropped += 1;
+                        continue;
+                    }
+                    //the repaired ring is checked again, and dropped if still invalid
+                    if !validate_simple_polygon(&sp).is_empty() {
+                        self.num_invalid_after_repair += 1;
+                        self.num_dropped += 1;
+                        continue;
+                    }
+                    sp.quadtree = Quadtree::calculate(&quadtree_bounds(&sp.bounds()), 18, 0.0);
+                    sps.push(sp);
+                }
+            }
+        }
+        bl.geometry_block.simple_polygons = sps;
+
+        let mut cps = Vec::with_capacity(bl.geometry_block.complicated_polygons.len());
+        for mut cp in std::mem::take(&mut bl.geometry_block.complicated_polygons) {
+            let issues = validate_complicated_polygon(&cp);
+            if issues.is_empty() {
+                cps.push(cp);
+                continue;
+            }
+            self.count(&issues);
+            if let Some(ee) = self.mp_errors.as_mut() {
+                if ee.len() < MAX_MP_ERRORS {
+                    ee.push(MultiPolygonError::invalid_after_assembly(&cp, &issues));
+                }
+            }
+            match self.mode {
+                ValidateMode::Flag => {
+                    cp.tags.push(flag_tag(&issues));
+                    cps.push(cp);
+                }
+                ValidateMode::Repair => {
+                    if !repair_complicated_polygon(&mut cp) {
+                        self.num_dropped += 1;
+                    } else if !validate_complicated_polygon(&cp).is_empty() {
+                        self.num_invalid_after_repair += 1;
+                        self.num_dropped += 1;
+                    } else {
+                        self.num_repaired += 1;
+                        cp.quadtree = Quadtree::calculate(&quadtree_bounds(&cp.bounds()), 18, 0.0);
+                        cps.push(cp);
+                    }
+                }
+            }
+        }
+        bl.geometry_block.complicated_polygons = cps;
+    }
+}
+
+impl<T> CallFinish for ValidateGeometries<T>
+where
+    T: CallFinish<CallType = WorkingBlock, ReturnType = Timings, ErrorType = Error> + ?Sized,
+{
+    type CallType = WorkingBlock;
+    type ReturnType = Timings;
+    type ErrorType = Error;
+
+    fn call(&mut self, mut bl: WorkingBlock) {
+        let tx = ThreadTimer::new();
+        self.process_block(&mut bl);
+        self.tm += tx.since();
+        self.out.call(bl);
+    }
+
+    fn finish(&mut self) -> ccResult<Timings, Error> {
+        let mut tms = self.out.finish()?;
+        tms.add("ValidateGeometries", self.tm);
+        let cc: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(k, v)| format!("{}: {}", k.as_str(), v))
+            .collect();
+        let mut msgs = vec![format!("invalid geometries [{}]", cc.join(", "))];
+        if self.mode == ValidateMode::Repair {
+            msgs.push(format!(
+                "repaired {}, reduced to largest ring {}, dropped {} ({} still invalid after repair)",
+                self.num_repaired, self.num_reduced, self.num_dropped, self.num_invalid_after_repair
+            ));
+        }
+        tms.add_other("ValidateGeometries", OtherData::Messages(msgs));
+        if let Some(ee) = self.mp_errors.take() {
+            tms.add_other("ValidateGeometries", OtherData::MultiPolygonErrors(ee));
+        }
+        Ok(tms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(pts: &[(i32, i32)]) -> Vec<LonLat> {
+        pts.iter().map(|(x, y)| LonLat::new(*x, *y)).collect()
+    }
+
+    #[test]
+    fn rings_sharing_a_segment_intersect() {
+        let ext = ring(&[(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)]);
+        let hole = ring(&[(0, 10), (5, 5), (0, 0), (0, 10)]);
+        assert!(rings_intersect(&[ext.as_slice(), hole.as_slice()]));
+
+        let hole = ring(&[(0, 10), (5, 5), (0, 5), (0, 10)]);
+        assert!(rings_intersect(&[ext.as_slice(), hole.as_slice()]));
+
+        let hole = ring(&[(2, 2), (2, 8), (8, 8), (2, 2)]);
+        assert!(!rings_intersect(&[ext.as_slice(), hole.as_slice()]));
+    }
+
+    fn square(x0: i32, y0: i32, x1: i32, y1: i32) -> Vec<LonLat> {
+        ring(&[(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)])
+    }
+
+    #[test]
+    fn validate_mode_from_str() {
+        assert_eq!(ValidateMode::from_str("repair").unwrap(), ValidateMode::Repair);
+        assert_eq!(ValidateMode::from_str("flag").unwrap(), ValidateMode::Flag);
+        assert!(ValidateMode::from_str("fix").is_err());
+    }
+
+    #[test]
+    fn separate_parts_overlap() {
+        let a = (square(0, 0, 10, 10), Vec::new());
+        let crossing = (square(5, 5, 15, 15), Vec::new());
+        let disjoint = (square(20, 0, 30, 10), Vec::new());
+        let corner = (square(10, 10, 20, 20), Vec::new());
+        let shared_edge = (square(10, 0, 20, 10), Vec::new());
+        let inside = (square(2, 2, 8, 8), Vec::new());
+
+        assert!(parts_overlap(&[a.clone(), crossing]));
+        assert!(!parts_overlap(&[a.clone(), disjoint]));
+        assert!(!parts_overlap(&[a.clone(), corner]));
+        assert!(parts_overlap(&[a.clone(), shared_edge]));
+        assert!(parts_overlap(&[a.clone(), inside.clone()]));
+        assert!(parts_overlap(&[inside, a]));
+    }
+
+    #[test]
+    fn island_in_hole_does_not_overlap() {
+        let mut hole = square(2, 2, 8, 8);
+        hole.reverse();
+        let lake = (square(0, 0, 10, 10), vec![hole]);
+        let island = (square(4, 4, 6, 6), Vec::new());
+        assert!(!parts_overlap(&[lake.clone(), island]));
+
+        let across_shore = (square(1, 4, 6, 6), Vec::new());
+        assert!(parts_overlap(&[lake, across_shore]));
+    }
+
+    #[test]
+    fn hole_on_shell_boundary_is_outside() {
+        let ext = ring(&[(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)]);
+        assert!(!ring_within(&ext, &ext));
+        assert!(ring_within(&ext, &ring(&[(0, 0), (5, 5), (0, 10), (0, 0)])));
+    }
+}
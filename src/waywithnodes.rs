@@ -130,12 +130,7 @@ impl std::fmt::Display for Locations {
 }
 
 fn node_has_tag(style: &GeometryStyle, n: &Node) -> bool {
-    for t in &n.tags {
-        if style.feature_keys.contains(&t.key) {
-            return true;
-        }
-    }
-    false
+    style.has_feature_key(&n.tags)
 }
 
 pub struct CollectWayNodes<T: ?Sized> {